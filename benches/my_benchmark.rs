@@ -1,12 +1,18 @@
 use std::sync::Arc;
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use raytracing::bvh::bvh::BVH;
 use raytracing::camera::Camera;
+use raytracing::common::{random_double, random_double_range};
+use raytracing::cube::Cube;
 use raytracing::hittable::{Hittable, HittableList};
 use raytracing::interval::Interval;
-use raytracing::material::{Dielectric, Lambertian, Material, Metal};
+use raytracing::material::{Dielectric, Lambertian, Metal};
 use raytracing::rays::Ray;
+use raytracing::rotation::{AxisRotation, Rotation};
 use raytracing::sphere::Sphere;
+use raytracing::textures::ConstantTexture;
+use raytracing::translate::Translate;
 use raytracing::vec3::{reflect, reflectance, refract, Vec3};
 
 pub fn bench_vec3_add(c: &mut Criterion) {
@@ -117,7 +123,7 @@ pub fn bench_refraction(c: &mut Criterion) {
 }
 
 pub fn bench_ray_at(c: &mut Criterion) {
-    let r = Ray::new(Vec3::new(1.0, 2.0, 3.0), Vec3::new(4.0, 5.0, 6.0));
+    let r = Ray::new(Vec3::new(1.0, 2.0, 3.0), Vec3::new(4.0, 5.0, 6.0), 0.0);
     c.bench_function("Ray at", |b| {
         b.iter(|| {
             black_box(r.at(7.0));
@@ -126,11 +132,14 @@ pub fn bench_ray_at(c: &mut Criterion) {
 }
 
 pub fn bench_colision_sphere(c: &mut Criterion) {
-    let r = Ray::new(Vec3::new(1.0, 2.0, 3.0), Vec3::new(4.0, 5.0, 6.0));
+    let r = Ray::new(Vec3::new(1.0, 2.0, 3.0), Vec3::new(4.0, 5.0, 6.0), 0.0);
     let s = Sphere::new(
         Vec3::new(1.0, 2.0, 3.0),
+        None,
         4.0,
-        Arc::new(Lambertian::new(Vec3::new(1.0, 1.0, 1.0))),
+        Arc::new(Lambertian::new(ConstantTexture::new(Vec3::new(
+            1.0, 1.0, 1.0,
+        )))),
     );
     c.bench_function("Colision sphere", |b| {
         b.iter(|| {
@@ -140,16 +149,22 @@ pub fn bench_colision_sphere(c: &mut Criterion) {
 }
 
 pub fn bench_colision_list(c: &mut Criterion) {
-    let r = Ray::new(Vec3::new(1.0, 2.0, 3.0), Vec3::new(4.0, 5.0, 6.0));
+    let r = Ray::new(Vec3::new(1.0, 2.0, 3.0), Vec3::new(4.0, 5.0, 6.0), 0.0);
     let s1 = Sphere::new(
         Vec3::new(1.0, 2.0, 3.0),
+        None,
         4.0,
-        Arc::new(Lambertian::new(Vec3::new(1.0, 1.0, 1.0))),
+        Arc::new(Lambertian::new(ConstantTexture::new(Vec3::new(
+            1.0, 1.0, 1.0,
+        )))),
     );
     let s2 = Sphere::new(
         Vec3::new(1.0, 2.0, 3.0),
+        None,
         4.0,
-        Arc::new(Lambertian::new(Vec3::new(1.0, 1.0, 1.0))),
+        Arc::new(Lambertian::new(ConstantTexture::new(Vec3::new(
+            1.0, 1.0, 1.0,
+        )))),
     );
     let mut list = HittableList::new();
     list.add(Box::new(s1));
@@ -177,21 +192,29 @@ fn simple_scene() -> HittableList {
     let mut world = HittableList::new();
     world.add(Box::new(Sphere::new(
         Vec3::new(0.0, 0.0, -1.0),
+        None,
         0.5,
-        Arc::new(Lambertian::new(Vec3::new(0.1, 0.2, 0.5))),
+        Arc::new(Lambertian::new(ConstantTexture::new(Vec3::new(
+            0.1, 0.2, 0.5,
+        )))),
     )));
     world.add(Box::new(Sphere::new(
         Vec3::new(0.0, -100.5, -1.0),
+        None,
         100.0,
-        Arc::new(Lambertian::new(Vec3::new(0.8, 0.8, 0.0))),
+        Arc::new(Lambertian::new(ConstantTexture::new(Vec3::new(
+            0.8, 0.8, 0.0,
+        )))),
     )));
     world.add(Box::new(Sphere::new(
         Vec3::new(1.0, 0.0, -1.0),
+        None,
         0.5,
         Arc::new(Metal::new(Vec3::new(0.8, 0.6, 0.2), 0.0)),
     )));
     world.add(Box::new(Sphere::new(
         Vec3::new(-1.0, 0.0, -1.0),
+        None,
         0.5,
         Arc::new(Dielectric::new(1.5)),
     )));
@@ -210,11 +233,11 @@ pub fn bench_camera(c: &mut Criterion) {
     camera.set_lookat(lookat);
     camera.set_lookfrom(lookfrom);
     camera.set_vup(vup);
-    camera.set_vertical_fov(vfov);
-    camera.set_aspect_ratio(aspect_ratio);
+    camera.set_vertical_fov(vfov).unwrap();
+    camera.set_aspect_ratio(aspect_ratio).unwrap();
     camera.set_defocus_angle(aperture);
-    camera.set_focus_distance(focus_dist);
-    let world = simple_scene();
+    camera.set_focus_distance(focus_dist).unwrap();
+    let world: Box<dyn Hittable> = Box::new(simple_scene());
     c.bench_function("Camera render", |b| {
         b.iter(|| {
             black_box(camera.render(&world, "test.ppm".to_string()));
@@ -222,6 +245,120 @@ pub fn bench_camera(c: &mut Criterion) {
     });
 }
 
+/// Geometry of the book's final scene: the ground made of boxes, the cluster of 1000 spheres and
+/// a few big spheres, all inside a BVH
+fn final_scene_geometry() -> BVH {
+    let white = Arc::new(Lambertian::new(ConstantTexture::new(Vec3::new(
+        0.73, 0.73, 0.73,
+    ))));
+    let mut boxes1 = HittableList::new();
+    for i in 0..20 {
+        for j in 0..20 {
+            let w = 100.0;
+            let x0 = -1000.0 + i as f64 * w;
+            let z0 = -1000.0 + j as f64 * w;
+            boxes1.add(Box::new(Cube::new(
+                Vec3::new(x0, 0.0, z0),
+                Vec3::new(x0 + w, random_double_range(1.0, 101.0), z0 + w),
+                white.clone(),
+            )));
+        }
+    }
+    let mut boxes2 = HittableList::new();
+    for _ in 0..1000 {
+        boxes2.add(Box::new(Sphere::new(
+            Vec3::random_range(0.0, 165.0),
+            None,
+            10.0,
+            white.clone(),
+        )));
+    }
+    let mut world = HittableList::new();
+    world.add(Box::new(boxes1.into_bvh(&Interval::new(0.0, 1.0))));
+    world.add(Box::new(Translate::new(
+        Rotation::new(
+            boxes2.into_bvh(&Interval::new(0.0, 1.0)),
+            AxisRotation::Yaxis,
+            15.0,
+        ),
+        Vec3::new(-100.0, 270.0, 395.0),
+    )));
+    world.add(Box::new(Sphere::new(
+        Vec3::new(220.0, 280.0, 300.0),
+        None,
+        80.0,
+        white.clone(),
+    )));
+    world.add(Box::new(Sphere::new(
+        Vec3::new(400.0, 200.0, 400.0),
+        None,
+        100.0,
+        white,
+    )));
+    world.into_bvh(&Interval::new(0.0, 1.0))
+}
+
+pub fn bench_bvh_traversal(c: &mut Criterion) {
+    let world = final_scene_geometry();
+    let lookfrom = Vec3::new(478.0, 278.0, -600.0);
+    let rays: Vec<Ray> = (0..1024)
+        .map(|_| {
+            let target = Vec3::new(
+                random_double_range(-200.0, 700.0),
+                random_double_range(0.0, 600.0),
+                random_double_range(0.0, 600.0),
+            );
+            Ray::new(lookfrom, target - lookfrom, random_double())
+        })
+        .collect();
+    c.bench_function("BVH traversal final scene", |b| {
+        b.iter(|| {
+            for ray in &rays {
+                black_box(world.hit(ray, &Interval::new(0.001, f64::INFINITY)));
+            }
+        })
+    });
+}
+
+/// The cluster of 1000 spheres of the final scene, through a BVH and through a uniform grid
+pub fn bench_grid_traversal(c: &mut Criterion) {
+    let white = Arc::new(Lambertian::new(ConstantTexture::new(Vec3::new(
+        0.73, 0.73, 0.73,
+    ))));
+    let cluster = || {
+        let mut spheres = HittableList::new();
+        for _ in 0..1000 {
+            spheres.add(Box::new(Sphere::new(
+                Vec3::random_range(0.0, 165.0),
+                None,
+                10.0,
+                white.clone(),
+            )));
+        }
+        spheres
+    };
+    let bvh = cluster().into_bvh(&Interval::new(0.0, 1.0));
+    let grid = cluster().into_grid(&Interval::new(0.0, 1.0), 16);
+    let lookfrom = Vec3::new(-200.0, 80.0, -300.0);
+    let rays: Vec<Ray> = (0..1024)
+        .map(|_| Ray::new(lookfrom, Vec3::random_range(0.0, 165.0) - lookfrom, 0.0))
+        .collect();
+    c.bench_function("BVH traversal sphere cluster", |b| {
+        b.iter(|| {
+            for ray in &rays {
+                black_box(bvh.hit(ray, &Interval::new(0.001, f64::INFINITY)));
+            }
+        })
+    });
+    c.bench_function("Grid traversal sphere cluster", |b| {
+        b.iter(|| {
+            for ray in &rays {
+                black_box(grid.hit(ray, &Interval::new(0.001, f64::INFINITY)));
+            }
+        })
+    });
+}
+
 pub fn fn_bench_all(c: &mut Criterion) {
     bench_vec3_add(c);
     bench_vec3_sub(c);
@@ -237,6 +374,8 @@ pub fn fn_bench_all(c: &mut Criterion) {
     bench_ray_at(c);
     bench_colision_sphere(c);
     bench_colision_list(c);
+    bench_bvh_traversal(c);
+    bench_grid_traversal(c);
     bench_metal_reflectance(c);
     bench_camera(c);
 }
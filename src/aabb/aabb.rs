@@ -1,7 +1,7 @@
 use std::{error::Error, fmt::Display};
 
 use crate::{
-    interval::Interval,
+    interval::{Interval, EMPTY},
     rays::Ray,
     vec3::{Point3, Vec3},
 };
@@ -39,32 +39,29 @@ impl AABB {
     }
     pub fn from_points(a: Point3, b: Point3) -> Self {
         AABB {
-            x: if a.x() <= b.x() {
-                Interval::new(a.x(), b.x())
-            } else {
-                Interval::new(b.x(), a.x())
-            },
-            y: if a.y() <= b.y() {
-                Interval::new(a.y(), b.y())
-            } else {
-                Interval::new(b.y(), a.y())
-            },
-            z: if a.z() <= b.z() {
-                Interval::new(a.z(), b.z())
-            } else {
-                Interval::new(b.z(), a.z())
-            },
+            x: Interval::new_ordered(a.x(), b.x()),
+            y: Interval::new_ordered(a.y(), b.y()),
+            z: Interval::new_ordered(a.z(), b.z()),
         }
     }
+    /// Box that contains nothing, to grow with `grow_to_include`. Every interval is empty, with
+    /// the minimum above the maximum
+    pub fn empty() -> Self {
+        AABB::new(EMPTY, EMPTY, EMPTY)
+    }
+    /// Grows the box in place just enough to contain `point`
+    pub fn grow_to_include(&mut self, point: Point3) {
+        self.grow_to_include_box(&AABB::from_points(point, point));
+    }
+    /// Grows the box in place just enough to contain `other`
+    pub fn grow_to_include_box(&mut self, other: &AABB) {
+        self.x = Interval::from_intervals(self.x, other.x);
+        self.y = Interval::from_intervals(self.y, other.y);
+        self.z = Interval::from_intervals(self.z, other.z);
+    }
+    /// Smallest box containing both boxes, the same as `surrounding_box`
     pub fn from_bbox(a: &AABB, b: &AABB) -> Self {
-        let x = if a.x <= b.x {
-            Interval::from_intervals(a.x, b.x)
-        } else {
-            Interval::from_intervals(b.x, a.x)
-        };
-        let y = Interval::from_intervals(a.y, b.y);
-        let z = Interval::from_intervals(a.z, b.z);
-        AABB { x, y, z }
+        surrounding_box(a, b)
     }
     pub fn axis_interval(&self, axis: usize) -> Result<Interval, AABBErrorKind> {
         match axis {
@@ -78,14 +75,19 @@ impl AABB {
     pub fn hit(&self, ray: &Ray, time_interval: &Interval) -> bool {
         let mut t_min = time_interval.min();
         let mut t_max = time_interval.max();
-        for i in 0..3 {
-            let axis = self.axis_interval(i).unwrap();
-            let inv_d = 1.0 / ray.direction().as_array()[i];
-            let mut t0 = (axis.min() - ray.origin().as_array()[i]) * inv_d;
-            let mut t1 = (axis.max() - ray.origin().as_array()[i]) * inv_d;
-            if inv_d < 0.0 {
-                std::mem::swap(&mut t0, &mut t1);
-            }
+        let origin = ray.origin();
+        let inv_direction = ray.inv_direction();
+        let sign = ray.sign();
+        for (i, axis) in [self.x, self.y, self.z].iter().enumerate() {
+            let inv_d = inv_direction.axis(i);
+            // A negative direction enters through the max side of the slab
+            let (near, far) = if sign[i] {
+                (axis.max(), axis.min())
+            } else {
+                (axis.min(), axis.max())
+            };
+            let t0 = (near - origin.axis(i)) * inv_d;
+            let t1 = (far - origin.axis(i)) * inv_d;
             t_min = t_min.max(t0);
             t_max = t_max.min(t1);
             if t_max <= t_min {
@@ -100,6 +102,10 @@ impl AABB {
     pub fn max(&self) -> Point3 {
         Point3::new(self.x.max(), self.y.max(), self.z.max())
     }
+    /// Same box moved by `offset`
+    pub fn offset(&self, offset: Vec3) -> AABB {
+        AABB::from_points(self.min() + offset, self.max() + offset)
+    }
     pub fn set_min(&mut self, offset: Vec3) {
         self.x += offset.axis(0);
         self.y += offset.axis(1);
@@ -111,6 +117,11 @@ impl AABB {
         self.y += offset.axis(1);
         self.z += offset.axis(2);
     }
+    /// Area of the six faces of the box
+    pub fn surface_area(&self) -> f64 {
+        let (dx, dy, dz) = (self.x.size(), self.y.size(), self.z.size());
+        2.0 * (dx * dy + dy * dz + dz * dx)
+    }
     pub fn pad_to_minimum(&mut self, delta: f64) {
         if self.x.size() < delta {
             self.x.expand_inplace(delta)
@@ -143,6 +154,7 @@ pub fn surrounding_box(box0: &AABB, box1: &AABB) -> AABB {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::common::seed_random;
     use crate::rays::Ray;
     use crate::vec3::{Point3, Vec3};
 
@@ -164,7 +176,7 @@ mod tests {
             Interval::new(0.0, 1.0),
             Interval::new(0.0, 1.0),
         );
-        let ray = Ray::new(Point3::new(0.5, 0.5, 0.5), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let ray = Ray::new(Point3::new(0.5, 0.5, 1.5), Vec3::new(0.0, 0.0, 1.0), 0.0);
         let time_interval = Interval::new(0.0, 1.0);
         assert_eq!(aabb.hit(&ray, &time_interval), false);
     }
@@ -184,4 +196,53 @@ mod tests {
         assert_eq!(surrounding.min(), Point3::new(0.0, 0.0, 0.0));
         assert_eq!(surrounding.max(), Point3::new(2.0, 2.0, 2.0));
     }
+    #[test]
+    fn test_aabb_grow() {
+        let mut aabb = AABB::empty();
+        let ray = Ray::new(Point3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert!(!aabb.hit(&ray, &Interval::new(0.0, 10.0)));
+
+        aabb.grow_to_include(Point3::new(1.0, -2.0, 0.5));
+        assert_eq!(aabb.min(), aabb.max());
+        for point in [
+            Point3::new(-1.0, 3.0, 0.0),
+            Point3::new(0.0, 0.0, 4.0),
+            Point3::new(0.5, 1.0, 2.0),
+        ] {
+            aabb.grow_to_include(point);
+        }
+        assert_eq!(aabb.min(), Point3::new(-1.0, -2.0, 0.0));
+        assert_eq!(aabb.max(), Point3::new(1.0, 3.0, 4.0));
+
+        aabb.grow_to_include_box(&AABB::from_points(
+            Point3::new(0.0, 0.0, -1.0),
+            Point3::new(5.0, 0.0, 0.0),
+        ));
+        assert_eq!(aabb.min(), Point3::new(-1.0, -2.0, -1.0));
+        assert_eq!(aabb.max(), Point3::new(5.0, 3.0, 4.0));
+    }
+    #[test]
+    fn from_bbox_matches_surrounding_box() {
+        seed_random(3);
+        let random_box = || {
+            AABB::from_points(
+                Vec3::random_range(-10.0, 10.0),
+                Vec3::random_range(-10.0, 10.0),
+            )
+        };
+        for _ in 0..100 {
+            let (a, b) = (random_box(), random_box());
+            let merged = AABB::from_bbox(&a, &b);
+            assert_eq!(merged, surrounding_box(&a, &b));
+            assert_eq!(merged, AABB::from_bbox(&b, &a));
+            for corner in [a.min(), a.max(), b.min(), b.max()] {
+                for axis in 0..3 {
+                    assert!(merged
+                        .axis_interval(axis)
+                        .unwrap()
+                        .contains(corner.axis(axis)));
+                }
+            }
+        }
+    }
 }
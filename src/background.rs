@@ -0,0 +1,326 @@
+use crate::{
+    color::Color,
+    common::{random_double, PI},
+    rays::Ray,
+    vec3::{from_spherical, Vec3},
+};
+
+/// Radiance seen by rays that miss every object of the scene
+pub trait Background: Send + Sync {
+    fn value(&self, ray: &Ray) -> Color;
+    /// Samples a direction towards the background together with its density over solid angle,
+    /// for backgrounds that can be importance sampled as a light
+    fn sample_direction(&self) -> Option<(Vec3, f64)> {
+        None
+    }
+    /// Density with which `sample_direction` picks `direction`
+    fn pdf_value(&self, _direction: &Vec3) -> f64 {
+        0.0
+    }
+}
+
+/// A flat color background, the same for every direction
+impl Background for Color {
+    fn value(&self, _ray: &Ray) -> Color {
+        *self
+    }
+}
+
+/// Analytic daylight sky from Preetham et al., "A Practical Analytic Model for Daylight". The
+/// sky is defined by the direction towards the sun, with +y as the zenith, and the turbidity of
+/// the atmosphere, from 2 (clear) to 10 (hazy). The radiance is scaled so the zenith has the
+/// given intensity, and a bright disk is added around the sun direction
+pub struct SkyBackground {
+    sun_direction: Vec3,
+    intensity: f64,
+    sun_cos_radius: f64,
+    sun_intensity: f64,
+    perez_y: [f64; 5],
+    perez_x: [f64; 5],
+    perez_yy: [f64; 5],
+    zenith: (f64, f64),
+    normalization: [f64; 3],
+}
+
+impl SkyBackground {
+    pub fn new(sun_direction: Vec3, turbidity: f64) -> Self {
+        let sun_direction = sun_direction.normalize();
+        let t = turbidity;
+        let perez_y = [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ];
+        let perez_x = [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ];
+        let perez_yy = [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ];
+        // Chromaticity of the zenith as a function of the sun zenith angle
+        let theta_s = f64::acos(sun_direction.y().clamp(-1.0, 1.0)).min(PI / 2.0);
+        let (t1, t2, t3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith_x = t * t * (0.00166 * t3 - 0.00375 * t2 + 0.00209 * t1)
+            + t * (-0.02903 * t3 + 0.06377 * t2 - 0.03202 * t1 + 0.00394)
+            + (0.11693 * t3 - 0.21196 * t2 + 0.06052 * t1 + 0.25886);
+        let zenith_y = t * t * (0.00275 * t3 - 0.00610 * t2 + 0.00317 * t1)
+            + t * (-0.04214 * t3 + 0.08970 * t2 - 0.04153 * t1 + 0.00516)
+            + (0.15346 * t3 - 0.26756 * t2 + 0.06670 * t1 + 0.26688);
+        let normalization = [
+            perez(&perez_y, 0.0, theta_s),
+            perez(&perez_x, 0.0, theta_s),
+            perez(&perez_yy, 0.0, theta_s),
+        ];
+        SkyBackground {
+            sun_direction,
+            intensity: 1.0,
+            sun_cos_radius: f64::cos(0.5 * PI / 180.0),
+            sun_intensity: 100.0,
+            perez_y,
+            perez_x,
+            perez_yy,
+            zenith: (zenith_x, zenith_y),
+            normalization,
+        }
+    }
+    /// Sets the luminance of the sky at the zenith
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+    /// Sets the angular radius, in degrees, and the luminance of the sun disk
+    pub fn with_sun(mut self, angular_radius: f64, intensity: f64) -> Self {
+        self.sun_cos_radius = f64::cos(angular_radius * PI / 180.0);
+        self.sun_intensity = intensity;
+        self
+    }
+    pub fn sun_direction(&self) -> Vec3 {
+        self.sun_direction
+    }
+}
+
+/// Perez distribution of the sky for the zenith angle `theta` and the angle `gamma` between the
+/// view and the sun directions
+fn perez(coefficients: &[f64; 5], theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coefficients;
+    // Directions below the horizon take the radiance of the horizon
+    let cos_theta = f64::cos(theta).max(0.01);
+    let cos_gamma = f64::cos(gamma);
+    (1.0 + a * f64::exp(b / cos_theta))
+        * (1.0 + c * f64::exp(d * gamma) + e * cos_gamma * cos_gamma)
+}
+
+/// Converts CIE xyY to linear sRGB
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Color {
+    let big_x = x * luminance / y;
+    let big_z = (1.0 - x - y) * luminance / y;
+    Color::new(
+        (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0),
+        (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0),
+        (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0),
+    )
+}
+
+impl Background for SkyBackground {
+    fn value(&self, ray: &Ray) -> Color {
+        let direction = ray.direction().normalize();
+        let theta = f64::acos(direction.y().clamp(-1.0, 1.0));
+        let cos_gamma = direction.dot_product(&self.sun_direction).clamp(-1.0, 1.0);
+        let gamma = f64::acos(cos_gamma);
+        let luminance = self.intensity * perez(&self.perez_y, theta, gamma) / self.normalization[0];
+        let x = self.zenith.0 * perez(&self.perez_x, theta, gamma) / self.normalization[1];
+        let y = self.zenith.1 * perez(&self.perez_yy, theta, gamma) / self.normalization[2];
+        let sky = xyy_to_rgb(x, y, luminance);
+        if cos_gamma >= self.sun_cos_radius {
+            sky + self.sun_intensity * self.intensity * Color::new(1.0, 1.0, 1.0)
+        } else {
+            sky
+        }
+    }
+}
+
+/// Equirectangular environment map of linear colors, which can be brighter than 1 like an HDRI.
+/// The first row is the top of the sky, +y, and the columns follow the longitude with the same
+/// convention as the sphere texture coordinates. Directions are importance sampled according to
+/// the luminance of the texels, so bright regions like the sun get most of the samples
+pub struct EnvironmentMap {
+    pixels: Vec<Color>,
+    width: usize,
+    height: usize,
+    /// Probability of picking each texel, row by row
+    texel_probability: Vec<f64>,
+    /// Cumulative distribution of picking each row
+    row_cdf: Vec<f64>,
+    /// Cumulative distribution of picking each column, given the row
+    column_cdf: Vec<f64>,
+}
+
+impl EnvironmentMap {
+    pub fn new(pixels: Vec<Color>, width: usize, height: usize) -> Self {
+        assert_eq!(pixels.len(), width * height, "Wrong number of pixels");
+        let row_sin = |j: usize| f64::sin(PI * (j as f64 + 0.5) / height as f64);
+        // Texels near the poles cover a smaller solid angle
+        let mut weights: Vec<f64> = pixels
+            .iter()
+            .enumerate()
+            .map(|(index, color)| color.luminance().max(0.0) * row_sin(index / width))
+            .collect();
+        if weights.iter().sum::<f64>() <= 0.0 {
+            weights = (0..pixels.len())
+                .map(|index| row_sin(index / width))
+                .collect();
+        }
+        let total: f64 = weights.iter().sum();
+        let texel_probability: Vec<f64> = weights.iter().map(|weight| weight / total).collect();
+
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut column_cdf = Vec::with_capacity(width * height);
+        let mut accumulated = 0.0;
+        for row in texel_probability.chunks(width) {
+            let row_probability: f64 = row.iter().sum();
+            accumulated += row_probability;
+            row_cdf.push(accumulated);
+            let mut row_accumulated = 0.0;
+            for probability in row {
+                row_accumulated += probability;
+                column_cdf.push(if row_probability > 0.0 {
+                    row_accumulated / row_probability
+                } else {
+                    0.0
+                });
+            }
+        }
+        EnvironmentMap {
+            pixels,
+            width,
+            height,
+            texel_probability,
+            row_cdf,
+            column_cdf,
+        }
+    }
+    /// Samples a direction with a density proportional to the luminance of the map
+    pub fn sample(&self) -> (Vec3, f64) {
+        let row = pick(&self.row_cdf, random_double());
+        let columns = &self.column_cdf[row * self.width..(row + 1) * self.width];
+        let column = pick(columns, random_double());
+        let theta = PI * (row as f64 + random_double()) / self.height as f64;
+        let phi = 2.0 * PI * (column as f64 + random_double()) / self.width as f64;
+        // The rows go down from +y, the other way around than the spherical coordinates
+        let direction = from_spherical(PI - theta, phi);
+        (direction, self.pdf(&direction))
+    }
+    /// Density over solid angle with which `sample` picks `direction`
+    pub fn pdf(&self, direction: &Vec3) -> f64 {
+        let (column, row, sin_theta) = self.texel(direction);
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let texel_solid_angle = 2.0 * PI * PI * sin_theta / (self.width * self.height) as f64;
+        self.texel_probability[row * self.width + column] / texel_solid_angle
+    }
+    /// Column and row of the texel seen in `direction`, and the sine of its polar angle
+    fn texel(&self, direction: &Vec3) -> (usize, usize, f64) {
+        let (theta, phi) = direction.normalize().to_spherical();
+        let theta = PI - theta;
+        let column = ((phi / (2.0 * PI) * self.width as f64) as usize).min(self.width - 1);
+        let row = ((theta / PI * self.height as f64) as usize).min(self.height - 1);
+        (column, row, theta.sin())
+    }
+}
+
+/// Index of the first entry of the cumulative distribution above `value`
+fn pick(cdf: &[f64], value: f64) -> usize {
+    cdf.partition_point(|&accumulated| accumulated <= value)
+        .min(cdf.len() - 1)
+}
+
+impl Background for EnvironmentMap {
+    fn value(&self, ray: &Ray) -> Color {
+        let (column, row, _) = self.texel(&ray.direction());
+        self.pixels[row * self.width + column]
+    }
+    fn sample_direction(&self) -> Option<(Vec3, f64)> {
+        Some(self.sample())
+    }
+    fn pdf_value(&self, direction: &Vec3) -> f64 {
+        self.pdf(direction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::Point3;
+
+    #[test]
+    fn sun_is_brighter_than_opposite_horizon() {
+        let sun = Vec3::new(1.0, 0.5, 0.0);
+        let sky = SkyBackground::new(sun, 3.0);
+        let towards_sun = sky.value(&Ray::new(Point3::default(), sun, 0.0));
+        let opposite = sky.value(&Ray::new(
+            Point3::default(),
+            Vec3::new(-1.0, 0.01, 0.0),
+            0.0,
+        ));
+        assert!(opposite.get_r() > 0.0 && opposite.get_b() > 0.0);
+        assert!(towards_sun.luminance() > 10.0 * opposite.luminance());
+    }
+
+    #[test]
+    fn flat_color_background() {
+        let color = Color::new(0.7, 0.8, 1.0);
+        let ray = Ray::new(Point3::default(), Vec3::new(0.0, 1.0, 0.0), 0.0);
+        assert_eq!(color.value(&ray), color);
+    }
+
+    #[test]
+    fn environment_map_samples_the_bright_texel() {
+        let (width, height) = (16, 8);
+        let mut pixels = vec![Color::new(0.01, 0.01, 0.01); width * height];
+        pixels[3 * width + 5] = Color::new(100.0, 100.0, 100.0);
+        let map = EnvironmentMap::new(pixels, width, height);
+
+        let samples = 2000;
+        let mut towards_bright = 0;
+        for _ in 0..samples {
+            let (direction, pdf) = map.sample();
+            assert!((map.pdf(&direction) - pdf).abs() < 1e-9 * pdf);
+            let (column, row, _) = map.texel(&direction);
+            if (column, row) == (5, 3) {
+                towards_bright += 1;
+            }
+        }
+        assert!(towards_bright > samples * 9 / 10, "{towards_bright}");
+
+        // Integrate the density over the sphere with the midpoint rule
+        let (steps_phi, steps_theta) = (512, 256);
+        let mut integral = 0.0;
+        for j in 0..steps_theta {
+            let theta = PI * (j as f64 + 0.5) / steps_theta as f64;
+            for i in 0..steps_phi {
+                let phi = 2.0 * PI * (i as f64 + 0.5) / steps_phi as f64;
+                let direction = Vec3::new(
+                    -theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                integral += map.pdf(&direction)
+                    * theta.sin()
+                    * (PI / steps_theta as f64)
+                    * (2.0 * PI / steps_phi as f64);
+            }
+        }
+        assert!((integral - 1.0).abs() < 1e-2, "{integral}");
+    }
+}
@@ -10,9 +10,17 @@ enum BVHNode {
     Leaf(Box<dyn Hittable>),
 }
 
+/// How much the surface area of the boxes can grow with `refit` before a rebuild is recommended
+const REBUILD_THRESHOLD: f64 = 2.0;
+
+/// Bounding volume hierarchy. Each node also keeps the summed surface area of the boxes under it
+/// when it was built, to tell how much refitting degraded the tree, and how many leaves are
+/// under it, to find them by index
 pub struct BVH {
     root: BVHNode,
     bbox: AABB,
+    built_cost: f64,
+    leaves: usize,
 }
 
 impl BVH {
@@ -73,6 +81,8 @@ impl BVH {
                     BVH {
                         root: BVHNode::Leaf(leaf),
                         bbox,
+                        built_cost: bbox.surface_area(),
+                        leaves: 1,
                     }
                 } else {
                     panic!("No bounding box");
@@ -82,16 +92,52 @@ impl BVH {
                 let right = BVH::new(hittable.drain(len / 2..).collect(), time_interval);
                 let left = BVH::new(hittable, time_interval);
                 let bbox = surrounding_box(&left.bbox, &right.bbox);
+                let built_cost = bbox.surface_area() + left.built_cost + right.built_cost;
+                let leaves = left.leaves + right.leaves;
                 BVH {
                     root: BVHNode::Branch {
                         left: Box::new(left),
                         right: Box::new(right),
                     },
                     bbox,
+                    built_cost,
+                    leaves,
                 }
             }
         }
     }
+    /// Recomputes the bounding boxes from the leaves up without splitting the objects again, for
+    /// animated scenes where the objects move but stay the same. The tree gets slower to traverse
+    /// as the objects drift away from where it was built, so it returns whether a rebuild with
+    /// `BVH::new` is recommended: when the summed surface area of the boxes, which the cost of
+    /// traversing them grows with, is more than twice the one it had when built
+    pub fn refit(&mut self, time_interval: &Interval) -> bool {
+        self.refit_cost(time_interval) > REBUILD_THRESHOLD * self.built_cost
+    }
+    fn refit_cost(&mut self, time_interval: &Interval) -> f64 {
+        match &mut self.root {
+            BVHNode::Leaf(leaf) => {
+                self.bbox = leaf.bounding_box(time_interval).expect("No bounding box");
+                self.bbox.surface_area()
+            }
+            BVHNode::Branch { left, right } => {
+                let cost = left.refit_cost(time_interval) + right.refit_cost(time_interval);
+                self.bbox = surrounding_box(&left.bbox, &right.bbox);
+                cost + self.bbox.surface_area()
+            }
+        }
+    }
+    /// Objects at the leaves, from left to right, to update them in place before a `refit`
+    pub fn leaves_mut(&mut self) -> Vec<&mut Box<dyn Hittable>> {
+        match &mut self.root {
+            BVHNode::Leaf(leaf) => vec![leaf],
+            BVHNode::Branch { left, right } => {
+                let mut leaves = left.leaves_mut();
+                leaves.extend(right.leaves_mut());
+                leaves
+            }
+        }
+    }
 }
 
 impl Hittable for BVH {
@@ -99,64 +145,102 @@ impl Hittable for BVH {
         if self.bbox.hit(ray, time_interval) {
             match &self.root {
                 BVHNode::Branch { left, right } => {
-                    let left_bbox = left.hit(ray, time_interval);
-                    let right_bbox = if left_bbox.is_some() {
-                        right.hit(
-                            ray,
-                            &Interval::new(time_interval.min(), left_bbox.as_ref().unwrap().t()),
-                        )
-                    } else {
-                        right.hit(ray, time_interval)
-                    };
-                    match (left_bbox, right_bbox) {
-                        (h, None) | (None, h) => h,
-                        (Some(left_hit), Some(right_hit)) => {
-                            if left_hit.t() < right_hit.t() {
-                                Some(left_hit)
-                            } else {
-                                Some(right_hit)
-                            }
-                        }
-                    }
-                }
-                BVHNode::Leaf(leaf) => {
-                    leaf.hit(ray, time_interval)
+                    // Like `HittableList::hit`, the right child is searched up to the left hit
+                    // included, so any hit it finds is the closest and wins ties with the left
+                    let left_hit = left.hit(ray, time_interval);
+                    let closest_so_far =
+                        left_hit.as_ref().map_or(time_interval.max(), |rec| rec.t());
+                    right
+                        .hit(ray, &Interval::new(time_interval.min(), closest_so_far))
+                        .or(left_hit)
                 }
+                BVHNode::Leaf(leaf) => leaf.hit(ray, time_interval),
             }
         } else {
             None
         }
     }
+    fn hit_any(&self, ray: &Ray, time_interval: &Interval) -> bool {
+        if !self.bbox.hit(ray, time_interval) {
+            return false;
+        }
+        match &self.root {
+            BVHNode::Branch { left, right } => {
+                left.hit_any(ray, time_interval) || right.hit_any(ray, time_interval)
+            }
+            BVHNode::Leaf(leaf) => leaf.hit_any(ray, time_interval),
+        }
+    }
     fn bounding_box(&self, _time_interval: &Interval) -> Option<AABB> {
         Some(self.bbox)
     }
+    /// The leaves from left to right, the order of `leaves_mut`. The objects are sorted when the
+    /// tree is built, so it isn't the order they were given in
+    fn child(&self, index: usize) -> Option<&dyn Hittable> {
+        match &self.root {
+            BVHNode::Leaf(leaf) => (index == 0).then_some(leaf.as_ref()),
+            BVHNode::Branch { left, right } => {
+                if index < left.leaves {
+                    left.child(index)
+                } else {
+                    right.child(index - left.leaves)
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::{f64::INFINITY, sync::Arc};
 
-    use crate::sphere::Sphere;
+    use crate::{
+        hittable::HittableList, material::Material, sphere::Sphere, triangle::Triangle, vec3::Vec3,
+    };
 
     use super::*;
 
+    fn spheres(centers: &[Vec3], material: &Arc<dyn Material>) -> Vec<Box<dyn Hittable>> {
+        centers
+            .iter()
+            .map(|&center| {
+                Box::new(Sphere::new(center, None, 1.0, material.clone())) as Box<dyn Hittable>
+            })
+            .collect()
+    }
+
+    /// Replaces each sphere leaf with one moved by `offset(index)`
+    fn move_leaves(bvh: &mut BVH, material: &Arc<dyn Material>, offset: impl Fn(usize) -> Vec3) {
+        let time_interval = Interval::new(0.0, 1.0);
+        for (index, leaf) in bvh.leaves_mut().into_iter().enumerate() {
+            let bbox = leaf.bounding_box(&time_interval).unwrap();
+            let center = 0.5 * (bbox.min() + bbox.max());
+            *leaf = Box::new(Sphere::new(
+                center + offset(index),
+                None,
+                1.0,
+                material.clone(),
+            ));
+        }
+    }
+
     #[test]
     fn new() {
         let sphere1 = Sphere::new(
             crate::vec3::Vec3::new(0.0, 0.0, 0.0),
             None,
             1.0,
-            Arc::new(crate::material::Lambertian::new(crate::vec3::Vec3::new(
-                0.0, 0.0, 0.0,
-            ))),
+            Arc::new(crate::material::Lambertian::new(
+                crate::textures::ConstantTexture::new(crate::vec3::Vec3::new(0.0, 0.0, 0.0)),
+            )),
         );
         let sphere2 = Sphere::new(
             crate::vec3::Vec3::new(0.0, 0.0, 0.0),
             None,
             1.0,
-            Arc::new(crate::material::Lambertian::new(crate::vec3::Vec3::new(
-                0.0, 0.0, 0.0,
-            ))),
+            Arc::new(crate::material::Lambertian::new(
+                crate::textures::ConstantTexture::new(crate::vec3::Vec3::new(0.0, 0.0, 0.0)),
+            )),
         );
         let bvh = BVH::new(
             vec![
@@ -174,17 +258,17 @@ mod tests {
             crate::vec3::Vec3::new(0.0, 0.0, 1.0),
             None,
             1.0,
-            Arc::new(crate::material::Lambertian::new(crate::vec3::Vec3::new(
-                0.0, 0.0, 0.0,
-            ))),
+            Arc::new(crate::material::Lambertian::new(
+                crate::textures::ConstantTexture::new(crate::vec3::Vec3::new(0.0, 0.0, 0.0)),
+            )),
         );
         let sphere2 = Sphere::new(
             crate::vec3::Vec3::new(0.0, 0.0, 2.0),
             None,
             1.0,
-            Arc::new(crate::material::Lambertian::new(crate::vec3::Vec3::new(
-                0.0, 0.0, 0.0,
-            ))),
+            Arc::new(crate::material::Lambertian::new(
+                crate::textures::ConstantTexture::new(crate::vec3::Vec3::new(0.0, 0.0, 0.0)),
+            )),
         );
         let bvh = BVH::new(
             vec![Box::new(sphere1) as Box<dyn Hittable>],
@@ -204,17 +288,17 @@ mod tests {
             crate::vec3::Vec3::new(0.0, 0.0, 2.0),
             None,
             1.0,
-            Arc::new(crate::material::Lambertian::new(crate::vec3::Vec3::new(
-                0.0, 0.0, 0.0,
-            ))),
+            Arc::new(crate::material::Lambertian::new(
+                crate::textures::ConstantTexture::new(crate::vec3::Vec3::new(0.0, 0.0, 0.0)),
+            )),
         );
         let sphere2 = Sphere::new(
             crate::vec3::Vec3::new(0.0, 0.0, 0.0),
             None,
             1.0,
-            Arc::new(crate::material::Lambertian::new(crate::vec3::Vec3::new(
-                0.0, 0.0, 0.0,
-            ))),
+            Arc::new(crate::material::Lambertian::new(
+                crate::textures::ConstantTexture::new(crate::vec3::Vec3::new(0.0, 0.0, 0.0)),
+            )),
         );
         let bvh = BVH::new(
             vec![
@@ -227,4 +311,88 @@ mod tests {
         assert_eq!(bbox.min(), crate::vec3::Vec3::new(-1.0, -1.0, -1.0));
         assert_eq!(bbox.max(), crate::vec3::Vec3::new(1.0, 1.0, 3.0));
     }
+
+    #[test]
+    fn coincident_hits_resolve_like_a_list() {
+        let material: Arc<dyn Material> = Arc::new(crate::material::Lambertian::new(
+            crate::textures::ConstantTexture::new(Vec3::new(0.5, 0.5, 0.5)),
+        ));
+        // Two overlapping triangles on the same plane, told apart by their texture coordinates
+        let triangle = |x: f64, u: f64| {
+            Box::new(
+                Triangle::new(
+                    Vec3::new(x, -1.0, 0.0),
+                    Vec3::new(x + 2.0, -1.0, 0.0),
+                    Vec3::new(x, 1.0, 0.0),
+                    material.clone(),
+                )
+                .with_uvs([(u, 0.0); 3]),
+            ) as Box<dyn Hittable>
+        };
+        let ray = Ray::new(Vec3::new(0.5, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let interval = Interval::new(0.0, INFINITY);
+
+        let mut list = HittableList::new();
+        list.add(triangle(-1.0, 0.25));
+        list.add(triangle(0.0, 0.75));
+        let expected = list.hit(&ray, &interval).unwrap();
+        for objects in [
+            vec![triangle(-1.0, 0.25), triangle(0.0, 0.75)],
+            vec![triangle(0.0, 0.75), triangle(-1.0, 0.25)],
+        ] {
+            let bvh = BVH::new(objects, &Interval::new(0.0, 1.0));
+            let hit = bvh.hit(&ray, &interval).unwrap();
+            assert_eq!(hit.t(), 5.0);
+            assert_eq!(hit.t(), expected.t());
+            assert_eq!(hit.u(), expected.u());
+        }
+    }
+
+    #[test]
+    fn refit_matches_rebuild_after_translation() {
+        let material: Arc<dyn Material> = Arc::new(crate::material::Lambertian::new(
+            crate::textures::ConstantTexture::new(Vec3::new(0.5, 0.5, 0.5)),
+        ));
+        let time_interval = Interval::new(0.0, 1.0);
+        let centers: Vec<Vec3> = (0..8)
+            .map(|i| Vec3::new(3.0 * i as f64, (i % 3) as f64, -(i % 2) as f64))
+            .collect();
+        let offset = Vec3::new(1.5, -2.0, 0.5);
+        let mut bvh = BVH::new(spheres(&centers, &material), &time_interval);
+        move_leaves(&mut bvh, &material, |_| offset);
+        assert!(!bvh.refit(&time_interval));
+
+        let moved: Vec<Vec3> = centers.iter().map(|&center| center + offset).collect();
+        let rebuilt = BVH::new(spheres(&moved, &material), &time_interval);
+        assert_eq!(
+            bvh.bounding_box(&time_interval),
+            rebuilt.bounding_box(&time_interval)
+        );
+        for center in moved {
+            let ray = Ray::new(
+                center + Vec3::new(0.0, 0.0, 10.0),
+                Vec3::new(0.0, 0.0, -1.0),
+                0.0,
+            );
+            let hit = bvh.hit(&ray, &Interval::new(0.0, INFINITY)).unwrap();
+            assert!((hit.t() - 9.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn refit_recommends_rebuild_after_scattering() {
+        let material: Arc<dyn Material> = Arc::new(crate::material::Lambertian::new(
+            crate::textures::ConstantTexture::new(Vec3::new(0.5, 0.5, 0.5)),
+        ));
+        let time_interval = Interval::new(0.0, 1.0);
+        let centers: Vec<Vec3> = (0..8)
+            .map(|i| Vec3::new(3.0 * i as f64, 0.0, 0.0))
+            .collect();
+        let mut bvh = BVH::new(spheres(&centers, &material), &time_interval);
+        // Neighbouring leaves end up far apart, so every box spans most of the scene
+        move_leaves(&mut bvh, &material, |index| {
+            Vec3::new(0.0, if index % 2 == 0 { 50.0 } else { -50.0 }, 0.0)
+        });
+        assert!(bvh.refit(&time_interval));
+    }
 }
@@ -1,20 +1,50 @@
-use crate::color::write_to_png;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::color::write_to_ppm;
+use crate::color::{
+    tonemap_pixel, tonemap_rgba_pixel, write_depth_to_png, write_rgba_to_png, write_to_png,
+    Matrix3, OutputFormat, Overlay, ToneMap,
+};
 
 use super::{
+    aabb::aabb::AABB,
+    background::Background,
     color::Color,
-    common::{degree_to_radians, random_double, INFINITY},
-    hittable::Hittable,
-    interval::Interval,
+    common::{degree_to_radians, random_double, seed_random, INFINITY},
+    hittable::{HitRecord, Hittable},
+    interval::{Interval, UNITY_INTERVAL},
+    light::LightList,
+    material::{Interface, Material, MediumStack, ScatterRecord},
     rays::Ray,
-    vec3::{random_on_disk, Point3, Vec3},
+    sampler::{sample_tent, square_to_disk, CameraSample, RandomSampler, Sampler},
+    textures::Texture,
+    vec3::{random_on_hemisphere, Point3, Vec3},
 };
+#[cfg(not(target_arch = "wasm32"))]
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
-use std::cmp;
+use std::{
+    cmp, fmt,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    fs,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex,
+    },
+    thread,
+};
 /// Definition of a camera. The camera is defined by the following parameters:
 /// - Aspect ratio
 /// - Image width
 /// - Image height
+/// - Requested height: Image height set explicitly instead of derived from the aspect ratio
 /// - Samples per pixel: Number of vectors casted per pixel
 /// - Pixel sample scale: 1 / samples per pixel
 /// - Center: Center of the camera
@@ -22,6 +52,8 @@ use std::cmp;
 /// - Delta u: Vector that represents the change in the u direction
 /// - Delta v: Vector that represents the change in the v direction
 /// - Max depth: Maximum depth of the ray
+/// - Max specular bounces: Maximum number of consecutive specular bounces of the ray, unlimited
+///   by default
 /// - Vfov: Vertical field of view
 /// - Lookfrom: Point where the camera is looking from
 /// - Lookat: Point where the camera is looking at
@@ -33,121 +65,414 @@ use std::cmp;
 /// - Focus distance: Distance of the focus plane
 /// - Defocus disk u: U vector of the defocus disk
 /// - Defocus disk v: V vector of the defocus disk
-/// - Background: Color of the background of the scene
-#[derive(Default)]
+/// - Background: Radiance of the rays that miss the scene, a flat color or a sky model
+/// - Primary background: Background seen by the camera rays that miss the scene, the background
+///   if unset. The background keeps lighting the scene through the bounced rays
+/// - Lights: Objects of the scene that can be sampled directly
+/// - Depth range: Distances mapped to 0 and 1 in the depth pass
+/// - Tile size: Side in pixels of the square tiles rendered by `render_tiles`
+/// - Seed: Seed of the random numbers used by `render_tiles`
+/// - Transparent background: Whether camera rays that miss the scene are transparent
+/// - Checkpoint interval: How often `render` writes the image accumulated so far
+/// - Shutter: Interval of time during which the shutter is open, [0, 1] if unset
+/// - Shutter profile: How the times of the camera rays are distributed over the exposure
+/// - Pixel filter: How the camera rays are spread around the centers of the pixels, a tent by
+///   default
+/// - Sampler: Source of the random numbers of the camera rays, independent ones if unset
+/// - Ray generator: Replaces the pinhole and the lens in building the camera rays, if set
+/// - Exposure: Stops by which the linear colors are scaled before they are written, 0 by default
+/// - Output primaries: Matrix applied to the linear colors before they are written, the identity
+///   by default
+/// - Chromatic aberration: How much the red and blue camera rays are magnified apart from the
+///   green ones, 0 by default
+/// - Aperture mask: Grayscale texture over the lens shaping the out of focus highlights, the
+///   whole disk if unset
+/// - Far clip: Distance from the camera past which the camera rays don't see anything, none if
+///   unset
+/// - Rays: Counters of the rays cast by the paths, reported by `render`
+/// - Clamp negative: Whether the negative components of the samples are clamped to 0 before they
+///   are accumulated
+/// - Overlay: Guides drawn over the image when `render` writes it, none by default
+/// - Output format: Format of the image written by `render`, from the extension of the file if
+///   unset
+/// - Independent pyramid: Whether `render_pyramid` renders every level instead of downsampling
+///   the first one
+/// - Accumulated: Sum of the samples of every pixel over the `accumulate` passes, with how many
+///   there are
+#[derive(Clone)]
 pub struct Camera {
-    aspect_ratio: Option<f64>,
-    image_width: Option<i32>,
+    aspect_ratio: f64,
+    image_width: i32,
     image_height: i32,
+    requested_height: Option<i32>,
     samples_per_pixel: i32,
     pixel_sample_scale: f64,
     center: Point3,
     pixel00_loc: Point3,
     delta_u: Vec3,
     delta_v: Vec3,
-    max_depth: Option<i32>,
-    vfov: Option<f64>,
-    lookfrom: Option<Point3>,
-    lookat: Option<Point3>,
-    vup: Option<Vec3>,
+    max_depth: i32,
+    max_specular_bounces: i32,
+    vfov: f64,
+    lookfrom: Point3,
+    lookat: Point3,
+    vup: Vec3,
     u: Vec3,
     v: Vec3,
     w: Vec3,
-    defocus_angle: Option<f64>,
-    focus_distance: Option<f64>,
+    defocus_angle: f64,
+    focus_distance: f64,
     defocus_disk_u: Vec3,
     defocus_disk_v: Vec3,
-    background: Option<Color>,
+    background: Arc<dyn Background>,
+    primary_background: Option<Arc<dyn Background>>,
+    lights: LightList,
+    depth_range: Interval,
+    tile_size: i32,
+    seed: u64,
+    transparent_background: bool,
+    checkpoint_interval: Option<Duration>,
+    shutter: Option<Interval>,
+    shutter_profile: ShutterProfile,
+    pixel_filter: PixelFilter,
+    sampler: Option<Arc<dyn Sampler>>,
+    ray_generator: Option<Arc<RayGenerator>>,
+    exposure: f64,
+    output_primaries: Matrix3,
+    chromatic_aberration: f64,
+    far_clip: Option<f64>,
+    aperture_mask: Option<Arc<dyn Texture>>,
+    rays: Arc<RayCounters>,
+    clamp_negative: bool,
+    overlay: Overlay,
+    output_format: Option<OutputFormat>,
+    independent_pyramid: bool,
+    accumulated: Vec<Vec<([f64; 3], u32)>>,
 }
 
-impl Camera {
-    /// Initializes the camera with the default values. If some of the values are not set, it will
-    /// print a warning message and use the default values. The default values are:
-    /// - Vfov: 90 degrees
-    /// - Lookfrom: (0,0,0)
-    /// - Lookat: (0,0,-1)
-    /// - Vup: (0,1,0)
-    /// - Focus distance: 10.0
-    /// - Defocus angle: 0.0
-    /// - Image width: 800
-    /// - Max depth: 50
-    /// - Aspect ratio: 16:9
-    /// - Background: 0,0,0
-    /// - Other values are calculated based on the previous values
-    fn initialize(&mut self) {
-        if self.vfov.is_none() {
-            eprintln!("No vertical field of view set, using default 90 degrees");
-            self.vfov = Some(90.0);
-        }
-        if self.lookfrom.is_none() {
-            eprintln!("No lookfrom point set, using default (0,0,0)");
-            self.lookfrom = Some(Default::default());
-        }
-        if self.lookat.is_none() {
-            eprintln!("No lookat point set, using default (0,0,-1)");
-            self.lookat = Some(Point3::new(0.0, 0.0, -1.0));
-        }
-        if self.vup.is_none() {
-            eprintln!("No default up direction set, using default (0,1,0)");
-            self.vup = Some(Vec3::new(0.0, 1.0, 0.0));
+impl Default for Camera {
+    /// Camera at the origin looking down -z with a 90 degrees field of view, rendering 800 pixels
+    /// wide at 16:9 with 10 samples per pixel, 50 bounces and a black background. The specular
+    /// bounces are unlimited, so only the depth limits the paths
+    fn default() -> Self {
+        Camera {
+            aspect_ratio: 16.0 / 9.0,
+            image_width: 800,
+            image_height: Default::default(),
+            requested_height: None,
+            samples_per_pixel: 10,
+            pixel_sample_scale: Default::default(),
+            center: Default::default(),
+            pixel00_loc: Default::default(),
+            delta_u: Default::default(),
+            delta_v: Default::default(),
+            max_depth: 50,
+            max_specular_bounces: i32::MAX,
+            vfov: 90.0,
+            lookfrom: Point3::default(),
+            lookat: Point3::new(0.0, 0.0, -1.0),
+            vup: Vec3::new(0.0, 1.0, 0.0),
+            u: Default::default(),
+            v: Default::default(),
+            w: Default::default(),
+            defocus_angle: 0.0,
+            focus_distance: 10.0,
+            defocus_disk_u: Default::default(),
+            defocus_disk_v: Default::default(),
+            background: Arc::new(Color::default()),
+            primary_background: None,
+            lights: Default::default(),
+            depth_range: Interval::new(0.0, 1000.0),
+            tile_size: 32,
+            seed: 0,
+            transparent_background: false,
+            checkpoint_interval: None,
+            shutter: None,
+            shutter_profile: Default::default(),
+            pixel_filter: Default::default(),
+            sampler: None,
+            ray_generator: None,
+            exposure: 0.0,
+            output_primaries: Default::default(),
+            chromatic_aberration: 0.0,
+            far_clip: None,
+            aperture_mask: None,
+            rays: Default::default(),
+            clamp_negative: false,
+            overlay: Default::default(),
+            output_format: None,
+            independent_pyramid: false,
+            accumulated: Vec::new(),
         }
-        if self.focus_distance.is_none() {
-            eprintln!("No focus distance set, using default 10.0");
-            self.focus_distance = Some(10.0);
-        }
-        if self.defocus_angle.is_none() {
-            eprintln!("No defocus angle set, using default 0.0");
-            self.defocus_angle = Some(0.0);
+    }
+}
+
+/// Builds the camera ray of the pixel (i, j) from the random numbers of a sample, drawn by the
+/// sampler of the camera
+pub type RayGenerator = dyn Fn(i32, i32, &Camera, &CameraSample) -> Ray + Send + Sync;
+
+/// Lens samples drawn for a point let through by the aperture mask before falling back to the
+/// last one it doesn't block
+const APERTURE_MASK_TRIES: usize = 64;
+
+/// Rays cast since the counters were reset, and samples with negative components clamped, shared
+/// by the threads of a render
+#[derive(Debug, Default)]
+struct RayCounters {
+    primary: AtomicU64,
+    total: AtomicU64,
+    negative: AtomicU64,
+}
+
+/// Numbers of a render, to compare the speed of changes to the acceleration structures or the
+/// sampling. The total counts every ray intersected with the scene: the camera rays, the rays
+/// they bounce into and the shadow rays towards the lights and the background. The negative
+/// samples are only counted when they are clamped
+#[derive(Clone, Copy, Debug)]
+pub struct RenderStats {
+    pub primary_rays: u64,
+    pub total_rays: u64,
+    pub negative_samples: u64,
+    pub duration: Duration,
+    pub rays_per_second: f64,
+}
+
+/// View and up directions of the faces of a cubemap
+const CUBEMAP_FACES: [(Vec3, Vec3); 6] = [
+    (Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)),
+    (Vec3::new(-1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)),
+    (Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, -1.0)),
+    (Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 0.0, 1.0)),
+    (Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 1.0, 0.0)),
+    (Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0)),
+];
+
+/// Distribution of the times of the camera rays over the exposure, from 0 when the shutter opens
+/// to 1 when it closes
+#[derive(Clone, Copy, Debug, Default)]
+pub enum ShutterProfile {
+    /// Every time is as likely, the shutter is fully open during the whole exposure
+    #[default]
+    Uniform,
+    /// The density grows linearly up to the middle of the exposure and back down, like a shutter
+    /// that takes the whole exposure to open and close
+    Triangle,
+    /// Maps a uniform random number in [0, 1) to a time, usually the inverse of the cumulative
+    /// distribution of the wanted profile
+    Custom(fn(f64) -> f64),
+}
+
+/// Distribution of the camera rays around the center of a pixel, which weighs the image behind
+/// it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PixelFilter {
+    /// Uniform over the pixel, [-0.5, 0.5] around its center
+    Box,
+    /// Triangular over [-1, 1] around the center, overlapping the neighbouring pixels. It
+    /// aliases less than the box at the cost of a slightly softer image
+    #[default]
+    Tent,
+}
+
+impl PixelFilter {
+    /// Maps a uniform random number in [0, 1) to an offset from the center of the pixel, in pixels
+    pub fn offset(&self, u: f64) -> f64 {
+        match self {
+            PixelFilter::Box => u - 0.5,
+            PixelFilter::Tent => sample_tent(u),
         }
-        if self.image_width.is_none() {
-            eprintln!("No image width set, using default 800");
-            self.image_width = Some(800);
+    }
+}
+
+/// Coordinates in which the normal pass is written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NormalSpace {
+    World,
+    /// Along the `u`, `v` and `w` axes of the camera: right, up and towards the camera, so a
+    /// surface facing the camera has normal (0, 0, 1)
+    View,
+}
+
+impl ShutterProfile {
+    /// Samples a time of the exposure following the profile
+    pub fn sample(&self) -> f64 {
+        self.time(random_double())
+    }
+    /// Maps a uniform random number in [0, 1) to a time of the exposure following the profile
+    pub fn time(&self, u: f64) -> f64 {
+        match self {
+            ShutterProfile::Uniform => u,
+            ShutterProfile::Triangle => {
+                if u < 0.5 {
+                    (0.5 * u).sqrt()
+                } else {
+                    1.0 - (0.5 * (1.0 - u)).sqrt()
+                }
+            }
+            ShutterProfile::Custom(profile) => profile(u),
         }
-        if self.max_depth.is_none() {
-            eprintln!("No max depth set, using default 50");
-            self.max_depth = Some(50);
+    }
+}
+
+/// Chained construction of a camera, in place of the setters:
+/// `Camera::builder().width(600).aspect_ratio(16.0 / 9.0).vfov(40.0).build()`. The settings left
+/// out keep the defaults of `Camera::default`, and the ones that can't render an image are
+/// rejected by `build`, as they are by the setters. Settings without a method here are set on
+/// the built camera
+#[derive(Clone, Default)]
+pub struct CameraBuilder {
+    camera: Camera,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CameraError {
+    NotPositive(&'static str, f64),
+    FieldOfView(f64),
+}
+
+impl std::error::Error for CameraError {}
+
+impl fmt::Display for CameraError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            CameraError::NotPositive(setting, value) => {
+                write!(f, "Error, the {} must be positive, got {}", setting, value)
+            }
+            CameraError::FieldOfView(vfov) => write!(
+                f,
+                "Error, the vertical field of view must be between 0 and 180 degrees, got {}",
+                vfov
+            ),
         }
-        if self.aspect_ratio.is_none() {
-            eprintln!("No aspect ratio set, using default 16:9");
-            self.aspect_ratio = Some(16.0 / 9.0);
+    }
+}
+
+impl CameraBuilder {
+    pub fn new() -> Self {
+        CameraBuilder::default()
+    }
+    pub fn width(mut self, width: i32) -> Self {
+        self.camera.image_width = width;
+        self
+    }
+    pub fn aspect_ratio(mut self, aspect_ratio: f64) -> Self {
+        self.camera.aspect_ratio = aspect_ratio;
+        self
+    }
+    pub fn samples_per_pixel(mut self, samples: i32) -> Self {
+        self.camera.samples_per_pixel = samples;
+        self
+    }
+    pub fn max_depth(mut self, depth: i32) -> Self {
+        self.camera.max_depth = depth;
+        self
+    }
+    pub fn max_specular_bounces(mut self, bounces: i32) -> Self {
+        self.camera.max_specular_bounces = bounces;
+        self
+    }
+    /// Vertical field of view, in degrees
+    pub fn vfov(mut self, vfov: f64) -> Self {
+        self.camera.vfov = vfov;
+        self
+    }
+    pub fn lookfrom(mut self, from: Point3) -> Self {
+        self.camera.lookfrom = from;
+        self
+    }
+    pub fn lookat(mut self, at: Point3) -> Self {
+        self.camera.lookat = at;
+        self
+    }
+    pub fn vup(mut self, vup: Vec3) -> Self {
+        self.camera.vup = vup;
+        self
+    }
+    pub fn defocus_angle(mut self, angle: f64) -> Self {
+        self.camera.defocus_angle = angle;
+        self
+    }
+    pub fn focus_distance(mut self, distance: f64) -> Self {
+        self.camera.focus_distance = distance;
+        self
+    }
+    pub fn background_color(mut self, color: Color) -> Self {
+        self.camera.background = Arc::new(color);
+        self
+    }
+    pub fn background<B: Background + 'static>(mut self, background: B) -> Self {
+        self.camera.background = Arc::new(background);
+        self
+    }
+    pub fn lights(mut self, lights: LightList) -> Self {
+        self.camera.lights = lights;
+        self
+    }
+    /// Checks the settings and returns the camera
+    pub fn build(self) -> Result<Camera, CameraError> {
+        self.camera.check_settings()?;
+        Ok(self.camera)
+    }
+}
+
+impl Camera {
+    /// Starts a `CameraBuilder`
+    pub fn builder() -> CameraBuilder {
+        CameraBuilder::new()
+    }
+    /// Checks the settings that can't render an image, for `CameraBuilder::build` and the setters
+    /// of those settings
+    fn check_settings(&self) -> Result<(), CameraError> {
+        let positive = [
+            ("image width", f64::from(self.image_width)),
+            ("samples per pixel", f64::from(self.samples_per_pixel)),
+            ("max depth", f64::from(self.max_depth)),
+            ("aspect ratio", self.aspect_ratio),
+            ("focus distance", self.focus_distance),
+        ];
+        for (setting, value) in positive {
+            if value <= 0.0 {
+                return Err(CameraError::NotPositive(setting, value));
+            }
         }
-        if self.background.is_none() {
-            eprintln!("No background color set, using the default pure black");
-            self.background = Some(Color::default());
+        if self.vfov <= 0.0 || self.vfov >= 180.0 {
+            return Err(CameraError::FieldOfView(self.vfov));
         }
-
+        Ok(())
+    }
+    /// Calculates the size of the image, the basis of the camera, the viewport and the defocus
+    /// disk from the settings. The settings that are not set keep the values of `Camera::default`
+    fn initialize(&mut self) {
         // Image
-        self.image_height = cmp::max(
-            (self.image_width.unwrap() as f64 / self.aspect_ratio.unwrap()) as i32,
-            1,
-        );
+        self.image_height = match self.requested_height {
+            Some(height) => height,
+            None => cmp::max((self.image_width as f64 / self.aspect_ratio) as i32, 1),
+        };
         // Camera
-        let theta = degree_to_radians(self.vfov.unwrap());
+        let theta = degree_to_radians(self.vfov);
         let h = f64::tan(theta / 2.0);
-        let viewport_height: f64 = 2.0 * h * self.focus_distance.unwrap();
+        let viewport_height: f64 = 2.0 * h * self.focus_distance;
         let viewport_width: f64 =
-            viewport_height * (self.image_width.unwrap() as f64 / self.image_height as f64);
-        self.center = self.lookfrom.unwrap();
+            viewport_height * (self.image_width as f64 / self.image_height as f64);
+        self.center = self.lookfrom;
         // Basis
-        self.w = (self.lookfrom.unwrap() - self.lookat.unwrap()).normalize();
-        self.u = self.vup.unwrap().cross_product(&self.w).normalize();
+        self.w = (self.lookfrom - self.lookat).normalize();
+        self.u = self.vup.cross_product(&self.w).normalize();
         self.v = self.w.cross_product(&self.u);
         // Aux vectors
         let viewport_u: Vec3 = viewport_width * self.u;
         let viewport_v: Vec3 = viewport_height * -self.v;
         // Pixel delta
-        self.delta_u = viewport_u / self.image_width.unwrap() as f64;
+        self.delta_u = viewport_u / self.image_width as f64;
         self.delta_v = viewport_v / self.image_height as f64;
         // Calculate the 00 pixel
-        let viewport_upper_left = self.center
-            - (self.focus_distance.unwrap() * self.w)
-            - viewport_u / 2.
-            - viewport_v / 2.;
+        let viewport_upper_left =
+            self.center - (self.focus_distance * self.w) - viewport_u / 2. - viewport_v / 2.;
         self.pixel00_loc = viewport_upper_left + 0.5 * (self.delta_u + self.delta_v);
         self.pixel_sample_scale = 1.0 / self.samples_per_pixel as f64;
         // Calculate the defocus disk
-        let defocus_radius = self.focus_distance.unwrap()
-            * f64::tan(degree_to_radians(self.defocus_angle.unwrap() / 2.0));
+        let defocus_radius =
+            self.focus_distance * f64::tan(degree_to_radians(self.defocus_angle / 2.0));
         // Calculate the defocus disk vectors
         self.defocus_disk_u = self.u * defocus_radius;
         self.defocus_disk_v = self.v * defocus_radius;
@@ -163,162 +488,1381 @@ impl Camera {
     ///    - Repeat for all pixels
     ///    - Close the file
     ///    - Print a message when the image is done
-    pub fn render(&mut self, world: &Box<dyn Hittable>, filename: String) {
+    ///
+    /// Returns how many rays the render cast and how long it took
+    pub fn render(&mut self, world: &Box<dyn Hittable>, filename: String) -> RenderStats {
+        self.rays.primary.store(0, Ordering::Relaxed);
+        self.rays.total.store(0, Ordering::Relaxed);
+        self.rays.negative.store(0, Ordering::Relaxed);
+        let start = Instant::now();
+        let image = match self.checkpoint_interval {
+            #[cfg(not(target_arch = "wasm32"))]
+            Some(interval) => self.render_with_checkpoints(world.as_ref(), &filename, interval),
+            _ => self.render_rgba_with_progress(world.as_ref(), true),
+        };
+        let duration = start.elapsed();
+        self.write_image(&filename, image, self.output_format(&filename));
+        let total_rays = self.rays.total.load(Ordering::Relaxed);
+        RenderStats {
+            primary_rays: self.rays.primary.load(Ordering::Relaxed),
+            total_rays,
+            negative_samples: self.rays.negative.load(Ordering::Relaxed),
+            duration,
+            rays_per_second: total_rays as f64 / duration.as_secs_f64(),
+        }
+    }
+    /// Format of the image written to `filename`, the one set or else the one of the extension
+    fn output_format(&self, filename: &str) -> OutputFormat {
+        self.output_format
+            .unwrap_or_else(|| OutputFormat::from_filename(filename))
+    }
+    /// Writes the image in `format`, a PNG being RGBA if the background is transparent and RGB
+    /// otherwise
+    fn write_image(&self, filename: &str, image: Vec<Vec<(Color, f64)>>, format: OutputFormat) {
+        let image = self.to_output(image);
+        let (width, height) = (self.image_width, self.image_height);
+        match format {
+            #[cfg(not(target_arch = "wasm32"))]
+            OutputFormat::PpmAscii => {
+                write_to_ppm(filename, &strip_alpha(image), width, height, self.overlay);
+            }
+            _ if self.transparent_background => {
+                write_rgba_to_png(filename, &image, width, height, self.overlay);
+            }
+            _ => write_to_png(filename, &strip_alpha(image), width, height, self.overlay),
+        }
+    }
+    /// Exposes the linear colors and converts them to the output primaries, keeping the alpha
+    fn to_output(&self, image: Vec<Vec<(Color, f64)>>) -> Vec<Vec<(Color, f64)>> {
+        let mut image = self.expose(image);
+        for (color, _) in image.iter_mut().flatten() {
+            *color = self.output_primaries.transform(*color);
+        }
+        image
+    }
+    /// Scales the linear colors by `2^exposure`, keeping the alpha
+    fn expose(&self, image: Vec<Vec<(Color, f64)>>) -> Vec<Vec<(Color, f64)>> {
+        let scale = 2.0_f64.powf(self.exposure);
+        image
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|(color, alpha)| (scale * color, alpha))
+                    .collect()
+            })
+            .collect()
+    }
+    /// Renders one sample of every pixel at a time into an accumulation buffer, while another
+    /// thread writes what has been accumulated so far to `filename` every `interval`. The
+    /// checkpoints are written to a temporary file and then moved over `filename`, so the file is
+    /// never seen half written
+    #[cfg(not(target_arch = "wasm32"))]
+    fn render_with_checkpoints(
+        &mut self,
+        world: &dyn Hittable,
+        filename: &str,
+        interval: Duration,
+    ) -> Vec<Vec<(Color, f64)>> {
         self.initialize();
+        let camera = &*self;
+        let width = camera.image_width;
+        let accumulator = Accumulator::new(width as usize, camera.image_height as usize);
 
-        // let mut file = File::create(filename).expect("Couldn't Open file");
-        // let header = format!(
-        //     "P3\n {} {} \n255\n",
-        //     self.image_width.unwrap(),
-        //     self.image_height
-        // );
-        // write!(file, "{}", header).expect("Couldn't write to file");
-        let sty = ProgressStyle::with_template(
-            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-        )
-        .unwrap()
-        .progress_chars("#>-");
-        let bar = ProgressBar::new(self.image_height as u64);
-        bar.set_style(sty);
-        bar.set_message("Rendering image...");
+        let bar = progress_bar(camera.samples_per_pixel as u64, "Rendering samples...");
+        let (done, finished) = mpsc::channel::<()>();
+        let snapshots = &accumulator;
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                let format = camera.output_format(filename);
+                let checkpoint = format!("{filename}.checkpoint.{}", format.extension());
+                while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(interval) {
+                    camera.write_image(&checkpoint, snapshots.snapshot(), format);
+                    fs::rename(&checkpoint, filename).expect("Couldn't write the checkpoint");
+                }
+            });
+            for index in 0..camera.samples_per_pixel {
+                (0..camera.image_height).into_par_iter().for_each(|j| {
+                    let samples: Vec<_> = (0..width)
+                        .map(|i| camera.trace_sample(i, j, index, world))
+                        .collect();
+                    accumulator.add_row(j as usize, &samples);
+                });
+                bar.inc(1);
+            }
+            drop(done);
+        });
+        bar.finish_with_message("\nRendering Done!!\n");
+        accumulator.snapshot()
+    }
+    /// Renders the image into memory instead of a file. Returns the linear colors of the pixels,
+    /// row by row, so callers can tonemap or inspect them themselves
+    pub fn render_to_buffer(&mut self, world: &dyn Hittable) -> Vec<Vec<Color>> {
+        strip_alpha(self.render_rgba(world))
+    }
+    /// Renders a pass of `samples_per_pixel` samples of every pixel and adds them to the ones of
+    /// the previous passes, kept in full precision until `take_accumulator`. The samples continue
+    /// the sequence of the sampler where the previous pass stopped, so passes of a stratified
+    /// sampler add up to a single render with all the samples. Changing the size of the image
+    /// starts over
+    pub fn accumulate(&mut self, world: &dyn Hittable) {
+        self.initialize();
+        let (width, height) = (self.image_width as usize, self.image_height as usize);
+        if self.accumulated.len() != height || self.accumulated.iter().any(|row| row.len() != width)
+        {
+            self.accumulated = vec![vec![([0.0; 3], 0); width]; height];
+        }
+        let accumulated = &self.accumulated;
+        let pass = self.render_pass(|i, j| {
+            let (_, count) = accumulated[j as usize][i as usize];
+            let first = count as i32;
+            // Summed in f64, the colors may be stored in single precision
+            let mut sum = [0.0; 3];
+            for index in first..first + self.samples_per_pixel {
+                let color = self.trace_sample(i, j, index, world).0.as_array();
+                for (total, channel) in sum.iter_mut().zip(color) {
+                    *total += channel;
+                }
+            }
+            sum
+        });
+        for (row, pass_row) in self.accumulated.iter_mut().zip(pass) {
+            for ((sum, count), pass_sum) in row.iter_mut().zip(pass_row) {
+                for (total, channel) in sum.iter_mut().zip(pass_sum) {
+                    *total += channel;
+                }
+                *count += self.samples_per_pixel as u32;
+            }
+        }
+    }
+    /// Takes the sums of the samples accumulated by `accumulate`, row by row, with the number of
+    /// samples of every pixel, and starts over. Dividing one by the other gives the image in full
+    /// precision. It is quantized to 8 bits only when converted for display or writing, by
+    /// `tonemap_pixel`, which every 8 bits output goes through: `write_to_png`, `write_to_ppm`
+    /// and `render_rgba8`
+    pub fn take_accumulator(&mut self) -> (Vec<Vec<[f64; 3]>>, Vec<Vec<u32>>) {
+        let accumulated = std::mem::take(&mut self.accumulated);
+        let sums = accumulated
+            .iter()
+            .map(|row| row.iter().map(|&(sum, _)| sum).collect())
+            .collect();
+        let counts = accumulated
+            .iter()
+            .map(|row| row.iter().map(|&(_, count)| count).collect())
+            .collect();
+        (sums, counts)
+    }
+    /// Renders only the direct lighting into memory: the light emitted by what the camera sees and
+    /// the light reaching it straight from the emitters and the background, without the indirect
+    /// bounces. Comparing it with `render_to_buffer` isolates the global illumination
+    pub fn render_direct_only(&mut self, world: &dyn Hittable) -> Vec<Vec<Color>> {
+        self.initialize();
+        self.render_pass(|i, j| {
+            let color: Color = (0..self.samples_per_pixel)
+                .map(|index| {
+                    let ray = self.get_ray(i, j, index);
+                    self.trace(&ray, world, self.max_depth, true).0
+                })
+                .sum();
+            color * self.pixel_sample_scale
+        })
+    }
+    /// Renders the image split in two buffers that add up to `render_to_buffer`: the light emitted
+    /// by the surfaces the camera rays hit, so only the lights seen directly, and the light they
+    /// scatter, with the background seen directly. The chromatic aberration is left out
+    pub fn render_split(&mut self, world: &dyn Hittable) -> (Vec<Vec<Color>>, Vec<Vec<Color>>) {
+        self.initialize();
+        let image = self.render_pass(|i, j| {
+            let (emission, scatter) = (0..self.samples_per_pixel)
+                .map(|index| {
+                    let ray = self.get_ray(i, j, index);
+                    let (color, _, emission) = self.trace(&ray, world, self.max_depth, false);
+                    (emission, color - emission)
+                })
+                .fold(
+                    (Color::default(), Color::default()),
+                    |(emission, scatter), sample| (emission + sample.0, scatter + sample.1),
+                );
+            (
+                emission * self.pixel_sample_scale,
+                scatter * self.pixel_sample_scale,
+            )
+        });
+        image.into_iter().map(|row| row.into_iter().unzip()).unzip()
+    }
+    /// Renders `levels` images, the first at full resolution and each of the others half the size
+    /// of the one before, down to 1 pixel. By default the others are box downsampled from the
+    /// first, each pixel the average of the 2x2 pixels under it, dropping the last row or column
+    /// of an odd size; with independent levels each is rendered at its own resolution
+    pub fn render_pyramid(&mut self, world: &dyn Hittable, levels: usize) -> Vec<Vec<Vec<Color>>> {
+        let mut pyramid = vec![self.render_to_buffer(world)];
+        let (width, height) = (self.image_width, self.image_height);
+        for level in 1..levels {
+            let (level_width, level_height) = (width >> level, height >> level);
+            if level_width == 0 || level_height == 0 {
+                break;
+            }
+            let image = if self.independent_pyramid {
+                let mut camera = self.clone();
+                camera.set_resolution(level_width, level_height);
+                camera.render_to_buffer(world)
+            } else {
+                downsample(pyramid.last().unwrap())
+            };
+            pyramid.push(image);
+        }
+        pyramid
+    }
+    /// Renders the image into memory with the alpha of each pixel, the fraction of its samples
+    /// whose camera ray hits the scene. With a transparent background the samples that miss are
+    /// black, so the colors come out premultiplied by the alpha. Nothing is printed, the progress
+    /// bar is only shown by `render`
+    pub fn render_rgba(&mut self, world: &dyn Hittable) -> Vec<Vec<(Color, f64)>> {
+        self.render_rgba_with_progress(world, false)
+    }
+    fn render_rgba_with_progress(
+        &mut self,
+        world: &dyn Hittable,
+        show_progress: bool,
+    ) -> Vec<Vec<(Color, f64)>> {
+        self.initialize();
+
+        let bar = if show_progress {
+            progress_bar(self.image_height as u64, "Rendering image...")
+        } else {
+            ProgressBar::hidden()
+        };
         let image: Vec<Vec<_>> = (0..self.image_height)
             .into_par_iter()
             .map(|j| {
                 bar.inc(1);
-                let pixel_colors: Vec<_> = (0..self.image_width.unwrap())
+                let pixel_colors: Vec<_> = (0..self.image_width)
                     .into_par_iter()
-                    .map(|i| {
-                        let mut pixel_color = Color::default();
-                        for _ in 0..self.samples_per_pixel {
-                            let ray: Ray = self.get_ray(i, j);
-                            pixel_color += self.ray_color(&ray, world, self.max_depth.unwrap());
-                        }
-                        pixel_color * self.pixel_sample_scale
-                    })
+                    .map(|i| self.sample_pixel_alpha(i, j, world))
                     .collect();
                 pixel_colors
             })
             .collect();
-        write_to_png(
-            &filename,
-            &image,
-            self.image_width.unwrap(),
-            self.image_height,
-        );
         bar.finish_with_message("\nRendering Done!!\n");
+        image
+    }
+    /// Renders the image into 8 bits RGBA bytes, row by row from the top left pixel, tonemapped
+    /// and gamma corrected like the PNG output. Returns the bytes with the width and the height.
+    /// Nothing is written to the filesystem, so it can be used to draw on a canvas from WASM. The
+    /// alpha is only below 255 with a transparent background
+    pub fn render_rgba8(&mut self, world: &dyn Hittable) -> (Vec<u8>, u32, u32) {
+        let image = self.render_rgba(world);
+        let image = self.to_output(image);
+        let bytes = image
+            .iter()
+            .flatten()
+            .flat_map(|&(color, alpha)| {
+                if self.transparent_background {
+                    tonemap_rgba_pixel(color, alpha)
+                } else {
+                    let [r, g, b] = tonemap_pixel(color, ToneMap::Clamp, 2.0);
+                    [r, g, b, u8::MAX]
+                }
+            })
+            .collect();
+        (bytes, self.image_width as u32, self.image_height as u32)
+    }
+    /// Number of tiles `render_tiles` splits the image into. They are indexed row by row, from the
+    /// top left corner
+    pub fn tile_count(&mut self) -> usize {
+        self.initialize();
+        let (columns, rows) = self.tile_grid();
+        columns * rows
+    }
+    /// Renders only the tiles with the given indices, so the image can be split among several
+    /// machines, and returns them tagged with their index. The random numbers of each tile are
+    /// seeded from the camera seed and the tile index, so a tile is the same no matter which call
+    /// renders it
+    pub fn render_tiles(
+        &mut self,
+        world: &dyn Hittable,
+        tile_indices: &[usize],
+    ) -> Vec<(usize, Vec<Vec<Color>>)> {
+        self.initialize();
+        let seed = self.seed;
+        tile_indices
+            .par_iter()
+            .map(|&index| {
+                let (columns, rows) = self.tile_bounds(index);
+                seed_random(seed.wrapping_add(index as u64));
+                let tile = rows
+                    .map(|j| {
+                        columns
+                            .clone()
+                            .map(|i| self.sample_pixel(i, j, world))
+                            .collect()
+                    })
+                    .collect();
+                (index, tile)
+            })
+            .collect()
+    }
+    /// Stitches the tiles returned by `render_tiles` back into the full image. Pixels of missing
+    /// tiles are black
+    pub fn assemble_tiles(&mut self, tiles: &[(usize, Vec<Vec<Color>>)]) -> Vec<Vec<Color>> {
+        self.initialize();
+        let mut image =
+            vec![vec![Color::default(); self.image_width as usize]; self.image_height as usize];
+        for (index, tile) in tiles {
+            let (columns, rows) = self.tile_bounds(*index);
+            for (row, j) in tile.iter().zip(rows) {
+                for (color, i) in row.iter().zip(columns.clone()) {
+                    image[j as usize][i as usize] = *color;
+                }
+            }
+        }
+        image
+    }
+    /// Number of columns and rows of tiles. The tiles of the last column and row are cut short
+    /// when the image size isn't a multiple of the tile size
+    fn tile_grid(&self) -> (usize, usize) {
+        let size = self.tile_size as usize;
+        (
+            (self.image_width as usize).div_ceil(size),
+            (self.image_height as usize).div_ceil(size),
+        )
     }
-    /// Returns the ray that goes from the camera to the pixel (i,j). The ray is calculated using
-    /// the following steps:
-    /// - Calculate the offset of the pixel. It is based on a 1 x 1 square, where we randomly sample from it
+    /// Ranges of the pixel columns and rows covered by a tile
+    fn tile_bounds(&self, index: usize) -> (Range<i32>, Range<i32>) {
+        let (columns, rows) = self.tile_grid();
+        assert!(index < columns * rows, "Tile {index} is out of the image");
+        let size = self.tile_size;
+        let x = (index % columns) as i32 * size;
+        let y = (index / columns) as i32 * size;
+        (
+            x..cmp::min(x + size, self.image_width),
+            y..cmp::min(y + size, self.image_height),
+        )
+    }
+    /// Renders a linear depth pass aligned with the beauty render. A ray is cast through the
+    /// center of each pixel and the distance to the first hit is normalized to the depth range,
+    /// so the near distance maps to 0 and the far one to 1. Misses are at the far value
+    pub fn render_depth_buffer(&mut self, world: &dyn Hittable) -> Vec<Vec<f64>> {
+        self.initialize();
+        let range = self.depth_range;
+        self.render_pass(|i, j| {
+            let ray = self.get_center_ray(i, j);
+            let distance = match world.hit(&ray, &Interval::new(0.001, INFINITY)) {
+                Some(rec) => rec.t() * ray.direction().magnitude(),
+                None => range.max(),
+            };
+            UNITY_INTERVAL.clamp((distance - range.min()) / range.size())
+        })
+    }
+    /// Renders the depth pass and writes it as a 16 bits grayscale PNG
+    pub fn render_depth(&mut self, world: &dyn Hittable, filename: String) {
+        let depth = self.render_depth_buffer(world);
+        write_depth_to_png(&filename, &depth, self.image_width, self.image_height);
+    }
+    /// Renders the image together with the variance of the luminance of the samples of each
+    /// pixel, to see where the image is noisy. The mean and the variance are accumulated with
+    /// Welford's running update, and the variance is the unbiased one of the samples, 0 for a
+    /// single sample
+    pub fn render_with_variance(
+        &mut self,
+        world: &dyn Hittable,
+    ) -> (Vec<Vec<Color>>, Vec<Vec<f64>>) {
+        self.initialize();
+        let pixels = self.render_pass(|i, j| {
+            let mut color = Color::default();
+            let (mut mean, mut squared_deviations) = (0.0, 0.0);
+            for index in 0..self.samples_per_pixel {
+                let (sample, _) = self.trace_sample(i, j, index, world);
+                color += sample;
+                let luminance = sample.luminance();
+                let delta = luminance - mean;
+                mean += delta / f64::from(index + 1);
+                squared_deviations += delta * (luminance - mean);
+            }
+            let variance = if self.samples_per_pixel > 1 {
+                squared_deviations / f64::from(self.samples_per_pixel - 1)
+            } else {
+                0.0
+            };
+            (color * self.pixel_sample_scale, variance)
+        });
+        pixels
+            .into_iter()
+            .map(|row| row.into_iter().unzip())
+            .unzip()
+    }
+    /// Renders the variance pass and writes it as a 16 bits grayscale PNG, scaled so the noisiest
+    /// pixel is white
+    pub fn render_variance(&mut self, world: &dyn Hittable, filename: String) {
+        let (_, variance) = self.render_with_variance(world);
+        let max = variance
+            .iter()
+            .flatten()
+            .fold(0.0, |max: f64, &v| max.max(v));
+        let scale = if max > 0.0 { 1.0 / max } else { 0.0 };
+        let normalized: Vec<Vec<f64>> = variance
+            .iter()
+            .map(|row| row.iter().map(|v| v * scale).collect())
+            .collect();
+        write_depth_to_png(&filename, &normalized, self.image_width, self.image_height);
+    }
+    /// Renders a cubemap seen from `center`: six square views of `face_size` pixels with a 90
+    /// degree field of view, looking along +x, -x, +y, -y, +z and -z in that order. The side
+    /// faces have +y up, the +y face has -z up and the -y face +z. The other settings of the
+    /// camera, like the samples and the background, are kept for every face
+    pub fn render_cubemap(
+        &self,
+        world: &dyn Hittable,
+        center: Point3,
+        face_size: i32,
+    ) -> [Vec<Vec<Color>>; 6] {
+        CUBEMAP_FACES.map(|(direction, up)| {
+            let mut face = self.clone();
+            face.set_lookfrom(center);
+            face.set_lookat(center + direction);
+            face.set_vup(up);
+            face.set_vertical_fov(90.0).unwrap();
+            face.set_defocus_angle(0.0);
+            face.set_resolution(face_size, face_size);
+            face.render_to_buffer(world)
+        })
+    }
+    /// Renders the normal pass: the normal of the first hit through the center of each pixel, in
+    /// world or view coordinates. Misses have a zero normal
+    pub fn render_normals(&mut self, world: &dyn Hittable, space: NormalSpace) -> Vec<Vec<Vec3>> {
+        self.initialize();
+        self.render_pass(|i, j| {
+            let ray = self.get_center_ray(i, j);
+            let Some(rec) = world.hit(&ray, &Interval::new(0.001, INFINITY)) else {
+                return Vec3::default();
+            };
+            let normal = rec.normal();
+            match space {
+                NormalSpace::World => normal,
+                NormalSpace::View => Vec3::new(
+                    normal.dot_product(&self.u),
+                    normal.dot_product(&self.v),
+                    normal.dot_product(&self.w),
+                ),
+            }
+        })
+    }
+    /// Renders an ambient occlusion pass, independent of materials and lights. The grayscale value
+    /// of each pixel is the cosine-weighted fraction of `samples` directions over the hemisphere of
+    /// the first hit that don't hit anything closer than `radius`. Misses are white
+    pub fn render_ao(
+        &mut self,
+        world: &dyn Hittable,
+        radius: f64,
+        samples: i32,
+    ) -> Vec<Vec<Color>> {
+        self.initialize();
+        self.render_pass(|i, j| {
+            let ray = self.get_center_ray(i, j);
+            let occlusion = self.ambient_occlusion(&ray, world, radius, samples);
+            Color::new(occlusion, occlusion, occlusion)
+        })
+    }
+    /// Fraction of the hemisphere around the first hit of `ray` that is unoccluded within
+    /// `radius`. Directions are sampled uniformly and weighted by their cosine with the normal
+    fn ambient_occlusion(&self, ray: &Ray, world: &dyn Hittable, radius: f64, samples: i32) -> f64 {
+        let rec = match world.hit(ray, &Interval::new(0.001, INFINITY)) {
+            Some(rec) => rec,
+            None => return 1.0,
+        };
+        let mut unoccluded = 0.0;
+        let mut total = 0.0;
+        for _ in 0..samples {
+            let direction = random_on_hemisphere(rec.normal());
+            let cosine = direction.dot_product(&rec.normal());
+            let occlusion_ray = Ray::new(rec.p(), direction, ray.time());
+            // A radius below the offset of the rays leaves nothing to occlude
+            let occluded =
+                radius > 0.001 && world.hit_any(&occlusion_ray, &Interval::new(0.001, radius));
+            if !occluded {
+                unoccluded += cosine;
+            }
+            total += cosine;
+        }
+        if total > 0.0 {
+            unoccluded / total
+        } else {
+            1.0
+        }
+    }
+    /// Renders the object ID pass: each pixel gets the color of the identifier of the first object
+    /// hit through its center, as given by `Color::from_id`. Misses and objects without an
+    /// identifier are black
+    pub fn render_object_id(&mut self, world: &dyn Hittable) -> Vec<Vec<Color>> {
+        self.initialize();
+        self.render_pass(|i, j| {
+            let ray = self.get_center_ray(i, j);
+            world
+                .hit(&ray, &Interval::new(0.001, INFINITY))
+                .and_then(|rec| rec.id())
+                .map_or(Color::default(), Color::from_id)
+        })
+    }
+    /// Renders the material ID pass: each pixel gets the color of the kind of the material of the
+    /// first object hit through its center, as given by `MaterialKind::color`. Misses and hits
+    /// without a material are black
+    pub fn render_material_ids(&mut self, world: &dyn Hittable) -> Vec<Vec<Color>> {
+        self.initialize();
+        self.render_pass(|i, j| {
+            let ray = self.get_center_ray(i, j);
+            world
+                .hit(&ray, &Interval::new(0.001, INFINITY))
+                .and_then(|rec| rec.get_material())
+                .map_or(Color::default(), |material| material.kind().color())
+        })
+    }
+    /// Renders the coverage of every pixel: the fraction of its camera rays that hit the scene,
+    /// with the same samples as `render` but without shading, which makes an antialiased matte
+    pub fn render_coverage(&mut self, world: &dyn Hittable) -> Vec<Vec<f64>> {
+        self.initialize();
+        self.render_pass(|i, j| {
+            let covered = (0..self.samples_per_pixel)
+                .filter(|&index| {
+                    let ray = self.get_ray(i, j, index);
+                    let interval = self.camera_ray_interval(&ray, Interval::new(0.001, INFINITY));
+                    world.hit_any(&ray, &interval)
+                })
+                .count();
+            covered as f64 * self.pixel_sample_scale
+        })
+    }
+    /// Averages the color of the samples of the pixel (i, j)
+    fn sample_pixel(&self, i: i32, j: i32, world: &dyn Hittable) -> Color {
+        self.sample_pixel_alpha(i, j, world).0
+    }
+    /// Averages the color of the samples of the pixel (i, j), together with the fraction of them
+    /// whose camera ray hits the scene
+    fn sample_pixel_alpha(&self, i: i32, j: i32, world: &dyn Hittable) -> (Color, f64) {
+        let (color, covered) =
+            (0..self.samples_per_pixel).fold((Color::default(), 0), |(color, covered), index| {
+                let (sample, hit) = self.trace_sample(i, j, index, world);
+                (color + sample, covered + i32::from(hit))
+            });
+        (
+            color * self.pixel_sample_scale,
+            f64::from(covered) * self.pixel_sample_scale,
+        )
+    }
+    /// Traces the sample `index` of the pixel (i, j), with whether its camera ray hits the scene.
+    /// With chromatic aberration each primary traces its own camera ray and keeps its component
+    fn trace_sample(&self, i: i32, j: i32, index: i32, world: &dyn Hittable) -> (Color, bool) {
+        let depth = self.max_depth;
+        let (sample, hit) = if self.chromatic_aberration == 0.0 {
+            self.trace_path(&self.get_ray(i, j, index), world, depth)
+        } else {
+            let [red, green, blue] = self
+                .get_channel_rays(i, j, index)
+                .map(|ray| self.trace_path(&ray, world, depth));
+            (Color::new(red.0.x(), green.0.y(), blue.0.z()), green.1)
+        };
+        (self.clamp_sample(sample), hit)
+    }
+    /// Clamps the negative components of a sample to 0 when enabled, counting the samples that
+    /// had any
+    fn clamp_sample(&self, sample: Color) -> Color {
+        if !self.clamp_negative || sample.as_array().iter().all(|&c| c >= 0.0) {
+            return sample;
+        }
+        self.rays.negative.fetch_add(1, Ordering::Relaxed);
+        Color::new(
+            sample.x().max(0.0),
+            sample.y().max(0.0),
+            sample.z().max(0.0),
+        )
+    }
+    /// Evaluates `pixel` for every pixel (i, j) of the image in parallel, returning the rows
+    fn render_pass<T: Send>(&self, pixel: impl Fn(i32, i32) -> T + Sync) -> Vec<Vec<T>> {
+        (0..self.image_height)
+            .into_par_iter()
+            .map(|j| {
+                (0..self.image_width)
+                    .into_par_iter()
+                    .map(|i| pixel(i, j))
+                    .collect()
+            })
+            .collect()
+    }
+    /// Returns the ray from the center of the camera through the center of the pixel (i,j),
+    /// without any jitter or defocus, at the middle of the exposure. Used by the non-beauty passes
+    fn get_center_ray(&self, i: i32, j: i32) -> Ray {
+        let pixel_center = self.pixel00_loc + (i as f64 * self.delta_u) + (j as f64 * self.delta_v);
+        let shutter = self.shutter();
+        let time = shutter.min() + shutter.size() * self.shutter_profile.time(0.5);
+        Ray::new(self.center, pixel_center - self.center, time)
+    }
+    /// Returns the sample `index` of the rays that go from the camera to the pixel (i,j). The ray
+    /// is calculated using the following steps:
+    /// - Draw the random numbers of the sample from the sampler
+    /// - Calculate the offset of the pixel, spread around its center by the pixel filter
     /// - Calculate the pixel sample. It is calculated by adding the offset to the pixel 00 location
     /// - Calculate the ray origin. If the defocus angle is less than or equal to 0, the ray origin is the center of the camera
     /// - Calculate the ray direction. It is calculated by subtracting the pixel sample from the ray origin
     /// - Return the ray
     ///
-    fn get_ray(&self, i: i32, j: i32) -> Ray {
-        let offset = self.sample_square();
+    fn get_ray(&self, i: i32, j: i32, index: i32) -> Ray {
+        let sample = self.camera_sample(i, j, index);
+        match &self.ray_generator {
+            Some(generator) => generator(i, j, self, &sample),
+            None => self.ray_from_sample(i, j, &sample, 1.0),
+        }
+    }
+    /// Returns the red, green and blue camera rays of the sample `index` of the pixel (i, j). They
+    /// share the random numbers of the sample, but the red and blue ones go through points of the
+    /// viewport scaled away from and towards its center by the chromatic aberration, so the colors
+    /// fringe more towards the edges of the frame. The green ray is the one of `get_ray`, and so
+    /// are the three with a ray generator
+    fn get_channel_rays(&self, i: i32, j: i32, index: i32) -> [Ray; 3] {
+        if self.ray_generator.is_some() {
+            return [self.get_ray(i, j, index); 3];
+        }
+        let sample = self.camera_sample(i, j, index);
+        [1.0, 0.0, -1.0].map(|spread| {
+            self.ray_from_sample(i, j, &sample, 1.0 + spread * self.chromatic_aberration)
+        })
+    }
+    fn camera_sample(&self, i: i32, j: i32, index: i32) -> CameraSample {
+        match &self.sampler {
+            Some(sampler) => sampler.sample(i, j, index),
+            None => RandomSampler.sample(i, j, index),
+        }
+    }
+    /// Builds the camera ray of `sample` for the pixel (i, j), magnifying the viewport around its
+    /// center by `magnification`
+    fn ray_from_sample(&self, i: i32, j: i32, sample: &CameraSample, magnification: f64) -> Ray {
+        let (offset_x, offset_y) = (
+            self.pixel_filter.offset(sample.pixel.0),
+            self.pixel_filter.offset(sample.pixel.1),
+        );
         let pixel_sample = self.pixel00_loc
-            + ((i as f64 + offset.x()) * self.delta_u + ((j as f64 + offset.y()) * self.delta_v));
-        let ray_origin = if self.defocus_angle.unwrap() <= 0.0 {
+            + ((i as f64 + offset_x) * self.delta_u + ((j as f64 + offset_y) * self.delta_v));
+        let viewport_center = self.pixel00_loc
+            + 0.5
+                * (f64::from(self.image_width - 1) * self.delta_u
+                    + f64::from(self.image_height - 1) * self.delta_v);
+        let pixel_sample = viewport_center + magnification * (pixel_sample - viewport_center);
+        let ray_origin = if self.defocus_angle <= 0.0 {
             self.center
         } else {
-            self.sample_disk()
+            self.sample_disk(sample.lens)
         };
         let ray_direction = pixel_sample - ray_origin;
-        let ray_time = random_double();
+        let shutter = self.shutter();
+        let ray_time = shutter.min() + shutter.size() * self.shutter_profile.time(sample.time);
         Ray::new(ray_origin, ray_direction, ray_time)
     }
     /// Samples a point in the defocus disk. The point is sampled using the following steps:
-    /// - Sample a point in the disk
+    /// - Map the lens sample from the square to the disk
+    /// - With an aperture mask, keep the point with a probability given by the mask there, and
+    ///   draw new random points until one is kept. After `APERTURE_MASK_TRIES` points, fall back
+    ///   to the last one where the mask is open at all, or keep drawing until there is one. A
+    ///   mask closed everywhere never returns
     /// - Calculate the point in the defocus disk by adding the point in the disk to the defocus disk vectors
     /// - Return the point in the defocus disk
-    fn sample_disk(&self) -> Vec3 {
-        let p = random_on_disk();
-        self.center + (p.x() * self.defocus_disk_u) + (p.y() * self.defocus_disk_v)
+    fn sample_disk(&self, (u, v): (f64, f64)) -> Vec3 {
+        let (x, y) = match &self.aperture_mask {
+            None => square_to_disk(u, v),
+            Some(mask) => {
+                let opening = |(x, y): (f64, f64)| {
+                    mask.value(0.5 * x + 0.5, 0.5 * y + 0.5, &self.center)
+                        .luminance()
+                };
+                let mut point = square_to_disk(u, v);
+                let mut last_open = None;
+                for _ in 0..APERTURE_MASK_TRIES {
+                    let open = opening(point);
+                    if random_double() < open {
+                        return self.disk_point(point);
+                    }
+                    if open > 0.0 {
+                        last_open = Some(point);
+                    }
+                    point = square_to_disk(random_double(), random_double());
+                }
+                // Never fall back to a point the mask blocks
+                while last_open.is_none() {
+                    if opening(point) > 0.0 {
+                        last_open = Some(point);
+                    }
+                    point = square_to_disk(random_double(), random_double());
+                }
+                last_open.unwrap()
+            }
+        };
+        self.disk_point((x, y))
     }
-    fn sample_square(&self) -> Vec3 {
-        Vec3::new(random_double() - 0.5, random_double() - 0.5, 0.)
+    /// The point of the defocus disk at (x, y) on the unit disk
+    fn disk_point(&self, (x, y): (f64, f64)) -> Vec3 {
+        self.center + (x * self.defocus_disk_u) + (y * self.defocus_disk_v)
     }
-    pub fn set_aspect_ratio(&mut self, aspect_ratio: f64) {
-        self.aspect_ratio = Some(aspect_ratio);
+    pub fn set_aspect_ratio(&mut self, aspect_ratio: f64) -> Result<(), CameraError> {
+        let previous = (self.aspect_ratio, self.requested_height.take());
+        self.aspect_ratio = aspect_ratio;
+        self.check_settings().inspect_err(|_| {
+            (self.aspect_ratio, self.requested_height) = previous;
+        })
     }
-    pub fn set_width(&mut self, width: i32) {
-        self.image_width = Some(width);
+    pub fn set_width(&mut self, width: i32) -> Result<(), CameraError> {
+        let previous = (self.image_width, self.requested_height.take());
+        self.image_width = width;
+        self.check_settings().inspect_err(|_| {
+            (self.image_width, self.requested_height) = previous;
+        })
     }
-    pub fn set_sample_per_pixel(&mut self, samples: i32) {
-        self.samples_per_pixel = samples;
+    /// Sets the exact size of the image in pixels, with the aspect ratio that follows from it,
+    /// instead of deriving the height from the width and the aspect ratio. Setting the width or
+    /// the aspect ratio afterwards derives the height again
+    pub fn set_resolution(&mut self, width: i32, height: i32) {
+        assert!(
+            width > 0 && height > 0,
+            "The resolution must be positive, got {width}x{height}"
+        );
+        self.image_width = width;
+        self.aspect_ratio = width as f64 / height as f64;
+        self.requested_height = Some(height);
+    }
+    /// A count that isn't positive is rejected, keeping the current one, 10 by default
+    pub fn set_sample_per_pixel(&mut self, samples: i32) -> Result<(), CameraError> {
+        let previous = std::mem::replace(&mut self.samples_per_pixel, samples);
+        self.check_settings()
+            .inspect_err(|_| self.samples_per_pixel = previous)
     }
-    pub fn set_max_depth(&mut self, depth: i32) {
-        self.max_depth = Some(depth);
+    /// A depth that isn't positive is rejected, keeping the current one, 50 by default
+    pub fn set_max_depth(&mut self, depth: i32) -> Result<(), CameraError> {
+        let previous = std::mem::replace(&mut self.max_depth, depth);
+        self.check_settings()
+            .inspect_err(|_| self.max_depth = previous)
     }
-    pub fn set_vertical_fov(&mut self, vfov: f64) {
-        self.vfov = Some(vfov);
+    /// Limits how many specular bounces in a row a path can take, so rays trapped between
+    /// dielectric and metal surfaces stop early. A path that exceeds it is terminated, keeping the
+    /// light it has gathered so far
+    pub fn set_max_specular_bounces(&mut self, bounces: i32) {
+        self.max_specular_bounces = bounces;
+    }
+    pub fn set_vertical_fov(&mut self, vfov: f64) -> Result<(), CameraError> {
+        let previous = std::mem::replace(&mut self.vfov, vfov);
+        self.check_settings().inspect_err(|_| self.vfov = previous)
     }
     pub fn set_lookfrom(&mut self, from: Point3) {
-        self.lookfrom = Some(from);
+        self.lookfrom = from;
     }
     pub fn set_lookat(&mut self, at: Point3) {
-        self.lookat = Some(at);
+        self.lookat = at;
     }
     pub fn set_vup(&mut self, vup: Vec3) {
-        self.vup = Some(vup);
+        self.vup = vup;
     }
     pub fn set_defocus_angle(&mut self, angle: f64) {
-        self.defocus_angle = Some(angle);
+        self.defocus_angle = angle;
     }
-    pub fn set_focus_distance(&mut self, distance: f64) {
-        self.focus_distance = Some(distance);
+    pub fn set_focus_distance(&mut self, distance: f64) -> Result<(), CameraError> {
+        let previous = std::mem::replace(&mut self.focus_distance, distance);
+        self.check_settings()
+            .inspect_err(|_| self.focus_distance = previous)
     }
     pub fn set_background_color(&mut self, color: Color) {
-        self.background = Some(color);
+        self.background = Arc::new(color);
+    }
+    pub fn set_background<B: Background + 'static>(&mut self, background: B) {
+        self.background = Arc::new(background);
+    }
+    /// Sets what the camera rays that miss the scene see, leaving the background for the rays
+    /// that bounce, so the framing and the lighting can differ
+    pub fn set_primary_background<B: Background + 'static>(&mut self, background: B) {
+        self.primary_background = Some(Arc::new(background));
+    }
+    pub fn set_primary_background_color(&mut self, color: Color) {
+        self.primary_background = Some(Arc::new(color));
+    }
+    pub fn set_lights(&mut self, lights: LightList) {
+        self.lights = lights;
+    }
+    pub fn set_depth_range(&mut self, near: f64, far: f64) {
+        self.depth_range = Interval::new(near, far);
+    }
+    /// Makes the pixels seen directly through the background transparent, black with alpha 0, and
+    /// `render` writes an RGBA image. Rays that miss after bouncing still get the background
+    /// color, so it keeps lighting the scene
+    pub fn set_transparent_background(&mut self, transparent: bool) {
+        self.transparent_background = transparent;
+    }
+    /// Clamps the negative components of the samples to 0 before they are averaged, so the rare
+    /// negative ones of numerical edge cases don't darken the pixels around them. `render`
+    /// reports how many samples were clamped
+    pub fn set_clamp_negative(&mut self, clamp: bool) {
+        self.clamp_negative = clamp;
+    }
+    /// Makes `render_pyramid` render every level at its resolution, trading time for levels
+    /// that are as sharp as a render of that size
+    pub fn set_independent_pyramid_levels(&mut self, independent: bool) {
+        self.independent_pyramid = independent;
+    }
+    /// Writes the images of `render` in `format` whatever the extension of the file
+    pub fn set_output_format(&mut self, format: OutputFormat) {
+        self.output_format = Some(format);
+    }
+    /// Draws framing guides over the image written by `render`, and its checkpoints
+    pub fn set_overlay(&mut self, overlay: Overlay) {
+        self.overlay = overlay;
+    }
+    /// Makes `render` overwrite the output file with the image accumulated so far every
+    /// `interval`, so it can be opened before the render is done
+    pub fn set_checkpoint_interval(&mut self, interval: Duration) {
+        self.checkpoint_interval = Some(interval);
+    }
+    /// Sets when the shutter opens and closes. The camera rays get times inside it, and the
+    /// bounding volumes of moving objects should be built over it too, see `Scene::from_objects`
+    pub fn set_shutter(&mut self, shutter: Interval) {
+        self.shutter = Some(shutter);
+    }
+    pub fn shutter(&self) -> Interval {
+        self.shutter.unwrap_or(UNITY_INTERVAL)
+    }
+    /// Shapes how the times of the camera rays are spread over the exposure, uniform by default
+    pub fn set_shutter_profile(&mut self, profile: ShutterProfile) {
+        self.shutter_profile = profile;
+    }
+    /// Sets how the camera rays are spread around the centers of the pixels, a tent by default
+    pub fn set_pixel_filter(&mut self, filter: PixelFilter) {
+        self.pixel_filter = filter;
+    }
+    /// Brightens (positive `stops`) or darkens (negative) the written image, multiplying the linear
+    /// colors by `2^stops` before they are tonemapped and gamma corrected
+    pub fn set_exposure(&mut self, stops: f64) {
+        self.exposure = stops;
+    }
+    /// Converts the colors to other primaries before they are tonemapped and written, for example
+    /// `Matrix3::ACESCG`. The renders are in Rec.709, left unchanged by default
+    pub fn set_output_primaries(&mut self, primaries: Matrix3) {
+        self.output_primaries = primaries;
+    }
+    /// Fringes the colors of the beauty renders like a simple lens: the red camera rays are
+    /// spread `strength` times further from the center of the frame than the green ones and the
+    /// blue rays as much closer, for example 0.01. Costs three camera paths per sample
+    pub fn set_chromatic_aberration(&mut self, strength: f64) {
+        self.chromatic_aberration = strength;
+    }
+    /// One line description of the framing: where the camera is, where it looks at, its field of
+    /// view and the size of the image
+    pub fn summary(&mut self) -> String {
+        self.initialize();
+        let point = |p: Point3| format!("({}, {}, {})", p.x(), p.y(), p.z());
+        format!(
+            "camera at {} looking at {}, vertical fov {}°, {}x{} pixels",
+            point(self.center),
+            point(self.lookat),
+            self.vfov,
+            self.image_width,
+            self.image_height
+        )
+    }
+    /// Whether some of `bbox` may be in the field of view, up to the far clip distance. The box is
+    /// only ruled out when all its corners are outside of the same side of the frustum, so boxes
+    /// just outside of a corner of it still pass. The lens is taken as a point, out of focus
+    /// objects just outside of the frustum can still blur into the image
+    pub fn sees_box(&mut self, bbox: &AABB) -> bool {
+        self.initialize();
+        let half_height = f64::tan(degree_to_radians(self.vfov) / 2.0);
+        let half_width = half_height * self.image_width as f64 / self.image_height as f64;
+        let (min, max) = (bbox.min(), bbox.max());
+        let corners: Vec<(f64, f64, f64)> = (0..8)
+            .map(|k| {
+                let corner = Point3::new(
+                    if k & 1 == 0 { min.x() } else { max.x() },
+                    if k & 2 == 0 { min.y() } else { max.y() },
+                    if k & 4 == 0 { min.z() } else { max.z() },
+                );
+                let d = corner - self.center;
+                (
+                    d.dot_product(&self.u),
+                    d.dot_product(&self.v),
+                    -d.dot_product(&self.w),
+                )
+            })
+            .collect();
+        let outside = |side: &dyn Fn(f64, f64, f64) -> bool| {
+            corners.iter().all(|&(x, y, depth)| side(x, y, depth))
+        };
+        let far = self.far_clip.unwrap_or(INFINITY);
+        !(outside(&|_, _, depth| depth <= 0.0)
+            || outside(&|_, _, depth| depth > far)
+            || outside(&|x, _, depth| x > depth * half_width)
+            || outside(&|x, _, depth| x < -depth * half_width)
+            || outside(&|_, y, depth| y > depth * half_height)
+            || outside(&|_, y, depth| y < -depth * half_height))
+    }
+    /// Right, up and backwards directions of the camera, the basis of its view space
+    pub fn view_basis(&mut self) -> (Vec3, Vec3, Vec3) {
+        self.initialize();
+        (self.u, self.v, self.w)
+    }
+    /// Shapes the lens with a grayscale mask read over the square around the defocus disk, with
+    /// (0, 0) at its bottom left corner: white lets the light through, black blocks it, and grays
+    /// partly. Out of focus highlights take the shape of the mask, within the disk. Only matters
+    /// with a defocus angle
+    pub fn set_aperture_mask<T: Texture + 'static>(&mut self, mask: T) {
+        self.aperture_mask = Some(Arc::new(mask));
+    }
+    /// Culls what is further than `far` from the camera: the camera rays that would hit it see
+    /// what is behind, usually the background. The bounced rays still reach it. `None` sees
+    /// everything, the default
+    pub fn set_far_clip(&mut self, far: Option<f64>) {
+        self.far_clip = far;
+    }
+    /// Sets where the random numbers of the camera rays come from, for example a `HaltonSampler`
+    /// to spread the samples of each pixel evenly
+    pub fn set_sampler<S: Sampler + 'static>(&mut self, sampler: S) {
+        self.sampler = Some(Arc::new(sampler));
+    }
+    /// Builds the camera rays with `generator` instead of the pinhole or the thin lens, for custom
+    /// lenses, distortions or light field arrays. It gets the random numbers of every sample, the
+    /// pixel filter, the lens and the shutter are left to it. The passes traced through the center
+    /// of the pixels keep the built-in rays
+    pub fn set_ray_generator(&mut self, generator: Box<RayGenerator>) {
+        self.ray_generator = Some(Arc::from(generator));
+    }
+    pub fn set_tile_size(&mut self, size: i32) {
+        self.tile_size = size;
+    }
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+    /// Casts a shadow ray from the hit point towards a random point of the lights. Returns 1.0 if
+    /// the light is reached and 0.0 if it is occluded or behind the surface. Averaged over the
+    /// samples of a pixel, it gives the fraction of the lights that is visible. If there are no
+    /// lights, the point is considered fully lit
+    fn light_visibility(&self, ray: &Ray, rec: &HitRecord, world: &dyn Hittable) -> f64 {
+        if self.lights.is_empty() {
+            return 1.0;
+        }
+        let to_light = self.lights.random(&rec.p());
+        if to_light.dot_product(&rec.normal()) <= 0.0 {
+            return 0.0;
+        }
+        // The direction reaches the light at t = 1, stop right before it so the light itself
+        // doesn't count as an occluder
+        let shadow_ray = Ray::new(rec.p(), to_light, ray.time());
+        self.count_ray(false);
+        if world.hit_any(&shadow_ray, &Interval::new(0.001, 0.999)) {
+            0.0
+        } else {
+            1.0
+        }
     }
     /// Calculates the color of the ray. The color is calculated using the following steps:
     /// - If the depth is less than or equal to 0, return the default color
     /// - If the ray intersects with an object:
+    ///   - Add the light emitted by the object
+    ///   - If the object bounds a nested transparent medium, find the refraction indices on both
+    ///     sides of the surface from the media the ray is in
     ///   - If the object scatters the ray, calculate the scattered ray and the attenuation
-    ///   - Return the attenuation multiplied by the color of the scattered ray
-    ///   - If the object does not scatter the ray, return the default color
-    ///   - If the ray does not intersect with an object, calculate the background color
-    ///   - Return the background color
+    ///   - If the material is not specular and there are lights, add the light arriving from a
+    ///     direction sampled towards them (next event estimation)
+    ///   - Add the attenuation multiplied by the color of the scattered ray, unless the bounce
+    ///     exceeds the limit of consecutive specular bounces
+    ///   - If the ray does not intersect with an object, return the background color
+    ///
+    /// Light reached both by sampling the lights and by following the scattered ray is weighted
+    /// with multiple importance sampling, so it isn't counted twice
     pub fn ray_color(&self, ray: &Ray, world: &Box<dyn Hittable>, depth: i32) -> Color {
-        if depth <= 0 {
-            return Color::default();
-        }
+        self.path_color(ray, world.as_ref(), depth)
+    }
+    /// Follows the path of a camera ray iteratively, so deep paths don't grow the stack. Each
+    /// bounce records the light it adds and the attenuation it applies to the rest of the path,
+    /// and the color is folded back from the end once the path stops
+    fn path_color(&self, ray: &Ray, world: &dyn Hittable, depth: i32) -> Color {
+        self.trace_path(ray, world, depth).0
+    }
+    /// Same as `path_color`, also telling whether the first ray of the path hit the scene. With a
+    /// transparent background, a first ray that misses is black
+    fn trace_path(&self, ray: &Ray, world: &dyn Hittable, depth: i32) -> (Color, bool) {
+        let (color, covered, _) = self.trace(ray, world, depth, false);
+        (color, covered)
+    }
+    /// Traces a path, keeping only the direct lighting if `direct_only` is set: the path stops at
+    /// the hit after the first diffuse bounce, which only adds the light it emits. Specular
+    /// bounces before it are still followed. Returns the light along the path, whether the camera
+    /// ray hit the scene and the part of the light emitted by the surface it hit
+    fn trace(
+        &self,
+        ray: &Ray,
+        world: &dyn Hittable,
+        depth: i32,
+        direct_only: bool,
+    ) -> (Color, bool, Color) {
         // Hack for floating point inacuracies. If the hit is super close to the
         // already intersected point, ignore it. Get rid of shadow acne
         let time_interval = Interval::new(0.001, INFINITY);
-        if let Some(rec) = world.hit(ray, &time_interval) {
-            let color_from_emission =
-                rec.get_material()
-                    .unwrap()
-                    .emmited(&rec.p(), rec.u(), rec.v());
-            if let Some(scatter_rec) = rec.get_material().as_ref().unwrap().scatter(ray, &rec) {
-                return color_from_emission
-                    + scatter_rec.attenuation
-                        * self.ray_color(&scatter_rec.scattered, world, depth - 1);
+        // Light emitted at each hit, light sampled directly from it and attenuation of the material
+        let mut bounces: Vec<(Color, Color, Color)> = Vec::new();
+        let mut ray = *ray;
+        // Density with which the previous hit picked the ray, `None` for camera rays and specular
+        // bounces, in which case the lights couldn't have been sampled and emission gets the
+        // full weight
+        let mut scattering_pdf: Option<f64> = None;
+        let mut specular_bounces = 0;
+        let mut remaining_depth = depth;
+        let mut media = MediumStack::new();
+        let mut covered = false;
+        let mut lit_directly = false;
+        let mut first_emission = Color::default();
+        let end = loop {
+            if remaining_depth <= 0 {
+                break Color::default();
+            }
+            let first_ray = remaining_depth == depth;
+            self.count_ray(first_ray);
+            let interval = if first_ray {
+                self.camera_ray_interval(&ray, time_interval)
+            } else {
+                time_interval
+            };
+            let Some(rec) = world.hit(&ray, &interval) else {
+                if first_ray && self.transparent_background {
+                    break Color::default();
+                }
+                let background = self.background_seen(first_ray);
+                let background_weight = match scattering_pdf {
+                    Some(pdf) => power_heuristic(pdf, background.pdf_value(&ray.direction())),
+                    None => 1.0,
+                };
+                break background_weight * background.value(&ray);
+            };
+            covered |= first_ray;
+            // A hit without a material neither scatters nor emits, it only blocks the path
+            let Some(material) = rec.get_material() else {
+                break Color::default();
+            };
+            if material.is_shadow_catcher() {
+                let background = self.background_seen(first_ray).value(&ray);
+                break background * self.light_visibility(&ray, &rec, world);
+            }
+            let emission_weight = match scattering_pdf {
+                Some(pdf) => {
+                    power_heuristic(pdf, self.lights.pdf_value(&ray.origin(), &ray.direction()))
+                }
+                None => 1.0,
+            };
+            let color_from_emission = emission_weight * material.emmited(&ray, &rec);
+            if first_ray {
+                first_emission = color_from_emission;
+            }
+            if direct_only && lit_directly {
+                break color_from_emission;
+            }
+            let Some(scatter_rec) = Self::scatter_nested(&mut media, &material, &ray, &rec) else {
+                break color_from_emission;
+            };
+            if scatter_rec.is_specular() {
+                if specular_bounces >= self.max_specular_bounces {
+                    break color_from_emission;
+                }
+                bounces.push((
+                    color_from_emission,
+                    Color::default(),
+                    scatter_rec.attenuation,
+                ));
+                scattering_pdf = None;
+                specular_bounces += 1;
+            } else {
+                let direct = self.direct_light(&ray, &rec, world);
+                bounces.push((color_from_emission, direct, scatter_rec.attenuation));
+                scattering_pdf = Some(scatter_rec.pdf);
+                specular_bounces = 0;
+                lit_directly = true;
+            }
+            ray = scatter_rec.scattered;
+            remaining_depth -= 1;
+        };
+        (gather_path(end, &bounces), covered, first_emission)
+    }
+    /// Scatters at the hit, resolving the refraction indices of nested transparent media on both
+    /// sides of the surface with the media the path is in. A surface hidden inside a medium of
+    /// higher priority lets the ray through unchanged
+    fn scatter_nested(
+        media: &mut MediumStack,
+        material: &Arc<dyn Material>,
+        ray: &Ray,
+        rec: &HitRecord,
+    ) -> Option<ScatterRecord> {
+        if material.medium().is_none() {
+            return material.scatter(ray, rec);
+        }
+        match media.interface(material, rec.front_face()) {
+            Interface::False => {
+                media.cross(material, rec.front_face());
+                Some(ScatterRecord {
+                    attenuation: Color::new(1.0, 1.0, 1.0),
+                    scattered: Ray::new(rec.p(), ray.direction(), ray.time()),
+                    pdf: 0.0,
+                })
+            }
+            Interface::Refract {
+                incident_index,
+                transmitted_index,
+            } => {
+                let scatter_rec =
+                    material.scatter_interface(ray, rec, incident_index, transmitted_index)?;
+                // The normal faces the incoming ray, so the transmitted rays go against it
+                if scatter_rec.scattered.direction().dot_product(&rec.normal()) < 0.0 {
+                    media.cross(material, rec.front_face());
+                }
+                Some(scatter_rec)
             }
-            return color_from_emission;
         }
-        self.background.unwrap()
     }
-}
-/// Calculates the color of the ray. The color is calculated using the following steps:
-/// - If the depth is less than or equal to 0, return the default color
-/// - If the ray intersects with an object:
-///   - If the object scatters the ray, calculate the scattered ray and the attenuation
-///   - Return the attenuation multiplied by the color of the scattered ray
-///   - If the object does not scatter the ray, return the default color
-///   - If the ray does not intersect with an object, calculate the background color
-///   - Return the background color
-fn ray_color(ray: &Ray, world: &Box<dyn Hittable>, depth: i32) -> Color {
-    if depth <= 0 {
-        return Color::default();
+    /// Counts a ray cast into the scene for the stats of the render
+    fn count_ray(&self, primary: bool) {
+        if primary {
+            self.rays.primary.fetch_add(1, Ordering::Relaxed);
+        }
+        self.rays.total.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Next event estimation: light reaching the hit point from a direction sampled towards the
+    /// lights, plus the one from a direction sampled towards the background when it can be
+    /// importance sampled, if the shadow rays aren't occluded. It still has to be attenuated by
+    /// the material at the hit point
+    fn direct_light(&self, ray: &Ray, rec: &HitRecord, world: &dyn Hittable) -> Color {
+        self.sample_lights(ray, rec, world) + self.sample_background(ray, rec, world)
+    }
+    fn sample_lights(&self, ray: &Ray, rec: &HitRecord, world: &dyn Hittable) -> Color {
+        if self.lights.is_empty() {
+            return Color::default();
+        }
+        let to_light = self.lights.random(&rec.p());
+        let shadow_ray = Ray::new(rec.p(), to_light, ray.time());
+        let light_pdf = self.lights.pdf_value(&rec.p(), &to_light);
+        let scattering_pdf = rec.get_material().map_or(0.0, |material| {
+            material.scattering_pdf(ray, rec, &shadow_ray)
+        });
+        if light_pdf <= 0.0 || scattering_pdf <= 0.0 {
+            return Color::default();
+        }
+        let Some(light_rec) = self
+            .lights
+            .hit(&shadow_ray, &Interval::new(0.001, INFINITY))
+        else {
+            return Color::default();
+        };
+        // Stop right before the light so it doesn't count as its own occluder
+        self.count_ray(false);
+        let before_light = light_rec.t() - 0.001;
+        if before_light > 0.001 && world.hit_any(&shadow_ray, &Interval::new(0.001, before_light)) {
+            return Color::default();
+        }
+        let Some(light_material) = light_rec.get_material() else {
+            return Color::default();
+        };
+        let emitted = light_material.emmited(&shadow_ray, &light_rec);
+        let weight = power_heuristic(light_pdf, scattering_pdf);
+        emitted * (weight * scattering_pdf / light_pdf)
+    }
+    /// Times at which a camera ray can hit the scene, cut at the far clip distance
+    fn camera_ray_interval(&self, ray: &Ray, time_interval: Interval) -> Interval {
+        match self.far_clip {
+            // The camera rays aren't normalized, the clip is a distance
+            // A clip closer than the start of the interval leaves nothing to hit but its start
+            Some(far) => {
+                let clip = far / ray.direction().magnitude();
+                Interval::new(time_interval.min(), clip.max(time_interval.min()))
+            }
+            None => time_interval,
+        }
+    }
+    /// Background seen by a ray that misses the scene, the primary one for the camera rays
+    fn background_seen(&self, first_ray: bool) -> &Arc<dyn Background> {
+        match &self.primary_background {
+            Some(background) if first_ray => background,
+            _ => &self.background,
+        }
+    }
+    fn sample_background(&self, ray: &Ray, rec: &HitRecord, world: &dyn Hittable) -> Color {
+        let background = &self.background;
+        let Some((direction, background_pdf)) = background.sample_direction() else {
+            return Color::default();
+        };
+        let shadow_ray = Ray::new(rec.p(), direction, ray.time());
+        let scattering_pdf = rec.get_material().map_or(0.0, |material| {
+            material.scattering_pdf(ray, rec, &shadow_ray)
+        });
+        if background_pdf <= 0.0 || scattering_pdf <= 0.0 {
+            return Color::default();
+        }
+        self.count_ray(false);
+        if world.hit_any(&shadow_ray, &Interval::new(0.001, INFINITY)) {
+            return Color::default();
+        }
+        let weight = power_heuristic(background_pdf, scattering_pdf);
+        background.value(&shadow_ray) * (weight * scattering_pdf / background_pdf)
+    }
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rays ({} from the camera) in {:.2?}, {:.0} rays/s",
+            self.total_rays, self.primary_rays, self.duration, self.rays_per_second
+        )?;
+        if self.negative_samples > 0 {
+            write!(f, ", {} negative samples clamped", self.negative_samples)?;
+        }
+        Ok(())
+    }
+}
+
+/// Halves the size of an image, averaging each 2x2 block of pixels
+fn downsample(image: &[Vec<Color>]) -> Vec<Vec<Color>> {
+    image
+        .chunks_exact(2)
+        .map(|rows| {
+            (0..rows[0].len() / 2)
+                .map(|i| {
+                    let block = rows.iter().flat_map(|row| &row[2 * i..2 * i + 2]);
+                    0.25 * block.copied().sum::<Color>()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Light reaching the camera along a path, adding up from where it ended the light emitted at
+/// each hit and the light sampled directly from there, attenuated by the materials on the way
+#[cfg(not(feature = "spectral"))]
+fn gather_path(end: Color, bounces: &[(Color, Color, Color)]) -> Color {
+    bounces
+        .iter()
+        .rev()
+        .fold(end, |color, &(emitted, direct, attenuation)| {
+            emitted + attenuation * (direct + color)
+        })
+}
+
+/// Traces the path over a set of wavelengths sampled for it, converting back to RGB at the end
+#[cfg(feature = "spectral")]
+fn gather_path(end: Color, bounces: &[(Color, Color, Color)]) -> Color {
+    crate::spectrum::gather_path(end, bounces, random_double())
+}
+
+/// Progress bar printed while rendering, left out on WASM where there is no terminal
+#[cfg(not(target_arch = "wasm32"))]
+fn progress_bar(len: u64, message: &'static str) -> ProgressBar {
+    let sty = ProgressStyle::with_template(
+        "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+    )
+    .unwrap()
+    .progress_chars("#>-");
+    let bar = ProgressBar::new(len);
+    bar.set_style(sty);
+    bar.set_message(message);
+    bar
+}
+
+#[cfg(target_arch = "wasm32")]
+struct ProgressBar;
+
+#[cfg(target_arch = "wasm32")]
+impl ProgressBar {
+    fn hidden() -> Self {
+        ProgressBar
+    }
+    fn inc(&self, _delta: u64) {}
+    fn finish_with_message(&self, _message: &'static str) {}
+}
+
+#[cfg(target_arch = "wasm32")]
+fn progress_bar(_len: u64, _message: &'static str) -> ProgressBar {
+    ProgressBar
+}
+
+/// Sum of the samples of every pixel, with how many of them hit the scene and how many there are.
+/// Each row has its own lock, so the rendering threads and the one taking snapshots only wait for
+/// each other on the same row
+#[cfg(not(target_arch = "wasm32"))]
+struct Accumulator {
+    rows: Vec<Mutex<Vec<(Color, u32, u32)>>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Accumulator {
+    fn new(width: usize, height: usize) -> Self {
+        Accumulator {
+            rows: (0..height)
+                .map(|_| Mutex::new(vec![(Color::default(), 0, 0); width]))
+                .collect(),
+        }
+    }
+    /// Adds one sample, with whether it hit the scene, to every pixel of row `j`
+    fn add_row(&self, j: usize, samples: &[(Color, bool)]) {
+        let mut row = self.rows[j].lock().unwrap();
+        for (pixel, &(color, hit)) in row.iter_mut().zip(samples) {
+            pixel.0 += color;
+            pixel.1 += u32::from(hit);
+            pixel.2 += 1;
+        }
+    }
+    /// Average color and coverage of every pixel so far. Pixels without samples are transparent
+    /// black
+    fn snapshot(&self) -> Vec<Vec<(Color, f64)>> {
+        self.rows
+            .iter()
+            .map(|row| {
+                row.lock()
+                    .unwrap()
+                    .iter()
+                    .map(|&(color, covered, count)| {
+                        if count == 0 {
+                            (Color::default(), 0.0)
+                        } else {
+                            let scale = 1.0 / f64::from(count);
+                            (color * scale, f64::from(covered) * scale)
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn strip_alpha(image: Vec<Vec<(Color, f64)>>) -> Vec<Vec<Color>> {
+    image
+        .into_iter()
+        .map(|row| row.into_iter().map(|(color, _)| color).collect())
+        .collect()
+}
+/// Multiple importance sampling weight of a sample drawn with density `pdf` when the same
+/// direction could also have been drawn with density `other_pdf`
+fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let pdf_squared = pdf * pdf;
+    let other_squared = other_pdf * other_pdf;
+    if pdf_squared + other_squared == 0.0 {
+        return 0.0;
+    }
+    pdf_squared / (pdf_squared + other_squared)
+}
+/// Calculates the color of the ray. The color is calculated using the following steps:
+/// - If the depth is less than or equal to 0, return the default color
+/// - If the ray intersects with an object:
+///   - If the object scatters the ray, calculate the scattered ray and the attenuation
+///   - Return the attenuation multiplied by the color of the scattered ray
+///   - If the object does not scatter the ray, return the default color
+///   - If the ray does not intersect with an object, calculate the background color
+///   - Return the background color
+fn ray_color(ray: &Ray, world: &Box<dyn Hittable>, depth: i32) -> Color {
+    if depth <= 0 {
+        return Color::default();
     }
     // Hack for floating point inacuracies. If the hit is super close to the
     // already intersected point, ignore it. Get rid of shadow acne
     let time_interval = Interval::new(0.001, INFINITY);
     if let Some(rec) = world.hit(ray, &time_interval) {
-        if let Some(scatter_rec) = rec.get_material().as_ref().unwrap().scatter(ray, &rec) {
+        if let Some(scatter_rec) = rec
+            .get_material()
+            .and_then(|material| material.scatter(ray, &rec))
+        {
             return scatter_rec.attenuation * ray_color(&scatter_rec.scattered, world, depth - 1);
         }
         return Color::default();
@@ -327,3 +1871,1722 @@ fn ray_color(ray: &Ray, world: &Box<dyn Hittable>, depth: i32) -> Color {
     let a: f64 = 0.5 * (unit_vector.y() + 1.0);
     (1.0 - a) * Color::new(1., 1., 1.) + a * Color::new(0.5, 0.7, 1.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::scalar_tolerance;
+    use crate::{
+        cube::Cube,
+        hittable::HittableList,
+        light::QuadLight,
+        material::{
+            Dielectric, DiffuseLight, FlatColor, Glass, Lambertian, MaterialKind, Metal,
+            ShadowCatcher,
+        },
+        medium::ConstantMedium,
+        named::Named,
+        quad::Quad,
+        sampler::HaltonSampler,
+        scene::Scene,
+        sphere::Sphere,
+        textures::ConstantTexture,
+    };
+    use std::sync::atomic::AtomicBool;
+
+    fn average_color(camera: &Camera, world: &Box<dyn Hittable>, ray: &Ray) -> Color {
+        let samples = 200;
+        let mut color = Color::default();
+        for _ in 0..samples {
+            color += camera.ray_color(ray, world, 10);
+        }
+        color / samples as f64
+    }
+
+    /// Recursive formulation of `Camera::path_color`, the iterative one must match it exactly. It
+    /// multiplies RGB colors, so it only applies without spectral rendering
+    #[cfg(not(feature = "spectral"))]
+    fn recursive_color(
+        camera: &Camera,
+        ray: &Ray,
+        world: &dyn Hittable,
+        depth: i32,
+        scattering_pdf: Option<f64>,
+        specular_bounces: i32,
+    ) -> Color {
+        if depth <= 0 {
+            return Color::default();
+        }
+        // Hack for floating point inacuracies. If the hit is super close to the
+        // already intersected point, ignore it. Get rid of shadow acne
+        let time_interval = Interval::new(0.001, INFINITY);
+        if let Some(rec) = world.hit(ray, &time_interval) {
+            let material = rec.get_material().unwrap();
+            if material.is_shadow_catcher() {
+                let background = camera.background.value(ray);
+                return background * camera.light_visibility(ray, &rec, world);
+            }
+            let emission_weight = match scattering_pdf {
+                Some(pdf) => power_heuristic(
+                    pdf,
+                    camera.lights.pdf_value(&ray.origin(), &ray.direction()),
+                ),
+                None => 1.0,
+            };
+            let color_from_emission = emission_weight * material.emmited(ray, &rec);
+            if let Some(scatter_rec) = material.scatter(ray, &rec) {
+                if scatter_rec.is_specular() {
+                    if specular_bounces >= camera.max_specular_bounces {
+                        return color_from_emission;
+                    }
+                    return color_from_emission
+                        + scatter_rec.attenuation
+                            * recursive_color(
+                                camera,
+                                &scatter_rec.scattered,
+                                world,
+                                depth - 1,
+                                None,
+                                specular_bounces + 1,
+                            );
+                }
+                return color_from_emission
+                    + scatter_rec.attenuation
+                        * (camera.direct_light(ray, &rec, world)
+                            + recursive_color(
+                                camera,
+                                &scatter_rec.scattered,
+                                world,
+                                depth - 1,
+                                Some(scatter_rec.pdf),
+                                0,
+                            ));
+            }
+            return color_from_emission;
+        }
+        let background = &camera.background;
+        let background_weight = match scattering_pdf {
+            Some(pdf) => power_heuristic(pdf, background.pdf_value(&ray.direction())),
+            None => 1.0,
+        };
+        background_weight * background.value(ray)
+    }
+
+    #[test]
+    #[cfg(not(feature = "spectral"))]
+    fn iterative_path_matches_recursion() {
+        let mut lights = LightList::new();
+        let mut world = HittableList::new();
+        world.add(Box::new(Quad::new(
+            Point3::new(-5.0, -1.0, -5.0),
+            Vec3::new(0.0, 0.0, 10.0),
+            Vec3::new(10.0, 0.0, 0.0),
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.7, 0.7, 0.7))),
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(-1.0, 0.0, -3.0),
+            None,
+            1.0,
+            Arc::new(Dielectric::new(1.5)),
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(1.2, 0.0, -3.0),
+            None,
+            1.0,
+            Arc::new(Metal::new(Color::new(0.8, 0.6, 0.2), 0.1)),
+        )));
+        world.add(Box::new(QuadLight::new(
+            Point3::new(-1.0, 3.0, -4.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            ConstantTexture::from_points(6.0, 6.0, 6.0),
+            &mut lights,
+        )));
+        let world: Box<dyn Hittable> = Box::new(world);
+        let mut camera = Camera::default();
+        camera.set_background_color(Color::new(0.1, 0.1, 0.2));
+        camera.set_lights(lights);
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.initialize();
+
+        for j in 0..8 {
+            for i in 0..16 {
+                let ray = camera.get_ray(i, j, 0);
+                seed_random((j * 16 + i) as u64);
+                let iterative = camera.ray_color(&ray, &world, 50);
+                seed_random((j * 16 + i) as u64);
+                let recursive = recursive_color(&camera, &ray, world.as_ref(), 50, None, 0);
+                assert_eq!(iterative, recursive, "pixel ({i}, {j})");
+            }
+        }
+    }
+
+    #[test]
+    fn light_sampling_reduces_the_noise() {
+        let mut world = HittableList::new();
+        world.add(Box::new(Quad::new(
+            Point3::new(-5.0, 0.0, -5.0),
+            Vec3::new(0.0, 0.0, 10.0),
+            Vec3::new(10.0, 0.0, 0.0),
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        let light: Arc<dyn Hittable> = Arc::new(Quad::new(
+            Point3::new(-0.5, 2.0, -0.5),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Arc::new(DiffuseLight::new(ConstantTexture::from_points(
+                10.0, 10.0, 10.0,
+            ))),
+        ));
+        world.add(Box::new(light.clone()));
+        let mut lights = LightList::new();
+        lights.add(light);
+        let world: Box<dyn Hittable> = Box::new(world);
+
+        let mut uniform = Camera::default();
+        uniform.set_background_color(Color::default());
+        uniform.initialize();
+        let mut sampled = Camera::default();
+        sampled.set_background_color(Color::default());
+        sampled.set_lights(lights);
+        sampled.initialize();
+
+        // Mean and variance of the red channel over many paths from the floor below the light
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let statistics = |camera: &Camera, samples: usize| {
+            let values: Vec<f64> = (0..samples)
+                .map(|_| camera.ray_color(&ray, &world, 4).x())
+                .collect();
+            let mean = values.iter().sum::<f64>() / samples as f64;
+            let variance =
+                values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / samples as f64;
+            (mean, variance)
+        };
+        // The reference takes more paths to converge
+        let (reference, reference_variance) = statistics(&uniform, 200000);
+        let (estimate, variance) = statistics(&sampled, 20000);
+        assert!(
+            reference > 0.1,
+            "the light should be visible, got {reference}"
+        );
+        assert!(
+            (estimate - reference).abs() < 0.05 * reference,
+            "{estimate} vs {reference}"
+        );
+        assert!(
+            variance < 0.5 * reference_variance,
+            "{variance} vs {reference_variance}"
+        );
+    }
+
+    #[test]
+    fn shadow_catcher_shows_only_shadows() {
+        let background = Color::new(0.2, 0.4, 0.6);
+        let mut world = HittableList::new();
+        world.add(Box::new(Quad::new(
+            Point3::new(-10.0, 0.0, -10.0),
+            Vec3::new(20.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 20.0),
+            Arc::new(ShadowCatcher::new()),
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 2.0, 0.0),
+            None,
+            1.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        let light: Arc<dyn Hittable> = Arc::new(Quad::new(
+            Point3::new(-0.25, 5.0, -0.25),
+            Vec3::new(0.5, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 0.5),
+            Arc::new(DiffuseLight::new(ConstantTexture::from_points(
+                4.0, 4.0, 4.0,
+            ))),
+        ));
+        world.add(Box::new(light.clone()));
+        let mut lights = LightList::new();
+        lights.add(light);
+        let world: Box<dyn Hittable> = Box::new(world);
+
+        let mut camera = Camera::default();
+        camera.set_background_color(background);
+        camera.set_lights(lights);
+        camera.initialize();
+
+        let shadowed = average_color(
+            &camera,
+            &world,
+            &Ray::new(Point3::new(5.0, 0.5, 0.0), Vec3::new(-5.0, -0.5, 0.0), 0.0),
+        );
+        let lit = average_color(
+            &camera,
+            &world,
+            &Ray::new(Point3::new(10.0, 0.5, 0.0), Vec3::new(-4.0, -0.5, 0.0), 0.0),
+        );
+        assert!((lit - background).magnitude() < scalar_tolerance(1e-9));
+        assert!(shadowed.get_b() < lit.get_b());
+    }
+
+    #[test]
+    fn buffer_shows_the_shadow_of_a_wall() {
+        let gray = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let mut world = HittableList::new();
+        world.add(Box::new(Quad::new(
+            Point3::new(-10.0, 0.0, -10.0),
+            Vec3::new(0.0, 0.0, 20.0),
+            Vec3::new(20.0, 0.0, 0.0),
+            gray.clone(),
+        )));
+        // A wall along z at x = 0 hides the floor on its left from a light on its right
+        world.add(Box::new(Quad::new(
+            Point3::new(0.0, 0.0, -10.0),
+            Vec3::new(0.0, 3.0, 0.0),
+            Vec3::new(0.0, 0.0, 20.0),
+            gray,
+        )));
+        world.add(Box::new(Quad::new(
+            Point3::new(3.0, 3.0, -1.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Arc::new(DiffuseLight::new(ConstantTexture::from_points(
+                15.0, 15.0, 15.0,
+            ))),
+        )));
+
+        let mut camera = Camera::default();
+        camera.set_width(20).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(32).unwrap();
+        camera.set_max_depth(4).unwrap();
+        camera.set_vertical_fov(60.0).unwrap();
+        camera.set_lookfrom(Point3::new(0.0, 5.0, 0.0));
+        camera.set_lookat(Point3::new(0.0, 0.0, 0.0));
+        camera.set_vup(Vec3::new(0.0, 0.0, -1.0));
+        camera.set_background_color(Color::default());
+        let image = camera.render_to_buffer(&world);
+        assert_eq!((image.len(), image[0].len()), (10, 20));
+        // Left of the wall is to the left of the image
+        let brightness = |columns: std::ops::Range<usize>| -> f64 {
+            image
+                .iter()
+                .flat_map(|row| &row[columns.clone()])
+                .map(|color| color.x() + color.y() + color.z())
+                .sum()
+        };
+        let (shadowed, lit) = (brightness(2..8), brightness(12..18));
+        assert!(lit > 0.0 && shadowed < 0.1 * lit, "{shadowed} vs {lit}");
+    }
+
+    #[test]
+    fn depth_pass_orders_geometry() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let mut world = HittableList::new();
+        // Left half of the frame is covered by a near sphere, the right half by a far one
+        world.add(Box::new(Sphere::new(
+            Point3::new(-3.0, 0.0, -3.0),
+            None,
+            1.5,
+            material.clone(),
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(6.0, 0.0, -6.0),
+            None,
+            2.0,
+            material,
+        )));
+        let world: Box<dyn Hittable> = Box::new(world);
+        let mut camera = Camera::default();
+        camera.set_width(40).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_depth_range(0.0, 10.0);
+        let depth = camera.render_depth_buffer(&world);
+
+        assert_eq!(depth.len(), 20);
+        assert_eq!(depth[0].len(), 40);
+        let near = depth[10][10];
+        let far = depth[10][30];
+        assert!(near > 0.0 && near < far, "near depth {}", near);
+        assert!(far < 1.0, "far depth {}", far);
+        assert_eq!(depth[0][0], 1.0);
+
+        let path = std::env::temp_dir().join("raytracing_depth_pass.png");
+        write_depth_to_png(path.to_str().unwrap(), &depth, 40, 20);
+        let image = ::image::open(&path).unwrap();
+        assert_eq!(image.color(), ::image::ColorType::L16);
+    }
+
+    #[test]
+    fn depth_pass_sees_moving_objects_in_the_middle_of_the_exposure() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -3.0),
+            Some(Point3::new(0.0, 0.0, -1.0)),
+            0.5,
+            material,
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(1).unwrap();
+        camera.set_aspect_ratio(1.0).unwrap();
+        camera.set_depth_range(0.0, 10.0);
+        // Halfway through this shutter the sphere is at z = -2.5, 2 away from the camera
+        camera.set_shutter(Interval::new(0.0, 0.5));
+        let depth = camera.render_depth_buffer(&world);
+        assert!(
+            (depth[0][0] - 0.2).abs() < scalar_tolerance(1e-9),
+            "{}",
+            depth[0][0]
+        );
+    }
+
+    #[test]
+    fn next_event_estimation_matches_path_tracing() {
+        let mut world = HittableList::new();
+        world.add(Box::new(Quad::new(
+            Point3::new(-5.0, 0.0, -5.0),
+            Vec3::new(0.0, 0.0, 10.0),
+            Vec3::new(10.0, 0.0, 0.0),
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        let mut lights = LightList::new();
+        let light = QuadLight::new(
+            Point3::new(-1.0, 1.0, -1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            ConstantTexture::from_points(4.0, 4.0, 4.0),
+            &mut lights,
+        );
+        world.add(Box::new(light));
+        let world: Box<dyn Hittable> = Box::new(world);
+
+        let mut path_traced = Camera::default();
+        path_traced.set_background_color(Color::default());
+        path_traced.initialize();
+        let mut sampled = Camera::default();
+        sampled.set_background_color(Color::default());
+        sampled.set_lights(lights);
+        sampled.initialize();
+
+        let ray = Ray::new(Point3::new(0.0, 0.5, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let samples = 20000;
+        let (mut reference, mut reference_squares) = (0.0, 0.0);
+        let (mut estimate, mut estimate_squares) = (0.0, 0.0);
+        for _ in 0..samples {
+            let traced = path_traced.ray_color(&ray, &world, 5).x();
+            reference += traced;
+            reference_squares += traced * traced;
+            let sample = sampled.ray_color(&ray, &world, 5).x();
+            estimate += sample;
+            estimate_squares += sample * sample;
+        }
+        let reference = reference / samples as f64;
+        let estimate = estimate / samples as f64;
+        // Sampling the light makes the estimate far less noisy
+        let reference_variance = reference_squares / samples as f64 - reference * reference;
+        let estimate_variance = estimate_squares / samples as f64 - estimate * estimate;
+        assert!(
+            estimate_variance < 0.5 * reference_variance,
+            "{estimate_variance} vs {reference_variance}"
+        );
+        assert!(
+            reference > 0.5,
+            "the light should be visible, got {reference}"
+        );
+        assert!(
+            (estimate - reference).abs() < 0.05 * reference,
+            "{estimate} vs {reference}"
+        );
+    }
+
+    #[test]
+    fn specular_bounce_limit_stops_paths_in_nested_glass() {
+        // Two nested glass slabs, made of the faces perpendicular to the view direction
+        let mut world = HittableList::new();
+        for z in [-3.0, -4.0, -6.0, -7.0] {
+            world.add(Box::new(Quad::new(
+                Point3::new(-5.0, -5.0, z),
+                Vec3::new(10.0, 0.0, 0.0),
+                Vec3::new(0.0, 10.0, 0.0),
+                Arc::new(Dielectric::new(1.5)),
+            )));
+        }
+        let world: Box<dyn Hittable> = Box::new(world);
+        let background = Color::new(1.0, 1.0, 1.0);
+        // Going through both slabs takes four refractions
+        let ray = Ray::new(Point3::default(), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let brightness = |bounces: i32| {
+            let mut camera = Camera::default();
+            camera.set_background_color(background);
+            camera.set_max_specular_bounces(bounces);
+            camera.initialize();
+            average_color(&camera, &world, &ray).x()
+        };
+
+        assert_eq!(brightness(0), 0.0);
+        assert!(brightness(3) < 0.3);
+        assert!(brightness(4) > 0.7);
+    }
+
+    #[test]
+    fn tiles_assemble_into_the_whole_frame() {
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -2.0),
+            None,
+            1.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(4).unwrap();
+        camera.set_background_color(Color::new(0.7, 0.8, 1.0));
+        camera.set_tile_size(5);
+        camera.set_seed(42);
+
+        let count = camera.tile_count();
+        assert_eq!(count, 8);
+        let all: Vec<usize> = (0..count).collect();
+        let whole = camera.render_tiles(&world, &all);
+        let whole = camera.assemble_tiles(&whole);
+        assert_eq!(whole.len(), 8);
+        assert!(whole.iter().all(|row| row.len() == 16));
+
+        let separate: Vec<_> = (0..count)
+            .rev()
+            .flat_map(|index| camera.render_tiles(&world, &[index]))
+            .collect();
+        assert_eq!(camera.assemble_tiles(&separate), whole);
+    }
+
+    #[test]
+    fn transparent_background_writes_alpha() {
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -2.0),
+            None,
+            1.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        let world: Box<dyn Hittable> = Box::new(world);
+        let mut camera = Camera::default();
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(4).unwrap();
+        camera.set_background_color(Color::new(0.7, 0.8, 1.0));
+        camera.set_transparent_background(true);
+        let path = std::env::temp_dir().join("transparent_background_writes_alpha.png");
+        camera.render(&world, path.to_str().unwrap().to_string());
+
+        let image = image::open(&path).unwrap().into_rgba8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image.dimensions(), (16, 8));
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 0, 0]);
+        let center = image.get_pixel(8, 4).0;
+        assert_eq!(center[3], 255);
+        assert!(center[0] > 0);
+    }
+
+    #[test]
+    fn center_cross_is_drawn_over_the_image() {
+        let world: Box<dyn Hittable> = Box::new(HittableList::new());
+        let background = Color::new(0.2, 0.4, 0.6);
+        let mut camera = Camera::default();
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(1).unwrap();
+        camera.set_background_color(background);
+        camera.set_overlay(Overlay::CenterCross);
+        let path = std::env::temp_dir().join("center_cross_is_drawn_over_the_image.png");
+        camera.render(&world, path.to_str().unwrap().to_string());
+
+        let image = image::open(&path).unwrap().into_rgb8();
+        std::fs::remove_file(&path).unwrap();
+        for (x, y) in [(7, 3), (8, 4), (7, 0), (0, 4), (15, 3)] {
+            assert_eq!(image.get_pixel(x, y).0, crate::color::OVERLAY_COLOR);
+        }
+        let unchanged = tonemap_pixel(background, ToneMap::Clamp, 2.0);
+        for (x, y) in [(0, 0), (6, 2), (9, 5), (15, 7)] {
+            assert_eq!(image.get_pixel(x, y).0, unchanged);
+        }
+    }
+
+    #[test]
+    fn render_reports_the_rays_cast() {
+        let mut lights = LightList::new();
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -2.0),
+            None,
+            1.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        world.add(Box::new(QuadLight::new(
+            Point3::new(-1.0, 2.0, -3.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            ConstantTexture::from_points(4.0, 4.0, 4.0),
+            &mut lights,
+        )));
+        let world: Box<dyn Hittable> = Box::new(world);
+        let mut camera = Camera::default();
+        camera.set_width(8).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(5).unwrap();
+        camera.set_lights(lights);
+        let path = std::env::temp_dir().join("render_reports_the_rays_cast.png");
+        let stats = camera.render(&world, path.to_str().unwrap().to_string());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(stats.primary_rays, 8 * 4 * 5);
+        // The rays hitting the sphere bounce and cast shadow rays
+        assert!(stats.total_rays > stats.primary_rays);
+        assert!(stats.rays_per_second > 0.0);
+        // The counters start over with every render
+        let stats = camera.render(&world, path.to_str().unwrap().to_string());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(stats.primary_rays, 8 * 4 * 5);
+    }
+
+    #[test]
+    fn pyramid_levels_average_the_level_above() {
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -2.0),
+            None,
+            1.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(4).unwrap();
+        camera.set_background_color(Color::new(0.7, 0.8, 1.0));
+        let pyramid = camera.render_pyramid(&world, 6);
+        let sizes: Vec<_> = pyramid
+            .iter()
+            .map(|image| (image[0].len(), image.len()))
+            .collect();
+        // Stops once the height is down to 1 pixel
+        assert_eq!(sizes, [(16, 8), (8, 4), (4, 2), (2, 1)]);
+        let (fine, coarse) = (&pyramid[0], &pyramid[1]);
+        for (j, row) in coarse.iter().enumerate() {
+            for (i, &pixel) in row.iter().enumerate() {
+                let block = fine[2 * j][2 * i]
+                    + fine[2 * j][2 * i + 1]
+                    + fine[2 * j + 1][2 * i]
+                    + fine[2 * j + 1][2 * i + 1];
+                assert!((pixel - block / 4.0).magnitude() < 1e-12);
+            }
+        }
+
+        camera.set_independent_pyramid_levels(true);
+        let pyramid = camera.render_pyramid(&world, 2);
+        assert_eq!((pyramid[1][0].len(), pyramid[1].len()), (8, 4));
+    }
+
+    #[test]
+    fn split_passes_add_up_to_the_beauty() {
+        // The simple light scene: a sphere on the ground, lit from a quad and a sphere above
+        let mut world = HittableList::new();
+        let gray = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 2.0, 0.0),
+            None,
+            2.0,
+            gray.clone(),
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, -1000.0, 0.0),
+            None,
+            1000.0,
+            gray,
+        )));
+        let light = Arc::new(DiffuseLight::new(ConstantTexture::from_points(
+            4.0, 4.0, 4.0,
+        )));
+        world.add(Box::new(Quad::new(
+            Point3::new(3.0, 1.0, -2.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            light.clone(),
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 7.0, 0.0),
+            None,
+            2.0,
+            light,
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(40).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(64).unwrap();
+        camera.set_max_depth(10).unwrap();
+        camera.set_vertical_fov(30.0).unwrap();
+        camera.set_lookfrom(Point3::new(26.0, 3.0, 6.0));
+        camera.set_lookat(Point3::new(0.0, 2.0, 0.0));
+        camera.set_pixel_filter(PixelFilter::Box);
+        let (emission, scatter) = camera.render_split(&world);
+
+        let sees_light = |i: i32, j: i32| {
+            let rec = world.hit(
+                &camera.get_center_ray(i, j),
+                &Interval::new(0.001, INFINITY),
+            );
+            rec.and_then(|rec| rec.get_material())
+                .is_some_and(|material| material.kind() == MaterialKind::Light)
+        };
+        let (mut lit, mut dark) = (0, 0);
+        for j in 1..19 {
+            for i in 1..39 {
+                let around = (-1..=1).flat_map(|dj| (-1..=1).map(move |di| (i + di, j + dj)));
+                let seen = around.map(|(i, j)| sees_light(i, j)).collect::<Vec<_>>();
+                let pixel = emission[j as usize][i as usize];
+                if seen.iter().all(|&seen| seen) {
+                    assert_eq!(pixel, Color::new(4.0, 4.0, 4.0));
+                    lit += 1;
+                } else if seen.iter().all(|&seen| !seen) {
+                    assert_eq!(pixel, Color::default());
+                    dark += 1;
+                }
+            }
+        }
+        assert!(lit > 0 && dark > 0, "{lit} lit, {dark} dark");
+
+        let total = |image: &[Vec<Color>]| image.iter().flatten().copied().sum::<Color>();
+        let split = total(&emission) + total(&scatter);
+        let beauty = total(&camera.render_to_buffer(&world));
+        // Without sampling the lights directly the paths that find them are noisy
+        assert!((split - beauty).magnitude() < 0.08 * beauty.magnitude());
+    }
+
+    #[test]
+    fn ray_generator_replaces_the_camera_rays() {
+        // A floor in the plane of the camera, which never sees it
+        let floor = Color::new(0.2, 0.9, 0.4);
+        let mut world = HittableList::new();
+        world.add(Box::new(Quad::new(
+            Point3::new(-10.0, 0.0, -10.0),
+            Vec3::new(0.0, 0.0, 20.0),
+            Vec3::new(20.0, 0.0, 0.0),
+            Arc::new(FlatColor::new(floor)),
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(8).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(2).unwrap();
+        let image = camera.render_to_buffer(&world);
+        assert!(image
+            .iter()
+            .flatten()
+            .all(|&pixel| pixel == Color::default()));
+
+        // Straight down from above the floor, one ray per pixel of a grid
+        camera.set_ray_generator(Box::new(|i, j, _camera, sample| {
+            let origin = Point3::new(f64::from(i) + sample.pixel.0, 5.0, -f64::from(j));
+            Ray::new(origin, Vec3::new(0.0, -1.0, 0.0), sample.time)
+        }));
+        let image = camera.render_to_buffer(&world);
+        assert!(image
+            .iter()
+            .flatten()
+            .all(|&pixel| (pixel - floor).magnitude() < 1e-12));
+    }
+
+    #[test]
+    fn negative_samples_are_clamped_and_counted() {
+        // Every camera ray misses into a background with a negative green
+        let world: Box<dyn Hittable> = Box::new(HittableList::new());
+        let mut camera = Camera::default();
+        camera.set_width(8).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(2).unwrap();
+        camera.set_background_color(Color::new(0.5, -0.2, 0.5));
+        let image = camera.render_to_buffer(world.as_ref());
+        assert!(image[0][0].y() < 0.0);
+
+        camera.set_clamp_negative(true);
+        assert_eq!(
+            camera.clamp_sample(Color::new(0.3, 0.2, 0.1)),
+            Color::new(0.3, 0.2, 0.1)
+        );
+        let path = std::env::temp_dir().join("negative_samples_are_clamped_and_counted.png");
+        let stats = camera.render(&world, path.to_str().unwrap().to_string());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(stats.negative_samples, 8 * 4 * 2);
+        assert!(stats.to_string().ends_with("64 negative samples clamped"));
+        let image = camera.render_to_buffer(world.as_ref());
+        assert!(image
+            .iter()
+            .flatten()
+            .all(|&pixel| (pixel - Color::new(0.5, 0.0, 0.5)).magnitude() < 1e-9));
+    }
+
+    #[test]
+    fn ppm_output_matches_png() {
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -2.0),
+            None,
+            1.0,
+            Arc::new(FlatColor::new(Color::new(0.2, 1.5, 0.05))),
+        )));
+        let world: Box<dyn Hittable> = Box::new(world);
+        let mut camera = Camera::default();
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(1).unwrap();
+        camera.set_background_color(Color::new(0.7, 0.3, 1.0));
+        camera.set_overlay(Overlay::Grid { spacing: 5 });
+        // Through the centers of the pixels, so both renders are the same
+        camera.set_ray_generator(Box::new(|i, j, camera, _| camera.get_center_ray(i, j)));
+
+        let png = std::env::temp_dir().join("ppm_output_matches_png.png");
+        let ppm = std::env::temp_dir().join("ppm_output_matches_png.ppm");
+        assert_eq!(
+            OutputFormat::from_filename(ppm.to_str().unwrap()),
+            OutputFormat::PpmAscii
+        );
+        camera.render(&world, png.to_str().unwrap().to_string());
+        camera.render(&world, ppm.to_str().unwrap().to_string());
+        let expected = image::open(&png).unwrap().into_rgb8();
+        let text = std::fs::read_to_string(&ppm).unwrap();
+        std::fs::remove_file(&png).unwrap();
+        std::fs::remove_file(&ppm).unwrap();
+
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("P3"));
+        assert_eq!(lines.next(), Some("16 8"));
+        assert_eq!(lines.next(), Some("255"));
+        let pixels: Vec<[u8; 3]> = lines
+            .map(|line| {
+                let values: Vec<u8> = line.split(' ').map(|v| v.parse().unwrap()).collect();
+                [values[0], values[1], values[2]]
+            })
+            .collect();
+        let png_pixels: Vec<[u8; 3]> = expected.pixels().map(|pixel| pixel.0).collect();
+        assert_eq!(pixels, png_pixels);
+        assert!(pixels.contains(&crate::color::OVERLAY_COLOR));
+
+        // The format set wins over the extension
+        camera.set_output_format(OutputFormat::PpmAscii);
+        camera.render(&world, png.to_str().unwrap().to_string());
+        let text = std::fs::read_to_string(&png).unwrap();
+        std::fs::remove_file(&png).unwrap();
+        assert!(text.starts_with("P3\n"));
+    }
+
+    #[test]
+    fn checkpoints_are_written_during_the_render() {
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -2.0),
+            None,
+            1.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        let world: Box<dyn Hittable> = Box::new(world);
+        let mut camera = Camera::default();
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(2000).unwrap();
+        camera.set_max_depth(4).unwrap();
+        camera.set_background_color(Color::new(0.7, 0.8, 1.0));
+        camera.set_checkpoint_interval(Duration::from_millis(5));
+        let path = std::env::temp_dir().join("checkpoints_are_written_during_the_render.png");
+        let _ = std::fs::remove_file(&path);
+
+        let finished = AtomicBool::new(false);
+        let checkpoint = thread::scope(|scope| {
+            scope.spawn(|| {
+                camera.render(&world, path.to_str().unwrap().to_string());
+                finished.store(true, Ordering::SeqCst);
+            });
+            while !finished.load(Ordering::SeqCst) {
+                if let Ok(image) = image::open(&path) {
+                    return Some(image.into_rgb8());
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            None
+        });
+
+        let checkpoint = checkpoint.expect("No checkpoint was written before the render finished");
+        assert_eq!(checkpoint.dimensions(), (16, 8));
+        let image = image::open(&path).unwrap().into_rgb8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image.dimensions(), (16, 8));
+        assert!(image.get_pixel(8, 4).0[0] > 0);
+    }
+
+    #[test]
+    fn triangle_shutter_concentrates_times_in_the_middle() {
+        seed_random(3);
+        let samples = 20000;
+        let middle_fraction = |profile: ShutterProfile| {
+            let times: Vec<f64> = (0..samples).map(|_| profile.sample()).collect();
+            assert!(times.iter().all(|t| (0.0..=1.0).contains(t)));
+            let mean = times.iter().sum::<f64>() / samples as f64;
+            let middle = times.iter().filter(|t| (0.25..0.75).contains(*t)).count();
+            (mean, middle as f64 / samples as f64)
+        };
+        let (uniform_mean, uniform_middle) = middle_fraction(ShutterProfile::Uniform);
+        let (triangle_mean, triangle_middle) = middle_fraction(ShutterProfile::Triangle);
+        assert!((uniform_mean - 0.5).abs() < 0.01);
+        assert!((triangle_mean - 0.5).abs() < 0.01);
+        // Half of the times fall in the middle half of the exposure for the uniform profile, three
+        // quarters of them for the triangle one
+        assert!((uniform_middle - 0.5).abs() < 0.02);
+        assert!((triangle_middle - 0.75).abs() < 0.02);
+
+        let (custom_mean, _) = middle_fraction(ShutterProfile::Custom(|u| u * u));
+        assert!((custom_mean - 1.0 / 3.0).abs() < 0.01);
+    }
+
+    /// Hits everything in front of it with a record that has no material
+    struct Unfinished;
+
+    impl Hittable for Unfinished {
+        fn hit(&self, _ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+            let mut rec = HitRecord::new();
+            rec.set_t(time_interval.min());
+            Some(rec)
+        }
+        fn bounding_box(&self, _time_interval: &Interval) -> Option<AABB> {
+            None
+        }
+    }
+
+    #[test]
+    fn hit_without_material_blocks_the_path() {
+        let world: Box<dyn Hittable> = Box::new(Unfinished);
+        let mut lights = LightList::new();
+        QuadLight::new(
+            Point3::new(-1.0, 1.0, -1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            ConstantTexture::from_points(4.0, 4.0, 4.0),
+            &mut lights,
+        );
+        let mut camera = Camera::default();
+        camera.set_background_color(Color::new(1.0, 1.0, 1.0));
+        camera.set_lights(lights);
+        camera.initialize();
+        let ray = Ray::new(Point3::default(), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert_eq!(camera.ray_color(&ray, &world, 10), Color::default());
+    }
+
+    #[test]
+    fn fog_under_a_light_shows_a_light_shaft() {
+        seed_random(13);
+        let mut world = HittableList::new();
+        world.add(Box::new(Quad::new(
+            Point3::new(-10.0, 0.0, -10.0),
+            Vec3::new(0.0, 0.0, 20.0),
+            Vec3::new(20.0, 0.0, 0.0),
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        let mut lights = LightList::new();
+        world.add(Box::new(QuadLight::new(
+            Point3::new(-0.25, 4.0, -0.25),
+            Vec3::new(0.5, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 0.5),
+            ConstantTexture::from_points(20.0, 20.0, 20.0),
+            &mut lights,
+        )));
+        world.add(Box::new(ConstantMedium::new(
+            Cube::new(
+                Point3::new(-5.0, 0.01, -5.0),
+                Point3::new(5.0, 3.9, 5.0),
+                Arc::new(Lambertian::new(ConstantTexture::default())),
+            ),
+            0.05,
+            ConstantTexture::from_points(1.0, 1.0, 1.0),
+        )));
+        let world: Box<dyn Hittable> = Box::new(world);
+        let mut camera = Camera::default();
+        camera.set_background_color(Color::default());
+        camera.set_lights(lights);
+        camera.initialize();
+
+        // Horizontal rays through the fog, one right below the light and one off to the side,
+        // both leaving the fog without hitting anything
+        let through_shaft = Ray::new(Point3::new(0.0, 2.0, 6.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let beside_shaft = Ray::new(Point3::new(3.5, 2.0, 6.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let shaft = average_color(&camera, &world, &through_shaft).x();
+        let fog = average_color(&camera, &world, &beside_shaft).x();
+        assert!(fog > 0.0, "the fog should scatter some light");
+        assert!(
+            shaft > 2.0 * fog,
+            "the shaft ({shaft}) should be brighter than the fog around it ({fog})"
+        );
+    }
+
+    #[test]
+    fn resolution_sets_the_exact_image_size() {
+        let mut camera = Camera::default();
+        camera.set_resolution(1920, 1080);
+        camera.set_sample_per_pixel(1).unwrap();
+        camera.set_max_depth(1).unwrap();
+        let image = camera.render_to_buffer(&HittableList::new());
+        assert_eq!(image.len(), 1080);
+        assert!(image.iter().all(|row| row.len() == 1920));
+
+        // A width that doesn't divide evenly by the aspect ratio is kept as is
+        camera.set_resolution(7, 3);
+        camera.initialize();
+        assert_eq!((camera.image_width, camera.image_height), (7, 3));
+        camera.set_width(14).unwrap();
+        camera.initialize();
+        assert_eq!(camera.image_height, 6);
+    }
+
+    #[test]
+    fn variance_is_higher_on_edges() {
+        // An emitting quad covering the left of the image up to the middle of column 8, so the
+        // samples of its pixels are all the same
+        let mut world = HittableList::new();
+        world.add(Box::new(Quad::new(
+            Point3::new(-10.0, -10.0, -1.0),
+            Vec3::new(10.125, 0.0, 0.0),
+            Vec3::new(0.0, 20.0, 0.0),
+            Arc::new(DiffuseLight::new(ConstantTexture::from_points(
+                1.0, 1.0, 1.0,
+            ))),
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_vertical_fov(90.0).unwrap();
+        camera.set_sample_per_pixel(64).unwrap();
+        camera.set_background_color(Color::default());
+        let (image, variance) = camera.render_with_variance(&world);
+
+        assert_eq!(variance.len(), 8);
+        assert!(variance[4][2].abs() < 1e-12);
+        assert!(variance[4][14].abs() < 1e-12);
+        assert_eq!(image[4][2], Color::new(1.0, 1.0, 1.0));
+        // Half of the samples of the edge pixel see the light, so the variance is about 1/4
+        assert!(variance[4][8] > 0.15, "got {}", variance[4][8]);
+    }
+
+    #[test]
+    fn view_space_normals_face_the_camera() {
+        let center = Point3::new(1.0, 2.0, -3.0);
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            center,
+            None,
+            1.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        for (lookfrom, vup) in [
+            (Point3::new(1.0, 2.0, 5.0), Vec3::new(0.0, 1.0, 0.0)),
+            (Point3::new(-6.0, 4.0, 1.0), Vec3::new(0.0, 1.0, 0.0)),
+            (Point3::new(1.0, 9.0, -3.5), Vec3::new(1.0, 0.0, 0.0)),
+        ] {
+            let mut camera = Camera::default();
+            camera.set_width(9).unwrap();
+            camera.set_aspect_ratio(1.0).unwrap();
+            camera.set_lookfrom(lookfrom);
+            camera.set_lookat(center);
+            camera.set_vup(vup);
+            // The center of the middle pixel looks straight at the center of the sphere
+            let view = camera.render_normals(&world, NormalSpace::View)[4][4];
+            assert!((view - Vec3::new(0.0, 0.0, 1.0)).magnitude() < scalar_tolerance(1e-9));
+            let normal = camera.render_normals(&world, NormalSpace::World)[4][4];
+            assert!(
+                (normal - (lookfrom - center).normalize()).magnitude() < scalar_tolerance(1e-9)
+            );
+        }
+    }
+
+    #[test]
+    fn frosted_tinted_glass_dims_and_blurs() {
+        seed_random(17);
+        // A small light behind a slab 2 units thick
+        let slab_with = |glass: Glass| {
+            let mut world = HittableList::new();
+            world.add(Box::new(Cube::new(
+                Point3::new(-5.0, -5.0, -3.0),
+                Point3::new(5.0, 5.0, -1.0),
+                Arc::new(glass),
+            )));
+            world.add(Box::new(Quad::new(
+                Point3::new(-0.3, -0.3, -6.0),
+                Vec3::new(0.6, 0.0, 0.0),
+                Vec3::new(0.0, 0.6, 0.0),
+                Arc::new(DiffuseLight::new(ConstantTexture::from_points(
+                    1.0, 1.0, 1.0,
+                ))),
+            )));
+            let world: Box<dyn Hittable> = Box::new(world);
+            world
+        };
+        let clear = slab_with(Glass::new(1.5, 0.0, Color::new(1.0, 1.0, 1.0)));
+        let frosted = slab_with(Glass::new(1.5, 0.3, Color::new(0.3, 0.9, 0.3)));
+        let mut camera = Camera::default();
+        camera.set_background_color(Color::default());
+        camera.initialize();
+
+        let at_light = Ray::new(Point3::default(), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let beside_light = Ray::new(Point3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let clear_at = average_color(&camera, &clear, &at_light);
+        let clear_beside = average_color(&camera, &clear, &beside_light);
+        let frosted_at = average_color(&camera, &frosted, &at_light);
+        let frosted_beside = average_color(&camera, &frosted, &beside_light);
+
+        // Clear glass only loses what the surfaces reflect
+        assert!(clear_at.x() > 0.85, "got {clear_at}");
+        assert_eq!(clear_beside, Color::default());
+        // The tint absorbs red more than green, and the roughness spreads the light around
+        assert!(frosted_at.y() < clear_at.y(), "got {frosted_at}");
+        assert!(frosted_at.x() < 0.5 * frosted_at.y(), "got {frosted_at}");
+        assert!(frosted_beside.y() > 0.0, "got {frosted_beside}");
+    }
+
+    #[test]
+    fn one_stop_of_exposure_doubles_the_linear_colors() {
+        let mut camera = Camera::default();
+        let image = vec![vec![
+            (Color::new(0.1, 0.2, 0.3), 1.0),
+            (Color::new(8.0, 0.0, 0.5), 0.5),
+        ]];
+        assert_eq!(camera.expose(image.clone()), image);
+
+        camera.set_exposure(1.0);
+        let exposed = camera.expose(image.clone());
+        for (bright, dim) in exposed[0].iter().zip(&image[0]) {
+            assert_eq!(bright.0, 2.0 * dim.0);
+            assert_eq!(bright.1, dim.1);
+        }
+        assert_eq!(
+            tonemap_pixel(exposed[0][0].0, ToneMap::Clamp, 2.0),
+            tonemap_pixel(Color::new(0.2, 0.4, 0.6), ToneMap::Clamp, 2.0)
+        );
+
+        camera.set_exposure(-2.0);
+        assert_eq!(camera.expose(image)[0][1].0, Color::new(2.0, 0.0, 0.125));
+    }
+
+    #[test]
+    fn shutter_drives_bounds_and_ray_times() {
+        let mut objects = HittableList::new();
+        objects.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -5.0),
+            Some(Point3::new(1.0, 0.0, -5.0)),
+            0.5,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(8).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_shutter(Interval::new(2.0, 3.0));
+        let mut scene = Scene::from_objects(objects, camera);
+
+        // The sphere is at x = 2 when the shutter opens and at x = 3 when it closes
+        assert_eq!(scene.shutter(), Interval::new(2.0, 3.0));
+        let bbox = scene.world().bounding_box(&scene.shutter()).unwrap();
+        assert_eq!(bbox.min().x(), 1.5);
+        assert_eq!(bbox.max().x(), 3.5);
+
+        let camera = scene.camera_mut();
+        camera.initialize();
+        let times: Vec<f64> = (0..1000).map(|k| camera.get_ray(4, 2, k).time()).collect();
+        assert!(times.iter().all(|time| (2.0..3.0).contains(time)));
+        let mean = times.iter().sum::<f64>() / times.len() as f64;
+        assert!((mean - 2.5).abs() < 0.05, "mean time {mean}");
+    }
+
+    #[test]
+    fn cubemap_faces_look_along_the_axes() {
+        // A light filling the view towards +x, and nothing else under a black sky
+        let mut world = HittableList::new();
+        world.add(Box::new(Quad::new(
+            Point3::new(5.0, -1000.0, -1000.0),
+            Vec3::new(0.0, 2000.0, 0.0),
+            Vec3::new(0.0, 0.0, 2000.0),
+            Arc::new(DiffuseLight::new(ConstantTexture::from_points(
+                1.0, 1.0, 1.0,
+            ))),
+        )));
+        let mut camera = Camera::default();
+        camera.set_sample_per_pixel(2).unwrap();
+        camera.set_background_color(Color::default());
+        // The edge of the light falls between two columns, the tent would blur it across them
+        camera.set_pixel_filter(PixelFilter::Box);
+        let faces = camera.render_cubemap(&world, Point3::new(1.0, 0.0, 0.0), 6);
+
+        for face in &faces {
+            assert_eq!(face.len(), 6);
+            assert!(face.iter().all(|row| row.len() == 6));
+        }
+        let brightness = |face: &Vec<Vec<Color>>| face.iter().flatten().map(Color::x).sum::<f64>();
+        assert_eq!(brightness(&faces[0]), 36.0);
+        assert_eq!(brightness(&faces[1]), 0.0);
+        // The sides of the cube see the light over half of their view
+        for face in &faces[2..] {
+            assert!(
+                (brightness(face) - 18.0).abs() <= 1.0,
+                "{}",
+                brightness(face)
+            );
+        }
+    }
+
+    #[test]
+    fn builder_sets_and_checks_the_settings() {
+        let mut camera = Camera::builder()
+            .width(4)
+            .aspect_ratio(1.0)
+            .samples_per_pixel(2)
+            .max_depth(3)
+            .vfov(40.0)
+            .lookfrom(Point3::new(0.0, 0.0, 1.0))
+            .lookat(Point3::new(0.0, 0.0, 0.0))
+            .background_color(Color::new(0.2, 0.4, 0.8))
+            .build()
+            .unwrap();
+        let world: Box<dyn Hittable> = Box::new(HittableList::new());
+        let image = camera.render_to_buffer(world.as_ref());
+        assert_eq!((image.len(), image[0].len()), (4, 4));
+        // The spectral renders only get the sky color back up to rounding
+        assert!(
+            image
+                .iter()
+                .flatten()
+                .all(|&color| (color - Color::new(0.2, 0.4, 0.8)).magnitude()
+                    < scalar_tolerance(1e-9))
+        );
+        assert_eq!(camera.max_depth, 3);
+        assert_eq!(camera.max_specular_bounces, i32::MAX);
+
+        assert_eq!(
+            Camera::builder().width(-4).build().err(),
+            Some(CameraError::NotPositive("image width", -4.0))
+        );
+        assert_eq!(
+            Camera::builder().samples_per_pixel(0).build().err(),
+            Some(CameraError::NotPositive("samples per pixel", 0.0))
+        );
+        assert_eq!(
+            Camera::builder().vfov(180.0).build().err(),
+            Some(CameraError::FieldOfView(180.0))
+        );
+
+        // The setters check the same settings and keep the current value
+        let mut camera = Camera::default();
+        assert!(camera.set_width(0).is_err());
+        assert!(camera.set_aspect_ratio(-1.0).is_err());
+        assert!(camera.set_focus_distance(0.0).is_err());
+        assert_eq!(
+            camera.set_vertical_fov(0.0),
+            Err(CameraError::FieldOfView(0.0))
+        );
+        assert!(camera.check_settings().is_ok());
+        assert_eq!(camera.vfov, Camera::default().vfov);
+    }
+
+    #[test]
+    fn unset_samples_and_depth_get_defaults() {
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -2.0),
+            None,
+            1.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(8).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        assert_eq!(
+            camera.set_max_depth(0),
+            Err(CameraError::NotPositive("max depth", 0.0))
+        );
+        camera.set_background_color(Color::new(0.7, 0.8, 1.0));
+        let image = camera.render_to_buffer(&world);
+
+        assert_eq!(camera.samples_per_pixel, 10);
+        assert_eq!(camera.max_depth, 50);
+        let pixels: Vec<f64> = image.iter().flatten().flat_map(Color::as_array).collect();
+        assert!(pixels.iter().all(|value| value.is_finite()));
+        // The sphere is lit by the sky instead of being black
+        assert!(image[2][4].x() > 0.0);
+    }
+
+    #[test]
+    fn rgba8_buffer_holds_every_pixel() {
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -2.0),
+            None,
+            1.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(4).unwrap();
+        camera.set_background_color(Color::new(0.7, 0.8, 1.0));
+        let (bytes, width, height) = camera.render_rgba8(&world);
+        assert_eq!((width, height), (16, 8));
+        assert_eq!(bytes.len(), (width * height * 4) as usize);
+        assert!(bytes.chunks(4).all(|pixel| pixel[3] == 255));
+        let corner = tonemap_pixel(Color::new(0.7, 0.8, 1.0), ToneMap::Clamp, 2.0);
+        assert_eq!(bytes[..3], corner);
+
+        camera.set_transparent_background(true);
+        let (bytes, _, _) = camera.render_rgba8(&world);
+        assert_eq!(bytes[..4], [0, 0, 0, 0]);
+        let center = ((4 * width + 8) * 4) as usize;
+        assert_eq!(bytes[center + 3], 255);
+    }
+
+    #[test]
+    fn output_primaries_convert_the_written_colors() {
+        let mut camera = Camera::default();
+        let image = vec![vec![
+            (Color::new(1.0, 0.0, 0.0), 1.0),
+            (Color::new(0.2, 0.4, 0.6), 0.5),
+        ]];
+        assert_eq!(camera.to_output(image.clone()), image);
+
+        camera.set_output_primaries(Matrix3::ACESCG);
+        camera.set_exposure(1.0);
+        let output = camera.to_output(image);
+        assert_eq!(
+            output[0][0].0,
+            Matrix3::ACESCG.transform(Color::new(2.0, 0.0, 0.0))
+        );
+        assert_eq!(output[0][1].1, 0.5);
+    }
+
+    #[test]
+    fn direct_only_render_misses_the_bounced_light() {
+        // A closed gray room lit from the ceiling, with a low panel under the light so the
+        // middle of the floor only gets light bounced off the walls
+        let gray = Arc::new(Lambertian::new(ConstantTexture::from_points(0.7, 0.7, 0.7)));
+        let mut lights = LightList::new();
+        let mut world = HittableList::new();
+        world.add(Box::new(Cube::new(
+            Point3::new(-1.0, -1.0, -1.0),
+            Point3::new(1.0, 1.0, 1.0),
+            gray.clone(),
+        )));
+        world.add(Box::new(QuadLight::new(
+            Point3::new(-0.3, 0.99, -0.3),
+            Vec3::new(0.6, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 0.6),
+            ConstantTexture::from_points(10.0, 10.0, 10.0),
+            &mut lights,
+        )));
+        world.add(Box::new(Quad::new(
+            Point3::new(-0.5, -0.5, -0.5),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            gray,
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(8).unwrap();
+        camera.set_aspect_ratio(1.0).unwrap();
+        camera.set_sample_per_pixel(64).unwrap();
+        camera.set_max_depth(10).unwrap();
+        // Looking under the panel, at the middle of the floor and the bottom of the far wall
+        camera.set_vertical_fov(30.0).unwrap();
+        camera.set_lookfrom(Point3::new(0.0, -0.8, 0.95));
+        camera.set_lookat(Point3::new(0.0, -1.0, 0.0));
+        camera.set_lights(lights);
+        // Keeps the rays of the middle pixels inside the shadow, the tent reaches past them
+        camera.set_pixel_filter(PixelFilter::Box);
+
+        let average = |image: &Vec<Vec<Color>>| {
+            Vec3::mean(image[3..5].iter().flat_map(|row| row[3..5].iter().copied())).x()
+        };
+        let direct_image = camera.render_direct_only(&world);
+        let direct = average(&direct_image);
+        let full = average(&camera.render_to_buffer(&world));
+        assert_eq!(direct, 0.0);
+        // The far wall is lit directly
+        assert!(direct_image[0].iter().all(|color| color.x() > 0.0));
+        assert!(full > 0.05, "the shadow gets {full} from the walls");
+    }
+
+    #[test]
+    fn chromatic_aberration_splits_edge_rays() {
+        /// Every sample at the center of its pixel
+        struct CenteredSampler;
+        impl Sampler for CenteredSampler {
+            fn sample(&self, _i: i32, _j: i32, _index: i32) -> CameraSample {
+                CameraSample {
+                    pixel: (0.5, 0.5),
+                    lens: (0.5, 0.5),
+                    time: 0.0,
+                }
+            }
+        }
+        let mut camera = Camera::default();
+        camera.set_width(9).unwrap();
+        camera.set_aspect_ratio(1.0).unwrap();
+        camera.set_sampler(CenteredSampler);
+        camera.set_chromatic_aberration(0.05);
+        camera.initialize();
+
+        let [red, green, blue] = camera.get_channel_rays(0, 4, 0);
+        assert_eq!(green.direction(), camera.get_ray(0, 4, 0).direction());
+        let angle = red
+            .direction()
+            .normalize()
+            .dot_product(&blue.direction().normalize())
+            .acos();
+        assert!(angle > 0.01, "edge rays {angle} radians apart");
+        // Red is spread outwards, to the left of the left edge
+        assert!(red.direction().x() < green.direction().x());
+        assert!(green.direction().x() < blue.direction().x());
+
+        let [red, green, blue] = camera.get_channel_rays(4, 4, 0);
+        assert!((red.direction() - green.direction()).magnitude() < 1e-12);
+        assert!((blue.direction() - green.direction()).magnitude() < 1e-12);
+    }
+
+    #[test]
+    fn flat_color_ignores_the_lights() {
+        let color = Color::new(0.2, 0.6, 0.9);
+        let render = |light_x: f64| {
+            let mut lights = LightList::new();
+            let mut world = HittableList::new();
+            world.add(Box::new(Sphere::new(
+                Point3::new(0.0, 0.0, -3.0),
+                None,
+                1.0,
+                Arc::new(FlatColor::new(color)),
+            )));
+            world.add(Box::new(QuadLight::new(
+                Point3::new(light_x, 2.0, -3.5),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+                ConstantTexture::from_points(10.0, 10.0, 10.0),
+                &mut lights,
+            )));
+            let mut camera = Camera::default();
+            camera.set_width(9).unwrap();
+            camera.set_aspect_ratio(1.0).unwrap();
+            camera.set_sample_per_pixel(16).unwrap();
+            camera.set_vertical_fov(20.0).unwrap();
+            camera.set_lights(lights);
+            camera.render_to_buffer(&world)
+        };
+        for light_x in [-3.0, 2.0] {
+            let image = render(light_x);
+            for pixel in image[3..6].iter().flat_map(|row| &row[3..6]) {
+                assert!(
+                    (*pixel - color).magnitude() < scalar_tolerance(1e-9),
+                    "{pixel:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn far_clip_culls_distant_geometry() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let mut world = HittableList::new();
+        for (x, z, radius) in [(-1.5, -4.0, 1.0), (1.5, -12.0, 2.0)] {
+            world.add(Box::new(Sphere::new(
+                Point3::new(x, 0.0, z),
+                None,
+                radius,
+                material.clone(),
+            )));
+        }
+        let sky = Color::new(0.7, 0.8, 1.0);
+        let mut camera = Camera::default();
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(16).unwrap();
+        camera.set_background_color(sky);
+        // The far sphere is barely wider than a pixel, the tent would spread most of the samples
+        // of its pixel past it
+        camera.set_pixel_filter(PixelFilter::Box);
+        // Pixels on each sphere
+        let (near, far) = ((4, 6), (4, 8));
+        let image = camera.render_to_buffer(&world);
+        assert_ne!(image[near.0][near.1], sky);
+        assert_ne!(image[far.0][far.1], sky);
+
+        camera.set_far_clip(Some(8.0));
+        let image = camera.render_to_buffer(&world);
+        assert_ne!(image[near.0][near.1], sky);
+        assert!((image[far.0][far.1] - sky).magnitude() < scalar_tolerance(1e-9));
+
+        // A clip closer than where the camera rays start hides everything
+        camera.set_far_clip(Some(0.0001));
+        let image = camera.render_to_buffer(&world);
+        assert!((image[near.0][near.1] - sky).magnitude() < scalar_tolerance(1e-9));
+    }
+
+    #[test]
+    fn ring_aperture_keeps_lens_samples_in_the_ring() {
+        /// Open between half and the whole radius of the lens
+        struct Ring;
+        impl Texture for Ring {
+            fn value(&self, u: f64, v: f64, _p: &Point3) -> Color {
+                let radius = (2.0 * u - 1.0).hypot(2.0 * v - 1.0);
+                if (0.5..=1.0).contains(&radius) {
+                    Color::new(1.0, 1.0, 1.0)
+                } else {
+                    Color::default()
+                }
+            }
+        }
+        seed_random(3);
+        let mut camera = Camera::default();
+        camera.set_defocus_angle(10.0);
+        camera.set_aperture_mask(Ring);
+        camera.initialize();
+        let lens_radius = camera.defocus_disk_u.magnitude();
+        let radii: Vec<f64> = (0..2000)
+            .map(|_| {
+                let point = camera.sample_disk((random_double(), random_double()));
+                (point - camera.center).magnitude() / lens_radius
+            })
+            .collect();
+        assert!(radii
+            .iter()
+            .all(|&radius| (0.5 - 1e-9..=1.0 + 1e-9).contains(&radius)));
+        // The ring covers three quarters of the disk, so the samples spread over it
+        let outer_half = radii.iter().filter(|&&radius| radius > 0.79).count();
+        assert!(outer_half > 800 && outer_half < 1200, "{outer_half}");
+    }
+
+    #[test]
+    fn nearly_closed_aperture_never_samples_a_blocked_point() {
+        /// Open only in a thin ring at the rim of the lens, so most points run out of tries
+        struct Rim;
+        impl Texture for Rim {
+            fn value(&self, u: f64, v: f64, _p: &Point3) -> Color {
+                let radius = (2.0 * u - 1.0).hypot(2.0 * v - 1.0);
+                if (0.98..=1.0).contains(&radius) {
+                    Color::new(1.0, 1.0, 1.0)
+                } else {
+                    Color::default()
+                }
+            }
+        }
+        seed_random(5);
+        let mut camera = Camera::default();
+        camera.set_defocus_angle(10.0);
+        camera.set_aperture_mask(Rim);
+        camera.initialize();
+        let lens_radius = camera.defocus_disk_u.magnitude();
+        for _ in 0..2000 {
+            let point = camera.sample_disk((random_double(), random_double()));
+            let radius = (point - camera.center).magnitude() / lens_radius;
+            assert!((0.98 - 1e-9..=1.0 + 1e-9).contains(&radius), "{radius}");
+        }
+    }
+
+    #[test]
+    fn object_id_pass_separates_objects() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let mut world = HittableList::new();
+        world.add(Box::new(Named::new(
+            Sphere::new(Point3::new(-3.0, 0.0, -3.0), None, 1.5, material.clone()),
+            1,
+        )));
+        world.add(Box::new(Named::new(
+            Sphere::new(Point3::new(3.0, 0.0, -3.0), None, 1.5, material),
+            2,
+        )));
+        assert_eq!(world.objects[0].object_id(), Some(1));
+        let mut camera = Camera::default();
+        camera.set_width(40).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        let ids = camera.render_object_id(&world);
+
+        let left = ids[10][10];
+        let right = ids[10][30];
+        assert_eq!(left, Color::from_id(1));
+        assert_eq!(right, Color::from_id(2));
+        assert_ne!(left, right);
+        assert_eq!(ids[0][20], Color::default());
+    }
+
+    #[test]
+    fn primary_background_only_frames_the_image() {
+        let mirror = Arc::new(Metal::new(Color::new(1.0, 1.0, 1.0), 0.0));
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -3.0),
+            None,
+            1.0,
+            mirror,
+        )));
+        let environment = Color::new(0.6, 0.7, 0.9);
+        let mut camera = Camera::default();
+        camera.set_width(20).unwrap();
+        camera.set_aspect_ratio(1.0).unwrap();
+        camera.set_sample_per_pixel(4).unwrap();
+        camera.set_background_color(environment);
+        camera.set_primary_background_color(Color::default());
+        let image = camera.render_to_buffer(&world);
+
+        assert_eq!(image[0][0], Color::default());
+        // The mirror reflects the environment back to the camera
+        let center = image[10][10];
+        assert!((center - environment).magnitude() < 1e-9, "{center:?}");
+    }
+
+    #[test]
+    fn accumulated_passes_match_a_single_render() {
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(-0.5, 0.0, -2.0),
+            None,
+            0.6,
+            Arc::new(FlatColor::new(Color::new(0.9, 0.3, 0.1))),
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.5, 0.2, -2.5),
+            None,
+            0.6,
+            Arc::new(FlatColor::new(Color::new(0.1, 0.4, 0.8))),
+        )));
+        let camera = || {
+            let mut camera = Camera::default();
+            camera.set_width(16).unwrap();
+            camera.set_aspect_ratio(1.0).unwrap();
+            camera.set_sampler(HaltonSampler::new(7));
+            camera
+        };
+        let mut passes = camera();
+        passes.set_sample_per_pixel(10).unwrap();
+        passes.accumulate(&world);
+        passes.accumulate(&world);
+        let (sums, counts) = passes.take_accumulator();
+        let mut single = camera();
+        single.set_sample_per_pixel(20).unwrap();
+        single.accumulate(&world);
+        let (expected, expected_counts) = single.take_accumulator();
+
+        assert!(counts.iter().flatten().all(|&count| count == 20));
+        assert_eq!(counts, expected_counts);
+        for (sum, expected) in sums.iter().flatten().zip(expected.iter().flatten()) {
+            for (channel, expected) in sum.iter().zip(expected) {
+                assert!((channel - expected).abs() < 1e-12);
+            }
+        }
+        // The edges of the spheres are partially covered, so the samples differ across a pixel
+        assert!(sums
+            .iter()
+            .flatten()
+            .any(|sum| ![0.0, 20.0 * 0.9, 20.0 * 0.1].contains(&sum[0])));
+        assert!(passes.take_accumulator().0.is_empty());
+    }
+
+    #[test]
+    fn coverage_is_antialiased_at_the_edges() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -2.0),
+            None,
+            1.0,
+            material,
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(32).unwrap();
+        camera.set_aspect_ratio(1.0).unwrap();
+        camera.set_sample_per_pixel(64).unwrap();
+        camera.set_sampler(HaltonSampler::new(3));
+        let coverage = camera.render_coverage(&world);
+
+        assert_eq!(coverage[16][16], 1.0);
+        assert_eq!(coverage[0][0], 0.0);
+        // The silhouette crosses the rows at the height of the center
+        let edge = coverage[16]
+            .iter()
+            .filter(|&&alpha| 0.0 < alpha && alpha < 1.0);
+        assert!(edge.count() >= 2);
+        assert!(coverage
+            .iter()
+            .flatten()
+            .all(|alpha| (0.0..=1.0).contains(alpha)));
+    }
+
+    #[test]
+    fn material_id_pass_separates_kinds() {
+        let diffuse = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let metal = Arc::new(Metal::new(Color::new(0.8, 0.8, 0.8), 0.0));
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(-3.0, 0.0, -3.0),
+            None,
+            1.5,
+            diffuse,
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(3.0, 0.0, -3.0),
+            None,
+            1.5,
+            metal,
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(40).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        let kinds = camera.render_material_ids(&world);
+
+        let left = kinds[10][10];
+        let right = kinds[10][30];
+        assert_eq!(left, MaterialKind::Lambertian.color());
+        assert_eq!(right, MaterialKind::Metal.color());
+        assert_ne!(left, right);
+        assert_eq!(kinds[0][20], Color::default());
+    }
+
+    #[test]
+    fn ambient_occlusion_darkens_corners() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let mut world = HittableList::new();
+        // Floor and two walls meeting at the origin
+        world.add(Box::new(Quad::new(
+            Point3::default(),
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 10.0),
+            material.clone(),
+        )));
+        world.add(Box::new(Quad::new(
+            Point3::default(),
+            Vec3::new(0.0, 10.0, 0.0),
+            Vec3::new(0.0, 0.0, 10.0),
+            material.clone(),
+        )));
+        world.add(Box::new(Quad::new(
+            Point3::default(),
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(0.0, 10.0, 0.0),
+            material,
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(8).unwrap();
+        camera.set_aspect_ratio(1.0).unwrap();
+        camera.initialize();
+
+        let open = Ray::new(Point3::new(8.0, 1.0, 8.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let corner = Ray::new(Point3::new(0.05, 1.0, 0.05), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let open = camera.ambient_occlusion(&open, &world, 1.0, 256);
+        let corner = camera.ambient_occlusion(&corner, &world, 1.0, 256);
+        assert!(open > 0.99, "open occlusion {}", open);
+        assert!(corner < 0.8, "corner occlusion {}", corner);
+        // A radius below the offset of the occlusion rays can't see the walls
+        let corner_ray = Ray::new(Point3::new(0.05, 1.0, 0.05), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        assert_eq!(
+            camera.ambient_occlusion(&corner_ray, &world, 0.0005, 16),
+            1.0
+        );
+
+        let image = camera.render_ao(&world, 1.0, 4);
+        assert_eq!(image.len(), 8);
+        assert_eq!(image[0].len(), 8);
+    }
+}
@@ -0,0 +1,186 @@
+use std::sync::Arc;
+
+use crate::aabb::aabb::{surrounding_box, AABB};
+use crate::hittable::{HitRecord, Hittable};
+use crate::interval::Interval;
+use crate::material::Material;
+use crate::rays::Ray;
+use crate::vec3::{Point3, Vec3};
+
+/// Sphere of `radius` swept along the segment from `a` to `b`: a cylinder around the segment
+/// closed by two hemispheres centered at the endpoints. When the endpoints coincide it is a sphere
+pub struct Capsule {
+    a: Point3,
+    b: Point3,
+    radius: f64,
+    material: Arc<dyn Material>,
+    bbox: AABB,
+}
+
+impl Capsule {
+    pub fn new(a: Point3, b: Point3, radius: f64, material: Arc<dyn Material>) -> Self {
+        let rvec = Vec3::new(radius, radius, radius);
+        let bbox = surrounding_box(
+            &AABB::from_points(a - rvec, a + rvec),
+            &AABB::from_points(b - rvec, b + rvec),
+        );
+        Capsule {
+            a,
+            b,
+            radius,
+            material,
+            bbox,
+        }
+    }
+    pub fn radius(&self) -> f64 {
+        self.radius
+    }
+    /// Roots of the ray against the infinite cylinder around the axis, kept where they fall
+    /// between the endpoints
+    fn body_roots(&self, ray: &Ray) -> Vec<f64> {
+        let axis = self.b - self.a;
+        let length_squared = axis.square_magnitude();
+        if length_squared == 0.0 {
+            return Vec::new();
+        }
+        let offset = ray.origin() - self.a;
+        let direction_along = axis.dot_product(&ray.direction());
+        let offset_along = axis.dot_product(&offset);
+        let a = length_squared * ray.direction().square_magnitude() - direction_along.powi(2);
+        let h =
+            length_squared * offset.dot_product(&ray.direction()) - offset_along * direction_along;
+        let c = length_squared * offset.square_magnitude()
+            - offset_along.powi(2)
+            - self.radius * self.radius * length_squared;
+        quadratic_roots(a, h, c)
+            .into_iter()
+            .filter(|&t| (0.0..=length_squared).contains(&(offset_along + t * direction_along)))
+            .collect()
+    }
+    /// Roots of the ray against the sphere at `center`, kept on the side of the hemisphere that
+    /// caps the body, where the axis points along `outward`
+    fn cap_roots(&self, ray: &Ray, center: Point3, outward: Vec3) -> Vec<f64> {
+        let offset = ray.origin() - center;
+        let a = ray.direction().square_magnitude();
+        let h = offset.dot_product(&ray.direction());
+        let c = offset.square_magnitude() - self.radius * self.radius;
+        quadratic_roots(a, h, c)
+            .into_iter()
+            .filter(|&t| (ray.at(t) - center).dot_product(&outward) >= 0.0)
+            .collect()
+    }
+    /// Outward normal at a point on the surface, from the closest point of the axis
+    fn normal_at(&self, p: Point3) -> Vec3 {
+        let axis = self.b - self.a;
+        let length_squared = axis.square_magnitude();
+        let along = if length_squared == 0.0 {
+            0.0
+        } else {
+            ((p - self.a).dot_product(&axis) / length_squared).clamp(0.0, 1.0)
+        };
+        (p - (self.a + along * axis)) / self.radius
+    }
+}
+
+/// Roots of `a t^2 + 2 h t + c`, in increasing order
+fn quadratic_roots(a: f64, h: f64, c: f64) -> Vec<f64> {
+    let discriminant = h * h - a * c;
+    if a == 0.0 || discriminant < 0.0 {
+        return Vec::new();
+    }
+    let sqrt_d = discriminant.sqrt();
+    vec![(-h - sqrt_d) / a, (-h + sqrt_d) / a]
+}
+
+impl Hittable for Capsule {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        let axis = self.b - self.a;
+        let t = self
+            .body_roots(ray)
+            .into_iter()
+            .chain(self.cap_roots(ray, self.a, -axis))
+            .chain(self.cap_roots(ray, self.b, axis))
+            .filter(|&t| time_interval.surround(t))
+            .reduce(f64::min)?;
+
+        let mut rec = HitRecord::new();
+        rec.set_t(t);
+        rec.set_colision_point(ray.at(t));
+        rec.set_face_normal(ray, self.normal_at(rec.p()));
+        rec.set_material(self.material.clone());
+        Some(rec)
+    }
+    fn bounding_box(&self, _time_interval: &Interval) -> Option<AABB> {
+        Some(self.bbox)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::scalar_tolerance;
+    use crate::{material::Lambertian, textures::ConstantTexture};
+
+    fn capsule(a: Point3, b: Point3) -> Capsule {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        Capsule::new(a, b, 1.0, material)
+    }
+
+    #[test]
+    fn hit_cylindrical_body() {
+        let capsule = capsule(Point3::new(0.0, -2.0, 0.0), Point3::new(0.0, 2.0, 0.0));
+        let bbox = capsule.bounding_box(&Interval::new(0.0, 1.0)).unwrap();
+        assert_eq!(bbox.min(), Vec3::new(-1.0, -3.0, -1.0));
+        assert_eq!(bbox.max(), Vec3::new(1.0, 3.0, 1.0));
+
+        let ray = Ray::new(Point3::new(0.0, 1.5, -5.0), Vec3::new(0.0, 0.0, 2.0), 0.0);
+        let rec = capsule
+            .hit(&ray, &Interval::new(0.001, f64::INFINITY))
+            .unwrap();
+        assert!((rec.t() - 2.0).abs() < 1e-9);
+        assert!((rec.p() - Point3::new(0.0, 1.5, -1.0)).magnitude() < 1e-9);
+        assert!((rec.normal() - Vec3::new(0.0, 0.0, -1.0)).magnitude() < 1e-9);
+        assert!(rec.front_face());
+
+        // From inside, the far wall is hit with the normal facing the ray
+        let inside = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        let rec = capsule
+            .hit(&inside, &Interval::new(0.001, f64::INFINITY))
+            .unwrap();
+        assert!((rec.t() - 1.0).abs() < 1e-9);
+        assert!(!rec.front_face());
+        assert!((rec.normal() - Vec3::new(-1.0, 0.0, 0.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn hit_hemispherical_cap() {
+        let capsule = capsule(Point3::new(0.0, -2.0, 0.0), Point3::new(0.0, 2.0, 0.0));
+        let interval = Interval::new(0.001, f64::INFINITY);
+        let down = Ray::new(Point3::new(0.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let rec = capsule.hit(&down, &interval).unwrap();
+        assert!((rec.t() - 7.0).abs() < scalar_tolerance(1e-9));
+        assert!((rec.normal() - Vec3::new(0.0, 1.0, 0.0)).magnitude() < scalar_tolerance(1e-9));
+
+        // Off the axis the ray meets the cap before the height of the body
+        let slanted = Ray::new(Point3::new(0.6, -10.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0);
+        let rec = capsule.hit(&slanted, &interval).unwrap();
+        assert!((rec.p().y() - (-2.0 - 0.8)).abs() < scalar_tolerance(1e-9));
+        assert!((rec.normal() - Vec3::new(0.6, -0.8, 0.0)).magnitude() < scalar_tolerance(1e-9));
+
+        // Past the cap there is nothing, even though the infinite cylinder would be hit
+        let miss = Ray::new(Point3::new(0.0, 3.5, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert!(capsule.hit(&miss, &interval).is_none());
+    }
+
+    #[test]
+    fn coincident_endpoints_are_a_sphere() {
+        let center = Point3::new(1.0, 2.0, 3.0);
+        let capsule = capsule(center, center);
+        let ray = Ray::new(Point3::new(1.0, 2.0, -2.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let rec = capsule
+            .hit(&ray, &Interval::new(0.001, f64::INFINITY))
+            .unwrap();
+        assert!((rec.t() - 4.0).abs() < 1e-9);
+        assert!((rec.normal() - Vec3::new(0.0, 0.0, -1.0)).magnitude() < 1e-9);
+    }
+}
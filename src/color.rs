@@ -1,9 +1,129 @@
-use crate::interval::Interval;
+use crate::interval::{Interval, UNITY_INTERVAL};
 use crate::vec3::Vec3;
-use image::ImageBuffer;
+use image::{ImageBuffer, Luma};
+#[cfg(not(target_arch = "wasm32"))]
 use std::fs::File;
+#[cfg(not(target_arch = "wasm32"))]
 use std::io::prelude::*;
 pub type Color = Vec3;
+
+/// Operator that maps linear radiance to the displayable [0, 1] range before gamma correction
+/// - Clamp: Values above 1 are clipped
+/// - Reinhard: Compresses each channel with `c / (1 + c)`, so highlights never clip
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ToneMap {
+    #[default]
+    Clamp,
+    Reinhard,
+}
+
+impl ToneMap {
+    pub fn apply(&self, x: f64) -> f64 {
+        match self {
+            ToneMap::Clamp => x,
+            ToneMap::Reinhard => {
+                if x > 0.0 {
+                    x / (1.0 + x)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+/// File format of the images written by `Camera::render`
+/// - Png: 8 bits PNG, RGBA if the background is transparent
+/// - PpmAscii: Plain text PPM, P3, with the same 8 bits values as the PNG, easy to diff. It has no
+///   alpha, the transparent pixels are black
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Png,
+    PpmAscii,
+}
+
+impl OutputFormat {
+    /// Format given by the extension of `filename`, PNG unless it is `.ppm`
+    pub fn from_filename(filename: &str) -> Self {
+        match std::path::Path::new(filename).extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("ppm") => OutputFormat::PpmAscii,
+            _ => OutputFormat::Png,
+        }
+    }
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::PpmAscii => "ppm",
+        }
+    }
+}
+/// Guides drawn over the written image to check the framing, in `OVERLAY_COLOR`. They are drawn
+/// on the 8 bits pixels, the rendered colors are left alone
+/// - None: Nothing is drawn
+/// - CenterCross: Lines through the center of the image, two pixels thick when the size is even
+/// - Grid: Lines every `spacing` pixels from the top left corner
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overlay {
+    #[default]
+    None,
+    CenterCross,
+    Grid {
+        spacing: u32,
+    },
+}
+
+/// Magenta, rare enough in renders to stand out
+pub const OVERLAY_COLOR: [u8; 3] = [255, 0, 255];
+
+impl Overlay {
+    /// Whether the overlay covers the pixel (x, y) of an image of `width` by `height`
+    pub fn covers(&self, x: u32, y: u32, width: u32, height: u32) -> bool {
+        let centered =
+            |k: u32, size: u32| k == size / 2 || (size.is_multiple_of(2) && k + 1 == size / 2);
+        match *self {
+            Overlay::None => false,
+            Overlay::CenterCross => centered(x, width) || centered(y, height),
+            Overlay::Grid { spacing } => {
+                spacing > 0 && (x.is_multiple_of(spacing) || y.is_multiple_of(spacing))
+            }
+        }
+    }
+}
+/// Linear transformation of colors, usually a change of primaries. The renders are in the Rec.709
+/// primaries of sRGB, so `REC709` leaves them unchanged and is the default
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Matrix3 {
+    rows: [[f64; 3]; 3],
+}
+
+impl Matrix3 {
+    pub const IDENTITY: Matrix3 = Matrix3::new([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+    pub const REC709: Matrix3 = Matrix3::IDENTITY;
+    /// From the Rec.709 primaries to the AP1 primaries of ACEScg, adapting the D65 white point to
+    /// the one of ACES
+    pub const ACESCG: Matrix3 = Matrix3::new([
+        [0.613_132_4, 0.339_538_0, 0.047_416_7],
+        [0.070_124_4, 0.916_394_0, 0.013_451_5],
+        [0.020_587_7, 0.109_574_6, 0.869_785_4],
+    ]);
+
+    pub const fn new(rows: [[f64; 3]; 3]) -> Self {
+        Matrix3 { rows }
+    }
+    pub fn transform(&self, color: Color) -> Color {
+        let [r, g, b] = self
+            .rows
+            .map(|row| row[0] * color.get_r() + row[1] * color.get_g() + row[2] * color.get_b());
+        Color::new(r, g, b)
+    }
+}
+
+impl Default for Matrix3 {
+    fn default() -> Self {
+        Matrix3::IDENTITY
+    }
+}
+
 impl Color {
     pub fn get_r(&self) -> f64 {
         self.x()
@@ -15,32 +135,264 @@ impl Color {
     pub fn get_b(&self) -> f64 {
         self.z()
     }
+    /// Relative luminance of the linear color, with the Rec. 709 weights
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.get_r() + 0.7152 * self.get_g() + 0.0722 * self.get_b()
+    }
+    /// Maps an object identifier to a distinct, stable color by hashing it. Used to encode the
+    /// object ID pass
+    pub fn from_id(id: u32) -> Self {
+        // Finalizer of MurmurHash3, so consecutive ids get unrelated colors
+        let mut hash = id;
+        hash ^= hash >> 16;
+        hash = hash.wrapping_mul(0x85eb_ca6b);
+        hash ^= hash >> 13;
+        hash = hash.wrapping_mul(0xc2b2_ae35);
+        hash ^= hash >> 16;
+        let channel = |shift: u32| f64::from((hash >> shift) & 0xff) / 255.0;
+        Color::new(channel(0), channel(8), channel(16))
+    }
+    /// Writes the color as a line of a plain PPM, quantized the way `write_to_png` does
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn write_color(&self, out: &mut File) {
-        let intensity = Interval::new(0.0, 0.999);
-        let rbyte = (intensity.clamp(linear_to_gamma(self.get_r())) * 256.0) as i32;
-        let gbyte = (intensity.clamp(linear_to_gamma(self.get_g())) * 256.) as i32;
-        let bbyte = (intensity.clamp(linear_to_gamma(self.get_b())) * 256.) as i32;
+        let [rbyte, gbyte, bbyte] = tonemap_pixel(*self, ToneMap::Clamp, 2.0);
         writeln!(out, "{} {} {}", rbyte, gbyte, bbyte).expect("Failed writing color!");
     }
 }
-pub fn write_to_png(filename: &str, image: &Vec<Vec<Vec3>>, width: i32, height: i32) {
+/// Writes an image as an 8 bits RGB PNG, quantized by `tonemap_pixel`. The overlay is drawn over
+/// the quantized pixels
+pub fn write_to_png(
+    filename: &str,
+    image: &Vec<Vec<Vec3>>,
+    width: i32,
+    height: i32,
+    overlay: Overlay,
+) {
     let mut encoder = ImageBuffer::new(width as u32, height as u32);
 
     println!("{}", image.len());
     for i in 0..height {
         for j in 0..width {
             let color = image[i as usize][j as usize];
-            let rbyte = (linear_to_gamma(color.get_r()) * 256.0) as u8;
-            let gbyte = (linear_to_gamma(color.get_g()) * 256.0) as u8;
-            let bbyte = (linear_to_gamma(color.get_b()) * 256.0) as u8;
-            encoder.put_pixel(j as u32, i as u32, image::Rgb([rbyte, gbyte, bbyte]));
+            let pixel = if overlay.covers(j as u32, i as u32, width as u32, height as u32) {
+                OVERLAY_COLOR
+            } else {
+                tonemap_pixel(color, ToneMap::Clamp, 2.0)
+            };
+            encoder.put_pixel(j as u32, i as u32, image::Rgb(pixel));
+        }
+    }
+    encoder.save(filename).unwrap();
+}
+/// Writes an image as a plain text PPM, a pixel per line. The overlay is drawn over the quantized
+/// pixels like in `write_to_png`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_to_ppm(
+    filename: &str,
+    image: &[Vec<Color>],
+    width: i32,
+    height: i32,
+    overlay: Overlay,
+) {
+    let mut out = File::create(filename).expect("Couldn't create the image");
+    writeln!(out, "P3\n{width} {height}\n255").expect("Failed writing the header!");
+    for i in 0..height {
+        for j in 0..width {
+            if overlay.covers(j as u32, i as u32, width as u32, height as u32) {
+                let [r, g, b] = OVERLAY_COLOR;
+                writeln!(out, "{r} {g} {b}").expect("Failed writing color!");
+            } else {
+                image[i as usize][j as usize].write_color(&mut out);
+            }
+        }
+    }
+}
+/// Writes an image with alpha as an 8 bits RGBA PNG. The colors are premultiplied by the alpha,
+/// as rendered with a transparent background, and are divided by it before the tone map since
+/// PNG stores straight alpha. The overlay is drawn opaque over the quantized pixels
+pub fn write_rgba_to_png(
+    filename: &str,
+    image: &[Vec<(Color, f64)>],
+    width: i32,
+    height: i32,
+    overlay: Overlay,
+) {
+    let mut encoder = ImageBuffer::new(width as u32, height as u32);
+    for i in 0..height {
+        for j in 0..width {
+            let (color, alpha) = image[i as usize][j as usize];
+            let pixel = if overlay.covers(j as u32, i as u32, width as u32, height as u32) {
+                let [r, g, b] = OVERLAY_COLOR;
+                [r, g, b, u8::MAX]
+            } else {
+                tonemap_rgba_pixel(color, alpha)
+            };
+            encoder.put_pixel(j as u32, i as u32, image::Rgba(pixel));
+        }
+    }
+    encoder.save(filename).unwrap();
+}
+/// Converts a linear color to 8 bits display values, the same way `write_to_png` does: the tone
+/// map is applied to each channel, followed by the gamma correction, and the result is quantized
+pub fn tonemap_pixel(color: Color, tonemap: ToneMap, gamma: f64) -> [u8; 3] {
+    let intensity = Interval::new(0.0, 0.999);
+    let quantize =
+        |x: f64| (intensity.clamp(linear_to_gamma(tonemap.apply(x), gamma)) * 256.0) as u8;
+    [
+        quantize(color.get_r()),
+        quantize(color.get_g()),
+        quantize(color.get_b()),
+    ]
+}
+/// Converts a linear color premultiplied by `alpha` to 8 bits straight alpha RGBA, the way
+/// `write_rgba_to_png` does
+pub fn tonemap_rgba_pixel(color: Color, alpha: f64) -> [u8; 4] {
+    let alpha = UNITY_INTERVAL.clamp(alpha);
+    let [r, g, b] = if alpha > 0.0 {
+        tonemap_pixel(color / alpha, ToneMap::Clamp, 2.0)
+    } else {
+        [0, 0, 0]
+    };
+    [r, g, b, (alpha * 255.0).round() as u8]
+}
+/// Flattens a render buffer, row by row, into linear RGB values in single precision, ready to be
+/// uploaded to a floating point texture
+pub fn to_f32_rgb(image: &[Vec<Color>]) -> Vec<f32> {
+    image
+        .iter()
+        .flatten()
+        .flat_map(Color::as_f32_array)
+        .collect()
+}
+/// Pulls down fireflies: a pixel more than `threshold` times as bright as the median of its 8
+/// neighbours is replaced by that median, component by component. Highlights spread over several
+/// pixels have bright neighbours and are kept, as is anything brighter than its surroundings by
+/// less than `threshold`
+pub fn median_firefly_filter(image: &[Vec<Color>], threshold: f64) -> Vec<Vec<Color>> {
+    let height = image.len();
+    let median = |values: &mut Vec<f64>| {
+        values.sort_by(f64::total_cmp);
+        let middle = values.len() / 2;
+        if values.len().is_multiple_of(2) {
+            0.5 * (values[middle - 1] + values[middle])
+        } else {
+            values[middle]
+        }
+    };
+    (0..height)
+        .map(|i| {
+            (0..image[i].len())
+                .map(|j| {
+                    let pixel = image[i][j];
+                    let neighbours: Vec<Color> = (i.saturating_sub(1)..(i + 2).min(height))
+                        .flat_map(|y| {
+                            (j.saturating_sub(1)..(j + 2).min(image[y].len()))
+                                .filter(move |&x| (y, x) != (i, j))
+                                .map(move |x| image[y][x])
+                        })
+                        .collect();
+                    if neighbours.is_empty() {
+                        return pixel;
+                    }
+                    let component = |axis: usize| {
+                        median(&mut neighbours.iter().map(|c| c.axis(axis)).collect())
+                    };
+                    let neighbourhood = Color::new(component(0), component(1), component(2));
+                    if pixel.luminance() > threshold * neighbourhood.luminance() {
+                        neighbourhood
+                    } else {
+                        pixel
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+/// Writes a buffer of normalized depths, in [0, 1], as a 16 bits grayscale PNG
+pub fn write_depth_to_png(filename: &str, depth: &[Vec<f64>], width: i32, height: i32) {
+    let mut encoder: ImageBuffer<Luma<u16>, Vec<u16>> =
+        ImageBuffer::new(width as u32, height as u32);
+    for i in 0..height {
+        for j in 0..width {
+            let value = depth[i as usize][j as usize] * f64::from(u16::MAX);
+            encoder.put_pixel(j as u32, i as u32, Luma([value.round() as u16]));
         }
     }
     encoder.save(filename).unwrap();
 }
-fn linear_to_gamma(x: f64) -> f64 {
+fn linear_to_gamma(x: f64, gamma: f64) -> f64 {
     if x > 0.0 {
-        return x.sqrt();
+        return x.powf(1.0 / gamma);
     }
     0.0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tonemap_matches_png_output() {
+        let white = Color::new(1.0, 1.0, 1.0);
+        assert_eq!(tonemap_pixel(white, ToneMap::Clamp, 2.0), [255, 255, 255]);
+        let color = Color::new(0.25, 0.0, 4.0);
+        assert_eq!(tonemap_pixel(color, ToneMap::Clamp, 2.0), [128, 0, 255]);
+        assert_eq!(tonemap_pixel(color, ToneMap::Reinhard, 1.0), [51, 0, 204]);
+    }
+
+    #[test]
+    fn primaries_matrices() {
+        let color = Color::new(0.3, 1.5, -0.2);
+        assert_eq!(Matrix3::IDENTITY.transform(color), color);
+        assert_eq!(Matrix3::default(), Matrix3::REC709);
+
+        let red = Matrix3::ACESCG.transform(Color::new(1.0, 0.0, 0.0));
+        assert_eq!(red, Color::new(0.613_132_4, 0.070_124_4, 0.020_587_7));
+        // White stays white, the rows add up to about 1
+        let white = Matrix3::ACESCG.transform(Color::new(1.0, 1.0, 1.0));
+        assert!((white - Color::new(1.0, 1.0, 1.0)).magnitude() < 1e-3);
+    }
+
+    #[test]
+    fn f32_buffer_is_flat_rgb() {
+        let image = vec![
+            vec![Color::new(1.0, 2.0, 3.0), Color::new(4.0, 5.0, 6.0)],
+            vec![Color::new(7.0, 8.0, 9.0), Color::new(0.5, 0.25, 0.125)],
+        ];
+        assert_eq!(
+            to_f32_rgb(&image),
+            [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 0.5, 0.25, 0.125]
+        );
+    }
+
+    #[test]
+    fn firefly_filter_keeps_broad_highlights() {
+        // Smooth ramp with a lone outlier and a bright 4x4 patch
+        let mut image: Vec<Vec<Color>> = (0..12)
+            .map(|i| {
+                (0..12)
+                    .map(|j| Color::new(1.0, 1.0, 1.0) * (0.4 + 0.01 * (i + j) as f64))
+                    .collect()
+            })
+            .collect();
+        image[3][3] = Color::new(50.0, 40.0, 60.0);
+        for row in &mut image[6..10] {
+            for pixel in &mut row[6..10] {
+                *pixel = Color::new(3.0, 3.0, 3.0);
+            }
+        }
+
+        let filtered = median_firefly_filter(&image, 8.0);
+        assert!(filtered[3][3].luminance() < 1.0, "{:?}", filtered[3][3]);
+        assert!((filtered[3][3] - image[3][4]).magnitude() < 0.1);
+        for (i, j) in (0..12).flat_map(|i| (0..12).map(move |j| (i, j))) {
+            if (i, j) != (3, 3) {
+                assert_eq!(filtered[i][j], image[i][j], "pixel {i}, {j}");
+            }
+        }
+        // A lower threshold takes the corners of the patch for fireflies too
+        let strict = median_firefly_filter(&image, 2.0);
+        assert!(strict[6][6].luminance() < 1.0);
+        assert_eq!(strict[7][7], image[7][7]);
+    }
+}
@@ -1,17 +1,63 @@
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::cell::RefCell;
 use std::f64::consts;
 
 pub const INFINITY: f64 = f64::MAX;
 pub const PI: f64 = consts::PI;
 
+thread_local! {
+    /// Generator behind all the random numbers of the renderer. Each thread has its own, seeded
+    /// from entropy unless `seed_random` is called
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
 pub fn degree_to_radians(degrees: f64) -> f64 {
     degrees * PI / 180.0
 }
 
+/// Reseeds the random generator of the current thread, making the numbers it draws from now on
+/// reproducible
+pub fn seed_random(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
 pub fn random_double() -> f64 {
-    rand::thread_rng().gen()
+    RNG.with(|rng| rng.borrow_mut().gen())
 }
 
 pub fn random_double_range(min: f64, max: f64) -> f64 {
     min + (max - min) * random_double()
 }
+
+/// Returns a random integer in [min, max]
+pub fn random_int(min: i32, max: i32) -> i32 {
+    RNG.with(|rng| rng.borrow_mut().gen_range(min..=max))
+}
+
+/// Finalizer of SplitMix64, mixing every bit of the input into every bit of the output
+pub fn split_mix(value: u64) -> u64 {
+    let mut hash = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Number in [0, 1) hashed from `key`, the same every time for the same key
+pub fn hash_double(key: u64) -> f64 {
+    (split_mix(key) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_numbers_repeat() {
+        seed_random(7);
+        let first: Vec<f64> = (0..8).map(|_| random_double()).collect();
+        seed_random(7);
+        let second: Vec<f64> = (0..8).map(|_| random_double()).collect();
+        assert_eq!(first, second);
+        assert!((0..100).all(|_| (2..=4).contains(&random_int(2, 4))));
+    }
+}
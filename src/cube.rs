@@ -13,6 +13,12 @@ pub struct Cube {
 
 impl Cube {
     pub fn new<T: Material + 'static>(a: Point3, b: Point3, material: Arc<T>) -> Self {
+        let material: Arc<dyn Material> = material;
+        Cube::with_face_materials(a, b, std::array::from_fn(|_| material.clone()))
+    }
+    /// Creates a cube with a material per face, in the order +x, -x, +y, -y, +z, -z. The normal
+    /// of each face points out of the cube
+    pub fn with_face_materials(a: Point3, b: Point3, materials: [Arc<dyn Material>; 6]) -> Self {
         let mut sides: HittableList = Default::default();
         let min = Point3::new(a.x().min(b.x()), a.y().min(b.y()), a.z().min(b.z()));
         let max = Point3::new(a.x().max(b.x()), a.y().max(b.y()), a.z().max(b.z()));
@@ -20,47 +26,17 @@ impl Cube {
         let dx = Vec3::new(max.x() - min.x(), 0.0, 0.0);
         let dy = Vec3::new(0.0, max.y() - min.y(), 0.0);
         let dz = Vec3::new(0.0, 0.0, max.z() - min.z());
-        sides.add(Box::new(Quad::new(
-            Point3::new(min.x(), min.y(), max.z()),
-            dx,
-            dy,
-            material.clone(),
-        )));
-
-        sides.add(Box::new(Quad::new(
-            Point3::new(max.x(), min.y(), max.z()),
-            -dz,
-            dy,
-            material.clone(),
-        )));
-
-        sides.add(Box::new(Quad::new(
-            Point3::new(max.x(), min.y(), min.z()),
-            -dx,
-            dy,
-            material.clone(),
-        )));
-
-        sides.add(Box::new(Quad::new(
-            Point3::new(min.x(), min.y(), min.z()),
-            dz,
-            dy,
-            material.clone(),
-        )));
-
-        sides.add(Box::new(Quad::new(
-            Point3::new(min.x(), max.y(), max.z()),
-            dx,
-            -dz,
-            material.clone(),
-        )));
-
-        sides.add(Box::new(Quad::new(
-            Point3::new(min.x(), min.y(), min.z()),
-            dx,
-            dz,
-            material,
-        )));
+        let faces = [
+            (Point3::new(max.x(), min.y(), max.z()), -dz, dy),
+            (Point3::new(min.x(), min.y(), min.z()), dz, dy),
+            (Point3::new(min.x(), max.y(), max.z()), dx, -dz),
+            (Point3::new(min.x(), min.y(), min.z()), dx, dz),
+            (Point3::new(min.x(), min.y(), max.z()), dx, dy),
+            (Point3::new(max.x(), min.y(), min.z()), -dx, dy),
+        ];
+        for ((q, u, v), material) in faces.into_iter().zip(materials) {
+            sides.add(Box::new(Quad::new(q, u, v, material)));
+        }
         Cube { sides }
     }
 }
@@ -79,4 +55,57 @@ impl Hittable for Cube {
     ) -> Option<crate::aabb::aabb::AABB> {
         self.sides.bounding_box(time_interval)
     }
+    /// The sides, in the order of `with_face_materials`: +x, -x, +y, -y, +z and -z
+    fn child(&self, index: usize) -> Option<&dyn Hittable> {
+        self.sides.child(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color::Color, common::INFINITY, interval::Interval, material::DiffuseLight, rays::Ray,
+        textures::ConstantTexture,
+    };
+
+    #[test]
+    fn each_face_gets_its_material() {
+        let colors = [
+            Color::new(1.0, 0.0, 0.0),
+            Color::new(0.0, 1.0, 0.0),
+            Color::new(0.0, 0.0, 1.0),
+            Color::new(1.0, 1.0, 0.0),
+            Color::new(0.0, 1.0, 1.0),
+            Color::new(1.0, 0.0, 1.0),
+        ];
+        let materials = colors.map(|color| {
+            Arc::new(DiffuseLight::new(ConstantTexture::new(color))) as Arc<dyn Material>
+        });
+        let cube = Cube::with_face_materials(
+            Point3::new(-1.0, -1.0, -1.0),
+            Point3::new(1.0, 1.0, 1.0),
+            materials,
+        );
+        let directions = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+        ];
+        for (outward, color) in directions.into_iter().zip(colors) {
+            // Off the center of the face, along the face
+            let offset = Vec3::new(0.3, 0.2, 0.1);
+            let origin = 5.0 * outward + offset - offset.dot_product(&outward) * outward;
+            let ray = Ray::new(origin, -outward, 0.0);
+            let rec = cube.hit(&ray, &Interval::new(0.001, INFINITY)).unwrap();
+            assert!((rec.t() - 4.0).abs() < 1e-9);
+            assert_eq!(rec.normal(), outward);
+            assert!(rec.front_face());
+            let material = rec.get_material().unwrap();
+            assert_eq!(material.emmited(&ray, &rec), color);
+        }
+    }
 }
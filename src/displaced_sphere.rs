@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use crate::aabb::aabb::AABB;
+use crate::common::PI;
+use crate::hittable::{HitRecord, Hittable};
+use crate::interval::Interval;
+use crate::material::Material;
+use crate::perlin::Perlin;
+use crate::rays::Ray;
+use crate::vec3::{Point3, Vec3};
+
+/// Marching steps across the shell of the displacement before giving up on a ray
+const DEFAULT_MAX_ITERATIONS: usize = 128;
+/// Halvings of the step where the ray crosses the surface
+const REFINEMENTS: usize = 32;
+
+/// Sphere whose surface is pushed in and out along the normal by Perlin noise, with the height
+/// in [-amplitude, amplitude], so the silhouette is bumpy too, unlike with a bump map. There is no
+/// closed form for the hits: the ray is marched in steps through the shell the surface lies in
+/// until it crosses the surface, and the crossing is then refined by bisection. Bumps thinner
+/// than a step can be missed, raise the iterations for finer noise
+pub struct DisplacedSphere {
+    center: Point3,
+    radius: f64,
+    amplitude: f64,
+    frequency: f64,
+    noise: Perlin,
+    max_iterations: usize,
+    material: Arc<dyn Material>,
+    bbox: AABB,
+}
+
+impl DisplacedSphere {
+    /// Sphere displaced by up to `amplitude`, with about `frequency` bumps across its radius
+    pub fn new(
+        center: Point3,
+        radius: f64,
+        amplitude: f64,
+        frequency: f64,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let extent = radius + amplitude.abs();
+        let rvec = Vec3::new(extent, extent, extent);
+        DisplacedSphere {
+            center,
+            radius,
+            amplitude: amplitude.abs(),
+            frequency,
+            noise: Perlin::new(256),
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            material,
+            bbox: AABB::from_points(center - rvec, center + rvec),
+        }
+    }
+    /// Sets how many steps the ray is marched in through the shell of the displacement
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations.max(1);
+        self
+    }
+    /// Height of the surface above the base sphere in the direction of `p`
+    fn height(&self, p: Point3) -> f64 {
+        let direction = (p - self.center).normalize();
+        // Shifted to positive coordinates, where the lattice of the noise doesn't fold over
+        let noise_point = self.frequency * (direction + Vec3::new(1.0, 1.0, 1.0));
+        self.amplitude * self.noise.noise(&noise_point).clamp(-1.0, 1.0)
+    }
+    /// Signed distance along the radius from the surface: negative inside, positive outside
+    fn offset(&self, p: Point3) -> f64 {
+        (p - self.center).magnitude() - self.radius - self.height(p)
+    }
+    /// Outward normal at `p`, the gradient of `offset` by central differences
+    fn normal_at(&self, p: Point3) -> Vec3 {
+        let h = 1e-4 * self.radius;
+        let axis = |e: Vec3| self.offset(p + h * e) - self.offset(p - h * e);
+        Vec3::new(
+            axis(Vec3::new(1.0, 0.0, 0.0)),
+            axis(Vec3::new(0.0, 1.0, 0.0)),
+            axis(Vec3::new(0.0, 0.0, 1.0)),
+        )
+        .normalize()
+    }
+    /// Times at which the ray is inside the sphere bounding the displaced surface
+    fn shell(&self, ray: &Ray) -> Option<(f64, f64)> {
+        let extent = self.radius + self.amplitude;
+        let oc = ray.origin() - self.center;
+        let a = ray.direction().square_magnitude();
+        let h = oc.dot_product(&ray.direction());
+        let c = oc.square_magnitude() - extent * extent;
+        let discriminant = h * h - a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let sqrt_d = discriminant.sqrt();
+        Some(((-h - sqrt_d) / a, (-h + sqrt_d) / a))
+    }
+}
+
+impl Hittable for DisplacedSphere {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        let (enter, exit) = self.shell(ray)?;
+        let start = enter.max(time_interval.min());
+        let end = exit.min(time_interval.max());
+        if start >= end {
+            return None;
+        }
+        let step = (end - start) / self.max_iterations as f64;
+        let inside = |t: f64| self.offset(ray.at(t)) < 0.0;
+        let starts_inside = inside(start);
+        let (mut near, mut far) = (1..=self.max_iterations)
+            .map(|k| (start + (k - 1) as f64 * step, start + k as f64 * step))
+            .find(|&(_, t)| inside(t) != starts_inside)?;
+        for _ in 0..REFINEMENTS {
+            let middle = 0.5 * (near + far);
+            if inside(middle) == starts_inside {
+                near = middle;
+            } else {
+                far = middle;
+            }
+        }
+        let t = far;
+        if !time_interval.surround(t) {
+            return None;
+        }
+
+        let mut rec = HitRecord::new();
+        rec.set_t(t);
+        rec.set_colision_point(ray.at(t));
+        rec.set_face_normal(ray, self.normal_at(rec.p()));
+        let (theta, phi) = (rec.p() - self.center).normalize().to_spherical();
+        rec.set_u(phi / (2.0 * PI));
+        rec.set_v(theta / PI);
+        rec.set_material(self.material.clone());
+        Some(rec)
+    }
+    fn bounding_box(&self, _time_interval: &Interval) -> Option<AABB> {
+        Some(self.bbox)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::INFINITY, material::Lambertian, sphere::Sphere, textures::ConstantTexture,
+    };
+
+    #[test]
+    fn displaced_silhouette_is_not_spherical() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let displaced = DisplacedSphere::new(Point3::default(), 1.0, 0.2, 3.0, material.clone());
+        let base = Sphere::new(Point3::default(), None, 1.0, material);
+        let bbox = displaced.bounding_box(&Interval::new(0.0, 1.0)).unwrap();
+        assert_eq!(bbox.max(), Vec3::new(1.2, 1.2, 1.2));
+
+        // Rays along -z grazing the base sphere, just inside and just outside of it, so they
+        // cross the shell over a short arc of the surface
+        let interval = Interval::new(0.001, INFINITY);
+        let (mut gained, mut lost) = (0, 0);
+        for k in 0..64 {
+            let angle = 2.0 * PI * k as f64 / 64.0;
+            for distance in [0.998, 1.002] {
+                let origin = Point3::new(distance * angle.cos(), distance * angle.sin(), 5.0);
+                let ray = Ray::new(origin, Vec3::new(0.0, 0.0, -1.0), 0.0);
+                let hit = displaced.hit(&ray, &interval);
+                match (hit.is_some(), base.hit(&ray, &interval).is_some()) {
+                    (true, false) => gained += 1,
+                    (false, true) => lost += 1,
+                    _ => {}
+                }
+                if let Some(rec) = hit {
+                    assert!(rec.normal().dot_product(&ray.direction()) < 0.0);
+                    let radius = rec.p().magnitude();
+                    assert!((0.8 - 1e-9..=1.2 + 1e-9).contains(&radius));
+                }
+            }
+        }
+        assert!(gained > 0, "no ray misses the sphere and hits the bumps");
+        assert!(lost > 0, "no ray hits the sphere and misses the dents");
+    }
+}
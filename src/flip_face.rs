@@ -0,0 +1,79 @@
+use crate::{
+    aabb::aabb::AABB,
+    hittable::{HitRecord, Hittable},
+    interval::Interval,
+    rays::Ray,
+    vec3::{Point3, Vec3},
+};
+
+/// Turns an object inside out: its hit records get the opposite normal and face, while the
+/// position, texture coordinates and material are kept. Fixes geometry imported with inward
+/// normals, and orients one-sided lights, like the ceiling light of a Cornell box
+pub struct FlipFace<H: Hittable> {
+    object: H,
+}
+
+impl<H: Hittable> FlipFace<H> {
+    pub fn new(object: H) -> Self {
+        FlipFace { object }
+    }
+}
+
+impl<H: Hittable> Hittable for FlipFace<H> {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        self.object.hit(ray, time_interval).map(|mut rec| {
+            rec.set_normal(-rec.normal());
+            rec.set_front_face(!rec.front_face());
+            rec
+        })
+    }
+    fn hit_any(&self, ray: &Ray, time_interval: &Interval) -> bool {
+        self.object.hit_any(ray, time_interval)
+    }
+    fn bounding_box(&self, time_interval: &Interval) -> Option<AABB> {
+        self.object.bounding_box(time_interval)
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.object.pdf_value(origin, direction)
+    }
+    fn random(&self, origin: &Point3) -> Vec3 {
+        self.object.random(origin)
+    }
+    fn object_id(&self) -> Option<u32> {
+        self.object.object_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{material::Lambertian, quad::Quad, textures::ConstantTexture};
+
+    #[test]
+    fn flipped_quad_reports_the_opposite_normal() {
+        let quad = || {
+            Quad::new(
+                Point3::new(-1.0, -1.0, 0.0),
+                Vec3::new(2.0, 0.0, 0.0),
+                Vec3::new(0.0, 2.0, 0.0),
+                Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+            )
+        };
+        let flipped = FlipFace::new(quad());
+        let ray = Ray::new(Point3::new(0.2, 0.4, 3.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let interval = Interval::new(0.001, f64::INFINITY);
+
+        let rec = quad().hit(&ray, &interval).unwrap();
+        let flipped_rec = flipped.hit(&ray, &interval).unwrap();
+        assert_eq!(flipped_rec.normal(), -rec.normal());
+        assert_eq!(flipped_rec.front_face(), !rec.front_face());
+        assert_eq!(flipped_rec.p(), rec.p());
+        assert_eq!((flipped_rec.u(), flipped_rec.v()), (rec.u(), rec.v()));
+        assert_eq!(
+            flipped.bounding_box(&interval),
+            quad().bounding_box(&interval)
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{
+    aabb::aabb::AABB,
+    common::INFINITY,
+    hittable::{HitRecord, Hittable},
+    interval::Interval,
+    rays::Ray,
+    vec3::Vec3,
+};
+
+/// Uniform grid over the bounds of the objects, each cell listing the objects whose box overlaps
+/// it. Rays walk the cells they cross in order, with the 3D DDA of Amanatides and Woo, and stop at
+/// the first cell holding a hit. For many small objects spread evenly, like a cluster of
+/// spheres, it can beat a BVH; for uneven scenes most cells are empty or crowded and the BVH is
+/// better. Objects spanning several cells are tested once per cell
+pub struct UniformGrid {
+    objects: Vec<Box<dyn Hittable>>,
+    cells: Vec<Vec<usize>>,
+    resolution: [usize; 3],
+    bbox: AABB,
+    cell_size: Vec3,
+}
+
+impl UniformGrid {
+    /// Grid of `resolution` cells along the longest side of the bounds, and as many along the
+    /// others as keeps the cells about cubic. Panics if an object has no bounding box
+    pub fn new(
+        objects: Vec<Box<dyn Hittable>>,
+        time_interval: &Interval,
+        resolution: usize,
+    ) -> Self {
+        let boxes: Vec<AABB> = objects
+            .iter()
+            .map(|object| object.bounding_box(time_interval).expect("No bounding box"))
+            .collect();
+        let mut bbox = AABB::empty();
+        for object_box in &boxes {
+            bbox.grow_to_include_box(object_box);
+        }
+        bbox.pad_to_minimum(0.0001);
+
+        let extent = bbox.max() - bbox.min();
+        let longest = extent.x().max(extent.y()).max(extent.z());
+        let resolution: [usize; 3] = std::array::from_fn(|axis| {
+            ((resolution.max(1) as f64 * extent.axis(axis) / longest).round() as usize).max(1)
+        });
+        let cell_size = Vec3::new(
+            extent.x() / resolution[0] as f64,
+            extent.y() / resolution[1] as f64,
+            extent.z() / resolution[2] as f64,
+        );
+        let mut grid = UniformGrid {
+            objects,
+            cells: vec![Vec::new(); resolution.iter().product()],
+            resolution,
+            bbox,
+            cell_size,
+        };
+        for (index, object_box) in boxes.iter().enumerate() {
+            let first = grid.cell_of(object_box.min());
+            let last = grid.cell_of(object_box.max());
+            for x in first[0]..=last[0] {
+                for y in first[1]..=last[1] {
+                    for z in first[2]..=last[2] {
+                        let cell = grid.cell_index([x, y, z]);
+                        grid.cells[cell].push(index);
+                    }
+                }
+            }
+        }
+        grid
+    }
+    /// Cell containing `point`, clamped to the grid
+    fn cell_of(&self, point: Vec3) -> [usize; 3] {
+        std::array::from_fn(|axis| {
+            let offset =
+                (point.axis(axis) - self.bbox.min().axis(axis)) / self.cell_size.axis(axis);
+            (offset.max(0.0) as usize).min(self.resolution[axis] - 1)
+        })
+    }
+    fn cell_index(&self, [x, y, z]: [usize; 3]) -> usize {
+        (z * self.resolution[1] + y) * self.resolution[0] + x
+    }
+    /// Times at which the ray enters and leaves the bounds of the grid, inside `time_interval`
+    fn clip(&self, ray: &Ray, time_interval: &Interval) -> Option<(f64, f64)> {
+        let (mut enter, mut exit) = (time_interval.min(), time_interval.max());
+        for axis in 0..3 {
+            let (origin, direction) = (ray.origin().axis(axis), ray.direction().axis(axis));
+            let (min, max) = (self.bbox.min().axis(axis), self.bbox.max().axis(axis));
+            if direction == 0.0 {
+                if origin < min || origin > max {
+                    return None;
+                }
+                continue;
+            }
+            let (t0, t1) = ((min - origin) / direction, (max - origin) / direction);
+            enter = enter.max(t0.min(t1));
+            exit = exit.min(t0.max(t1));
+        }
+        (enter <= exit).then_some((enter, exit))
+    }
+    /// Calls `visit` with the objects of every cell the ray crosses inside `time_interval`, in
+    /// order, together with the time the ray leaves the cell, until it returns true
+    fn walk(
+        &self,
+        ray: &Ray,
+        time_interval: &Interval,
+        mut visit: impl FnMut(&[usize], f64) -> bool,
+    ) {
+        let Some((enter, exit)) = self.clip(ray, time_interval) else {
+            return;
+        };
+        let mut cell = self.cell_of(ray.at(enter));
+        let mut next = [INFINITY; 3];
+        let mut delta = [INFINITY; 3];
+        let mut step = [0isize; 3];
+        for axis in 0..3 {
+            let direction = ray.direction().axis(axis);
+            if direction == 0.0 {
+                continue;
+            }
+            let size = self.cell_size.axis(axis);
+            let forward = direction > 0.0;
+            let boundary =
+                self.bbox.min().axis(axis) + (cell[axis] + usize::from(forward)) as f64 * size;
+            next[axis] = (boundary - ray.origin().axis(axis)) / direction;
+            delta[axis] = size / direction.abs();
+            step[axis] = if forward { 1 } else { -1 };
+        }
+        loop {
+            let axis = (0..3).min_by(|&a, &b| next[a].total_cmp(&next[b])).unwrap();
+            let leave = next[axis].min(exit);
+            if visit(&self.cells[self.cell_index(cell)], leave) || next[axis] > exit {
+                return;
+            }
+            let moved = cell[axis] as isize + step[axis];
+            if moved < 0 || moved >= self.resolution[axis] as isize {
+                return;
+            }
+            cell[axis] = moved as usize;
+            next[axis] += delta[axis];
+        }
+    }
+}
+
+impl Hittable for UniformGrid {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        let mut closest: Option<HitRecord> = None;
+        self.walk(ray, time_interval, |objects, leave| {
+            for &index in objects {
+                let max = closest.as_ref().map_or(time_interval.max(), |rec| rec.t());
+                if let Some(rec) =
+                    self.objects[index].hit(ray, &Interval::new(time_interval.min(), max))
+                {
+                    closest = Some(rec);
+                }
+            }
+            // A hit past the cell may still be beaten by an object of the next cells
+            closest.as_ref().is_some_and(|rec| rec.t() <= leave)
+        });
+        closest
+    }
+    fn hit_any(&self, ray: &Ray, time_interval: &Interval) -> bool {
+        let mut found = false;
+        self.walk(ray, time_interval, |objects, _| {
+            found = objects
+                .iter()
+                .any(|&index| self.objects[index].hit_any(ray, time_interval));
+            found
+        });
+        found
+    }
+    fn bounding_box(&self, _time_interval: &Interval) -> Option<AABB> {
+        Some(self.bbox)
+    }
+    fn child(&self, index: usize) -> Option<&dyn Hittable> {
+        self.objects.get(index).map(|object| object.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        common::{random_double_range, seed_random},
+        hittable::HittableList,
+        material::Lambertian,
+        sphere::Sphere,
+        textures::ConstantTexture,
+        vec3::Point3,
+    };
+
+    fn cluster() -> Vec<Box<dyn Hittable>> {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        (0..300)
+            .map(|_| {
+                Box::new(Sphere::new(
+                    Vec3::random_range(0.0, 165.0),
+                    None,
+                    10.0,
+                    material.clone(),
+                )) as Box<dyn Hittable>
+            })
+            .collect()
+    }
+
+    #[test]
+    fn grid_hits_match_a_list() {
+        seed_random(11);
+        let interval = Interval::new(0.0, 1.0);
+        let list = HittableList { objects: cluster() };
+        seed_random(11);
+        let grid = UniformGrid::new(cluster(), &interval, 12);
+        assert_eq!(grid.resolution.iter().max(), Some(&12));
+
+        let times = Interval::new(0.001, INFINITY);
+        let mut hits = 0;
+        for _ in 0..2000 {
+            // From outside the cluster and from inside it, where the walk starts mid grid
+            let origin = if random_double_range(0.0, 1.0) < 0.5 {
+                Point3::new(-300.0, 80.0, -300.0)
+            } else {
+                Vec3::random_range(20.0, 140.0)
+            };
+            let target = Vec3::random_range(-20.0, 185.0);
+            let ray = Ray::new(origin, target - origin, 0.0);
+            let expected = list.hit(&ray, &times);
+            let found = grid.hit(&ray, &times);
+            assert_eq!(grid.hit_any(&ray, &times), expected.is_some());
+            match (expected, found) {
+                (Some(expected), Some(found)) => {
+                    assert_eq!(found.t(), expected.t());
+                    assert_eq!(found.p(), expected.p());
+                    assert_eq!(found.normal(), expected.normal());
+                    hits += 1;
+                }
+                (None, None) => {}
+                (expected, found) => panic!("{expected:?} != {found:?} towards {target:?}"),
+            }
+        }
+        assert!(hits > 500, "{hits}");
+    }
+}
@@ -1,4 +1,7 @@
 use crate::aabb::aabb::{surrounding_box, AABB};
+use crate::bvh::bvh::BVH;
+use crate::color::Color;
+use crate::grid::UniformGrid;
 use crate::interval::Interval;
 use crate::material::Material;
 use crate::rays::Ray;
@@ -15,6 +18,10 @@ pub struct HitRecord {
     mat: Option<Arc<dyn Material>>,
     u: f64,
     v: f64,
+    dpdu: Option<Vec3>,
+    dpdv: Option<Vec3>,
+    id: Option<u32>,
+    tint: Option<Color>,
 }
 #[derive(Default)]
 pub struct HittableList {
@@ -28,6 +35,8 @@ impl Debug for HitRecord {
             .field("normal", &self.normal)
             .field("t", &self.t)
             .field("front_face", &self.front_face)
+            .field("id", &self.id)
+            .field("tint", &self.tint)
             .finish()
     }
 }
@@ -51,6 +60,16 @@ impl HitRecord {
     pub fn v(&self) -> f64 {
         self.v
     }
+    /// Partial derivative of the hit point with respect to `u`, if the primitive knows its
+    /// parameterization
+    pub fn dpdu(&self) -> Option<Vec3> {
+        self.dpdu
+    }
+    /// Partial derivative of the hit point with respect to `v`, if the primitive knows its
+    /// parameterization
+    pub fn dpdv(&self) -> Option<Vec3> {
+        self.dpdv
+    }
     pub fn set_u(&mut self, u: f64) {
         self.u = u
     }
@@ -58,6 +77,12 @@ impl HitRecord {
     pub fn set_v(&mut self, v: f64) {
         self.v = v
     }
+    pub fn set_dpdu(&mut self, dpdu: Vec3) {
+        self.dpdu = Some(dpdu);
+    }
+    pub fn set_dpdv(&mut self, dpdv: Vec3) {
+        self.dpdv = Some(dpdv);
+    }
     pub fn set_t(&mut self, t: f64) {
         self.t = t;
     }
@@ -84,6 +109,23 @@ impl HitRecord {
     pub fn front_face(&self) -> bool {
         self.front_face
     }
+    pub fn set_front_face(&mut self, front_face: bool) {
+        self.front_face = front_face;
+    }
+    /// Identifier of the object that was hit, if it was given one
+    pub fn id(&self) -> Option<u32> {
+        self.id
+    }
+    pub fn set_id(&mut self, id: u32) {
+        self.id = Some(id);
+    }
+    /// Color the primitive multiplies into the albedo of the material, white when it sets none
+    pub fn tint(&self) -> Color {
+        self.tint.unwrap_or(Color::new(1.0, 1.0, 1.0))
+    }
+    pub fn set_tint(&mut self, tint: Color) {
+        self.tint = Some(tint);
+    }
 }
 
 impl HittableList {
@@ -96,6 +138,66 @@ impl HittableList {
     pub fn add(&mut self, new: Box<dyn Hittable>) {
         self.objects.push(new);
     }
+    /// Moves all the objects of `other` into this list
+    pub fn extend(&mut self, other: HittableList) {
+        self.objects.extend(other.objects);
+    }
+    /// Builds a BVH over the objects of the list, consuming it
+    pub fn into_bvh(self, time_interval: &Interval) -> BVH {
+        BVH::new(self.objects, time_interval)
+    }
+    /// Builds a uniform grid over the objects of the list, consuming it
+    pub fn into_grid(self, time_interval: &Interval, resolution: usize) -> UniformGrid {
+        UniformGrid::new(self.objects, time_interval, resolution)
+    }
+}
+
+impl<H: Hittable + ?Sized> Hittable for Box<H> {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        self.as_ref().hit(ray, time_interval)
+    }
+    fn object_id(&self) -> Option<u32> {
+        self.as_ref().object_id()
+    }
+    fn hit_any(&self, ray: &Ray, time_interval: &Interval) -> bool {
+        self.as_ref().hit_any(ray, time_interval)
+    }
+    fn bounding_box(&self, time_interval: &Interval) -> Option<AABB> {
+        self.as_ref().bounding_box(time_interval)
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.as_ref().pdf_value(origin, direction)
+    }
+    fn random(&self, origin: &Point3) -> Vec3 {
+        self.as_ref().random(origin)
+    }
+    fn child(&self, index: usize) -> Option<&dyn Hittable> {
+        self.as_ref().child(index)
+    }
+}
+
+impl<H: Hittable + ?Sized> Hittable for Arc<H> {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        self.as_ref().hit(ray, time_interval)
+    }
+    fn object_id(&self) -> Option<u32> {
+        self.as_ref().object_id()
+    }
+    fn hit_any(&self, ray: &Ray, time_interval: &Interval) -> bool {
+        self.as_ref().hit_any(ray, time_interval)
+    }
+    fn bounding_box(&self, time_interval: &Interval) -> Option<AABB> {
+        self.as_ref().bounding_box(time_interval)
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.as_ref().pdf_value(origin, direction)
+    }
+    fn random(&self, origin: &Point3) -> Vec3 {
+        self.as_ref().random(origin)
+    }
+    fn child(&self, index: usize) -> Option<&dyn Hittable> {
+        self.as_ref().child(index)
+    }
 }
 
 impl Hittable for HittableList {
@@ -111,20 +213,92 @@ impl Hittable for HittableList {
         }
         temp_rec
     }
+    fn hit_any(&self, ray: &Ray, time_interval: &Interval) -> bool {
+        self.objects
+            .iter()
+            .any(|object| object.hit_any(ray, time_interval))
+    }
     fn bounding_box(&self, time_interval: &Interval) -> Option<AABB> {
         match &self.objects.first() {
             Some(first) => match first.bounding_box(time_interval) {
                 Some(bbox) => self.objects.iter().skip(1).try_fold(bbox, |acc, hittable| {
-                    hittable.bounding_box(time_interval).map(|bbox| surrounding_box(&acc, &bbox))
+                    hittable
+                        .bounding_box(time_interval)
+                        .map(|bbox| surrounding_box(&acc, &bbox))
                 }),
                 _ => None,
             },
             _ => None,
         }
     }
+    fn child(&self, index: usize) -> Option<&dyn Hittable> {
+        self.objects.get(index).map(|object| object.as_ref())
+    }
 }
 
 pub trait Hittable: Send + Sync {
     fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord>;
     fn bounding_box(&self, time_interval: &Interval) -> Option<AABB>;
+    /// Returns true if anything is hit inside the interval. Shadow rays only care about
+    /// occlusion, so implementors can stop at the first hit instead of searching the closest one
+    fn hit_any(&self, ray: &Ray, time_interval: &Interval) -> bool {
+        self.hit(ray, time_interval).is_some()
+    }
+    /// Probability density, with respect to solid angle, of `random` generating `direction`
+    /// from `origin`. Objects that can't be sampled return 0
+    fn pdf_value(&self, _origin: &Point3, _direction: &Vec3) -> f64 {
+        0.0
+    }
+    /// Returns a vector from `origin` to a random point of the object
+    fn random(&self, _origin: &Point3) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+    /// Identifier reported in the hit records of the object, used by the object ID pass
+    fn object_id(&self) -> Option<u32> {
+        None
+    }
+    /// Part `index` of a composite object, like an object of a list or a side of a cube, to look
+    /// into it. Primitives have no parts
+    fn child(&self, _index: usize) -> Option<&dyn Hittable> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::INFINITY, material::Lambertian, sphere::Sphere, textures::ConstantTexture,
+    };
+
+    #[test]
+    fn bvh_from_list_hits_like_the_list() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let sphere = |x: f64| {
+            Box::new(Sphere::new(
+                Point3::new(x, 0.0, -3.0),
+                None,
+                1.0,
+                material.clone(),
+            ))
+        };
+        let mut list = HittableList::new();
+        list.add(sphere(-1.5));
+        let mut other = HittableList::new();
+        other.add(sphere(1.5));
+        list.extend(other);
+        assert_eq!(list.objects.len(), 2);
+
+        let reference = HittableList {
+            objects: vec![sphere(-1.5), sphere(1.5)],
+        };
+        let bvh = list.into_bvh(&Interval::new(0.0, 1.0));
+        let interval = Interval::new(0.001, INFINITY);
+        for x in [-2.0, -1.5, -0.2, 0.0, 0.7, 1.5, 2.6, 4.0] {
+            let ray = Ray::new(Point3::new(x, 0.3, 0.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+            let expected = reference.hit(&ray, &interval).map(|rec| (rec.t(), rec.p()));
+            let actual = bvh.hit(&ray, &interval).map(|rec| (rec.t(), rec.p()));
+            assert_eq!(actual, expected);
+        }
+    }
 }
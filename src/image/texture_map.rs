@@ -1,10 +1,24 @@
 use std::io::{Error, ErrorKind};
+use std::path::Path;
 
 use image::{GenericImageView, ImageError, ImageReader};
 
 use crate::textures::ImageTexture;
 
+/// Loads an image file as a texture. Radiance HDR and OpenEXR files keep their float values, the
+/// other formats are converted to 8 bits RGB
 pub fn read_image(path: String) -> Result<ImageTexture, ImageError> {
+    let high_dynamic_range = Path::new(&path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("hdr") || extension.eq_ignore_ascii_case("exr")
+        });
+    if high_dynamic_range {
+        let img = image::open(path)?.into_rgb32f();
+        let (ux, uy) = img.dimensions();
+        return Ok(ImageTexture::from_rgb_f32(img.into_raw(), ux, uy));
+    }
     let image = image::open(path);
     match image {
         Ok(img) => {
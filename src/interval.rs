@@ -24,9 +24,23 @@ impl Default for Interval {
 }
 
 impl Interval {
+    /// Interval from `min` to `max`. Inverted bounds make an empty interval that contains nothing,
+    /// usually a bug, so they are flagged in debug builds. Use `EMPTY` for an empty interval and
+    /// `new_ordered` for bounds in any order
     pub fn new(min: f64, max: f64) -> Self {
+        debug_assert!(
+            min <= max || min.is_nan() || max.is_nan(),
+            "Inverted interval [{min}, {max}]"
+        );
         Interval { min, max }
     }
+    /// Interval between `a` and `b`, whichever is smaller
+    pub fn new_ordered(a: f64, b: f64) -> Self {
+        Interval {
+            min: a.min(b),
+            max: a.max(b),
+        }
+    }
     pub fn from_intervals(a: Interval, b: Interval) -> Self {
         let min = a.min().min(b.min());
         let max = a.max().max(b.max());
@@ -92,3 +106,24 @@ impl AddAssign<f64> for Interval {
         self.max = rhs + self.max;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordered_interval_sorts_the_bounds() {
+        let interval = Interval::new_ordered(5.0, 1.0);
+        assert_eq!(interval.min(), 1.0);
+        assert_eq!(interval.max(), 5.0);
+        assert!(interval.contains(3.0));
+        assert_eq!(interval, Interval::new_ordered(1.0, 5.0));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Inverted interval")]
+    fn inverted_interval_is_flagged() {
+        Interval::new(5.0, 1.0);
+    }
+}
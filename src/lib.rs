@@ -1,20 +1,33 @@
 pub mod aabb;
+pub mod background;
 pub mod bvh;
 pub mod camera;
+pub mod capsule;
 pub mod cmd;
 pub mod color;
 pub mod common;
 pub mod cube;
+pub mod displaced_sphere;
+pub mod flip_face;
+pub mod grid;
 pub mod hittable;
 pub mod image;
 pub mod interval;
+pub mod light;
 pub mod material;
 pub mod medium;
+pub mod mesh;
+pub mod named;
 pub mod perlin;
 pub mod quad;
 pub mod rays;
 pub mod rotation;
+pub mod sampler;
+pub mod scene;
+#[cfg(feature = "spectral")]
+pub mod spectrum;
 pub mod sphere;
 pub mod textures;
 pub mod translate;
+pub mod triangle;
 pub mod vec3;
@@ -0,0 +1,255 @@
+use std::sync::Arc;
+
+use crate::{
+    aabb::aabb::{surrounding_box, AABB},
+    common::random_int,
+    hittable::{HitRecord, Hittable},
+    interval::Interval,
+    material::DiffuseLight,
+    quad::Quad,
+    rays::Ray,
+    sphere::Sphere,
+    textures::Texture,
+    vec3::{Point3, Vec3},
+};
+
+/// Collection of the emitters of a scene that can be sampled directly. The lights are shared
+/// with the world through an `Arc`, so the same object is hit by camera rays and sampled by the
+/// camera when looking for the lights
+#[derive(Clone, Default)]
+pub struct LightList {
+    lights: Vec<Arc<dyn Hittable>>,
+}
+
+/// Quad that emits light only from its front face, the side of `u x v`. It is registered as a
+/// light when constructed, so the camera can sample it directly
+pub struct QuadLight {
+    quad: Quad,
+}
+
+/// Sphere that emits light from its whole surface. It is sampled uniformly over the cone it
+/// subtends, so bigger spheres light from a wider range of directions and cast softer shadows.
+/// It is registered as a light when constructed
+pub struct SphereLight {
+    sphere: Sphere,
+}
+
+impl LightList {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    pub fn add(&mut self, light: Arc<dyn Hittable>) {
+        self.lights.push(light);
+    }
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+}
+
+impl Hittable for LightList {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        let mut temp_rec: Option<HitRecord> = None;
+        let mut closest_so_far = time_interval.max();
+        for light in &self.lights {
+            if let Some(rec) = light.hit(ray, &Interval::new(time_interval.min(), closest_so_far)) {
+                closest_so_far = rec.t();
+                temp_rec = Some(rec);
+            }
+        }
+        temp_rec
+    }
+    fn bounding_box(&self, time_interval: &Interval) -> Option<AABB> {
+        let mut boxes = self
+            .lights
+            .iter()
+            .map(|light| light.bounding_box(time_interval));
+        let first = boxes.next()??;
+        boxes.try_fold(first, |acc, bbox| {
+            bbox.map(|bbox| surrounding_box(&acc, &bbox))
+        })
+    }
+    /// Every light is picked with the same probability, so the density is the average of the
+    /// densities of each light
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        let weight = 1.0 / self.lights.len() as f64;
+        self.lights
+            .iter()
+            .map(|light| weight * light.pdf_value(origin, direction))
+            .sum()
+    }
+    fn random(&self, origin: &Point3) -> Vec3 {
+        if self.lights.is_empty() {
+            return Vec3::new(1.0, 0.0, 0.0);
+        }
+        let index = random_int(0, self.lights.len() as i32 - 1) as usize;
+        self.lights[index].random(origin)
+    }
+}
+
+impl QuadLight {
+    pub fn new<T: Texture + 'static>(
+        q: Point3,
+        u: Vec3,
+        v: Vec3,
+        emit: T,
+        lights: &mut LightList,
+    ) -> Arc<QuadLight> {
+        let light = Arc::new(QuadLight {
+            quad: Quad::new(q, u, v, Arc::new(DiffuseLight::one_sided(emit))),
+        });
+        lights.add(light.clone());
+        light
+    }
+}
+
+impl SphereLight {
+    pub fn new<T: Texture + 'static>(
+        center: Point3,
+        radius: f64,
+        emit: T,
+        lights: &mut LightList,
+    ) -> Arc<SphereLight> {
+        let light = Arc::new(SphereLight {
+            sphere: Sphere::new(center, None, radius, Arc::new(DiffuseLight::new(emit))),
+        });
+        lights.add(light.clone());
+        light
+    }
+    pub fn radius(&self) -> f64 {
+        self.sphere.radius()
+    }
+}
+
+impl Hittable for SphereLight {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        self.sphere.hit(ray, time_interval)
+    }
+    fn bounding_box(&self, time_interval: &Interval) -> Option<AABB> {
+        self.sphere.bounding_box(time_interval)
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.sphere.pdf_value(origin, direction)
+    }
+    fn random(&self, origin: &Point3) -> Vec3 {
+        self.sphere.random(origin)
+    }
+}
+
+impl Hittable for QuadLight {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        self.quad.hit(ray, time_interval)
+    }
+    fn bounding_box(&self, time_interval: &Interval) -> Option<AABB> {
+        self.quad.bounding_box(time_interval)
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.quad.pdf_value(origin, direction)
+    }
+    fn random(&self, origin: &Point3) -> Vec3 {
+        self.quad.random(origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color::Color, common::seed_random, material::Lambertian, textures::ConstantTexture,
+    };
+
+    fn emission(light: &QuadLight, ray: &Ray) -> Color {
+        let rec = light
+            .hit(ray, &Interval::new(0.001, f64::INFINITY))
+            .unwrap();
+        rec.get_material().unwrap().emmited(ray, &rec)
+    }
+
+    #[test]
+    fn quad_light_is_one_sided_and_sampleable() {
+        let mut lights = LightList::new();
+        // u x v points down, so the light faces the floor
+        let light = QuadLight::new(
+            Point3::new(-1.0, 2.0, -1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            ConstantTexture::from_points(4.0, 4.0, 4.0),
+            &mut lights,
+        );
+        assert_eq!(lights.len(), 1);
+
+        let from_below = Ray::new(Point3::default(), Vec3::new(0.0, 1.0, 0.0), 0.0);
+        let from_above = Ray::new(Point3::new(0.0, 4.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        assert_eq!(emission(&light, &from_below), Color::new(4.0, 4.0, 4.0));
+        assert_eq!(emission(&light, &from_above), Color::default());
+
+        let origin = Point3::default();
+        assert!(light.pdf_value(&origin, &Vec3::new(0.1, 1.0, -0.2)) > 0.0);
+        assert!(lights.pdf_value(&origin, &lights.random(&origin)) > 0.0);
+        assert_eq!(light.pdf_value(&origin, &Vec3::new(0.0, -1.0, 0.0)), 0.0);
+    }
+
+    /// Number of points along the floor, every 0.05 from under the occluder, that see only part
+    /// of the light
+    fn penumbra_width(radius: f64) -> usize {
+        let mut lights = LightList::new();
+        let light = SphereLight::new(
+            Point3::new(0.0, 5.0, 0.0),
+            radius,
+            ConstantTexture::from_points(4.0, 4.0, 4.0),
+            &mut lights,
+        );
+        assert_eq!(light.radius(), radius);
+        let occluder = Sphere::new(
+            Point3::new(0.0, 1.0, 0.0),
+            None,
+            0.5,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        );
+        let samples = 400;
+        (0..60)
+            .map(|k| Point3::new(k as f64 * 0.05, 0.0, 0.0))
+            .filter(|&point| {
+                let visible = (0..samples)
+                    .filter(|_| {
+                        let ray = Ray::new(point, lights.random(&point), 0.0);
+                        !occluder.hit_any(&ray, &Interval::new(0.001, f64::INFINITY))
+                    })
+                    .count() as f64
+                    / samples as f64;
+                (0.05..0.95).contains(&visible)
+            })
+            .count()
+    }
+
+    #[test]
+    fn bigger_sphere_lights_widen_the_penumbra() {
+        seed_random(3);
+        let small = penumbra_width(0.1);
+        let big = penumbra_width(1.0);
+        assert!(big > 2 * small, "penumbra of {big} points against {small}");
+
+        // Every sampled direction reaches the light, which is sampled over its cone
+        let mut lights = LightList::new();
+        let light = SphereLight::new(
+            Point3::new(0.0, 5.0, 0.0),
+            1.0,
+            ConstantTexture::from_points(4.0, 4.0, 4.0),
+            &mut lights,
+        );
+        let origin = Point3::new(1.0, 0.0, 0.0);
+        for _ in 0..100 {
+            let direction = lights.random(&origin);
+            assert!(light.hit_any(
+                &Ray::new(origin, direction, 0.0),
+                &Interval::new(0.001, f64::INFINITY)
+            ));
+            assert!(lights.pdf_value(&origin, &direction) > 0.0);
+        }
+    }
+}
@@ -1,17 +1,18 @@
 #![warn(clippy::pedantic)]
 use raytracing::{
-    bvh::bvh::BVH,
     camera::Camera,
     cmd::cmd_args,
     color::Color,
-    common::{random_double, random_double_range},
+    common::{hash_double, random_double, random_double_range, split_mix},
     cube::Cube,
-    hittable::{Hittable, HittableList},
-    interval::Interval,
-    material::{self, Dielectric, DiffuseLight, Isotropic, Lambertian, Metal},
+    hittable::HittableList,
+    material::{
+        self, Dielectric, DiffuseLight, Isotropic, Lambertian, MaterialKind, Metal, PaletteMaterial,
+    },
     medium::ConstantMedium,
     quad::Quad,
     rotation::{AxisRotation, Rotation},
+    scene::Scene,
     sphere::Sphere,
     textures::{CheckerPattern, ConstantTexture, ImageTexture, NoiseTexture},
     translate::Translate,
@@ -19,18 +20,18 @@ use raytracing::{
 };
 use std::sync::Arc;
 
-fn random_scene() -> (Box<dyn Hittable>, Camera) {
+fn random_scene() -> Scene {
     let mut camera: Camera = Default::default();
-    camera.set_aspect_ratio(16. / 9.);
-    camera.set_width(600);
-    camera.set_sample_per_pixel(20);
-    camera.set_max_depth(10);
-    camera.set_vertical_fov(80.0);
+    camera.set_aspect_ratio(16. / 9.).unwrap();
+    camera.set_width(600).unwrap();
+    camera.set_sample_per_pixel(20).unwrap();
+    camera.set_max_depth(10).unwrap();
+    camera.set_vertical_fov(80.0).unwrap();
     camera.set_lookfrom(Point3::new(0.0, 0.0, 9.0));
     camera.set_lookat(Point3::new(0.0, 0.0, 0.0));
     camera.set_vup(Vec3::new(0.0, 1.0, 0.0));
     camera.set_defocus_angle(0.0);
-    camera.set_focus_distance(10.0);
+    camera.set_focus_distance(10.0).unwrap();
     camera.set_background_color(Color::new(0.7, 0.8, 1.0));
     let mut world: HittableList = Default::default();
     let checker = Lambertian::new(CheckerPattern::new(
@@ -45,33 +46,37 @@ fn random_scene() -> (Box<dyn Hittable>, Camera) {
         Arc::new(checker),
     )));
 
+    // 16 diffuse, 3 metals and a glass, so the materials keep the same odds whatever the seed
+    let mut palette: Vec<Arc<dyn material::Material>> = (0..16)
+        .map(|k| {
+            let albedo = Color::from_id(k) * Color::from_id(k + 16);
+            Arc::new(Lambertian::new(ConstantTexture::new(albedo))) as Arc<dyn material::Material>
+        })
+        .collect();
+    for k in 0..3 {
+        let albedo = Color::new(0.5, 0.5, 0.5) + 0.5 * Color::from_id(k + 32);
+        palette.push(Arc::new(Metal::new(albedo, 0.2 * f64::from(k))));
+    }
+    palette.push(Arc::new(Dielectric::new(1.5)));
+    let materials = PaletteMaterial::new(palette, 0);
+
     for a in -11..11 {
         for b in -11..11 {
-            let choose_mat = random_double();
+            // Everything about a sphere comes from its index, so the scene is the same every run
+            let index = u64::try_from((a + 11) * 22 + b + 11).unwrap();
+            let key = split_mix(index);
+            let jitter = |dimension: u64| hash_double(key.wrapping_add(dimension));
             let center = Point3::new(
-                f64::from(a) + 0.9 * random_double(),
+                f64::from(a) + 0.9 * jitter(0),
                 0.2,
-                f64::from(b) + 0.9 * random_double(),
+                f64::from(b) + 0.9 * jitter(1),
             );
             if (center - Point3::new(4.0, 0.2, 0.0)).magnitude() > 0.9 {
-                let material: Arc<dyn material::Material>;
-                if choose_mat < 0.8 {
-                    // diffuse
-                    let albedo = Color::random() * Color::random();
-                    material = Arc::new(Lambertian::new(ConstantTexture::new(albedo)));
-                    let center2 = center + Vec3::new(0.0, random_double_range(0.0, 0.5), 0.0);
-                    world.add(Box::new(Sphere::new(center, Some(center2), 0.2, material)));
-                } else if choose_mat < 0.95 {
-                    // metal
-                    let albedo = Color::random_range(0.5, 1.0);
-                    let fuzz = random_double_range(0.0, 0.5);
-                    material = Arc::new(Metal::new(albedo, fuzz));
-                    world.add(Box::new(Sphere::new(center, None, 0.2, material)));
-                } else {
-                    // glass
-                    material = Arc::new(Dielectric::new(1.5));
-                    world.add(Box::new(Sphere::new(center, None, 0.2, material)));
-                }
+                let material = materials.choose(index);
+                // The diffuse spheres bounce during the exposure
+                let center2 = (material.kind() == MaterialKind::Lambertian)
+                    .then(|| center + Vec3::new(0.0, 0.5 * jitter(2), 0.0));
+                world.add(Box::new(Sphere::new(center, center2, 0.2, material)));
             }
         }
     }
@@ -99,25 +104,22 @@ fn random_scene() -> (Box<dyn Hittable>, Camera) {
         1.0,
         material3,
     )));
-    (
-        Box::new(BVH::new(world.objects, &Interval::new(0.0, 1.0))),
-        camera,
-    )
+    Scene::from_objects(world, camera)
     // Box::new(world)
 }
 
-fn checkered_spheres() -> (Box<dyn Hittable>, Camera) {
+fn checkered_spheres() -> Scene {
     let mut camera: Camera = Default::default();
-    camera.set_aspect_ratio(16. / 9.);
-    camera.set_width(600);
-    camera.set_sample_per_pixel(20);
-    camera.set_max_depth(10);
-    camera.set_vertical_fov(80.0);
+    camera.set_aspect_ratio(16. / 9.).unwrap();
+    camera.set_width(600).unwrap();
+    camera.set_sample_per_pixel(20).unwrap();
+    camera.set_max_depth(10).unwrap();
+    camera.set_vertical_fov(80.0).unwrap();
     camera.set_lookfrom(Point3::new(0.0, 0.0, 9.0));
     camera.set_lookat(Point3::new(0.0, 0.0, 0.0));
     camera.set_vup(Vec3::new(0.0, 1.0, 0.0));
     camera.set_defocus_angle(0.0);
-    camera.set_focus_distance(10.0);
+    camera.set_focus_distance(10.0).unwrap();
     camera.set_background_color(Color::new(0.7, 0.8, 1.0));
 
     let mut world: HittableList = Default::default();
@@ -144,24 +146,21 @@ fn checkered_spheres() -> (Box<dyn Hittable>, Camera) {
         10.0,
         Arc::new(checker),
     )));
-    (
-        Box::new(BVH::new(world.objects, &Interval::new(0.0, 1.0))),
-        camera,
-    )
+    Scene::from_objects(world, camera)
 }
 
-fn perlin_spheres() -> (Box<dyn Hittable>, Camera) {
+fn perlin_spheres() -> Scene {
     let mut camera: Camera = Default::default();
-    camera.set_aspect_ratio(16. / 9.);
-    camera.set_width(600);
-    camera.set_sample_per_pixel(20);
-    camera.set_max_depth(10);
-    camera.set_vertical_fov(80.0);
+    camera.set_aspect_ratio(16. / 9.).unwrap();
+    camera.set_width(600).unwrap();
+    camera.set_sample_per_pixel(20).unwrap();
+    camera.set_max_depth(10).unwrap();
+    camera.set_vertical_fov(80.0).unwrap();
     camera.set_lookfrom(Point3::new(0.0, 0.0, 9.0));
     camera.set_lookat(Point3::new(0.0, 0.0, 0.0));
     camera.set_vup(Vec3::new(0.0, 1.0, 0.0));
     camera.set_defocus_angle(0.0);
-    camera.set_focus_distance(10.0);
+    camera.set_focus_distance(10.0).unwrap();
     camera.set_background_color(Color::new(0.7, 0.8, 1.0));
     let mut world: HittableList = Default::default();
     let perlin_texture = NoiseTexture::new(256, 4.0);
@@ -181,48 +180,42 @@ fn perlin_spheres() -> (Box<dyn Hittable>, Camera) {
     );
     world.add(Box::new(perlin_sphere));
     world.add(Box::new(ground));
-    (
-        Box::new(BVH::new(world.objects, &Interval::new(0.0, 1.0))),
-        camera,
-    )
+    Scene::from_objects(world, camera)
 }
 
-fn earth() -> (Box<dyn Hittable>, Camera) {
+fn earth() -> Scene {
     let mut camera: Camera = Default::default();
-    camera.set_aspect_ratio(16. / 9.);
-    camera.set_width(600);
-    camera.set_sample_per_pixel(20);
-    camera.set_max_depth(10);
-    camera.set_vertical_fov(80.0);
+    camera.set_aspect_ratio(16. / 9.).unwrap();
+    camera.set_width(600).unwrap();
+    camera.set_sample_per_pixel(20).unwrap();
+    camera.set_max_depth(10).unwrap();
+    camera.set_vertical_fov(80.0).unwrap();
     camera.set_lookfrom(Point3::new(0.0, 0.0, 9.0));
     camera.set_lookat(Point3::new(0.0, 0.0, 0.0));
     camera.set_vup(Vec3::new(0.0, 1.0, 0.0));
     camera.set_defocus_angle(0.0);
-    camera.set_focus_distance(10.0);
+    camera.set_focus_distance(10.0).unwrap();
     camera.set_background_color(Color::new(0.7, 0.8, 1.0));
     let mut world: HittableList = Default::default();
     let earth_texture = ImageTexture::from("earthmap.png".to_string());
     let earth_surface = Arc::new(Lambertian::new(earth_texture));
     let earth = Box::new(Sphere::new(Point3::default(), None, 2.0, earth_surface));
     world.add(earth);
-    (
-        Box::new(BVH::new(world.objects, &Interval::new(0.0, 1.0))),
-        camera,
-    )
+    Scene::from_objects(world, camera)
 }
 
-fn boxes() -> (Box<dyn Hittable>, Camera) {
+fn boxes() -> Scene {
     let mut camera: Camera = Default::default();
-    camera.set_aspect_ratio(16. / 9.);
-    camera.set_width(600);
-    camera.set_sample_per_pixel(20);
-    camera.set_max_depth(10);
-    camera.set_vertical_fov(80.0);
+    camera.set_aspect_ratio(16. / 9.).unwrap();
+    camera.set_width(600).unwrap();
+    camera.set_sample_per_pixel(20).unwrap();
+    camera.set_max_depth(10).unwrap();
+    camera.set_vertical_fov(80.0).unwrap();
     camera.set_lookfrom(Point3::new(0.0, 0.0, 9.0));
     camera.set_lookat(Point3::new(0.0, 0.0, 0.0));
     camera.set_vup(Vec3::new(0.0, 1.0, 0.0));
     camera.set_defocus_angle(0.0);
-    camera.set_focus_distance(10.0);
+    camera.set_focus_distance(10.0).unwrap();
     camera.set_background_color(Color::new(0.7, 0.8, 1.0));
     let mut world: HittableList = Default::default();
     let left_red = Arc::new(Lambertian::new(ConstantTexture::new(Color::new(
@@ -273,24 +266,21 @@ fn boxes() -> (Box<dyn Hittable>, Camera) {
         Vec3::new(0.0, 0.0, -4.0),
         lower_teal,
     )));
-    (
-        Box::new(BVH::new(world.objects, &Interval::new(0.0, 1.0))),
-        camera,
-    )
+    Scene::from_objects(world, camera)
 }
 
-fn simple_light() -> (Box<dyn Hittable>, Camera) {
+fn simple_light() -> Scene {
     let mut camera: Camera = Default::default();
-    camera.set_aspect_ratio(16. / 9.);
-    camera.set_width(600);
-    camera.set_sample_per_pixel(20);
-    camera.set_max_depth(10);
-    camera.set_vertical_fov(20.0);
+    camera.set_aspect_ratio(16. / 9.).unwrap();
+    camera.set_width(600).unwrap();
+    camera.set_sample_per_pixel(20).unwrap();
+    camera.set_max_depth(10).unwrap();
+    camera.set_vertical_fov(20.0).unwrap();
     camera.set_lookfrom(Point3::new(26.0, 3.0, 6.0));
     camera.set_lookat(Point3::new(0.0, 2.0, 0.0));
     camera.set_vup(Vec3::new(0.0, 1.0, 0.0));
     camera.set_defocus_angle(0.0);
-    camera.set_focus_distance(10.0);
+    camera.set_focus_distance(10.0).unwrap();
 
     let mut world: HittableList = Default::default();
     let perlin_texture = NoiseTexture::new(256, 4.0);
@@ -327,24 +317,21 @@ fn simple_light() -> (Box<dyn Hittable>, Camera) {
         2.0,
         Arc::new(diff_light),
     )));
-    (
-        Box::new(BVH::new(world.objects, &Interval::new(0.0, 1.0))),
-        camera,
-    )
+    Scene::from_objects(world, camera)
 }
 
-fn cornell_box() -> (Box<dyn Hittable>, Camera) {
+fn cornell_box() -> Scene {
     let mut camera: Camera = Default::default();
-    camera.set_width(400);
-    camera.set_sample_per_pixel(50);
-    camera.set_aspect_ratio(1.0);
-    camera.set_max_depth(50);
-    camera.set_vertical_fov(40.0);
+    camera.set_width(400).unwrap();
+    camera.set_sample_per_pixel(50).unwrap();
+    camera.set_aspect_ratio(1.0).unwrap();
+    camera.set_max_depth(50).unwrap();
+    camera.set_vertical_fov(40.0).unwrap();
     camera.set_lookfrom(Point3::new(278.0, 278.0, -800.0));
     camera.set_lookat(Point3::new(278.0, 278.0, 0.0));
     camera.set_vup(Vec3::new(0.0, 1.0, 0.0));
     camera.set_defocus_angle(0.0);
-    camera.set_focus_distance(10.0);
+    camera.set_focus_distance(10.0).unwrap();
     camera.set_background_color(Color::new(0.0, 0.0, 0.0));
     let mut world: HittableList = Default::default();
     let red = Lambertian::new(ConstantTexture::new(Color::new(0.65, 0.05, 0.05)));
@@ -420,24 +407,21 @@ fn cornell_box() -> (Box<dyn Hittable>, Camera) {
         Vec3::new(130.0, 0.0, 65.0),
     );
     world.add(Box::new(box2));
-    (
-        Box::new(BVH::new(world.objects, &Interval::new(0.0, 1.0))),
-        camera,
-    )
+    Scene::from_objects(world, camera)
 }
 
-fn cornell_box_smoke() -> (Box<dyn Hittable>, Camera) {
+fn cornell_box_smoke() -> Scene {
     let mut camera: Camera = Default::default();
-    camera.set_width(600);
-    camera.set_aspect_ratio(1.0);
-    camera.set_sample_per_pixel(200);
-    camera.set_max_depth(50);
-    camera.set_vertical_fov(40.0);
+    camera.set_width(600).unwrap();
+    camera.set_aspect_ratio(1.0).unwrap();
+    camera.set_sample_per_pixel(200).unwrap();
+    camera.set_max_depth(50).unwrap();
+    camera.set_vertical_fov(40.0).unwrap();
     camera.set_lookfrom(Point3::new(278.0, 278.0, -800.0));
     camera.set_lookat(Point3::new(278.0, 278.0, 0.0));
     camera.set_vup(Vec3::new(0.0, 1.0, 0.0));
     camera.set_defocus_angle(0.0);
-    camera.set_focus_distance(10.0);
+    camera.set_focus_distance(10.0).unwrap();
     camera.set_background_color(Color::new(0.0, 0.0, 0.0));
     let mut world: HittableList = Default::default();
     let red = Lambertian::new(ConstantTexture::new(Color::new(0.65, 0.05, 0.05)));
@@ -521,26 +505,19 @@ fn cornell_box_smoke() -> (Box<dyn Hittable>, Camera) {
         0.01,
         ConstantTexture::new(Color::default()),
     )));
-    (
-        Box::new(BVH::new(world.objects, &Interval::new(0.0, 1.0))),
-        camera,
-    )
+    Scene::from_objects(world, camera)
 }
 
-fn final_scene(
-    image_width: i32,
-    samples_per_pixel: i32,
-    max_depth: i32,
-) -> (Box<dyn Hittable>, Camera) {
+fn final_scene(image_width: i32, samples_per_pixel: i32, max_depth: i32) -> Scene {
     let mut world: HittableList = Default::default();
     let mut boxes1: HittableList = Default::default();
     let mut camera: Camera = Default::default();
-    camera.set_aspect_ratio(16.0 / 9.0);
-    camera.set_width(image_width);
-    camera.set_sample_per_pixel(samples_per_pixel);
-    camera.set_max_depth(max_depth);
+    camera.set_aspect_ratio(16.0 / 9.0).unwrap();
+    camera.set_width(image_width).unwrap();
+    camera.set_sample_per_pixel(samples_per_pixel).unwrap();
+    camera.set_max_depth(max_depth).unwrap();
     camera.set_background_color(Color::default());
-    camera.set_vertical_fov(40.0);
+    camera.set_vertical_fov(40.0).unwrap();
     camera.set_lookfrom(Vec3::new(478.0, 278.0, -600.0));
     camera.set_lookat(Vec3::new(278.0, 278.0, 278.0));
     camera.set_vup(Vec3::new(0.0, 1.0, 0.0));
@@ -564,7 +541,7 @@ fn final_scene(
             )));
         }
     }
-    world.add(Box::new(BVH::new(boxes1.objects, &Interval::new(0.0, 1.0))));
+    world.add(Box::new(boxes1.into_bvh(&camera.shutter())));
     let light: DiffuseLight<ConstantTexture> = DiffuseLight::new(Color::new(7.0, 7.0, 7.0).into());
     world.add(Box::new(Quad::new(
         Point3::new(123.0, 554.0, 147.0),
@@ -626,7 +603,7 @@ fn final_scene(
     }
     world.add(Box::new(Translate::new(
         Rotation::new(
-            BVH::new(boxes2.objects, &Interval::new(0.0, 1.0)),
+            boxes2.into_bvh(&camera.shutter()),
             AxisRotation::Yaxis,
             15.0,
         ),
@@ -641,15 +618,13 @@ fn final_scene(
         100.0,
         emat,
     )));
-    (
-        Box::new(BVH::new(world.objects, &Interval::new(0.0, 1.0))),
-        camera,
-    )
+    Scene::from_objects(world, camera)
 }
 
 fn main() {
     // World
-    let (world, mut camera) = final_scene(1080, 5000 / 2, 50);
+    let mut scene = final_scene(1080, 5000 / 2, 50);
     let filename = cmd_args().unwrap();
-    camera.render(&world, filename);
+    let stats = scene.render(filename);
+    println!("{stats}");
 }
@@ -1,38 +1,134 @@
 use crate::color::Color;
-use crate::common::random_double;
+use crate::common::{random_double, split_mix, PI};
 use crate::hittable::HitRecord;
 use crate::rays::Ray;
-use crate::textures::Texture;
+use crate::textures::{ConstantTexture, ImageTexture, Texture};
 use crate::vec3;
+use crate::vec3::Vec3;
 use crate::vec3::{random_unit_vector, reflect, refract};
-use crate::vec3::{Point3, Vec3};
+use std::sync::Arc;
 
 pub struct Lambertian<T: Texture> {
     albedo: T,
 }
 
 pub struct Metal {
-    albedo: Color,
+    albedo: Arc<dyn Texture>,
     fuzz: f64,
+    roughness_map: Option<Arc<dyn Texture>>,
 }
 
 pub struct Dielectric {
     refraction_index: f64,
+    priority: u32,
+    roughness: f64,
+    roughness_map: Option<Arc<dyn Texture>>,
+    split: fn() -> f64,
 }
 
+/// Tinted, possibly frosted glass: a rough dielectric whose inside absorbs light following the
+/// Beer–Lambert law. The color is the fraction of each channel that gets through one unit of
+/// distance inside the glass. The distance is the one from where the ray entered to where it hits
+/// the surface from inside, so nothing else should be inside the glass
+pub struct Glass {
+    dielectric: Dielectric,
+    absorption: Vec3,
+}
+
+/// Transparent media a ray is inside of, like the glass and the water of a glass of water. Each
+/// medium is entered through the front face of a surface whose material has `medium` and left
+/// through its back face. Where media overlap, the one with the highest priority fills the
+/// overlap, and among equal priorities the last one entered
+#[derive(Default)]
+pub struct MediumStack {
+    media: Vec<StackedMedium>,
+}
+
+struct StackedMedium {
+    /// Address of the material, identifying the medium
+    material: usize,
+    refraction_index: f64,
+    priority: u32,
+}
+
+/// How a ray crosses the surface of a nested medium
+#[derive(Debug, PartialEq)]
+pub enum Interface {
+    /// The surface is inside a medium of higher priority, so it doesn't exist optically
+    False,
+    /// The surface separates the medium the ray comes from and the one it goes into
+    Refract {
+        incident_index: f64,
+        transmitted_index: f64,
+    },
+}
+
+/// Broad family of a material, for debugging passes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaterialKind {
+    Lambertian,
+    Metal,
+    Dielectric,
+    Light,
+    Isotropic,
+    Other,
+}
+
+/// Fixed palette of materials picked by the index of an object instead of the random numbers, so
+/// a procedural scene built again with the same seed gets the same materials whatever was drawn
+/// before. The index is hashed with the seed, neighbouring objects get unrelated materials
+pub struct PaletteMaterial {
+    palette: Vec<Arc<dyn Material>>,
+    seed: u64,
+}
+
+/// Ray scattered by a material. The pdf is the density over solid angle with which the direction
+/// was picked, the same as `Material::scattering_pdf`, and 0.0 for specular scattering, whose
+/// attenuation is used as it is
 pub struct ScatterRecord {
     pub attenuation: Color,
     pub scattered: Ray,
+    pub pdf: f64,
+}
+
+impl ScatterRecord {
+    /// Whether the direction was picked by a specular bounce, that can't be weighted by a pdf
+    pub fn is_specular(&self) -> bool {
+        self.pdf <= 0.0
+    }
 }
 
 pub struct DiffuseLight<T: Texture> {
     texture: T,
+    one_sided: bool,
 }
 
 pub struct Isotropic<T: Texture> {
     texture: T,
 }
 
+/// Surface for compositing CG onto a backplate. It doesn't scatter nor emit light; instead the
+/// camera shades it with the background color, darkened by how occluded the lights are from the
+/// hit point, so only the shadows cast on it are visible
+#[derive(Default)]
+pub struct ShadowCatcher;
+
+/// Flat, unlit color for layout previews: it doesn't scatter and emits its color, so objects show
+/// as solid silhouettes whatever the lights. Being emissive, it also lights the surfaces that
+/// bounce rays onto it
+pub struct FlatColor {
+    color: Color,
+}
+
+/// Material capture shading for stylized previews: the color is read from an image of a lit
+/// sphere at the normal seen from the camera, `(x, y)` of the view-space normal mapped to `(u, v)`
+/// in [0, 1]. The view is fixed by the camera basis `(u, v, w)` given at construction, see
+/// `Camera::view_basis`. Like `FlatColor` it doesn't scatter and emits the color
+pub struct Matcap {
+    image: ImageTexture,
+    basis: (Vec3, Vec3, Vec3),
+}
+
 impl<T: Texture> Lambertian<T> {
     pub fn new(albedo: T) -> Self {
         Lambertian { albedo }
@@ -45,30 +141,57 @@ impl<T: Texture> Material for Lambertian<T> {
         if scatter_direction.near_zero() {
             scatter_direction = rec.normal();
         }
-        let scatter_record = ScatterRecord {
-            attenuation: self.albedo.value(rec.u(), rec.v(), &rec.p()),
-            scattered: Ray::new(rec.p(), scatter_direction, ray_in.time()),
-        };
-        Some(scatter_record)
+        let scattered = Ray::new(rec.p(), scatter_direction, ray_in.time());
+        Some(ScatterRecord {
+            attenuation: rec.tint() * self.albedo.value(rec.u(), rec.v(), &rec.p()),
+            pdf: self.scattering_pdf(ray_in, rec, &scattered),
+            scattered,
+        })
+    }
+    /// `scatter` picks directions around the normal with the cosine distribution
+    fn scattering_pdf(&self, _ray_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        let cosine = rec.normal().dot_product(&scattered.direction().normalize());
+        if cosine < 0.0 {
+            0.0
+        } else {
+            cosine / PI
+        }
+    }
+    fn kind(&self) -> MaterialKind {
+        MaterialKind::Lambertian
     }
 }
 
 impl Metal {
     pub fn new(color: Color, fuzz: f64) -> Self {
+        Metal::textured(Arc::new(ConstantTexture::new(color)), fuzz)
+    }
+    /// Creates a metal whose albedo is sampled from a texture at each hit, allowing patterned or
+    /// tinted mirrors
+    pub fn textured(albedo: Arc<dyn Texture>, fuzz: f64) -> Self {
         Metal {
-            albedo: color,
+            albedo,
             fuzz: if fuzz < 1.0 { fuzz } else { 1.0 },
+            roughness_map: None,
         }
     }
+    /// Makes the fuzz vary across the surface, read from the luminance of a grayscale texture at
+    /// each hit instead of the scalar fuzz
+    pub fn with_roughness_map(mut self, roughness_map: Arc<dyn Texture>) -> Self {
+        self.roughness_map = Some(roughness_map);
+        self
+    }
 }
 
 impl Material for Metal {
     fn scatter(&self, ray_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let fuzz = roughness_at(self.fuzz, &self.roughness_map, rec);
         let reflected: Vec3 = reflect(&ray_in.direction().normalize(), &rec.normal()).normalize()
-            + (self.fuzz * random_unit_vector());
+            + (fuzz * random_unit_vector());
         let scatter_record = ScatterRecord {
-            attenuation: self.albedo,
+            attenuation: rec.tint() * self.albedo.value(rec.u(), rec.v(), &rec.p()),
             scattered: Ray::new(rec.p(), reflected, ray_in.time()),
+            pdf: 0.0,
         };
         if scatter_record
             .scattered
@@ -81,45 +204,177 @@ impl Material for Metal {
             None
         }
     }
+    fn kind(&self) -> MaterialKind {
+        MaterialKind::Metal
+    }
 }
 
 impl Dielectric {
     pub fn new(index: f64) -> Self {
         Dielectric {
             refraction_index: index,
+            priority: 0,
+            roughness: 0.0,
+            roughness_map: None,
+            split: random_double,
         }
     }
+    /// Replaces the random numbers in [0, 1) that choose between reflection, when the number is
+    /// below the reflectance, and refraction. Defaults to `random_double`, a constant makes the
+    /// choice deterministic
+    pub fn with_split(mut self, split: fn() -> f64) -> Self {
+        self.split = split;
+        self
+    }
+    /// Blurs the reflections and refractions like frosted glass, perturbing the scattered
+    /// directions the way the fuzz of `Metal` does. Defaults to 0, a perfectly smooth surface
+    pub fn with_roughness(mut self, roughness: f64) -> Self {
+        self.roughness = roughness.min(1.0);
+        self
+    }
+    /// Makes the roughness vary across the surface, read from the luminance of a grayscale
+    /// texture at each hit instead of the scalar roughness
+    pub fn with_roughness_map(mut self, roughness_map: Arc<dyn Texture>) -> Self {
+        self.roughness_map = Some(roughness_map);
+        self
+    }
+    /// Sets the priority of the medium when it overlaps other nested dielectrics, the highest
+    /// one fills the overlap. Defaults to 0
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
 }
 
 impl Material for Dielectric {
+    /// Scatters as if the dielectric was surrounded by vacuum
     fn scatter(&self, ray_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
-        let refraction_ratio = if rec.front_face() {
-            1.0 / self.refraction_index
+        if rec.front_face() {
+            self.scatter_interface(ray_in, rec, 1.0, self.refraction_index)
         } else {
-            self.refraction_index
-        };
+            self.scatter_interface(ray_in, rec, self.refraction_index, 1.0)
+        }
+    }
+    fn medium(&self) -> Option<(f64, u32)> {
+        Some((self.refraction_index, self.priority))
+    }
+    fn scatter_interface(
+        &self,
+        ray_in: &Ray,
+        rec: &HitRecord,
+        incident_index: f64,
+        transmitted_index: f64,
+    ) -> Option<ScatterRecord> {
+        let refraction_ratio = incident_index / transmitted_index;
         let unit_direction = ray_in.direction().normalize();
         let cos_theta = f64::min(-unit_direction.dot_product(&rec.normal()), 1.0);
         let sin_theta = f64::sqrt(1.0 - cos_theta * cos_theta);
         let cannot_refract: bool = refraction_ratio * sin_theta > 1.0;
-        let direction =
-            if cannot_refract || vec3::reflectance(cos_theta, refraction_ratio) > random_double() {
+        let mut direction =
+            if cannot_refract || vec3::reflectance(cos_theta, refraction_ratio) > (self.split)() {
                 reflect(&unit_direction, &rec.normal())
             } else {
                 refract(&unit_direction, &rec.normal(), refraction_ratio)
             };
+        let roughness = roughness_at(self.roughness, &self.roughness_map, rec);
+        if roughness > 0.0 {
+            let rough = direction.normalize() + roughness * random_unit_vector();
+            // Keep the perturbed direction on the side of the surface it was scattered to
+            let side = direction.dot_product(&rec.normal());
+            if rough.dot_product(&rec.normal()) * side > 0.0 {
+                direction = rough;
+            }
+        }
         let scatter_record = ScatterRecord {
             attenuation: Color::new(1.0, 1.0, 1.0),
             scattered: Ray::new(rec.p(), direction, ray_in.time()),
+            pdf: 0.0,
         };
 
         Some(scatter_record)
     }
+    fn kind(&self) -> MaterialKind {
+        MaterialKind::Dielectric
+    }
+}
+
+impl Glass {
+    pub fn new(refraction_index: f64, roughness: f64, color: Color) -> Self {
+        let coefficient = |transmitted: f64| -transmitted.clamp(1e-12, 1.0).ln();
+        Glass {
+            dielectric: Dielectric::new(refraction_index).with_roughness(roughness),
+            absorption: Vec3::new(
+                coefficient(color.x()),
+                coefficient(color.y()),
+                coefficient(color.z()),
+            ),
+        }
+    }
+    /// Sets the priority of the medium when it overlaps other nested dielectrics, as for
+    /// `Dielectric::with_priority`
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.dielectric = self.dielectric.with_priority(priority);
+        self
+    }
+    /// Fraction of the light left after going `distance` through the glass
+    fn transmittance(&self, distance: f64) -> Color {
+        Color::new(
+            (-self.absorption.x() * distance).exp(),
+            (-self.absorption.y() * distance).exp(),
+            (-self.absorption.z() * distance).exp(),
+        )
+    }
+}
+
+impl Material for Glass {
+    /// Scatters as if the glass was surrounded by vacuum
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let (incident_index, transmitted_index) = if rec.front_face() {
+            (1.0, self.dielectric.refraction_index)
+        } else {
+            (self.dielectric.refraction_index, 1.0)
+        };
+        self.scatter_interface(ray_in, rec, incident_index, transmitted_index)
+    }
+    fn medium(&self) -> Option<(f64, u32)> {
+        self.dielectric.medium()
+    }
+    fn scatter_interface(
+        &self,
+        ray_in: &Ray,
+        rec: &HitRecord,
+        incident_index: f64,
+        transmitted_index: f64,
+    ) -> Option<ScatterRecord> {
+        let mut scatter_record =
+            self.dielectric
+                .scatter_interface(ray_in, rec, incident_index, transmitted_index)?;
+        // Hitting the back face, the ray went through the glass since it entered it
+        if !rec.front_face() {
+            let distance = rec.t() * ray_in.direction().magnitude();
+            scatter_record.attenuation = scatter_record.attenuation * self.transmittance(distance);
+        }
+        Some(scatter_record)
+    }
+    fn kind(&self) -> MaterialKind {
+        MaterialKind::Dielectric
+    }
 }
 
 impl<T: Texture> DiffuseLight<T> {
     pub fn new(texture: T) -> Self {
-        DiffuseLight { texture }
+        DiffuseLight {
+            texture,
+            one_sided: false,
+        }
+    }
+    /// Creates a light that only emits from the front face of the surface, the side its normal
+    /// points to. The back face is black
+    pub fn one_sided(texture: T) -> Self {
+        DiffuseLight {
+            texture,
+            one_sided: true,
+        }
     }
 }
 
@@ -127,8 +382,14 @@ impl<T: Texture> Material for DiffuseLight<T> {
     fn scatter(&self, _ray_in: &Ray, _rec: &HitRecord) -> Option<ScatterRecord> {
         None
     }
-    fn emmited(&self, p: &Point3, u: f64, v: f64) -> Color {
-        self.texture.value(u, v, p)
+    fn emmited(&self, _ray_in: &Ray, rec: &HitRecord) -> Color {
+        if self.one_sided && !rec.front_face() {
+            return Color::default();
+        }
+        self.texture.value(rec.u(), rec.v(), &rec.p())
+    }
+    fn kind(&self) -> MaterialKind {
+        MaterialKind::Light
     }
 }
 
@@ -139,20 +400,553 @@ impl<T: Texture> Isotropic<T> {
 }
 
 impl<T: Texture> Material for Isotropic<T> {
+    /// Scatters in a uniformly random direction, always a unit vector since the hit point inside a
+    /// medium has no meaningful normal to fall back on
     fn scatter(&self, ray_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
         Some(ScatterRecord {
             attenuation: self.texture.value(rec.u(), rec.v(), &rec.p()),
             scattered: Ray::new(rec.p(), random_unit_vector(), ray_in.time()),
+            pdf: 1.0 / (4.0 * PI),
         })
     }
-    fn emmited(&self, _p: &Point3, _u: f64, _v: f64) -> Color {
+    fn emmited(&self, _ray_in: &Ray, _rec: &HitRecord) -> Color {
         Color::default()
     }
+    /// `scatter` picks directions uniformly over the sphere. Not being specular, the camera
+    /// samples the lights from inside media, which is what draws light shafts in fog
+    fn scattering_pdf(&self, _ray_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+    fn kind(&self) -> MaterialKind {
+        MaterialKind::Isotropic
+    }
+}
+
+impl ShadowCatcher {
+    pub fn new() -> Self {
+        ShadowCatcher
+    }
+}
+
+impl Material for ShadowCatcher {
+    fn scatter(&self, _ray_in: &Ray, _rec: &HitRecord) -> Option<ScatterRecord> {
+        None
+    }
+    fn is_shadow_catcher(&self) -> bool {
+        true
+    }
+}
+
+impl FlatColor {
+    pub fn new(color: Color) -> Self {
+        FlatColor { color }
+    }
+}
+
+impl Material for FlatColor {
+    fn scatter(&self, _ray_in: &Ray, _rec: &HitRecord) -> Option<ScatterRecord> {
+        None
+    }
+    fn emmited(&self, _ray_in: &Ray, _rec: &HitRecord) -> Color {
+        self.color
+    }
+}
+
+impl Matcap {
+    pub fn new(image: ImageTexture, basis: (Vec3, Vec3, Vec3)) -> Self {
+        Matcap { image, basis }
+    }
+}
+
+impl Material for Matcap {
+    fn scatter(&self, _ray_in: &Ray, _rec: &HitRecord) -> Option<ScatterRecord> {
+        None
+    }
+    fn emmited(&self, _ray_in: &Ray, rec: &HitRecord) -> Color {
+        let (u, v, _) = self.basis;
+        let normal = rec.normal();
+        self.image.value(
+            0.5 * normal.dot_product(&u) + 0.5,
+            0.5 * normal.dot_product(&v) + 0.5,
+            &rec.p(),
+        )
+    }
+}
+
+impl MediumStack {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Classifies the crossing of the surface of `material`, entering its medium through the
+    /// front face or leaving it through the back one. Outside of every medium the index is 1
+    pub fn interface(&self, material: &Arc<dyn Material>, front_face: bool) -> Interface {
+        let Some((refraction_index, priority)) = material.medium() else {
+            return Interface::False;
+        };
+        let address = medium_address(material);
+        let outside = self.dominant(|medium| medium.material != address);
+        if outside.is_some_and(|medium| medium.priority > priority) {
+            return Interface::False;
+        }
+        let outside_index = outside.map_or(1.0, |medium| medium.refraction_index);
+        if front_face {
+            Interface::Refract {
+                incident_index: outside_index,
+                transmitted_index: refraction_index,
+            }
+        } else {
+            Interface::Refract {
+                incident_index: refraction_index,
+                transmitted_index: outside_index,
+            }
+        }
+    }
+    /// Records that the ray went through the surface of `material`, entering or leaving its medium
+    pub fn cross(&mut self, material: &Arc<dyn Material>, front_face: bool) {
+        let Some((refraction_index, priority)) = material.medium() else {
+            return;
+        };
+        let address = medium_address(material);
+        if front_face {
+            self.media.push(StackedMedium {
+                material: address,
+                refraction_index,
+                priority,
+            });
+        } else if let Some(index) = self
+            .media
+            .iter()
+            .rposition(|medium| medium.material == address)
+        {
+            self.media.remove(index);
+        }
+    }
+    /// Refraction index of the medium the ray is currently in
+    pub fn current_index(&self) -> f64 {
+        self.dominant(|_| true)
+            .map_or(1.0, |medium| medium.refraction_index)
+    }
+    /// Medium with the highest priority among the ones accepted by `filter`, the last entered on
+    /// ties
+    fn dominant(&self, filter: impl Fn(&StackedMedium) -> bool) -> Option<&StackedMedium> {
+        self.media.iter().filter(|medium| filter(medium)).fold(
+            None,
+            |best: Option<&StackedMedium>, medium| match best {
+                Some(best) if best.priority > medium.priority => Some(best),
+                _ => Some(medium),
+            },
+        )
+    }
+}
+
+impl MaterialKind {
+    /// Color of the kind in the material ID pass
+    pub fn color(self) -> Color {
+        match self {
+            MaterialKind::Lambertian => Color::new(0.8, 0.8, 0.8),
+            MaterialKind::Metal => Color::new(0.2, 0.4, 1.0),
+            MaterialKind::Dielectric => Color::new(0.2, 1.0, 0.8),
+            MaterialKind::Light => Color::new(1.0, 0.9, 0.1),
+            MaterialKind::Isotropic => Color::new(0.7, 0.2, 1.0),
+            MaterialKind::Other => Color::new(1.0, 0.2, 0.2),
+        }
+    }
+}
+
+impl PaletteMaterial {
+    /// Panics if the palette is empty
+    pub fn new(palette: Vec<Arc<dyn Material>>, seed: u64) -> Self {
+        assert!(!palette.is_empty(), "The palette has no materials");
+        PaletteMaterial { palette, seed }
+    }
+    /// Material of the object `index`. A material repeated in the palette is picked more often
+    pub fn choose(&self, index: u64) -> Arc<dyn Material> {
+        let hash = split_mix(self.seed ^ split_mix(index));
+        self.palette[(hash % self.palette.len() as u64) as usize].clone()
+    }
+}
+
+fn medium_address(material: &Arc<dyn Material>) -> usize {
+    Arc::as_ptr(material) as *const () as usize
 }
 
 pub trait Material: Send + Sync {
     fn scatter(&self, ray_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord>;
-    fn emmited(&self, p: &Point3, u: f64, v: f64) -> Color {
+    fn emmited(&self, ray_in: &Ray, rec: &HitRecord) -> Color {
         Color::default()
     }
+    /// Density over solid angle with which `scatter` picks the direction of `scattered`. The
+    /// camera relies on `attenuation * scattering_pdf` being the BSDF times the cosine term to
+    /// evaluate directions it sampled itself, like the ones towards the lights. Specular
+    /// materials, that can't be evaluated for an arbitrary direction, return 0.0
+    fn scattering_pdf(&self, _ray_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
+        0.0
+    }
+    /// Refraction index and priority of the transparent medium enclosed by the surface, for
+    /// materials that can be nested inside each other. The camera then keeps track of the media
+    /// the ray is in with a `MediumStack` and scatters with `scatter_interface`
+    fn medium(&self) -> Option<(f64, u32)> {
+        None
+    }
+    /// Scatters the ray at the boundary between two media, given the refraction index of the side
+    /// the ray comes from and of the side it goes into. Defaults to `scatter`
+    fn scatter_interface(
+        &self,
+        ray_in: &Ray,
+        rec: &HitRecord,
+        _incident_index: f64,
+        _transmitted_index: f64,
+    ) -> Option<ScatterRecord> {
+        self.scatter(ray_in, rec)
+    }
+    /// Whether the camera should shade this surface as a shadow catcher instead of following
+    /// the scattered rays
+    fn is_shadow_catcher(&self) -> bool {
+        false
+    }
+    /// Family of the material, shown by `Camera::render_material_ids`
+    fn kind(&self) -> MaterialKind {
+        MaterialKind::Other
+    }
+}
+
+/// Roughness at the hit: the luminance of the roughness map if there is one, the scalar otherwise
+fn roughness_at(scalar: f64, roughness_map: &Option<Arc<dyn Texture>>, rec: &HitRecord) -> f64 {
+    match roughness_map {
+        Some(map) => map
+            .value(rec.u(), rec.v(), &rec.p())
+            .luminance()
+            .clamp(0.0, 1.0),
+        None => scalar,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::scalar_tolerance;
+    use crate::{
+        common::INFINITY, hittable::Hittable, interval::Interval, quad::Quad,
+        textures::CheckerPattern, vec3::Point3,
+    };
+
+    /// Average angle between the directions scattered by the material where the ray hits a quad
+    /// on the z = 0 plane and the closest of the directions scattered by a perfectly smooth surface
+    fn spread(material: Arc<dyn Material>, ray: &Ray, smooth: &[Vec3]) -> f64 {
+        let quad = Quad::new(
+            Point3::new(-1.0, -1.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            material.clone(),
+        );
+        let rec = quad.hit(ray, &Interval::new(0.001, INFINITY)).unwrap();
+        let samples = 1000;
+        let total: f64 = (0..samples)
+            .filter_map(|_| material.scatter(ray, &rec))
+            .map(|scatter_rec| {
+                let direction = scatter_rec.scattered.direction().normalize();
+                smooth
+                    .iter()
+                    .map(|s| direction.dot_product(s).clamp(-1.0, 1.0).acos())
+                    .fold(PI, f64::min)
+            })
+            .sum();
+        total / samples as f64
+    }
+
+    /// Scatters `samples` random rays off random surface points, panicking if the attenuation of
+    /// a channel is ever above 1, which would create energy. Emission isn't checked, lights are
+    /// expected to add energy
+    fn assert_energy_conserving(material: &dyn Material, samples: usize) {
+        for _ in 0..samples {
+            let normal = random_unit_vector();
+            let mut direction = random_unit_vector();
+            if direction.dot_product(&normal) > 0.0 {
+                direction = -direction;
+            }
+            let point = Point3::random_range(-10.0, 10.0);
+            let ray_in = Ray::new(point - direction, direction, 0.0);
+            let mut rec = HitRecord::new();
+            rec.set_t(1.0);
+            rec.set_colision_point(point);
+            rec.set_face_normal(&ray_in, normal);
+            rec.set_u(random_double());
+            rec.set_v(random_double());
+            if let Some(scatter_rec) = material.scatter(&ray_in, &rec) {
+                let attenuation = scatter_rec.attenuation;
+                assert!(
+                    attenuation.as_array().iter().all(|&channel| channel <= 1.0),
+                    "Attenuation {attenuation:?} creates energy at {rec:?}"
+                );
+            }
+        }
+    }
+
+    /// Smooth where x < 0 and fully rough where x > 0, for y in [0, 1)
+    fn two_tone_roughness() -> Arc<dyn Texture> {
+        Arc::new(CheckerPattern::new(
+            1.0,
+            ConstantTexture::from_points(1.0, 1.0, 1.0),
+            ConstantTexture::from_points(0.0, 0.0, 0.0),
+        ))
+    }
+
+    #[test]
+    fn nested_dielectric_uses_relative_index() {
+        let outer: Arc<dyn Material> = Arc::new(Dielectric::new(1.2));
+        let inner: Arc<dyn Material> = Arc::new(Dielectric::new(1.8));
+        let mut stack = MediumStack::new();
+        assert_eq!(
+            stack.interface(&outer, true),
+            Interface::Refract {
+                incident_index: 1.0,
+                transmitted_index: 1.2
+            }
+        );
+        stack.cross(&outer, true);
+        let interface = stack.interface(&inner, true);
+        assert_eq!(
+            interface,
+            Interface::Refract {
+                incident_index: 1.2,
+                transmitted_index: 1.8
+            }
+        );
+
+        // Refraction into the inner sphere follows Snell's law with the ratio of both indices
+        let Interface::Refract {
+            incident_index,
+            transmitted_index,
+        } = interface
+        else {
+            unreachable!()
+        };
+        let incoming = Vec3::new(1.0, -1.0, 0.0).normalize();
+        let ray_in = Ray::new(Point3::new(-1.0, 1.0, 0.0), incoming, 0.0);
+        let mut rec = HitRecord::new();
+        rec.set_face_normal(&ray_in, Vec3::new(0.0, 1.0, 0.0));
+        let refracted = loop {
+            let scattered = inner
+                .scatter_interface(&ray_in, &rec, incident_index, transmitted_index)
+                .unwrap()
+                .scattered
+                .direction()
+                .normalize();
+            if scattered.y() < 0.0 {
+                break scattered;
+            }
+        };
+        let sin_in = incoming.x();
+        let sin_out = refracted.x();
+        assert!((sin_out - sin_in * 1.2 / 1.8).abs() < scalar_tolerance(1e-9));
+
+        stack.cross(&inner, true);
+        assert_eq!(stack.current_index(), 1.8);
+        stack.cross(&inner, false);
+        assert_eq!(stack.current_index(), 1.2);
+    }
+
+    #[test]
+    fn forced_split_refracts_and_reflects() {
+        let incoming = Vec3::new(1.0, -1.0, 0.0).normalize();
+        let ray_in = Ray::new(Point3::new(-1.0, 1.0, 0.0), incoming, 0.0);
+        let mut rec = HitRecord::new();
+        rec.set_face_normal(&ray_in, Vec3::new(0.0, 1.0, 0.0));
+        let scattered = |material: Dielectric| {
+            material
+                .scatter(&ray_in, &rec)
+                .unwrap()
+                .scattered
+                .direction()
+        };
+
+        let refracting = Dielectric::new(1.5).with_split(|| 1.0);
+        let expected = refract(&incoming, &rec.normal(), 1.0 / 1.5);
+        assert!((scattered(refracting) - expected).magnitude() < 1e-12);
+        let reflecting = Dielectric::new(1.5).with_split(|| 0.0);
+        let expected = reflect(&incoming, &rec.normal());
+        assert!((scattered(reflecting) - expected).magnitude() < 1e-12);
+
+        // Past the critical angle from inside nothing refracts, whatever the split
+        let grazing = Vec3::new(1.0, -0.2, 0.0).normalize();
+        let ray_in = Ray::new(Point3::new(-1.0, 0.2, 0.0), grazing, 0.0);
+        let mut rec = HitRecord::new();
+        rec.set_face_normal(&ray_in, Vec3::new(0.0, -1.0, 0.0));
+        let reflected = Dielectric::new(1.5)
+            .with_split(|| 1.0)
+            .scatter(&ray_in, &rec)
+            .unwrap();
+        let expected = reflect(&grazing, &rec.normal());
+        assert!((reflected.scattered.direction() - expected).magnitude() < 1e-12);
+    }
+
+    #[test]
+    fn lower_priority_surface_is_ignored() {
+        let water: Arc<dyn Material> = Arc::new(Dielectric::new(1.33));
+        let glass: Arc<dyn Material> = Arc::new(Dielectric::new(1.5).with_priority(1));
+        let mut stack = MediumStack::new();
+        stack.cross(&glass, true);
+        assert_eq!(stack.interface(&water, true), Interface::False);
+        stack.cross(&water, true);
+        assert_eq!(stack.current_index(), 1.5);
+        assert_eq!(
+            stack.interface(&glass, false),
+            Interface::Refract {
+                incident_index: 1.5,
+                transmitted_index: 1.33
+            }
+        );
+    }
+
+    #[test]
+    fn textured_metal_samples_albedo() {
+        let checker = CheckerPattern::new(
+            1.0,
+            ConstantTexture::from_points(1.0, 0.0, 0.0),
+            ConstantTexture::from_points(0.0, 0.0, 1.0),
+        );
+        let metal = Metal::textured(Arc::new(checker), 0.0);
+        let ray_in = Ray::new(Point3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let mut rec = HitRecord::new();
+        rec.set_face_normal(&ray_in, Vec3::new(0.0, 0.0, 1.0));
+
+        rec.set_colision_point(Point3::new(0.5, 0.5, 0.0));
+        rec.set_u(0.25);
+        rec.set_v(0.25);
+        let first = metal.scatter(&ray_in, &rec).unwrap().attenuation;
+
+        rec.set_colision_point(Point3::new(1.5, 0.5, 0.0));
+        rec.set_u(0.75);
+        rec.set_v(0.25);
+        let second = metal.scatter(&ray_in, &rec).unwrap().attenuation;
+
+        assert_eq!(first, Color::new(1.0, 0.0, 0.0));
+        assert_eq!(second, Color::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn roughness_map_changes_scatter_spread() {
+        let metal: Arc<dyn Material> = Arc::new(
+            Metal::new(Color::new(0.9, 0.9, 0.9), 0.0).with_roughness_map(two_tone_roughness()),
+        );
+        let glass: Arc<dyn Material> =
+            Arc::new(Dielectric::new(1.5).with_roughness_map(two_tone_roughness()));
+        let down = Vec3::new(0.0, 0.0, -1.0);
+        let smooth_ray = Ray::new(Point3::new(-0.5, 0.5, 1.0), down, 0.0);
+        let rough_ray = Ray::new(Point3::new(0.5, 0.5, 1.0), down, 0.0);
+
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        assert!(spread(metal.clone(), &smooth_ray, &[up]) < 1e-6);
+        assert!(spread(metal, &rough_ray, &[up]) > 0.3);
+        // At normal incidence the glass lets the ray through or reflects it back
+        assert!(spread(glass.clone(), &smooth_ray, &[down, up]) < 1e-6);
+        assert!(spread(glass, &rough_ray, &[down, up]) > 0.3);
+    }
+
+    #[test]
+    fn matcap_reads_the_view_space_normal() {
+        // Red on the left half of the capture, blue on the right
+        let capture = ImageTexture::from_rgb(&[255, 0, 0, 0, 0, 255], 2, 1);
+        let mut camera = crate::camera::Camera::default();
+        camera.set_lookfrom(Point3::new(0.0, 0.0, 0.0));
+        camera.set_lookat(Point3::new(1.0, 0.0, 0.0));
+        let matcap = Matcap::new(capture, camera.view_basis());
+
+        // Looking down +x, the right of the view is +z
+        let ray_in = Ray::new(Point3::default(), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        let mut rec = HitRecord::new();
+        rec.set_normal(Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(matcap.emmited(&ray_in, &rec), Color::new(0.0, 0.0, 1.0));
+        rec.set_normal(Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(matcap.emmited(&ray_in, &rec), Color::new(1.0, 0.0, 0.0));
+        assert!(matcap.scatter(&ray_in, &rec).is_none());
+    }
+
+    #[test]
+    fn materials_conserve_energy() {
+        let checker = || {
+            CheckerPattern::new(
+                0.5,
+                ConstantTexture::from_points(1.0, 1.0, 1.0),
+                ConstantTexture::from_points(0.1, 0.6, 0.3),
+            )
+        };
+        assert_energy_conserving(&Lambertian::new(checker()), 10_000);
+        assert_energy_conserving(&Metal::new(Color::new(0.9, 0.8, 1.0), 0.3), 10_000);
+        assert_energy_conserving(&Metal::textured(Arc::new(checker()), 1.0), 10_000);
+        assert_energy_conserving(&Isotropic::new(checker()), 10_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "creates energy")]
+    fn over_bright_material_fails_the_energy_check() {
+        let too_bright = Lambertian::new(ConstantTexture::from_points(2.0, 0.5, 0.5));
+        assert_energy_conserving(&too_bright, 100);
+    }
+
+    #[test]
+    fn palette_assignment_depends_only_on_seed_and_index() {
+        let palette = || -> Vec<Arc<dyn Material>> {
+            vec![
+                Arc::new(Lambertian::new(ConstantTexture::from_points(0.8, 0.2, 0.2))),
+                Arc::new(Lambertian::new(ConstantTexture::from_points(0.2, 0.8, 0.2))),
+                Arc::new(Metal::new(Color::new(0.9, 0.9, 0.9), 0.1)),
+                Arc::new(Dielectric::new(1.5)),
+            ]
+        };
+        let first = PaletteMaterial::new(palette(), 7);
+        let second = PaletteMaterial::new(palette(), 7);
+        // Drawing random numbers in between doesn't change the assignment
+        random_double();
+        let slot = |chooser: &PaletteMaterial, index| {
+            let chosen = chooser.choose(index);
+            chooser
+                .palette
+                .iter()
+                .position(|material| Arc::ptr_eq(material, &chosen))
+                .unwrap()
+        };
+        let slots: Vec<_> = (0..200).map(|index| slot(&first, index)).collect();
+        assert_eq!(
+            slots,
+            (0..200)
+                .map(|index| slot(&second, index))
+                .collect::<Vec<_>>()
+        );
+        // Every material is used, and another seed shuffles them
+        assert!((0..4).all(|k| slots.contains(&k)));
+        let other = PaletteMaterial::new(palette(), 8);
+        assert!((0..200).any(|index| slot(&other, index) != slots[index as usize]));
+    }
+
+    #[test]
+    fn lambertian_pdf_integrates_to_one() {
+        crate::common::seed_random(9);
+        let material = Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5));
+        let ray_in = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(0.3, -1.0, 0.0), 0.0);
+        let mut rec = HitRecord::new();
+        rec.set_face_normal(&ray_in, Vec3::new(0.0, 1.0, 0.0));
+
+        // Uniform directions over the sphere have a density of 1 / 4π
+        let samples = 200_000;
+        let integral: f64 = (0..samples)
+            .map(|_| Ray::new(rec.p(), random_unit_vector(), 0.0))
+            .map(|direction| material.scattering_pdf(&ray_in, &rec, &direction) * 4.0 * PI)
+            .sum::<f64>()
+            / samples as f64;
+        assert!((integral - 1.0).abs() < 0.01, "{integral}");
+
+        let scatter_rec = material.scatter(&ray_in, &rec).unwrap();
+        assert!(!scatter_rec.is_specular());
+        let expected = material.scattering_pdf(&ray_in, &rec, &scatter_rec.scattered);
+        assert_eq!(scatter_rec.pdf, expected);
+        assert!(Metal::new(Color::new(0.8, 0.8, 0.8), 0.0)
+            .scatter(&ray_in, &rec)
+            .unwrap()
+            .is_specular());
+        assert!(Dielectric::new(1.5)
+            .scatter(&ray_in, &rec)
+            .unwrap()
+            .is_specular());
+    }
 }
@@ -11,6 +11,9 @@ use crate::{
 use std::f64::consts::E;
 use std::sync::Arc;
 
+/// Volume of constant density inside a boundary, like fog or smoke. Rays are scattered at a
+/// random distance inside it, so a shadow ray crossing it gets through with the probability
+/// given by the transmittance along the ray. The rays scattered inside it have unit direction
 pub struct ConstantMedium<H: Hittable, T: Texture> {
     boundary: H,
     neg_inv_density: f64,
@@ -46,7 +49,9 @@ impl<H: Hittable, T: Texture + 'static> Hittable for ConstantMedium<H, T> {
                 if rec1.t() < 0.0 {
                     rec1.set_t(0.0)
                 }
-                let ray_length = ray.direction().square_magnitude();
+                // Distance travelled per unit of t, the direction of the ray may not be a unit
+                // vector
+                let ray_length = ray.direction().magnitude();
                 let distance_inside_boundary = (rec2.t() - rec1.t()) * ray_length;
                 let hit_distance = self.neg_inv_density * random_double().ln();
                 if hit_distance > distance_inside_boundary {
@@ -67,3 +72,55 @@ impl<H: Hittable, T: Texture + 'static> Hittable for ConstantMedium<H, T> {
         self.boundary.bounding_box(time_interval)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::scalar_tolerance;
+    use crate::{
+        common::seed_random, cube::Cube, material::Lambertian, textures::ConstantTexture,
+        vec3::Point3,
+    };
+
+    fn fog() -> ConstantMedium<Cube, ConstantTexture> {
+        let boundary = Cube::new(
+            Point3::new(-1.0, -1.0, -1.0),
+            Point3::new(1.0, 1.0, 1.0),
+            Arc::new(Lambertian::new(ConstantTexture::default())),
+        );
+        ConstantMedium::new(boundary, 2.0, ConstantTexture::from_points(1.0, 1.0, 1.0))
+    }
+
+    #[test]
+    fn scattering_distance_does_not_depend_on_ray_direction_length() {
+        let fog = fog();
+        let origin = Point3::new(0.0, 0.0, 5.0);
+        let interval = Interval::new(0.001, INFINITY);
+        for seed in 0..20 {
+            seed_random(seed);
+            let unit = fog.hit(&Ray::new(origin, Vec3::new(0.0, 0.0, -1.0), 0.0), &interval);
+            seed_random(seed);
+            let long = fog.hit(&Ray::new(origin, Vec3::new(0.0, 0.0, -3.0), 0.0), &interval);
+            match (unit, long) {
+                (Some(unit), Some(long)) => assert!((unit.p() - long.p()).magnitude() < 1e-9),
+                (None, None) => {}
+                _ => panic!("Scaling the direction changed whether the ray scattered"),
+            }
+        }
+    }
+
+    #[test]
+    fn scattered_directions_are_unit() {
+        seed_random(1);
+        let fog = fog();
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.5), Vec3::new(0.0, 0.0, -0.1), 0.0);
+        let rec = (0..100)
+            .find_map(|_| fog.hit(&ray, &Interval::new(0.001, INFINITY)))
+            .expect("The ray should scatter inside the fog");
+        let material = rec.get_material().unwrap();
+        for _ in 0..100 {
+            let scattered = material.scatter(&ray, &rec).unwrap().scattered;
+            assert!((scattered.direction().magnitude() - 1.0).abs() < scalar_tolerance(1e-12));
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use std::{error::Error, fmt::Display, fs, io, sync::Arc};
+
+use crate::{
+    bvh::bvh::BVH,
+    hittable::Hittable,
+    interval::Interval,
+    material::Material,
+    triangle::Triangle,
+    vec3::{Point3, Vec3},
+};
+
+/// Builds a terrain of `width_cells` by `depth_cells` square cells of side 1, starting at the
+/// origin over the xz plane, with the height of each vertex given by `height_fn(x, z)`. Every cell
+/// is split into two triangles, shaded with the vertex normals averaged from the faces around each
+/// vertex, unless `flat_shading` is set, and textured with coordinates going from 0 to 1 across
+/// the whole grid
+pub fn heightfield(
+    width_cells: usize,
+    depth_cells: usize,
+    height_fn: impl Fn(f64, f64) -> f64,
+    material: Arc<dyn Material>,
+    flat_shading: bool,
+) -> BVH {
+    assert!(
+        width_cells > 0 && depth_cells > 0,
+        "A heightfield needs at least one cell"
+    );
+    let columns = width_cells + 1;
+    let index = |i: usize, k: usize| k * columns + i;
+    let vertices: Vec<Point3> = (0..=depth_cells)
+        .flat_map(|k| (0..=width_cells).map(move |i| (i as f64, k as f64)))
+        .map(|(x, z)| Point3::new(x, height_fn(x, z), z))
+        .collect();
+    let uv = |i: usize, k: usize| (i as f64 / width_cells as f64, k as f64 / depth_cells as f64);
+
+    // Faces wound so their normals point up for a flat terrain
+    let faces: Vec<[(usize, usize); 3]> = (0..depth_cells)
+        .flat_map(|k| {
+            (0..width_cells).flat_map(move |i| {
+                [
+                    [(i, k), (i, k + 1), (i + 1, k)],
+                    [(i + 1, k), (i, k + 1), (i + 1, k + 1)],
+                ]
+            })
+        })
+        .collect();
+    // Adding the unnormalized face normals weights each face by its area
+    let mut normals = vec![Vec3::default(); vertices.len()];
+    for face in &faces {
+        let [a, b, c] = face.map(|(i, k)| vertices[index(i, k)]);
+        let face_normal = (b - a).cross_product(&(c - a));
+        for &(i, k) in face {
+            normals[index(i, k)] += face_normal;
+        }
+    }
+
+    let triangles: Vec<Box<dyn Hittable>> = faces
+        .iter()
+        .map(|face| {
+            let [a, b, c] = face.map(|(i, k)| vertices[index(i, k)]);
+            let triangle = Triangle::new(a, b, c, material.clone())
+                .with_normals(face.map(|(i, k)| normals[index(i, k)]))
+                .with_uvs(face.map(|(i, k)| uv(i, k)))
+                .with_flat_shading(flat_shading);
+            Box::new(triangle) as Box<dyn Hittable>
+        })
+        .collect();
+    BVH::new(triangles, &Interval::new(0.0, 1.0))
+}
+
+/// Why an OBJ mesh couldn't be loaded, with the path of the file and, for a malformed file, the
+/// line at fault
+#[derive(Debug)]
+pub enum MeshError {
+    Io(String, io::Error),
+    Malformed {
+        path: String,
+        line: usize,
+        reason: String,
+    },
+    Empty(String),
+}
+
+impl Error for MeshError {}
+
+impl Display for MeshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            MeshError::Io(path, error) => {
+                write!(f, "Error, couldn't read the mesh {}: {}", path, error)
+            }
+            MeshError::Malformed { path, line, reason } => {
+                write!(f, "Error, line {} of the mesh {}: {}", line, path, reason)
+            }
+            MeshError::Empty(path) => write!(f, "Error, the mesh {} has no faces", path),
+        }
+    }
+}
+
+/// Loads the triangles of a Wavefront OBJ file into a BVH, all with `material`. Only the
+/// vertices, `v`, texture coordinates, `vt`, normals, `vn`, and faces, `f`, are read; other lines
+/// are skipped. Faces with more than three vertices are split into a fan around their first one.
+/// A face whose vertices all have normals is shaded with them, otherwise with its geometric
+/// normal, and likewise for the texture coordinates
+pub fn load_obj(path: &str, material: Arc<dyn Material>) -> Result<BVH, MeshError> {
+    let source =
+        fs::read_to_string(path).map_err(|error| MeshError::Io(path.to_string(), error))?;
+    let malformed = |line: usize, reason: String| MeshError::Malformed {
+        path: path.to_string(),
+        line,
+        reason,
+    };
+
+    let mut positions: Vec<Point3> = Vec::new();
+    let mut uvs: Vec<(f64, f64)> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut triangles: Vec<Box<dyn Hittable>> = Vec::new();
+    for (number, line) in source.lines().enumerate().map(|(i, line)| (i + 1, line)) {
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let values = |tokens: std::str::SplitWhitespace, count: usize| {
+            let values = tokens
+                .take(count)
+                .map(|token| {
+                    token
+                        .parse::<f64>()
+                        .map_err(|_| malformed(number, format!("{} isn't a number", token)))
+                })
+                .collect::<Result<Vec<f64>, MeshError>>()?;
+            if values.len() < count {
+                return Err(malformed(number, format!("expected {} numbers", count)));
+            }
+            Ok(values)
+        };
+        match keyword {
+            "v" => {
+                let v = values(tokens, 3)?;
+                positions.push(Point3::new(v[0], v[1], v[2]));
+            }
+            "vt" => {
+                let vt = values(tokens, 2)?;
+                uvs.push((vt[0], vt[1]));
+            }
+            "vn" => {
+                let vn = values(tokens, 3)?;
+                normals.push(Vec3::new(vn[0], vn[1], vn[2]));
+            }
+            "f" => {
+                let corners = tokens
+                    .map(|corner| {
+                        parse_corner(corner, [positions.len(), uvs.len(), normals.len()])
+                            .map_err(|reason| malformed(number, reason))
+                    })
+                    .collect::<Result<Vec<[Option<usize>; 3]>, MeshError>>()?;
+                if corners.len() < 3 {
+                    return Err(malformed(number, "a face needs three vertices".to_string()));
+                }
+                for k in 1..corners.len() - 1 {
+                    let face = [corners[0], corners[k], corners[k + 1]];
+                    let [a, b, c] = face.map(|[v, _, _]| positions[v.unwrap()]);
+                    let mut triangle = Triangle::new(a, b, c, material.clone());
+                    if face.iter().all(|[_, vt, _]| vt.is_some()) {
+                        triangle = triangle.with_uvs(face.map(|[_, vt, _]| uvs[vt.unwrap()]));
+                    }
+                    if face.iter().all(|[_, _, vn]| vn.is_some()) {
+                        triangle =
+                            triangle.with_normals(face.map(|[_, _, vn]| normals[vn.unwrap()]));
+                    }
+                    triangles.push(Box::new(triangle));
+                }
+            }
+            _ => {}
+        }
+    }
+    if triangles.is_empty() {
+        return Err(MeshError::Empty(path.to_string()));
+    }
+    Ok(BVH::new(triangles, &Interval::new(0.0, 1.0)))
+}
+
+/// Indices into the vertices, texture coordinates and normals of a face corner, `v`, `v/vt`,
+/// `v//vn` or `v/vt/vn`. OBJ counts from 1, or backwards from the last element read when negative
+fn parse_corner(corner: &str, counts: [usize; 3]) -> Result<[Option<usize>; 3], String> {
+    let mut indices = [None; 3];
+    for (slot, part) in corner.split('/').enumerate() {
+        if slot >= 3 {
+            return Err(format!("{} has too many indices", corner));
+        }
+        if part.is_empty() && slot > 0 {
+            continue;
+        }
+        let index: i64 = part
+            .parse()
+            .map_err(|_| format!("{} isn't a face corner", corner))?;
+        let count = counts[slot] as i64;
+        let resolved = if index < 0 { count + index } else { index - 1 };
+        if index == 0 || !(0..count).contains(&resolved) {
+            return Err(format!(
+                "{} refers to an element that doesn't exist",
+                corner
+            ));
+        }
+        indices[slot] = Some(resolved as usize);
+    }
+    Ok(indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::scalar_tolerance;
+    use crate::{common::INFINITY, material::Lambertian, rays::Ray, textures::ConstantTexture};
+
+    fn write_obj(name: &str, source: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, source).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn obj_quad_is_loaded_and_hit() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let source = "# A unit square facing +z, as one polygon\n\
+            v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+            vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+            vn 0 0 1\n\
+            g square\n\
+            f 1/1/1 2/2/1 3/3/1 -1/-1/-1\n";
+        let path = write_obj("obj_quad_is_loaded_and_hit.obj", source);
+        let quad = load_obj(&path, material.clone()).unwrap();
+        let ray = Ray::new(Point3::new(0.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let rec = quad.hit(&ray, &Interval::new(0.001, INFINITY)).unwrap();
+        assert!((rec.t() - 1.0).abs() < 1e-9);
+        assert_eq!(rec.normal(), Vec3::new(0.0, 0.0, 1.0));
+        assert!((rec.u() - 0.5).abs() < 1e-9 && (rec.v() - 0.5).abs() < 1e-9);
+
+        for (name, source, line) in [
+            ("obj_bad_number.obj", "v 0 0 0\nv 1 x 0\n", 2),
+            (
+                "obj_bad_index.obj",
+                "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n",
+                4,
+            ),
+            ("obj_short_face.obj", "v 0 0 0\nv 1 0 0\nf 1 2\n", 3),
+        ] {
+            match load_obj(&write_obj(name, source), material.clone()) {
+                Err(MeshError::Malformed { line: at, .. }) => assert_eq!(at, line, "{name}"),
+                Err(e) => panic!("Expected {name} to be malformed, got {e}"),
+                Ok(_) => panic!("Expected {name} to fail"),
+            }
+        }
+        let empty = write_obj("obj_without_faces.obj", "v 0 0 0\n");
+        assert!(matches!(
+            load_obj(&empty, material),
+            Err(MeshError::Empty(_))
+        ));
+    }
+
+    #[test]
+    fn flat_heightfield_is_planar() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let terrain = heightfield(4, 3, |_, _| 2.0, material, false);
+        let bbox = terrain.bounding_box(&Interval::new(0.0, 1.0)).unwrap();
+        assert_eq!(bbox.min().x(), 0.0);
+        assert_eq!(bbox.max().x(), 4.0);
+        assert_eq!(bbox.max().z(), 3.0);
+
+        for (x, z) in [(0.1, 0.1), (0.5, 0.5), (1.7, 2.3), (3.99, 2.99), (2.0, 1.0)] {
+            let ray = Ray::new(Point3::new(x, 10.0, z), Vec3::new(0.0, -1.0, 0.0), 0.0);
+            let rec = terrain.hit(&ray, &Interval::new(0.001, INFINITY)).unwrap();
+            assert!((rec.p().y() - 2.0).abs() < scalar_tolerance(1e-9));
+            assert!((rec.normal() - Vec3::new(0.0, 1.0, 0.0)).magnitude() < scalar_tolerance(1e-9));
+            assert!((rec.u() - x / 4.0).abs() < scalar_tolerance(1e-9));
+            assert!((rec.v() - z / 3.0).abs() < scalar_tolerance(1e-9));
+        }
+    }
+
+    #[test]
+    fn sloped_heightfield_averages_normals() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let terrain = heightfield(2, 2, |x, _| x, material, false);
+        let ray = Ray::new(Point3::new(1.0, 10.0, 1.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let rec = terrain.hit(&ray, &Interval::new(0.001, INFINITY)).unwrap();
+        let expected = Vec3::new(-1.0, 1.0, 0.0).normalize();
+        assert!((rec.normal() - expected).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn flat_shaded_heightfield_shows_facets() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        // Slope up to x = 1, then flat. Smooth shading would bend the normals around the ridge
+        let faceted = heightfield(2, 2, |x, _| x.min(1.0), material, true);
+        let ray = Ray::new(Point3::new(0.5, 10.0, 0.5), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let rec = faceted.hit(&ray, &Interval::new(0.001, INFINITY)).unwrap();
+        let expected = Vec3::new(-1.0, 1.0, 0.0).normalize();
+        assert!((rec.normal() - expected).magnitude() < 1e-9);
+    }
+}
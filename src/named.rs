@@ -0,0 +1,47 @@
+use crate::{
+    aabb::aabb::AABB,
+    hittable::{HitRecord, Hittable},
+    interval::Interval,
+    rays::Ray,
+    vec3::{Point3, Vec3},
+};
+
+/// Attaches an identifier to an object, reported in its hit records. If the wrapped object
+/// already sets an identifier, like a named object inside a named group, the innermost one is
+/// kept
+pub struct Named<H: Hittable> {
+    object: H,
+    id: u32,
+}
+
+impl<H: Hittable> Named<H> {
+    pub fn new(object: H, id: u32) -> Self {
+        Named { object, id }
+    }
+}
+
+impl<H: Hittable> Hittable for Named<H> {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        self.object.hit(ray, time_interval).map(|mut rec| {
+            if rec.id().is_none() {
+                rec.set_id(self.id);
+            }
+            rec
+        })
+    }
+    fn hit_any(&self, ray: &Ray, time_interval: &Interval) -> bool {
+        self.object.hit_any(ray, time_interval)
+    }
+    fn bounding_box(&self, time_interval: &Interval) -> Option<AABB> {
+        self.object.bounding_box(time_interval)
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.object.pdf_value(origin, direction)
+    }
+    fn random(&self, origin: &Point3) -> Vec3 {
+        self.object.random(origin)
+    }
+    fn object_id(&self) -> Option<u32> {
+        Some(self.id)
+    }
+}
@@ -70,15 +70,41 @@ impl Perlin {
         }
         p
     }
+    /// Sum of `depth` octaves of noise, each at twice the frequency and half the weight of the
+    /// one before
     pub fn turbulence(&self, p: &Point3, depth: usize) -> f64 {
         let mut accum = 0.0;
         let mut temp_p = *p;
         let mut weight = 1.0;
         for _ in 0..depth {
-            accum += weight * self.noise(p);
+            accum += weight * self.noise(&temp_p);
             weight *= 0.5;
             temp_p *= 2.0;
         }
         f64::abs(accum)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::seed_random;
+
+    #[test]
+    fn turbulence_adds_finer_octaves() {
+        seed_random(5);
+        let perlin = Perlin::new(256);
+        let weights: f64 = (0..7).map(|octave| 0.5_f64.powi(octave)).sum();
+        let points = (0..20).map(|k| Point3::new(0.37 * k as f64, 1.3, 2.0 + 0.11 * k as f64));
+        let differing = points
+            .filter(|p| {
+                let single_octave = weights * perlin.noise(p).abs();
+                (perlin.turbulence(p, 7) - single_octave).abs() > 1e-3
+            })
+            .count();
+        assert!(differing > 15, "{differing} points differ");
+        // A single octave is the noise itself
+        let p = Point3::new(0.4, 0.8, 1.5);
+        assert_eq!(perlin.turbulence(&p, 1), perlin.noise(&p).abs());
+    }
+}
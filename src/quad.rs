@@ -1,10 +1,32 @@
 use crate::aabb::aabb::{surrounding_box, AABB};
+use crate::color::Color;
+use crate::common::{random_double, INFINITY};
 use crate::hittable::{HitRecord, Hittable};
 use crate::interval::{Interval, UNITY_INTERVAL};
 use crate::material::Material;
 use crate::rays::Ray;
 use crate::vec3::{Point3, Vec3};
+use std::fmt;
 use std::sync::Arc;
+#[derive(Debug, PartialEq)]
+pub enum QuadError {
+    UvScale(f64, f64),
+}
+
+impl std::error::Error for QuadError {}
+
+impl fmt::Display for QuadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            QuadError::UvScale(su, sv) => write!(
+                f,
+                "Error, the texture coordinate scales must be positive, got {} and {}",
+                su, sv
+            ),
+        }
+    }
+}
+
 pub struct Quad {
     q: Point3,
     u: Vec3,
@@ -14,6 +36,9 @@ pub struct Quad {
     normal: Vec3,
     d: f64,
     w: Vec3,
+    area: f64,
+    uv_scale: (f64, f64),
+    tint: Option<Color>,
 }
 
 impl Quad {
@@ -28,6 +53,7 @@ impl Quad {
         let normal = n.normalize();
         let d = normal.dot_product(&q);
         let w = n / n.dot_product(&n);
+        let area = n.magnitude();
 
         Quad {
             q,
@@ -38,7 +64,64 @@ impl Quad {
             normal,
             d,
             w,
+            area,
+            uv_scale: (1.0, 1.0),
+            tint: None,
+        }
+    }
+    /// Multiplies the texture coordinates, which otherwise go from 0 to 1 along the edges, so a
+    /// texture that repeats outside of [0, 1] is tiled `su` by `sv` times over the quad. The
+    /// image textures clamp the coordinates instead, they only stretch their last texels. Scales
+    /// that aren't positive are rejected, they would divide the tangents by zero or flip them
+    pub fn with_uv_scale(mut self, su: f64, sv: f64) -> Result<Self, QuadError> {
+        if su <= 0.0 || sv <= 0.0 {
+            return Err(QuadError::UvScale(su, sv));
         }
+        self.uv_scale = (su, sv);
+        Ok(self)
+    }
+    /// Colors the quad by multiplying `tint` into the albedo of diffuse and metal materials
+    pub fn with_tint(mut self, tint: Color) -> Self {
+        self.tint = Some(tint);
+        self
+    }
+    /// Quad with the corners `a`, `b`, `c` and `d`, in order around it, facing the side of
+    /// `(b - a) x (d - a)`. Panics if they aren't the corners of a parallelogram, which also
+    /// catches corners off the plane or out of order
+    pub fn from_corners(
+        a: Point3,
+        b: Point3,
+        c: Point3,
+        d: Point3,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let (u, v) = (b - a, d - a);
+        let tolerance = 1e-9 * (u.magnitude() + v.magnitude());
+        assert!(
+            (a + u + v - c).magnitude() <= tolerance,
+            "The corners {a:?}, {b:?}, {c:?}, {d:?} are not a parallelogram in order"
+        );
+        Quad::new(a, u, v, material)
+    }
+    /// Rectangle of `width` by `height` centered at `center` and facing `normal`. Seen from the
+    /// front, the width goes to the right and the height up, with up being +y or, for rectangles
+    /// facing along the y axis, -z. Facing an axis, the rectangle is aligned with the other two
+    pub fn rect(
+        center: Point3,
+        width: f64,
+        height: f64,
+        normal: Vec3,
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let w = normal.normalize();
+        let up = if w.y().abs() > 0.9 {
+            Vec3::new(0.0, 0.0, -1.0)
+        } else {
+            Vec3::new(0.0, 1.0, 0.0)
+        };
+        let right = up.cross_product(&w).normalize();
+        let (u, v) = (width * right, height * w.cross_product(&right));
+        Quad::new(center - 0.5 * (u + v), u, v, material)
     }
 }
 
@@ -68,11 +151,179 @@ impl Hittable for Quad {
         rec.set_face_normal(ray, self.normal);
         rec.set_colision_point(ray.at(t));
         rec.set_material(self.material.clone());
-        rec.set_u(alpha);
-        rec.set_v(beta);
+        let (su, sv) = self.uv_scale;
+        rec.set_u(su * alpha);
+        rec.set_v(sv * beta);
+        rec.set_dpdu(self.u / su);
+        rec.set_dpdv(self.v / sv);
+        if let Some(tint) = self.tint {
+            rec.set_tint(tint);
+        }
         Some(rec)
     }
     fn bounding_box(&self, _time_interval: &Interval) -> Option<AABB> {
         Some(self.bbox)
     }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        match self.hit(
+            &Ray::new(*origin, *direction, 0.0),
+            &Interval::new(0.001, INFINITY),
+        ) {
+            Some(rec) => {
+                let distance_squared = rec.t() * rec.t() * direction.square_magnitude();
+                let cosine = f64::abs(direction.dot_product(&rec.normal()) / direction.magnitude());
+                distance_squared / (cosine * self.area)
+            }
+            None => 0.0,
+        }
+    }
+    fn random(&self, origin: &Point3) -> Vec3 {
+        let p = self.q + (random_double() * self.u) + (random_double() * self.v);
+        p - *origin
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{material::Lambertian, textures::ConstantTexture};
+
+    #[test]
+    fn dpdu_matches_edges() {
+        let quad = Quad::new(
+            Point3::new(-1.0, -1.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 3.0, 0.0),
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        );
+        let ray = Ray::new(Point3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let rec = quad
+            .hit(&ray, &Interval::new(0.001, f64::INFINITY))
+            .unwrap();
+        assert_eq!(rec.dpdu(), Some(Vec3::new(2.0, 0.0, 0.0)));
+        assert_eq!(rec.dpdv(), Some(Vec3::new(0.0, 3.0, 0.0)));
+    }
+
+    #[test]
+    fn uv_scale_tiles_the_texture_coordinates() {
+        let quad = Quad::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            gray(),
+        )
+        .with_uv_scale(2.0, 2.0)
+        .unwrap();
+        let interval = Interval::new(0.001, INFINITY);
+        let toward = |x, y| Ray::new(Point3::new(x, y, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+
+        let far = quad.hit(&toward(2.0, 2.0), &interval).unwrap();
+        assert!((far.u() - 2.0).abs() < 1e-9 && (far.v() - 2.0).abs() < 1e-9);
+        let middle = quad.hit(&toward(1.0, 0.5), &interval).unwrap();
+        assert!((middle.u() - 1.0).abs() < 1e-9 && (middle.v() - 0.5).abs() < 1e-9);
+        assert_eq!(middle.dpdu(), Some(Vec3::new(1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn zero_uv_scale_is_rejected() {
+        let quad = || {
+            Quad::new(
+                Point3::new(0.0, 0.0, 0.0),
+                Vec3::new(2.0, 0.0, 0.0),
+                Vec3::new(0.0, 2.0, 0.0),
+                gray(),
+            )
+        };
+        assert_eq!(
+            quad().with_uv_scale(0.0, 2.0).err(),
+            Some(QuadError::UvScale(0.0, 2.0))
+        );
+        assert!(quad().with_uv_scale(2.0, -1.0).is_err());
+    }
+
+    #[test]
+    fn tint_multiplies_the_albedo() {
+        let white = Arc::new(Lambertian::new(ConstantTexture::from_points(1.0, 1.0, 1.0)));
+        let corner = Point3::new(-1.0, -1.0, 0.0);
+        let (u, v) = (Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0));
+        let ray = Ray::new(Point3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let interval = Interval::new(0.001, INFINITY);
+
+        let plain = Quad::new(corner, u, v, white.clone());
+        let rec = plain.hit(&ray, &interval).unwrap();
+        assert_eq!(rec.tint(), Color::new(1.0, 1.0, 1.0));
+        let scattered = white.scatter(&ray, &rec).unwrap();
+        assert_eq!(scattered.attenuation, Color::new(1.0, 1.0, 1.0));
+
+        let red = Quad::new(corner, u, v, white.clone()).with_tint(Color::new(0.9, 0.1, 0.1));
+        let rec = red.hit(&ray, &interval).unwrap();
+        let scattered = white.scatter(&ray, &rec).unwrap();
+        assert_eq!(scattered.attenuation, Color::new(0.9, 0.1, 0.1));
+    }
+
+    fn gray() -> Arc<dyn Material> {
+        Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)))
+    }
+
+    fn assert_same_geometry(quad: &Quad, other: &Quad) {
+        assert_eq!((quad.q, quad.u, quad.v), (other.q, other.u, other.v));
+        assert_eq!(quad.normal, other.normal);
+        assert_eq!(quad.bbox, other.bbox);
+    }
+
+    #[test]
+    fn corners_build_the_same_quad() {
+        let square = Quad::new(
+            Point3::new(1.0, 0.0, -2.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -2.0),
+            gray(),
+        );
+        let from_corners = Quad::from_corners(
+            Point3::new(1.0, 0.0, -2.0),
+            Point3::new(3.0, 0.0, -2.0),
+            Point3::new(3.0, 0.0, -4.0),
+            Point3::new(1.0, 0.0, -4.0),
+            gray(),
+        );
+        assert_same_geometry(&from_corners, &square);
+
+        let rect = Quad::rect(
+            Point3::new(2.0, 0.0, -3.0),
+            2.0,
+            2.0,
+            Vec3::new(0.0, 1.0, 0.0),
+            gray(),
+        );
+        assert_same_geometry(&rect, &square);
+        assert_eq!(rect.normal, Vec3::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn rect_faces_its_normal() {
+        let rect = Quad::rect(
+            Point3::new(0.0, 1.0, 0.0),
+            4.0,
+            2.0,
+            Vec3::new(0.0, 0.0, 3.0),
+            gray(),
+        );
+        assert_eq!(rect.normal, Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(rect.bbox.max().x() - rect.bbox.min().x(), 4.0);
+        assert_eq!(rect.bbox.max().y() - rect.bbox.min().y(), 2.0);
+        let ray = Ray::new(Point3::new(1.9, 1.9, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(rect.hit(&ray, &Interval::new(0.001, INFINITY)).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "not a parallelogram")]
+    fn scrambled_corners_are_rejected() {
+        Quad::from_corners(
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            Point3::new(1.0, 1.0, 0.0),
+            gray(),
+        );
+    }
 }
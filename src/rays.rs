@@ -1,18 +1,30 @@
 use crate::vec3::*;
 
+/// A ray with an origin, a direction and the time it was cast. The inverse of the direction and
+/// the sign of each of its components are computed once at construction, since the BVH tests them
+/// against every box it traverses
 #[derive(Clone, Copy, Default)]
 pub struct Ray {
     origin: Point3,
     direction: Vec3,
     tm: f64,
+    inv_direction: Vec3,
+    sign: [bool; 3],
 }
 
 impl Ray {
     pub fn new(origin: Point3, direction: Vec3, time: f64) -> Self {
+        let inv_direction = direction.recip();
         Ray {
             origin,
             direction,
             tm: time,
+            inv_direction,
+            sign: [
+                inv_direction.x() < 0.0,
+                inv_direction.y() < 0.0,
+                inv_direction.z() < 0.0,
+            ],
         }
     }
     pub fn origin(&self) -> Point3 {
@@ -27,4 +39,28 @@ impl Ray {
     pub fn time(&self) -> f64 {
         self.tm
     }
+    /// Component-wise reciprocal of the direction
+    pub fn inv_direction(&self) -> Vec3 {
+        self.inv_direction
+    }
+    /// Whether each component of the inverse direction is negative
+    pub fn sign(&self) -> [bool; 3] {
+        self.sign
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inverse_direction_matches_reciprocal() {
+        let direction = Vec3::new(2.0, -0.5, 0.0);
+        let ray = Ray::new(Point3::default(), direction, 0.0);
+        let inv = ray.inv_direction();
+        assert_eq!(inv.x(), 1.0 / direction.x());
+        assert_eq!(inv.y(), 1.0 / direction.y());
+        assert_eq!(inv.z(), 1.0 / direction.z());
+        assert_eq!(ray.sign(), [false, true, false]);
+    }
 }
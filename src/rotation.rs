@@ -4,7 +4,7 @@ use crate::{
     hittable::{HitRecord, Hittable},
     interval::Interval,
     rays::Ray,
-    vec3::Point3,
+    vec3::{Point3, Vec3},
 };
 
 pub struct Rotation<H: Hittable> {
@@ -133,6 +133,24 @@ impl<H: Hittable> Hittable for Rotation<H> {
                 self.cos_theta * rec.normal().axis(b_axis)
                     + self.sin_theta * rec.normal().axis(a_axis),
             );
+            let rotate_back = |vector: Vec3| {
+                let mut rotated = vector;
+                rotated.set_axis(
+                    a_axis,
+                    self.cos_theta * vector.axis(a_axis) - self.sin_theta * vector.axis(b_axis),
+                );
+                rotated.set_axis(
+                    b_axis,
+                    self.cos_theta * vector.axis(b_axis) + self.sin_theta * vector.axis(a_axis),
+                );
+                rotated
+            };
+            if let Some(dpdu) = rec.dpdu() {
+                rec.set_dpdu(rotate_back(dpdu));
+            }
+            if let Some(dpdv) = rec.dpdv() {
+                rec.set_dpdv(rotate_back(dpdv));
+            }
             rec.set_normal(normal);
             rec.set_colision_point(p);
             return Some(rec);
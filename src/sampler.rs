@@ -0,0 +1,183 @@
+use std::f64::consts::PI;
+
+use crate::common::{hash_double, random_double};
+
+/// Random numbers in [0, 1) used to build one camera ray: the position inside the pixel, the point
+/// of the lens and the time of the exposure
+#[derive(Clone, Copy, Debug)]
+pub struct CameraSample {
+    pub pixel: (f64, f64),
+    pub lens: (f64, f64),
+    pub time: f64,
+}
+
+/// Source of the camera samples. `index` is the number of the sample inside the pixel (i, j), so
+/// samplers can spread the samples of a pixel evenly instead of drawing them independently
+pub trait Sampler: Send + Sync {
+    fn sample(&self, i: i32, j: i32, index: i32) -> CameraSample;
+}
+
+/// Independent uniform random numbers for every sample
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomSampler;
+
+/// Halton sequence over the five dimensions of the camera samples, with bases 2, 3, 5, 7 and 11.
+/// The samples of a pixel are consecutive points of the sequence, so they cover the pixel, the
+/// lens and the exposure evenly. Each pixel shifts the sequence by its own random offset, wrapping
+/// around, so neighbouring pixels don't repeat the same pattern
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HaltonSampler {
+    seed: u64,
+}
+
+const HALTON_BASES: [u64; 5] = [2, 3, 5, 7, 11];
+
+impl Sampler for RandomSampler {
+    fn sample(&self, _i: i32, _j: i32, _index: i32) -> CameraSample {
+        CameraSample {
+            pixel: (random_double(), random_double()),
+            lens: (random_double(), random_double()),
+            time: random_double(),
+        }
+    }
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        HaltonSampler { seed }
+    }
+    /// Coordinate `dimension` of the sample `index` of the pixel (i, j)
+    fn coordinate(&self, i: i32, j: i32, index: i32, dimension: usize) -> f64 {
+        // The first point of the sequence is 0 in every dimension, skip it
+        let point = radical_inverse(index as u64 + 1, HALTON_BASES[dimension]);
+        let key = ((i as u32 as u64) << 32 | j as u32 as u64) ^ self.seed.rotate_left(17);
+        let offset = hash_double(key.wrapping_add(dimension as u64));
+        (point + offset).fract()
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn sample(&self, i: i32, j: i32, index: i32) -> CameraSample {
+        let coordinate = |dimension| self.coordinate(i, j, index, dimension);
+        CameraSample {
+            pixel: (coordinate(0), coordinate(1)),
+            lens: (coordinate(2), coordinate(3)),
+            time: coordinate(4),
+        }
+    }
+}
+
+/// Mirrors the digits of `index` in `base` around the decimal point
+fn radical_inverse(mut index: u64, base: u64) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut factor = inverse_base;
+    let mut result = 0.0;
+    while index > 0 {
+        result += (index % base) as f64 * factor;
+        index /= base;
+        factor *= inverse_base;
+    }
+    result
+}
+
+/// Maps a uniform random number in [0, 1) to an offset in [-1, 1] with the triangular density
+/// `1 - |x|` of the tent filter, peaking at 0
+pub fn sample_tent(u: f64) -> f64 {
+    if u < 0.5 {
+        (2.0 * u).sqrt() - 1.0
+    } else {
+        1.0 - (2.0 - 2.0 * u).sqrt()
+    }
+}
+
+/// Maps a point of the unit square to the unit disk keeping the areas, with the concentric
+/// mapping of Shirley and Chiu, so evenly spread points stay evenly spread
+pub fn square_to_disk(u: f64, v: f64) -> (f64, f64) {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (radius, theta) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    (radius * theta.cos(), radius * theta.sin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::seed_random;
+
+    /// Largest difference between the fraction of points inside a box anchored at the origin and
+    /// the area of the box, over the boxes with a corner at the points
+    fn star_discrepancy(points: &[(f64, f64)]) -> f64 {
+        let n = points.len() as f64;
+        let xs: Vec<f64> = points.iter().map(|p| p.0).chain([1.0]).collect();
+        let ys: Vec<f64> = points.iter().map(|p| p.1).chain([1.0]).collect();
+        let corners = xs.iter().flat_map(|&x| ys.iter().map(move |&y| (x, y)));
+        corners
+            .map(|(x, y)| {
+                let closed = points.iter().filter(|p| p.0 <= x && p.1 <= y).count() as f64;
+                let open = points.iter().filter(|p| p.0 < x && p.1 < y).count() as f64;
+                let area = x * y;
+                (closed / n - area).max(area - open / n)
+            })
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn halton_pixel_offsets_are_more_even_than_random() {
+        seed_random(5);
+        let count = 64;
+        let halton = HaltonSampler::new(0);
+        let pixels = [(0, 0), (3, 7), (120, 45), (-2, 9)];
+        let (mut even, mut random) = (0.0, 0.0);
+        for &(i, j) in &pixels {
+            let halton_offsets: Vec<_> = (0..count).map(|k| halton.sample(i, j, k).pixel).collect();
+            let random_offsets: Vec<_> = (0..count)
+                .map(|k| RandomSampler.sample(i, j, k).pixel)
+                .collect();
+            even += star_discrepancy(&halton_offsets) / pixels.len() as f64;
+            random += star_discrepancy(&random_offsets) / pixels.len() as f64;
+        }
+        assert!(
+            even < 0.7 * random,
+            "Halton discrepancy {even} should be well below random {random}"
+        );
+    }
+
+    #[test]
+    fn tent_offsets_are_triangular() {
+        let count = 10000;
+        let offsets: Vec<f64> = (0..count)
+            .map(|k| sample_tent((k as f64 + 0.5) / count as f64))
+            .collect();
+        assert!(offsets.iter().all(|x| (-1.0..=1.0).contains(x)));
+        assert_eq!(sample_tent(0.5), 0.0);
+        // The density 1 - |x| puts 19% of the samples within 0.1 of the center and 1% within 0.1
+        // of the edges
+        let within = |range: std::ops::Range<f64>| {
+            offsets.iter().filter(|x| range.contains(&x.abs())).count() as f64 / count as f64
+        };
+        assert!((within(0.0..0.1) - 0.19).abs() < 0.005);
+        assert!((within(0.9..1.0) - 0.01).abs() < 0.005);
+        assert!((within(0.0..0.5) - 0.75).abs() < 0.005);
+    }
+
+    #[test]
+    fn disk_mapping_stays_inside_disk() {
+        for (u, v) in [
+            (0.0, 0.0),
+            (1.0, 0.5),
+            (0.5, 0.5),
+            (0.25, 0.9),
+            (0.999, 0.001),
+        ] {
+            let (x, y) = square_to_disk(u, v);
+            assert!(x * x + y * y <= 1.0 + 1e-12);
+        }
+        assert_eq!(square_to_disk(0.5, 0.5), (0.0, 0.0));
+    }
+}
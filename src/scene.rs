@@ -0,0 +1,289 @@
+use std::sync::Arc;
+
+use crate::{
+    aabb::aabb::AABB,
+    camera::{Camera, RenderStats},
+    color::Color,
+    hittable::{HitRecord, Hittable, HittableList},
+    interval::Interval,
+    light::LightList,
+    rays::Ray,
+};
+
+/// Everything needed to render an image: the objects of the world, the camera looking at them
+/// and the lights that can be sampled directly. The interval during which the shutter is open is
+/// the one of the camera, [0, 1] by default
+pub struct Scene {
+    world: Arc<dyn Hittable>,
+    camera: Camera,
+    lights: Option<LightList>,
+}
+
+/// One object of a world, found by following the `path` of indices through the parts of the
+/// composite objects, that stands for the whole world
+struct Solo {
+    world: Arc<dyn Hittable>,
+    path: Vec<usize>,
+}
+
+impl Scene {
+    pub fn new(world: Box<dyn Hittable>, camera: Camera) -> Self {
+        Scene {
+            world: Arc::from(world),
+            camera,
+            lights: None,
+        }
+    }
+    /// Puts the objects in a BVH built over the shutter of the camera, so the bounds of the moving
+    /// objects cover every time the camera rays can have
+    pub fn from_objects(objects: HittableList, camera: Camera) -> Self {
+        let world = objects.into_bvh(&camera.shutter());
+        Scene::new(Box::new(world), camera)
+    }
+    /// Lights handed to the camera for direct sampling when rendering
+    pub fn with_lights(mut self, lights: LightList) -> Self {
+        self.lights = Some(lights);
+        self
+    }
+    /// Sets the shutter of the camera. A world already built over another shutter isn't rebuilt,
+    /// set it on the camera before `from_objects` instead
+    pub fn with_shutter(mut self, shutter: Interval) -> Self {
+        self.camera.set_shutter(shutter);
+        self
+    }
+    pub fn world(&self) -> &dyn Hittable {
+        self.world.as_ref()
+    }
+    /// Scene with the same camera and lights but only one object of the world, to check its
+    /// geometry and materials in isolation. The object is given by its index in the world, or by
+    /// a path like `2.4` into composite objects: the side 4 of the cube at index 2. The indices of
+    /// a BVH follow its leaves from left to right, see `BVH::leaves_mut`. The lights are still
+    /// sampled if they aren't soloed, so the object is lit as in the full scene. Returns `None`
+    /// if there is no object at `path`
+    pub fn solo(&self, path: &str) -> Option<Scene> {
+        let path = path
+            .split('.')
+            .map(|index| index.trim().parse().ok())
+            .collect::<Option<Vec<usize>>>()?;
+        let solo = Solo {
+            world: self.world.clone(),
+            path,
+        };
+        solo.try_object()?;
+        Some(Scene {
+            world: Arc::new(solo),
+            camera: self.camera.clone(),
+            lights: self.lights.clone(),
+        })
+    }
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        &mut self.camera
+    }
+    pub fn lights(&self) -> Option<&LightList> {
+        self.lights.as_ref()
+    }
+    pub fn shutter(&self) -> Interval {
+        self.camera.shutter()
+    }
+    /// Renders the scene and writes it as a PNG, returning the stats of the render
+    pub fn render(&mut self, filename: String) -> RenderStats {
+        self.prepare_camera();
+        let world: Box<dyn Hittable> = Box::new(self.world.clone());
+        self.camera.render(&world, filename)
+    }
+    /// Renders the scene into memory, returning the linear colors of the pixels row by row
+    pub fn render_to_buffer(&mut self) -> Vec<Vec<Color>> {
+        self.prepare_camera();
+        self.camera.render_to_buffer(self.world.as_ref())
+    }
+    /// Human readable description of the framing: the bounds of the world, the camera and a
+    /// warning when the world is entirely out of the view of the camera, a common cause of black
+    /// images
+    pub fn summary(&mut self) -> String {
+        let bounds = self.world.bounding_box(&self.camera.shutter());
+        let mut lines = vec![match bounds {
+            Some(bbox) => {
+                let (min, max) = (bbox.min(), bbox.max());
+                format!(
+                    "scene bounds ({}, {}, {}) to ({}, {}, {})",
+                    min.x(),
+                    min.y(),
+                    min.z(),
+                    max.x(),
+                    max.y(),
+                    max.z()
+                )
+            }
+            None => "scene bounds unknown".to_string(),
+        }];
+        lines.push(self.camera.summary());
+        if let Some(warning) = self.framing_warning() {
+            lines.push(warning);
+        }
+        lines.join("\n")
+    }
+    /// Warning about a world entirely out of the view of the camera, if it is
+    fn framing_warning(&mut self) -> Option<String> {
+        let bbox = self.world.bounding_box(&self.camera.shutter())?;
+        (!self.camera.sees_box(&bbox)).then(|| {
+            "Warning: the scene is entirely outside of the field of view of the camera".to_string()
+        })
+    }
+    fn prepare_camera(&mut self) {
+        if let Some(lights) = &self.lights {
+            self.camera.set_lights(lights.clone());
+        }
+        if let Some(warning) = self.framing_warning() {
+            eprintln!("{warning}");
+        }
+    }
+}
+
+impl Solo {
+    fn try_object(&self) -> Option<&dyn Hittable> {
+        self.path
+            .iter()
+            .try_fold(self.world.as_ref(), |object, &index| object.child(index))
+    }
+    fn object(&self) -> &dyn Hittable {
+        self.try_object()
+            .expect("The soloed object was checked to exist")
+    }
+}
+
+impl Hittable for Solo {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        self.object().hit(ray, time_interval)
+    }
+    fn hit_any(&self, ray: &Ray, time_interval: &Interval) -> bool {
+        self.object().hit_any(ray, time_interval)
+    }
+    fn bounding_box(&self, time_interval: &Interval) -> Option<AABB> {
+        self.object().bounding_box(time_interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cube::Cube,
+        hittable::HittableList,
+        light::QuadLight,
+        material::Lambertian,
+        quad::Quad,
+        sphere::Sphere,
+        textures::ConstantTexture,
+        vec3::{Point3, Vec3},
+    };
+
+    #[test]
+    fn render_scene_to_buffer() {
+        let mut lights = LightList::new();
+        let mut world = HittableList::new();
+        world.add(Box::new(Quad::new(
+            Point3::new(-2.0, -2.0, -1.0),
+            Vec3::new(4.0, 0.0, 0.0),
+            Vec3::new(0.0, 4.0, 0.0),
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )));
+        world.add(Box::new(QuadLight::new(
+            Point3::new(-1.0, -1.0, 1.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            ConstantTexture::from_points(2.0, 2.0, 2.0),
+            &mut lights,
+        )));
+        let mut camera = Camera::default();
+        camera.set_width(8).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(4).unwrap();
+        camera.set_max_depth(4).unwrap();
+        let mut scene = Scene::new(Box::new(world), camera).with_lights(lights);
+
+        assert_eq!(scene.lights().map(LightList::len), Some(1));
+        assert_eq!(scene.shutter(), Interval::new(0.0, 1.0));
+        let image = scene.render_to_buffer();
+        assert_eq!(image.len(), 4);
+        assert!(image.iter().all(|row| row.len() == 8));
+        assert!(image[2][4].x() > 0.0);
+    }
+
+    #[test]
+    fn summary_warns_about_a_scene_behind_the_camera() {
+        let gray = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let sphere = |z| {
+            let mut world = HittableList::new();
+            world.add(Box::new(Sphere::new(
+                Point3::new(0.0, 0.0, z),
+                None,
+                1.0,
+                gray.clone(),
+            )));
+            world
+        };
+        let mut camera = Camera::default();
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+
+        let mut in_view = Scene::new(Box::new(sphere(-5.0)), camera.clone());
+        let summary = in_view.summary();
+        assert!(
+            summary.contains("scene bounds (-1, -1, -6) to (1, 1, -4)"),
+            "{summary}"
+        );
+        assert!(
+            summary.contains("camera at (0, 0, 0) looking at (0, 0, -1)"),
+            "{summary}"
+        );
+        assert!(!summary.contains("Warning"), "{summary}");
+
+        let mut behind = Scene::new(Box::new(sphere(5.0)), camera.clone());
+        assert!(behind.summary().contains("outside of the field of view"));
+        // Off to the side, past the edge of the 90° field of view
+        let mut aside = Scene::new(Box::new(sphere(-5.0)), camera);
+        aside.camera_mut().set_lookat(Point3::new(1.0, 0.0, 0.0));
+        assert!(aside.summary().contains("outside of the field of view"));
+    }
+
+    #[test]
+    fn solo_renders_one_object() {
+        let gray = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let mut world = HittableList::new();
+        for x in [-1.5, 1.5] {
+            world.add(Box::new(Sphere::new(
+                Point3::new(x, 0.0, -4.0),
+                None,
+                1.0,
+                gray.clone(),
+            )));
+        }
+        world.add(Box::new(Cube::new(
+            Point3::new(-0.5, -0.5, -9.0),
+            Point3::new(0.5, 0.5, -8.0),
+            gray,
+        )));
+        let sky = Color::new(0.7, 0.8, 1.0);
+        let mut camera = Camera::default();
+        camera.set_width(16).unwrap();
+        camera.set_aspect_ratio(2.0).unwrap();
+        camera.set_sample_per_pixel(4).unwrap();
+        camera.set_background_color(sky);
+        let mut scene = Scene::new(Box::new(world), camera);
+        assert_ne!(scene.render_to_buffer()[4][9], sky);
+
+        let image = scene.solo("0").unwrap().render_to_buffer();
+        // The first sphere is on the left and the second one is gone
+        assert_ne!(image[4][6], sky);
+        assert!((image[4][9] - sky).magnitude() < 1e-9);
+
+        assert!(scene.solo("2.0").is_some());
+        assert!(scene.solo("2.6").is_none());
+        assert!(scene.solo("0.0").is_none());
+        assert!(scene.solo("3").is_none());
+        assert!(scene.solo("first").is_none());
+    }
+}
@@ -0,0 +1,192 @@
+use std::ops::{Add, Mul};
+
+use crate::color::Color;
+
+/// Range of the wavelengths traced, in nanometers
+pub const WAVELENGTH_MIN: f64 = 380.0;
+pub const WAVELENGTH_MAX: f64 = 720.0;
+/// Number of wavelengths carried by every path: the hero wavelength and the ones spread evenly
+/// after it over the range
+pub const WAVELENGTH_COUNT: usize = 4;
+
+/// Peaks of the smooth spectra the red, green and blue components are upsampled to, with their
+/// width, in nanometers
+const BASIS_PEAKS: [f64; 3] = [610.0, 545.0, 455.0];
+const BASIS_WIDTH: f64 = 35.0;
+
+/// Wavelengths carried by a path. The hero wavelength is sampled uniformly over the range and the
+/// others are shifted from it by a fraction of the range, wrapping around, so together they cover
+/// the range evenly
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Wavelengths([f64; WAVELENGTH_COUNT]);
+
+/// Values of a spectrum at the wavelengths of a path
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampledSpectrum([f64; WAVELENGTH_COUNT]);
+
+impl Wavelengths {
+    /// Picks the hero wavelength from a uniform random number in [0, 1)
+    pub fn sample(u: f64) -> Self {
+        Wavelengths(std::array::from_fn(|k| {
+            let offset = (u + k as f64 / WAVELENGTH_COUNT as f64).fract();
+            WAVELENGTH_MIN + offset * (WAVELENGTH_MAX - WAVELENGTH_MIN)
+        }))
+    }
+    pub fn values(&self) -> [f64; WAVELENGTH_COUNT] {
+        self.0
+    }
+}
+
+impl SampledSpectrum {
+    /// Upsamples a linear RGB color to a smooth spectrum: a mix of three overlapping bumps, one
+    /// per component, that add up to 1 at every wavelength. Grays become flat spectra and
+    /// reflectances in [0, 1] stay in [0, 1]
+    pub fn from_rgb(color: Color, wavelengths: &Wavelengths) -> Self {
+        SampledSpectrum(wavelengths.0.map(|lambda| {
+            let [r, g, b] = basis(lambda);
+            color.x() * r + color.y() * g + color.z() * b
+        }))
+    }
+    /// Film response: the color whose upsampled spectrum is the closest, in the least squares
+    /// sense, to this one at the wavelengths. The spectrum upsampled from a color gives back the
+    /// same color exactly, so only products of spectra differ from the RGB renderer
+    pub fn to_rgb(&self, wavelengths: &Wavelengths) -> Color {
+        let weights = wavelengths.0.map(basis);
+        let mut normal = [[0.0; 3]; 3];
+        let mut projection = [0.0; 3];
+        for (value, weight) in self.0.iter().zip(&weights) {
+            for i in 0..3 {
+                projection[i] += value * weight[i];
+                for k in 0..3 {
+                    normal[i][k] += weight[i] * weight[k];
+                }
+            }
+        }
+        let inverse = invert(&normal);
+        let row = |i: usize| (0..3).map(|k| inverse[i][k] * projection[k]).sum();
+        Color::new(row(0), row(1), row(2))
+    }
+    pub fn values(&self) -> [f64; WAVELENGTH_COUNT] {
+        self.0
+    }
+}
+
+impl Add for SampledSpectrum {
+    type Output = SampledSpectrum;
+    fn add(self, rhs: Self) -> Self::Output {
+        SampledSpectrum(std::array::from_fn(|k| self.0[k] + rhs.0[k]))
+    }
+}
+
+impl Mul for SampledSpectrum {
+    type Output = SampledSpectrum;
+    fn mul(self, rhs: Self) -> Self::Output {
+        SampledSpectrum(std::array::from_fn(|k| self.0[k] * rhs.0[k]))
+    }
+}
+
+/// Weights of the red, green and blue bumps at `lambda`
+fn basis(lambda: f64) -> [f64; 3] {
+    let bumps = BASIS_PEAKS.map(|peak| (-0.5 * ((lambda - peak) / BASIS_WIDTH).powi(2)).exp());
+    let total: f64 = bumps.iter().sum();
+    bumps.map(|bump| bump / total)
+}
+
+fn invert(m: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let cofactor = |i: usize, k: usize| {
+        let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+        let (c0, c1) = ((k + 1) % 3, (k + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let determinant: f64 = (0..3).map(|k| m[0][k] * cofactor(0, k)).sum();
+    std::array::from_fn(|i| std::array::from_fn(|k| cofactor(k, i) / determinant))
+}
+
+/// Light reaching the camera along a path, traced over the wavelengths picked by `u`. The bounces
+/// hold, from the camera on, the light emitted at each hit, the light sampled directly from
+/// there and the attenuation of the material, and `end` is the light found where the path
+/// stopped. Every color is upsampled before they are multiplied, so colored lights over colored
+/// surfaces mix their spectra instead of their RGB components
+pub fn gather_path(end: Color, bounces: &[(Color, Color, Color)], u: f64) -> Color {
+    let wavelengths = Wavelengths::sample(u);
+    let upsample = |color| SampledSpectrum::from_rgb(color, &wavelengths);
+    let radiance = bounces.iter().rev().fold(
+        upsample(end),
+        |radiance, &(emitted, direct, attenuation)| {
+            upsample(emitted) + upsample(attenuation) * (upsample(direct) + radiance)
+        },
+    );
+    radiance.to_rgb(&wavelengths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Average of `gather_path` over evenly spread hero wavelengths
+    fn average_path(end: Color, bounces: &[(Color, Color, Color)]) -> Color {
+        let count = 1000;
+        let total: Color = (0..count)
+            .map(|n| gather_path(end, bounces, (n as f64 + 0.5) / count as f64))
+            .sum();
+        total / count as f64
+    }
+
+    #[test]
+    fn wavelengths_cover_the_range() {
+        let wavelengths = Wavelengths::sample(0.9).values();
+        assert!(wavelengths
+            .iter()
+            .all(|lambda| (WAVELENGTH_MIN..WAVELENGTH_MAX).contains(lambda)));
+        let mut sorted = wavelengths;
+        sorted.sort_by(f64::total_cmp);
+        let spacing = (WAVELENGTH_MAX - WAVELENGTH_MIN) / WAVELENGTH_COUNT as f64;
+        for pair in sorted.windows(2) {
+            assert!((pair[1] - pair[0] - spacing).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn gray_is_flat() {
+        let gray = Color::new(0.4, 0.4, 0.4);
+        let spectrum = SampledSpectrum::from_rgb(gray, &Wavelengths::sample(0.3));
+        assert!(spectrum
+            .values()
+            .iter()
+            .all(|value| (value - 0.4).abs() < crate::vec3::scalar_tolerance(1e-12)));
+    }
+
+    #[test]
+    fn neutral_gray_scene_matches_rgb() {
+        // Light from a gray emitter off two gray bounces
+        let light = Color::new(4.0, 4.0, 4.0);
+        let gray = Color::new(0.5, 0.5, 0.5);
+        let bounces = [(Color::default(), Color::default(), gray); 2];
+        let rgb = gray * gray * light;
+        let spectral = average_path(light, &bounces);
+        assert!(
+            (spectral - rgb).magnitude() < 1e-3,
+            "{spectral:?} != {rgb:?}"
+        );
+    }
+
+    #[test]
+    fn white_light_keeps_surface_colors() {
+        let orange = Color::new(0.9, 0.5, 0.1);
+        let bounces = [(Color::default(), Color::new(1.0, 1.0, 1.0), orange)];
+        let spectral = average_path(Color::default(), &bounces);
+        assert!((spectral - orange).magnitude() < 1e-3, "{spectral:?}");
+    }
+
+    #[test]
+    fn red_surface_under_green_light_differs_from_rgb() {
+        let red = Color::new(0.8, 0.05, 0.05);
+        let green_light = Color::new(0.0, 5.0, 0.0);
+        let bounces = [(Color::default(), Color::default(), red)];
+        let rgb = red * green_light;
+        let spectral = average_path(green_light, &bounces);
+        // The spectra overlap where the RGB components don't, so more light is reflected
+        assert!(spectral.x() + spectral.y() + spectral.z() > 2.0 * (rgb.x() + rgb.y() + rgb.z()));
+        assert!((spectral - rgb).magnitude() > 0.1, "{spectral:?} ~ {rgb:?}");
+    }
+}
@@ -1,16 +1,20 @@
 use std::sync::Arc;
 
 use crate::aabb::aabb::{surrounding_box, AABB};
-use crate::common::PI;
+use crate::common::{random_double, INFINITY, PI};
 use crate::hittable::{HitRecord, Hittable};
 use crate::interval::Interval;
 use crate::material::Material;
 use crate::rays::Ray;
 use crate::vec3::{Point3, Vec3};
 
+/// Sphere that can move and change its radius linearly during the shutter. At time 0 it is
+/// centered at the first center with the starting radius, and at time 1 at the second center with
+/// the end radius
 pub struct Sphere {
     center: Ray,
     radius: f64,
+    radius_change: f64,
     material: Arc<dyn Material>,
     bbox: AABB,
 }
@@ -18,32 +22,65 @@ pub struct Sphere {
 impl Hittable for Sphere {
     fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
         let current_center = self.center.at(ray.time());
+        let current_radius = self.radius_at(ray.time());
         let trajeto = ray.origin() - current_center;
         let a = ray.direction().dot_product(&ray.direction());
         let h = trajeto.dot_product(&ray.direction());
-        let c = trajeto.square_magnitude() - self.radius * self.radius;
+        let c = trajeto.square_magnitude() - current_radius * current_radius;
         let discriminant: f64 = h * h - a * c;
         if discriminant < 0.0 {
             return None;
         }
         let sqrt_d = f64::sqrt(discriminant);
-        let root = (-h - sqrt_d) / a;
-        if !time_interval.surround(root) {
-            return None;
-        }
+        // The far root is hit when the ray starts inside the sphere
+        let root = [(-h - sqrt_d) / a, (-h + sqrt_d) / a]
+            .into_iter()
+            .find(|&root| time_interval.surround(root))?;
         let mut rec = HitRecord::new();
         rec.set_t(root);
         rec.set_colision_point(ray.at(root));
-        rec.set_normal((rec.p() - current_center) / self.radius);
+        let outward_normal = (rec.p() - current_center) / current_radius;
+        rec.set_face_normal(ray, outward_normal);
         rec.set_material(self.material.clone());
-        let (u, v) = self.get_sphere_uv(&rec.normal());
+        let (u, v) = self.get_sphere_uv(&outward_normal);
         rec.set_u(u);
         rec.set_v(v);
+        let (dpdu, dpdv) = self.get_sphere_derivatives(u, v, current_radius);
+        rec.set_dpdu(dpdu);
+        rec.set_dpdv(dpdv);
 
         Some(rec)
     }
-    fn bounding_box(&self, _time_interval: &Interval) -> Option<AABB> {
-        Some(self.bbox)
+    /// Box around the sphere over the whole time interval. The center and the radius change
+    /// linearly, so it is enough to enclose the spheres at both ends of the interval
+    fn bounding_box(&self, time_interval: &Interval) -> Option<AABB> {
+        Some(surrounding_box(
+            &self.bbox_at(time_interval.min()),
+            &self.bbox_at(time_interval.max()),
+        ))
+    }
+    /// Density of `random` over solid angle: uniform inside the cone subtended by the sphere
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        if !self.hit_any(
+            &Ray::new(*origin, *direction, 0.0),
+            &Interval::new(0.001, INFINITY),
+        ) {
+            return 0.0;
+        }
+        let cos_theta_max = self.cos_theta_max(origin);
+        1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+    }
+    /// Vector from `origin` to the point of the sphere seen along a direction of the cone, so
+    /// the point is reached at t = 1 like `Quad::random`
+    fn random(&self, origin: &Point3) -> Vec3 {
+        let direction = self.random_cone(origin);
+        match self.hit(
+            &Ray::new(*origin, direction, 0.0),
+            &Interval::new(0.001, INFINITY),
+        ) {
+            Some(rec) => rec.t() * direction,
+            None => direction,
+        }
     }
 }
 
@@ -54,27 +91,35 @@ impl Sphere {
         radius: f64,
         material: Arc<dyn Material>,
     ) -> Sphere {
+        Sphere::growing(first_center, second_center, radius, radius, material)
+    }
+    /// Creates a sphere whose radius goes from `radius` at time 0 to `end_radius` at time 1. The
+    /// bounding box is the union of the boxes of the starting and ending spheres
+    pub fn growing(
+        first_center: Point3,
+        second_center: Option<Point3>,
+        radius: f64,
+        end_radius: f64,
+        material: Arc<dyn Material>,
+    ) -> Sphere {
+        let next_center = second_center.unwrap_or(first_center);
+        let center = Ray::new(first_center, next_center - first_center, 0.0);
+        let mut sphere = Sphere {
+            center,
+            radius,
+            radius_change: end_radius - radius,
+            material,
+            bbox: AABB::default(),
+        };
+        sphere.bbox = surrounding_box(&sphere.bbox_at(0.0), &sphere.bbox_at(1.0));
+        sphere
+    }
+    /// Box around the sphere at the given time
+    fn bbox_at(&self, time: f64) -> AABB {
+        let center = self.center.at(time);
+        let radius = self.radius_at(time).abs();
         let rvec = Vec3::new(radius, radius, radius);
-        if let Some(next_center) = second_center {
-            let center = Ray::new(first_center, next_center - first_center, 0.0);
-            let bbox1 = AABB::from_points(first_center - rvec, first_center + rvec);
-            let bbox2 = AABB::from_points(next_center - rvec, next_center + rvec);
-
-            Sphere {
-                center,
-                radius,
-                material,
-                bbox: surrounding_box(&bbox1, &bbox2),
-            }
-        } else {
-            let bbox = AABB::from_points(first_center - rvec, first_center + rvec);
-            Sphere {
-                center: Ray::new(first_center, Default::default(), 0.0),
-                radius,
-                material,
-                bbox,
-            }
-        }
+        AABB::from_points(center - rvec, center + rvec)
     }
     pub fn center(&self) -> Point3 {
         self.center.origin()
@@ -82,14 +127,51 @@ impl Sphere {
     pub fn radius(&self) -> f64 {
         self.radius
     }
+    /// Radius of the sphere at the given time
+    pub fn radius_at(&self, time: f64) -> f64 {
+        self.radius + self.radius_change * time
+    }
     pub fn bbox(&self) -> AABB {
         self.bbox
     }
+    /// Samples a unit direction uniformly inside the cone subtended by the sphere at time 0 as
+    /// seen from `origin`, so every direction hits it. From inside the sphere the cone is the
+    /// whole sphere of directions
+    pub fn random_cone(&self, origin: &Point3) -> Vec3 {
+        let axis = (self.center() - *origin).normalize();
+        let cos_theta_max = self.cos_theta_max(origin);
+        let r1 = random_double();
+        let r2 = random_double();
+        let z = 1.0 + r2 * (cos_theta_max - 1.0);
+        let phi = 2.0 * PI * r1;
+        let sin_theta = f64::sqrt(1.0 - z * z);
+        let (tangent, bitangent) = axis.orthonormal_basis();
+        tangent * (phi.cos() * sin_theta) + bitangent * (phi.sin() * sin_theta) + axis * z
+    }
+    /// Cosine of the half angle of the cone subtended by the sphere at time 0 from `origin`
+    fn cos_theta_max(&self, origin: &Point3) -> f64 {
+        let distance_squared = (self.center() - *origin).square_magnitude();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return -1.0;
+        }
+        f64::sqrt(1.0 - radius_squared / distance_squared)
+    }
     pub fn get_sphere_uv(&self, p: &Point3) -> (f64, f64) {
-        let theta = f64::acos(-p.y());
-        let phi = f64::atan2(-p.z(), p.x()) + PI;
+        let (theta, phi) = p.to_spherical();
         (phi / (2.0 * PI), theta / PI)
     }
+    /// Partial derivatives of the surface point with respect to the `(u, v)` given by
+    /// `get_sphere_uv`, where `phi = 2 * PI * u` and `theta = PI * v`, for the given radius
+    pub fn get_sphere_derivatives(&self, u: f64, v: f64, radius: f64) -> (Vec3, Vec3) {
+        let phi = 2.0 * PI * u;
+        let theta = PI * v;
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        let dpdu = 2.0 * PI * radius * Vec3::new(sin_theta * sin_phi, 0.0, sin_theta * cos_phi);
+        let dpdv = PI * radius * Vec3::new(-cos_theta * cos_phi, sin_theta, cos_theta * sin_phi);
+        (dpdu, dpdv)
+    }
 }
 
 #[cfg(test)]
@@ -97,6 +179,7 @@ mod tests {
     use crate::{material::Lambertian, textures::ConstantTexture};
 
     use super::*;
+    use crate::vec3::scalar_tolerance;
 
     #[test]
     fn check_bbox() {
@@ -139,4 +222,136 @@ mod tests {
         assert_eq!(hit.p(), Vec3::new(0.0, 0.0, -1.0));
         assert_eq!(hit.normal(), Vec3::new(0.0, 0.0, -1.0));
     }
+    #[test]
+    fn ray_from_inside_hits_the_far_wall() {
+        let sphere = Sphere::new(
+            Point3::new(0.0, 0.0, 0.0),
+            None,
+            2.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.1, 0.2, 0.3))),
+        );
+        let ray = Ray::new(Point3::new(0.0, 0.5, 0.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let hit = sphere
+            .hit(&ray, &Interval::new(0.001, f64::INFINITY))
+            .unwrap();
+        let far = f64::sqrt(4.0 - 0.25);
+        assert!((hit.t() - far).abs() < 1e-12);
+        assert!((hit.p() - Point3::new(0.0, 0.5, far)).magnitude() < 1e-12);
+        // The normal faces the ray, from the back of the surface
+        assert!(!hit.front_face());
+        assert!(hit.normal().dot_product(&ray.direction()) < 0.0);
+
+        // The near root is behind the start of the interval, the far one is still hit
+        let grazing = Ray::new(Point3::new(0.0, 0.0, -2.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let hit = sphere.hit(&grazing, &Interval::new(0.5, f64::INFINITY));
+        assert!((hit.unwrap().t() - 4.0).abs() < 1e-12);
+    }
+    #[test]
+    fn off_axis_hit_within_scalar_precision() {
+        // Bound on the error of a hit when the coordinates are stored as `Scalar`
+        let tolerance = scalar_tolerance(1e-12);
+        let (center, radius) = ([1.3, -0.7, 2.1], 0.9);
+        let (origin, direction) = ([-0.2, 0.4, -1.5], [0.35, -0.3, 0.9]);
+        let sphere = Sphere::new(
+            Point3::new(center[0], center[1], center[2]),
+            None,
+            radius,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.1, 0.2, 0.3))),
+        );
+        let ray = Ray::new(
+            Point3::new(origin[0], origin[1], origin[2]),
+            Vec3::new(direction[0], direction[1], direction[2]),
+            0.0,
+        );
+        let rec = sphere
+            .hit(&ray, &Interval::new(0.001, f64::INFINITY))
+            .unwrap();
+
+        // Near root of the quadratic, worked out in plain f64 whatever the storage
+        let oc: Vec<f64> = (0..3).map(|k| center[k] - origin[k]).collect();
+        let a: f64 = direction.iter().map(|d| d * d).sum();
+        let h: f64 = (0..3).map(|k| direction[k] * oc[k]).sum();
+        let c = oc.iter().map(|o| o * o).sum::<f64>() - radius * radius;
+        let t = (h - (h * h - a * c).sqrt()) / a;
+        assert!((rec.t() - t).abs() < tolerance, "{} != {t}", rec.t());
+        let p = rec.p().as_array();
+        for k in 0..3 {
+            assert!((p[k] - (origin[k] + t * direction[k])).abs() < tolerance);
+        }
+        assert!((rec.normal().magnitude() - 1.0).abs() < tolerance);
+    }
+    #[test]
+    fn check_growing_radius() {
+        let sphere = Sphere::growing(
+            Point3::new(0.0, 0.0, 0.0),
+            None,
+            1.0,
+            2.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.1, 0.2, 0.3))),
+        );
+        assert_eq!(sphere.radius_at(0.0), 1.0);
+        assert_eq!(sphere.radius_at(1.0), 2.0);
+        assert_eq!(sphere.bbox().min(), Vec3::new(-2.0, -2.0, -2.0));
+        assert_eq!(sphere.bbox().max(), Vec3::new(2.0, 2.0, 2.0));
+
+        let interval = Interval::new(0.0, f64::INFINITY);
+        let start = Ray::new(Point3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let end = Ray::new(Point3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 1.0);
+        let start = sphere.hit(&start, &interval).unwrap();
+        let end = sphere.hit(&end, &interval).unwrap();
+        assert_eq!(start.t(), 4.0);
+        assert_eq!(end.t(), 3.0);
+        assert_eq!(end.normal(), Vec3::new(0.0, 0.0, -1.0));
+    }
+    #[test]
+    fn check_derivatives_are_tangent() {
+        let sphere = Sphere::new(
+            Point3::new(0.0, 0.0, 0.0),
+            None,
+            2.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.1, 0.2, 0.3))),
+        );
+        let ray = Ray::new(Point3::new(1.0, 0.5, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let hit = sphere
+            .hit(&ray, &Interval::new(0.0, f64::INFINITY))
+            .unwrap();
+        let dpdu = hit.dpdu().unwrap();
+        let dpdv = hit.dpdv().unwrap();
+        assert!(dpdu.dot_product(&hit.normal()).abs() < scalar_tolerance(1e-9));
+        assert!(dpdv.dot_product(&hit.normal()).abs() < scalar_tolerance(1e-9));
+        // The parameterization is right handed with respect to the outward normal
+        assert!(dpdu.cross_product(&dpdv).dot_product(&hit.normal()) > 0.0);
+    }
+    #[test]
+    fn cone_samples_stay_inside_subtended_cone() {
+        let sphere = Sphere::new(
+            Point3::new(0.0, 0.0, 0.0),
+            None,
+            1.0,
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.1, 0.2, 0.3))),
+        );
+        let origin = Point3::new(3.0, 4.0, 0.0);
+        let axis = (sphere.center() - origin).normalize();
+        let half_angle = f64::asin(1.0 / 5.0);
+        for _ in 0..1000 {
+            let direction = sphere.random_cone(&origin);
+            assert!((direction.magnitude() - 1.0).abs() < scalar_tolerance(1e-9));
+            let angle = direction.dot_product(&axis).clamp(-1.0, 1.0).acos();
+            assert!(angle <= half_angle + 1e-9, "{angle} > {half_angle}");
+        }
+        let solid_angle = 2.0 * PI * (1.0 - half_angle.cos());
+        let pdf = sphere.pdf_value(&origin, &axis);
+        assert!((pdf - 1.0 / solid_angle).abs() < scalar_tolerance(1e-9));
+        assert_eq!(sphere.pdf_value(&origin, &-axis), 0.0);
+
+        // The vectors from `random` end on the sphere, from outside and from inside it
+        for origin in [origin, Point3::new(0.2, -0.3, 0.1)] {
+            for _ in 0..100 {
+                let point = origin + sphere.random(&origin);
+                assert!(
+                    ((point - sphere.center()).magnitude() - 1.0).abs() < scalar_tolerance(1e-9)
+                );
+            }
+        }
+    }
 }
@@ -1,3 +1,7 @@
+use std::{error::Error, fmt::Display, io};
+
+use image::ImageError;
+
 use crate::{
     color::Color, image::texture_map::read_image, interval::Interval, perlin::Perlin, vec3::Point3,
 };
@@ -17,16 +21,34 @@ pub struct CheckerPattern<T: Texture, U: Texture> {
     even: T,
     odd: U,
 }
+/// Texture from an image, 8 bits per channel normalized to [0, 1], or floats kept as they are for
+/// the high dynamic range images, whose radiance can be well above 1
 #[derive(Default)]
 pub struct ImageTexture {
     image: Vec<u8>,
+    image_f32: Vec<f32>,
     ux: u32,
     uy: u32,
 }
+/// Marble-like bands of Perlin turbulence along z, going from black to `color`, with
+/// `turbulence_depth` octaves of noise bending them
 #[derive(Clone, Default)]
 pub struct NoiseTexture {
     noise: Perlin,
     scale: f64,
+    color: Color,
+    turbulence_depth: usize,
+}
+/// Bricks laid in rows over the texture coordinates, every other row shifted by half a brick.
+/// The sizes are in texture space, and the mortar thickness is the width of the gap between two
+/// bricks. The edges of the mortar are blended with a smoothstep so they don't look jagged
+#[derive(Clone, Default)]
+pub struct BrickTexture {
+    mortar: Color,
+    brick: Color,
+    brick_width: f64,
+    brick_height: f64,
+    mortar_thickness: f64,
 }
 
 impl ConstantTexture {
@@ -72,7 +94,50 @@ impl<T: Texture, U: Texture> Texture for CheckerPattern<T, U> {
 
 impl ImageTexture {
     pub fn new(image: Vec<u8>, ux: u32, uy: u32) -> Self {
-        ImageTexture { image, ux, uy }
+        ImageTexture {
+            image,
+            image_f32: Vec::new(),
+            ux,
+            uy,
+        }
+    }
+    /// Creates a texture from linear float RGB pixels, row by row from the top, sampled without
+    /// any normalization
+    pub fn from_rgb_f32(image: Vec<f32>, ux: u32, uy: u32) -> Self {
+        assert_eq!(
+            image.len(),
+            3 * ux as usize * uy as usize,
+            "Expected {ux}x{uy} pixels of 3 channels"
+        );
+        ImageTexture {
+            image: Vec::new(),
+            image_f32: image,
+            ux,
+            uy,
+        }
+    }
+    /// Creates a texture from decoded 8 bits RGB pixels, row by row from the top
+    pub fn from_rgb(data: &[u8], width: u32, height: u32) -> Self {
+        ImageTexture::from_channels(data, width, height, 3)
+    }
+    /// Creates a texture from decoded 8 bits RGBA pixels, row by row from the top. The alpha is
+    /// dropped, textures are opaque
+    pub fn from_rgba(data: &[u8], width: u32, height: u32) -> Self {
+        ImageTexture::from_channels(data, width, height, 4)
+    }
+    /// Keeps the first three of every `channels` bytes, the layout `value` reads
+    fn from_channels(data: &[u8], width: u32, height: u32, channels: usize) -> Self {
+        let pixels = width as usize * height as usize;
+        assert_eq!(
+            data.len(),
+            pixels * channels,
+            "Expected {width}x{height} pixels of {channels} channels"
+        );
+        let image = data
+            .chunks_exact(channels)
+            .flat_map(|pixel| pixel[..3].iter().copied())
+            .collect();
+        ImageTexture::new(image, width, height)
     }
 }
 
@@ -81,9 +146,16 @@ impl Texture for ImageTexture {
         if self.uy <= 0 {
             return Color::new(0.0, 1.0, 1.0);
         }
-        let i = (Interval::new(0.0, 1.0).clamp(u) * self.ux as f64) as usize;
-        let j = ((1.0 - Interval::new(0.0, 1.0).clamp(v)) * self.uy as f64) as usize;
+        // u = 1 and v = 0 fall on the far edges, kept inside the last column and row
+        let i = ((Interval::new(0.0, 1.0).clamp(u) * self.ux as f64) as usize)
+            .min(self.ux as usize - 1);
+        let j = (((1.0 - Interval::new(0.0, 1.0).clamp(v)) * self.uy as f64) as usize)
+            .min(self.uy as usize - 1);
         let idx: usize = 3 * i + 3 * self.ux as usize * j;
+        if !self.image_f32.is_empty() {
+            let [r, g, b] = [0, 1, 2].map(|k| f64::from(self.image_f32[idx + k]));
+            return Color::new(r, g, b);
+        }
         let r = self.image[idx] as f64 / 255.0;
         let g = self.image[idx + 1] as f64 / 255.0;
         let b = self.image[idx + 2] as f64 / 255.0;
@@ -92,32 +164,240 @@ impl Texture for ImageTexture {
 }
 
 impl NoiseTexture {
+    /// Black and white marble, with 10 octaves of turbulence
     pub fn new(point_count: usize, scale: f64) -> Self {
+        NoiseTexture::with_color(point_count, scale, None, 10)
+    }
+    /// Marble of `color`, white if `None`, with `turbulence_depth` octaves of turbulence
+    pub fn with_color(
+        point_count: usize,
+        scale: f64,
+        color: Option<Color>,
+        turbulence_depth: usize,
+    ) -> Self {
         NoiseTexture {
             noise: Perlin::new(point_count),
             scale,
+            color: color.unwrap_or(Color::new(1.0, 1.0, 1.0)),
+            turbulence_depth,
         }
     }
 }
 
 impl Texture for NoiseTexture {
     fn value(&self, _u: f64, _v: f64, p: &Point3) -> Color {
-        Color::new(0.5, 0.5, 0.5)
-            * (1.0 + f64::sin(self.scale * p.z() + 10.0 * self.noise.turbulence(p, 10)))
+        let turbulence = self.noise.turbulence(p, self.turbulence_depth);
+        let intensity = 0.5 * (1.0 + f64::sin(self.scale * p.z() + 10.0 * turbulence));
+        intensity * self.color
+    }
+}
+
+impl BrickTexture {
+    pub fn new(
+        mortar: Color,
+        brick: Color,
+        brick_width: f64,
+        brick_height: f64,
+        mortar_thickness: f64,
+    ) -> Self {
+        BrickTexture {
+            mortar,
+            brick,
+            brick_width,
+            brick_height,
+            mortar_thickness,
+        }
     }
 }
 
+impl Texture for BrickTexture {
+    fn value(&self, u: f64, v: f64, _p: &Point3) -> Color {
+        let row = (v / self.brick_height).floor();
+        let offset = if row.rem_euclid(2.0) == 1.0 {
+            0.5 * self.brick_width
+        } else {
+            0.0
+        };
+        let x = (u + offset).rem_euclid(self.brick_width);
+        let y = v.rem_euclid(self.brick_height);
+        // Distance to the closest edge of the brick, the middle of the gap being on the edge
+        let distance = x
+            .min(self.brick_width - x)
+            .min(y)
+            .min(self.brick_height - y);
+        let half_gap = 0.5 * self.mortar_thickness;
+        let blend = 0.25 * half_gap;
+        let t = smoothstep(half_gap - blend, half_gap + blend, distance);
+        (1.0 - t) * self.mortar + t * self.brick
+    }
+}
+
+/// Hermite interpolation from 0 at `edge0` to 1 at `edge1`
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = Interval::new(0.0, 1.0).clamp((x - edge0) / (edge1 - edge0));
+    t * t * (3.0 - 2.0 * t)
+}
+
 impl From<Color> for ConstantTexture {
     fn from(value: Color) -> Self {
         Self { albedo: value }
     }
 }
 
+/// Why an image texture couldn't be loaded, with the path of the image
+#[derive(Debug)]
+pub enum TextureError {
+    NotFound(String),
+    UnsupportedFormat(String),
+    Invalid(String, ImageError),
+}
+
+impl Error for TextureError {}
+
+impl Display for TextureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            TextureError::NotFound(path) => write!(f, "Error, the image {} doesn't exist", path),
+            TextureError::UnsupportedFormat(path) => {
+                write!(f, "Error, the format of the image {} isn't supported", path)
+            }
+            TextureError::Invalid(path, error) => {
+                write!(f, "Error, couldn't read the image {}: {}", path, error)
+            }
+        }
+    }
+}
+
+/// Takes a `&str`, since `From<String>` already gives a `TryFrom<String>` that can't fail
+impl TryFrom<&str> for ImageTexture {
+    type Error = TextureError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let path = value.to_string();
+        read_image(path.clone()).map_err(|error| match error {
+            ImageError::IoError(e) if e.kind() == io::ErrorKind::NotFound => {
+                TextureError::NotFound(path)
+            }
+            ImageError::Unsupported(_) => TextureError::UnsupportedFormat(path),
+            error => TextureError::Invalid(path, error),
+        })
+    }
+}
+
+/// Same as `try_from`, but a texture that can't be loaded is logged and left empty, which renders
+/// as cyan
 impl From<String> for ImageTexture {
     fn from(value: String) -> Self {
-        match read_image(value) {
+        match ImageTexture::try_from(value.as_str()) {
             Ok(texture) => texture,
-            Err(_e) => ImageTexture::default(),
+            Err(e) => {
+                eprintln!("{}, using an empty texture", e);
+                ImageTexture::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::scalar_tolerance;
+
+    #[test]
+    fn noise_color_scales_the_bands() {
+        let white = NoiseTexture::new(256, 4.0);
+        let mut orange = white.clone();
+        orange.color = Color::new(1.0, 0.5, 0.2);
+        let mut blue = white.clone();
+        blue.color = Color::new(0.1, 0.2, 0.9);
+        for k in 0..10 {
+            let p = Point3::new(0.3 * k as f64, 0.7, 0.2 * k as f64);
+            let intensity = white.value(0.0, 0.0, &p);
+            assert_eq!(intensity.x(), intensity.y());
+            for texture in [&orange, &blue] {
+                let expected = intensity.x() * texture.color;
+                let error = (texture.value(0.0, 0.0, &p) - expected).magnitude();
+                assert!(error < scalar_tolerance(1e-12), "{error}");
+            }
         }
+        let grey = NoiseTexture::with_color(256, 4.0, Some(Color::new(0.5, 0.5, 0.5)), 10);
+        assert_eq!(grey.color, Color::new(0.5, 0.5, 0.5));
+        assert_eq!(grey.turbulence_depth, white.turbulence_depth);
+    }
+
+    #[test]
+    fn bricks_and_mortar() {
+        let mortar = Color::new(0.8, 0.8, 0.8);
+        let brick = Color::new(0.6, 0.2, 0.1);
+        let texture = BrickTexture::new(mortar, brick, 0.2, 0.1, 0.02);
+        let p = Point3::default();
+
+        assert_eq!(texture.value(0.1, 0.05, &p), brick);
+        assert_eq!(texture.value(0.2, 0.05, &p), mortar);
+        assert_eq!(texture.value(0.1, 0.1, &p), mortar);
+        // The second row is shifted by half a brick, so its gap is below the center of the first
+        assert_eq!(texture.value(0.1, 0.15, &p), mortar);
+        assert_eq!(texture.value(0.2, 0.15, &p), brick);
+        let edge = texture.value(0.2 - 0.01, 0.05, &p);
+        assert!(edge.x() < mortar.x() && edge.x() > brick.x());
+    }
+
+    #[test]
+    fn images_from_memory() {
+        let red = Color::new(1.0, 0.0, 0.0);
+        let rgba = [255, 0, 0, 128].repeat(4);
+        let texture = ImageTexture::from_rgba(&rgba, 2, 2);
+        for (u, v) in [(0.1, 0.1), (0.9, 0.1), (0.1, 0.9), (0.9, 0.9)] {
+            assert_eq!(texture.value(u, v, &Point3::default()), red);
+        }
+
+        // The top left pixel is green, the others blue
+        let mut rgb = [0, 0, 255].repeat(4);
+        rgb[..3].copy_from_slice(&[0, 255, 0]);
+        let texture = ImageTexture::from_rgb(&rgb, 2, 2);
+        let p = Point3::default();
+        assert_eq!(texture.value(0.1, 0.9, &p), Color::new(0.0, 1.0, 0.0));
+        assert_eq!(texture.value(0.9, 0.9, &p), Color::new(0.0, 0.0, 1.0));
+        assert_eq!(texture.value(0.1, 0.1, &p), Color::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn missing_image_is_an_error() {
+        let path = "textures/does_not_exist.png".to_string();
+        match ImageTexture::try_from(path.as_str()) {
+            Err(TextureError::NotFound(missing)) => assert_eq!(missing, path),
+            Err(e) => panic!("Expected a missing image, got {e}"),
+            Ok(_) => panic!("Expected a missing image to fail"),
+        }
+        let texture = ImageTexture::from(path);
+        assert_eq!(
+            texture.value(0.5, 0.5, &Point3::default()),
+            Color::new(0.0, 1.0, 1.0)
+        );
+    }
+
+    #[test]
+    fn hdr_image_keeps_values_above_one() {
+        // 2x1 Radiance HDR, a bright pixel next to a dim one
+        let path = std::env::temp_dir().join("hdr_image_keeps_values_above_one.hdr");
+        let pixels = vec![4.0, 4.0, 4.0, 0.25, 0.5, 0.125];
+        image::Rgb32FImage::from_raw(2, 1, pixels)
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        let texture = ImageTexture::try_from(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let p = Point3::default();
+        let bright = texture.value(0.25, 0.5, &p);
+        assert!(
+            (bright - Color::new(4.0, 4.0, 4.0)).magnitude() < 0.05,
+            "{bright:?}"
+        );
+        let dim = texture.value(0.75, 0.5, &p);
+        assert!(
+            (dim - Color::new(0.25, 0.5, 0.125)).magnitude() < 0.01,
+            "{dim:?}"
+        );
     }
 }
@@ -1,4 +1,5 @@
 use crate::{
+    aabb::aabb::AABB,
     hittable::{HitRecord, Hittable},
     interval::Interval,
     rays::Ray,
@@ -25,10 +26,43 @@ impl<H: Hittable> Hittable for Translate<H> {
         }
         None
     }
-    fn bounding_box(&self, time_interval: &Interval) -> Option<crate::aabb::aabb::AABB> {
-        self.object.bounding_box(time_interval).map(|mut b| {
-            b.set_max(self.offset);
-            b
-        })
+    fn bounding_box(&self, time_interval: &Interval) -> Option<AABB> {
+        self.object
+            .bounding_box(time_interval)
+            .map(|bbox| bbox.offset(self.offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{cube::Cube, material::Lambertian, textures::ConstantTexture, vec3::Point3};
+
+    #[test]
+    fn translated_box_moves_both_corners() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let cube = Cube::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 1.0, 1.0),
+            material,
+        );
+        let interval = Interval::new(0.0, 1.0);
+        let original = cube.bounding_box(&interval).unwrap();
+        let offset = Vec3::new(2.0, -3.0, 0.5);
+        let translated = Translate::new(cube, offset);
+        let bbox = translated.bounding_box(&interval).unwrap();
+        // The faces of the cube are padded a little
+        assert!((bbox.min() - Point3::new(2.0, -3.0, 0.5)).magnitude() < 1e-3);
+        assert!((bbox.max() - Point3::new(3.0, -2.0, 1.5)).magnitude() < 1e-3);
+        assert_eq!(bbox.min(), original.min() + offset);
+        assert_eq!(bbox.max(), original.max() + offset);
+
+        let ray = Ray::new(Point3::new(2.5, -2.5, -5.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let rec = translated
+            .hit(&ray, &Interval::new(0.001, f64::INFINITY))
+            .unwrap();
+        assert_eq!(rec.p(), Point3::new(2.5, -2.5, 0.5));
     }
 }
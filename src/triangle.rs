@@ -0,0 +1,344 @@
+use crate::aabb::aabb::AABB;
+use crate::color::Color;
+use crate::hittable::{HitRecord, Hittable};
+use crate::interval::Interval;
+use crate::material::Material;
+use crate::rays::Ray;
+use crate::vec3::{Point3, Vec3};
+use std::sync::Arc;
+
+/// Triangle with optional per-vertex normals, interpolated for smooth shading, and per-vertex
+/// texture coordinates. Without them, the normal is the one of the plane, on the side of
+/// `(b - a) x (c - a)`, and the texture coordinates are the barycentric ones of `b` and `c`. With
+/// flat shading the vertex normals are ignored, which helps to see the facets when debugging.
+/// The watertight intersection is slower but never lets a ray slip through, or hit twice, the edge
+/// shared by two triangles of a mesh
+pub struct Triangle {
+    vertices: [Point3; 3],
+    normals: Option<[Vec3; 3]>,
+    flat_shading: bool,
+    watertight: bool,
+    uvs: [(f64, f64); 3],
+    colors: Option<[Color; 3]>,
+    material: Arc<dyn Material>,
+    bbox: AABB,
+    normal: Vec3,
+}
+
+impl Triangle {
+    pub fn new(a: Point3, b: Point3, c: Point3, material: Arc<dyn Material>) -> Self {
+        let mut bbox = AABB::empty();
+        for vertex in [a, b, c] {
+            bbox.grow_to_include(vertex);
+        }
+        bbox.pad_to_minimum(0.0001);
+        let normal = (b - a).cross_product(&(c - a)).normalize();
+        Triangle {
+            vertices: [a, b, c],
+            normals: None,
+            flat_shading: false,
+            watertight: false,
+            uvs: [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)],
+            colors: None,
+            material,
+            bbox,
+            normal,
+        }
+    }
+    /// Sets the normals at the vertices, interpolated across the triangle for shading
+    pub fn with_normals(mut self, normals: [Vec3; 3]) -> Self {
+        self.normals = Some(normals.map(|normal| normal.normalize()));
+        self
+    }
+    /// Shades with the normal of the plane even if the vertices have normals
+    pub fn with_flat_shading(mut self, flat_shading: bool) -> Self {
+        self.flat_shading = flat_shading;
+        self
+    }
+    /// Intersects with the watertight test of Woop et al. instead of Möller–Trumbore
+    pub fn with_watertight(mut self, watertight: bool) -> Self {
+        self.watertight = watertight;
+        self
+    }
+    /// Sets the texture coordinates at the vertices
+    pub fn with_uvs(mut self, uvs: [(f64, f64); 3]) -> Self {
+        self.uvs = uvs;
+        self
+    }
+    /// Sets the colors at the vertices, interpolated across the triangle and multiplied into the
+    /// albedo of diffuse and metal materials
+    pub fn with_vertex_colors(mut self, colors: [Color; 3]) -> Self {
+        self.colors = Some(colors);
+        self
+    }
+    /// Colors the whole triangle with `tint`
+    pub fn with_tint(self, tint: Color) -> Self {
+        self.with_vertex_colors([tint; 3])
+    }
+    /// Normal of the plane of the triangle
+    pub fn normal(&self) -> Vec3 {
+        self.normal
+    }
+    /// Partial derivatives of the surface point with respect to the texture coordinates
+    fn derivatives(&self) -> (Vec3, Vec3) {
+        let [a, b, c] = self.vertices;
+        let (e1, e2) = (b - a, c - a);
+        let [uv0, uv1, uv2] = self.uvs;
+        let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+        let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+        let determinant = du1 * dv2 - dv1 * du2;
+        if determinant.abs() < 1e-12 {
+            return (e1, e2);
+        }
+        (
+            (dv2 * e1 - dv1 * e2) / determinant,
+            (du1 * e2 - du2 * e1) / determinant,
+        )
+    }
+    /// Möller–Trumbore intersection: the time of the hit and the barycentric coordinates of it
+    fn moller_trumbore(&self, ray: &Ray) -> Option<(f64, [f64; 3])> {
+        let [a, b, c] = self.vertices;
+        let (e1, e2) = (b - a, c - a);
+        let p = ray.direction().cross_product(&e2);
+        let determinant = e1.dot_product(&p);
+        if determinant.abs() < 1e-12 {
+            return None;
+        }
+        let inverse = 1.0 / determinant;
+        let s = ray.origin() - a;
+        let beta = s.dot_product(&p) * inverse;
+        if !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+        let q = s.cross_product(&e1);
+        let gamma = ray.direction().dot_product(&q) * inverse;
+        if gamma < 0.0 || beta + gamma > 1.0 {
+            return None;
+        }
+        let t = e2.dot_product(&q) * inverse;
+        Some((t, [1.0 - beta - gamma, beta, gamma]))
+    }
+    /// Watertight intersection of Woop, Benthin and Wald: the vertices are moved to a space where
+    /// the ray starts at the origin and runs along +z, and the edge functions are evaluated there
+    /// from the same rounded coordinates for every triangle sharing an edge. A ray exactly on an
+    /// edge belongs to the triangle for which the edge runs up, or left if it is horizontal, so
+    /// of two neighbours only one takes it
+    fn watertight(&self, ray: &Ray) -> Option<(f64, [f64; 3])> {
+        let direction = ray.direction().as_array();
+        let kz = (0..3)
+            .max_by(|&i, &k| direction[i].abs().total_cmp(&direction[k].abs()))
+            .unwrap();
+        let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+        // Keeps the winding of the triangle when the ray runs along -z
+        if direction[kz] < 0.0 {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        let shear_x = direction[kx] / direction[kz];
+        let shear_y = direction[ky] / direction[kz];
+        let shear_z = 1.0 / direction[kz];
+        let [a, b, c] = self.vertices.map(|vertex| {
+            let p = (vertex - ray.origin()).as_array();
+            (
+                p[kx] - shear_x * p[kz],
+                p[ky] - shear_y * p[kz],
+                shear_z * p[kz],
+            )
+        });
+
+        let edge = |p: (f64, f64, f64), q: (f64, f64, f64)| p.0 * q.1 - p.1 * q.0;
+        let (u, v, w) = (edge(c, b), edge(a, c), edge(b, a));
+        let determinant = u + v + w;
+        if determinant == 0.0 {
+            return None;
+        }
+        let inside = |value: f64, p: (f64, f64, f64), q: (f64, f64, f64)| {
+            if value != 0.0 {
+                return (value > 0.0) == (determinant > 0.0);
+            }
+            let (dx, dy) = (
+                (q.0 - p.0) * determinant.signum(),
+                (q.1 - p.1) * determinant.signum(),
+            );
+            dy > 0.0 || (dy == 0.0 && dx < 0.0)
+        };
+        if !(inside(u, b, c) && inside(v, c, a) && inside(w, a, b)) {
+            return None;
+        }
+        let t = (u * a.2 + v * b.2 + w * c.2) / determinant;
+        Some((t, [u / determinant, v / determinant, w / determinant]))
+    }
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, ray: &Ray, time_interval: &Interval) -> Option<HitRecord> {
+        let (t, [alpha, beta, gamma]) = if self.watertight {
+            self.watertight(ray)?
+        } else {
+            self.moller_trumbore(ray)?
+        };
+        if !time_interval.contains(t) {
+            return None;
+        }
+
+        let mut rec = HitRecord::new();
+        rec.set_t(t);
+        rec.set_colision_point(ray.at(t));
+        rec.set_face_normal(ray, self.normal);
+        if let Some([na, nb, nc]) = self.normals.filter(|_| !self.flat_shading) {
+            let shading = (alpha * na + beta * nb + gamma * nc).normalize();
+            rec.set_normal(if rec.front_face() { shading } else { -shading });
+        }
+        let [uv0, uv1, uv2] = self.uvs;
+        rec.set_u(alpha * uv0.0 + beta * uv1.0 + gamma * uv2.0);
+        rec.set_v(alpha * uv0.1 + beta * uv1.1 + gamma * uv2.1);
+        let (dpdu, dpdv) = self.derivatives();
+        rec.set_dpdu(dpdu);
+        rec.set_dpdv(dpdv);
+        if let Some([ca, cb, cc]) = self.colors {
+            rec.set_tint(alpha * ca + beta * cb + gamma * cc);
+        }
+        rec.set_material(self.material.clone());
+        Some(rec)
+    }
+    fn bounding_box(&self, _time_interval: &Interval) -> Option<AABB> {
+        Some(self.bbox)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::scalar_tolerance;
+    use crate::{material::Lambertian, textures::ConstantTexture};
+
+    fn unit_triangle() -> Triangle {
+        Triangle::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5))),
+        )
+    }
+
+    #[test]
+    fn centroid_hit_has_barycentric_uvs() {
+        let triangle = unit_triangle();
+        let interval = Interval::new(0.001, f64::INFINITY);
+        let centroid = Point3::new(1.0, 1.0, 0.0) / 3.0;
+        let ray = Ray::new(
+            centroid + Vec3::new(0.0, 0.0, 2.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            0.0,
+        );
+        let rec = triangle.hit(&ray, &interval).unwrap();
+        assert!((rec.t() - 2.0).abs() < scalar_tolerance(1e-9));
+        assert!((rec.p() - centroid).magnitude() < scalar_tolerance(1e-9));
+        assert!(
+            (rec.u() - 1.0 / 3.0).abs() < scalar_tolerance(1e-9)
+                && (rec.v() - 1.0 / 3.0).abs() < scalar_tolerance(1e-9)
+        );
+
+        // Just past the hypotenuse, and just past the edge along x
+        for point in [
+            Point3::new(0.5005, 0.5005, 2.0),
+            Point3::new(0.5, -0.001, 2.0),
+        ] {
+            let ray = Ray::new(point, Vec3::new(0.0, 0.0, -1.0), 0.0);
+            assert!(triangle.hit(&ray, &interval).is_none(), "{point:?}");
+        }
+        assert!(triangle.bbox.max().z() - triangle.bbox.min().z() > 0.0);
+    }
+
+    #[test]
+    fn back_face_flips_the_normal() {
+        let triangle = unit_triangle();
+        let interval = Interval::new(0.001, f64::INFINITY);
+        let front = Ray::new(Point3::new(0.2, 0.2, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let rec = triangle.hit(&front, &interval).unwrap();
+        assert!(rec.front_face());
+        assert_eq!(rec.normal(), Vec3::new(0.0, 0.0, 1.0));
+
+        let back = Ray::new(Point3::new(0.2, 0.2, -1.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let rec = triangle.hit(&back, &interval).unwrap();
+        assert!(!rec.front_face());
+        assert_eq!(rec.normal(), Vec3::new(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn flat_shading_ignores_vertex_normals() {
+        let material = Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        let smooth = Triangle::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            material,
+        )
+        .with_normals([
+            Vec3::new(-1.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.0, 1.0),
+            Vec3::new(0.0, 1.0, 1.0),
+        ]);
+        let ray = Ray::new(Point3::new(0.25, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let interval = Interval::new(0.001, f64::INFINITY);
+
+        let smooth_normal = smooth.hit(&ray, &interval).unwrap().normal();
+        let expected = (0.5 * Vec3::new(-1.0, 0.0, 1.0).normalize()
+            + 0.25 * Vec3::new(1.0, 0.0, 1.0).normalize()
+            + 0.25 * Vec3::new(0.0, 1.0, 1.0).normalize())
+        .normalize();
+        assert!((smooth_normal - expected).magnitude() < 1e-9);
+
+        let flat = smooth.with_flat_shading(true);
+        assert_eq!(flat.hit(&ray, &interval).unwrap().normal(), flat.normal());
+        assert_eq!(flat.normal(), Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn shared_edge_is_hit_exactly_once() {
+        let material: Arc<dyn Material> =
+            Arc::new(Lambertian::new(ConstantTexture::from_points(0.5, 0.5, 0.5)));
+        // Two triangles wound the same way, sharing the edge from (0, -1) to (0, 1)
+        let left = Triangle::new(
+            Point3::new(-1.0, 0.0, 0.0),
+            Point3::new(0.0, -1.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            material.clone(),
+        )
+        .with_watertight(true);
+        let right = Triangle::new(
+            Point3::new(0.0, -1.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            material,
+        )
+        .with_watertight(true);
+        let interval = Interval::new(0.001, f64::INFINITY);
+
+        for k in 0..19 {
+            let y = -0.9 + 0.1 * k as f64;
+            // Straight at the edge from both sides, and slanted so the edge is met off the axes
+            for (origin, direction) in [
+                (Point3::new(0.0, y, 2.0), Vec3::new(0.0, 0.0, -1.0)),
+                (Point3::new(0.0, y, -2.0), Vec3::new(0.0, 0.0, 1.0)),
+                (
+                    Point3::new(0.3, y + 0.7, 3.0),
+                    Vec3::new(-0.1, -0.7 / 3.0, -1.0),
+                ),
+            ] {
+                let ray = Ray::new(origin, direction, 0.0);
+                let hits = [&left, &right]
+                    .iter()
+                    .filter_map(|triangle| triangle.hit(&ray, &interval))
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    hits.len(),
+                    1,
+                    "ray from {origin:?} hit {} times",
+                    hits.len()
+                );
+                let p = hits[0].p();
+                assert!(p.x().abs() < 1e-9 && p.z().abs() < 1e-9);
+            }
+        }
+    }
+}
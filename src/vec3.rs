@@ -1,6 +1,34 @@
 use crate::common::{random_double, random_double_range};
 use std::fmt::{Display, Formatter, Result};
+use std::iter::{Product, Sum};
 use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+
+/// Type the coordinates are stored in. The `f32` feature halves the size of the vectors, and of
+/// everything made of them like meshes and images, at the cost of precision. The API stays in
+/// `f64` either way: the coordinates are widened when read and the arithmetic runs in `f64`, only
+/// the results are rounded when they are stored
+#[cfg(not(feature = "f32"))]
+pub type Scalar = f64;
+#[cfg(feature = "f32")]
+pub type Scalar = f32;
+
+/// Coordinate in double precision, a no-op unless the `f32` feature is on
+#[allow(clippy::unnecessary_cast)]
+const fn widen(value: Scalar) -> f64 {
+    value as f64
+}
+
+/// Tolerance of the tests on values worked out from coordinates stored as `Scalar`: `bound` in
+/// double precision, and no less than the rounding of single precision with the `f32` feature
+#[cfg(test)]
+pub(crate) fn scalar_tolerance(bound: f64) -> f64 {
+    if cfg!(feature = "f32") {
+        bound.max(1e-5)
+    } else {
+        bound
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 /// Implementation of a 3D vector
 /// # Examples
@@ -14,7 +42,8 @@ use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssi
 /// assert_eq!(c.z(), -3.);
 /// ```
 /// # Notes
-/// All the fields are private, so you need to use the getters and setters to access them
+/// All the fields are private, so you need to use the getters and setters to access them. They
+/// are stored as `Scalar`
 /// # Fields
 /// * `x` - The x coordinate of the vector
 /// * `y` - The y coordinate of the vector
@@ -35,92 +64,92 @@ use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssi
 /// * `dot_product(other: &Vec3) -> f64` - Returns the dot product of the vector with another vector
 /// * `cross_product_inplace(other: &Vec3)` - Calculates the cross product of the vector with another vector in place
 /// * `cross_product(other: &Vec3) -> Vec3` - Calculates the cross product of the vector with another vector
+/// * `to_spherical() -> (f64, f64)` - Returns the spherical coordinates (theta, phi) of a unit vector
 /// * `random() -> Vec3` - Returns a random vector
 /// * `random_range(min: f64, max: f64) -> Vec3` - Returns a random vector with coordinates in the given range
 /// * `near_zero() -> bool` - Returns true if the vector is near zero
+/// * `recip() -> Vec3` - Returns the component-wise reciprocal of the vector
+/// * `orthonormal_basis() -> (Vec3, Vec3)` - Returns two tangents that complete an orthonormal basis with the vector
+/// * `project_onto(other: &Vec3) -> Vec3` - Returns the component of the vector parallel to another vector
+/// * `reject_from(other: &Vec3) -> Vec3` - Returns the component of the vector perpendicular to another vector
+/// * `mean(iter: impl Iterator<Item = Vec3>) -> Vec3` - Returns the average of the vectors, or zero if there are none
+/// * `as_f32_array() -> [f32; 3]` - Returns the coordinates in single precision
+/// * `from_f32_array(array: [f32; 3]) -> Vec3` - Creates a new Vec3 from single precision coordinates
 ///
 pub struct Vec3 {
-    x: f64,
-    y: f64,
-    z: f64,
+    x: Scalar,
+    y: Scalar,
+    z: Scalar,
 }
 pub type Point3 = Vec3;
 
 impl Default for Vec3 {
     fn default() -> Self {
-        Vec3 {
-            x: 0.,
-            y: 0.,
-            z: 0.,
-        }
+        Vec3::new(0., 0., 0.)
     }
 }
 
 impl Vec3 {
-    pub fn new(x: f64, y: f64, z: f64) -> Self {
-        Vec3 { x, y, z }
+    pub const fn new(x: f64, y: f64, z: f64) -> Self {
+        Vec3 {
+            x: x as Scalar,
+            y: y as Scalar,
+            z: z as Scalar,
+        }
     }
     pub fn unit_vector() -> Self {
         Vec3::new(1., 1., 1.)
     }
     pub fn x(&self) -> f64 {
-        self.x
+        widen(self.x)
     }
     pub fn y(&self) -> f64 {
-        self.y
+        widen(self.y)
     }
     pub fn z(&self) -> f64 {
-        self.z
+        widen(self.z)
     }
     pub fn set_x(&mut self, x: f64) {
-        self.x = x;
+        self.x = x as Scalar;
     }
     pub fn set_y(&mut self, y: f64) {
-        self.y = y;
+        self.y = y as Scalar;
     }
     pub fn set_z(&mut self, z: f64) {
-        self.z = z;
+        self.z = z as Scalar;
     }
     pub fn square_magnitude(&self) -> f64 {
-        self.x * self.x + self.y * self.y + self.z * self.z
+        self.x() * self.x() + self.y() * self.y() + self.z() * self.z()
     }
     pub fn magnitude(&self) -> f64 {
         f64::sqrt(self.square_magnitude())
     }
     pub fn normalize_inplace(&mut self) {
         // Possível bug pode vir daqui
-        let magnitude = self.magnitude();
-        self.x /= magnitude;
-        self.y /= magnitude;
-        self.z /= magnitude;
+        *self = self.normalize();
     }
 
     pub fn normalize(&self) -> Self {
         // Possível bug pode vir daqui
         let magnitude = self.magnitude();
-        Vec3 {
-            x: self.x / magnitude,
-            y: self.y / magnitude,
-            z: self.z / magnitude,
-        }
+        Vec3::new(
+            self.x() / magnitude,
+            self.y() / magnitude,
+            self.z() / magnitude,
+        )
     }
     pub fn dot_product(&self, other: &Vec3) -> f64 {
-        self.x * other.x + self.y * other.y + self.z * other.z
+        self.x() * other.x() + self.y() * other.y() + self.z() * other.z()
     }
     pub fn cross_product_inplace(&mut self, other: &Vec3) {
-        let new_x = self.y * other.z - self.z * other.y;
-        let new_y = self.z * other.x - self.x * other.z;
-        let new_z = self.x * other.y - self.y * other.x;
-        self.x = new_x;
-        self.y = new_y;
-        self.z = new_z;
+        *self = self.cross_product(other);
     }
     pub fn cross_product(&self, other: &Vec3) -> Self {
-        Vec3 {
-            x: self.y * other.z - self.z * other.y,
-            y: self.z * other.x - self.x * other.z,
-            z: self.x * other.y - self.y * other.x,
-        }
+        Vec3::new(
+            self.y() * other.z() - self.z() * other.y(),
+            self.z() * other.x() - self.x() * other.z(),
+            self.x() * other.y() - self.y() * other.x(),
+        )
     }
     pub fn random() -> Self {
         Self::new(random_double(), random_double(), random_double())
@@ -134,24 +163,82 @@ impl Vec3 {
     }
     pub fn near_zero(&self) -> bool {
         const EPS: f64 = 1e-8;
-        self.x.abs() < EPS && self.y.abs() < EPS && self.z.abs() < EPS
+        self.x().abs() < EPS && self.y().abs() < EPS && self.z().abs() < EPS
+    }
+    pub fn recip(&self) -> Self {
+        Vec3::new(self.x().recip(), self.y().recip(), self.z().recip())
+    }
+    /// Returns two unit tangents that, together with this vector, form a right handed orthonormal
+    /// basis. The vector must be normalized. Uses the branchless construction from Duff et al.,
+    /// "Building an Orthonormal Basis, Revisited", which stays stable near the poles
+    pub fn orthonormal_basis(&self) -> (Vec3, Vec3) {
+        let sign = f64::copysign(1.0, self.z());
+        let a = -1.0 / (sign + self.z());
+        let b = self.x() * self.y() * a;
+        let tangent = Vec3::new(
+            1.0 + sign * self.x() * self.x() * a,
+            sign * b,
+            -sign * self.x(),
+        );
+        let bitangent = Vec3::new(b, sign + self.y() * self.y() * a, -self.y());
+        (tangent, bitangent)
+    }
+    /// Component of the vector parallel to `other`. Projecting onto a zero vector gives zero
+    pub fn project_onto(&self, other: &Vec3) -> Self {
+        let square_magnitude = other.square_magnitude();
+        if square_magnitude == 0.0 {
+            return Vec3::default();
+        }
+        *other * (self.dot_product(other) / square_magnitude)
+    }
+    /// Component of the vector perpendicular to `other`, so that the projection and the
+    /// rejection add up to the vector
+    pub fn reject_from(&self, other: &Vec3) -> Self {
+        *self - self.project_onto(other)
+    }
+    /// Spherical coordinates `(theta, phi)` of a unit vector. `theta` goes from 0 at -y to PI at
+    /// +y, and `phi` from 0 at -x, through +z, -z, up to 2 PI around the y axis. Inverse of
+    /// `from_spherical`
+    pub fn to_spherical(&self) -> (f64, f64) {
+        let theta = f64::acos((-self.y()).clamp(-1.0, 1.0));
+        let phi = f64::atan2(-self.z(), self.x()) + std::f64::consts::PI;
+        (theta, phi)
+    }
+    /// Average of the vectors of the iterator. An empty iterator gives zero
+    pub fn mean(iter: impl Iterator<Item = Vec3>) -> Self {
+        let (sum, count) = iter.fold((Vec3::default(), 0usize), |(sum, count), v| {
+            (sum + v, count + 1)
+        });
+        if count == 0 {
+            return sum;
+        }
+        sum / count as f64
     }
     pub fn as_array(&self) -> [f64; 3] {
-        [self.x, self.y, self.z]
+        [self.x(), self.y(), self.z()]
+    }
+    /// Coordinates rounded to single precision, as GPUs and most image libraries want them
+    #[allow(clippy::unnecessary_cast)]
+    pub fn as_f32_array(&self) -> [f32; 3] {
+        [self.x as f32, self.y as f32, self.z as f32]
+    }
+    pub fn from_f32_array(array: [f32; 3]) -> Self {
+        let [x, y, z] = array.map(f64::from);
+        Vec3::new(x, y, z)
     }
     pub fn axis(&self, axis: usize) -> f64 {
         match axis {
-            0 => self.x,
-            1 => self.y,
-            2 => self.z,
+            0 => self.x(),
+            1 => self.y(),
+            2 => self.z(),
             _ => panic!("No axis founded"),
         }
     }
     pub fn set_axis(&mut self, axis: usize, value: f64) {
         match axis {
-            0 => self.x = value,
-            1 => self.y = value,
-            2 => self.z = value,
+            0 => self.x = value as Scalar,
+            1 => self.y = value as Scalar,
+            2 => self.z = value as Scalar,
             _ => panic!("No axis founded"),
         }
     }
@@ -188,13 +275,20 @@ pub fn random_on_disk() -> Vec3 {
         }
     }
 }
+/// Unit vector with the spherical coordinates `(theta, phi)`, in the convention of
+/// `Vec3::to_spherical`
+pub fn from_spherical(theta: f64, phi: f64) -> Vec3 {
+    let (sin_theta, cos_theta) = theta.sin_cos();
+    let (sin_phi, cos_phi) = phi.sin_cos();
+    Vec3::new(-cos_phi * sin_theta, -cos_theta, sin_phi * sin_theta)
+}
 /// Returns the reflection of a vector
 pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
     *v - 2.0 * v.dot_product(n) * *n
 }
 /// Returns the simulation of a refraction
 pub fn refract(uv: &Vec3, n: &Vec3, etai_over_etat: f64) -> Vec3 {
-    let cos_theta = f64::min(uv.dot_product(n), 1.0);
+    let cos_theta = f64::min(-uv.dot_product(n), 1.0);
     let r_out_perp = etai_over_etat * (*uv + cos_theta * *n);
     let r_out_parallel = -f64::sqrt(f64::abs(1.0 - r_out_perp.square_magnitude())) * *n;
     r_out_perp + r_out_parallel
@@ -208,91 +302,71 @@ pub fn reflectance(cosine: f64, refraction_index: f64) -> f64 {
 
 impl SubAssign<Vec3> for Vec3 {
     fn sub_assign(&mut self, rhs: Self) {
-        self.x = self.x - rhs.x;
-        self.y = self.y - rhs.y;
-        self.z = self.z - rhs.z;
+        *self = *self - rhs;
     }
 }
 impl AddAssign<Vec3> for Vec3 {
     fn add_assign(&mut self, rhs: Self) {
-        self.x = self.x + rhs.x;
-        self.y = self.y + rhs.y;
-        self.z = self.z + rhs.z;
+        *self = *self + rhs;
     }
 }
 impl MulAssign<f64> for Vec3 {
     fn mul_assign(&mut self, rhs: f64) {
-        self.x = self.x * rhs;
-        self.y = self.y * rhs;
-        self.z = self.z * rhs;
+        *self = *self * rhs;
     }
 }
 impl DivAssign<f64> for Vec3 {
     fn div_assign(&mut self, rhs: f64) {
-        self.x = self.x / rhs;
-        self.y = self.y / rhs;
-        self.z = self.z / rhs;
+        *self = *self / rhs;
     }
 }
 
 impl Mul<f64> for Vec3 {
     type Output = Vec3;
     fn mul(self, rhs: f64) -> Self {
-        Vec3::new(self.x * rhs, self.y * rhs, self.z * rhs)
+        Vec3::new(self.x() * rhs, self.y() * rhs, self.z() * rhs)
     }
 }
 
 impl Mul<Vec3> for f64 {
     type Output = Vec3;
     fn mul(self, rhs: Vec3) -> Vec3 {
-        Vec3::new(rhs.x * self, rhs.y * self, rhs.z * self)
+        Vec3::new(rhs.x() * self, rhs.y() * self, rhs.z() * self)
     }
 }
 
 impl Div<f64> for Vec3 {
     type Output = Vec3;
     fn div(self, rhs: f64) -> Self {
-        Vec3::new(self.x / rhs, self.y / rhs, self.z / rhs)
+        Vec3::new(self.x() / rhs, self.y() / rhs, self.z() / rhs)
     }
 }
 
 impl Div<Vec3> for f64 {
     type Output = Vec3;
     fn div(self, rhs: Vec3) -> Vec3 {
-        Vec3::new(rhs.x / self, rhs.y / self, rhs.z / self)
+        Vec3::new(rhs.x() / self, rhs.y() / self, rhs.z() / self)
     }
 }
 
 impl Add for Vec3 {
     type Output = Vec3;
     fn add(self, rhs: Self) -> Self {
-        Vec3 {
-            x: self.x + rhs.x,
-            y: self.y + rhs.y,
-            z: self.z + rhs.z,
-        }
+        Vec3::new(self.x() + rhs.x(), self.y() + rhs.y(), self.z() + rhs.z())
     }
 }
 
 impl Sub for Vec3 {
     type Output = Vec3;
     fn sub(self, rhs: Self) -> Self {
-        Vec3 {
-            x: self.x - rhs.x,
-            y: self.y - rhs.y,
-            z: self.z - rhs.z,
-        }
+        Vec3::new(self.x() - rhs.x(), self.y() - rhs.y(), self.z() - rhs.z())
     }
 }
 
 impl Neg for Vec3 {
     type Output = Vec3;
     fn neg(self) -> Self {
-        Vec3 {
-            x: -self.x,
-            y: -self.y,
-            z: -self.z,
-        }
+        Vec3::new(-self.x(), -self.y(), -self.z())
     }
 }
 impl Mul for Vec3 {
@@ -303,9 +377,28 @@ impl Mul for Vec3 {
     }
 }
 
+impl Sum for Vec3 {
+    fn sum<I: Iterator<Item = Vec3>>(iter: I) -> Self {
+        iter.fold(Vec3::default(), |acc, v| acc + v)
+    }
+}
+
+impl<'a> Sum<&'a Vec3> for Vec3 {
+    fn sum<I: Iterator<Item = &'a Vec3>>(iter: I) -> Self {
+        iter.copied().sum()
+    }
+}
+
+/// Component-wise product, like the attenuations along a path
+impl Product for Vec3 {
+    fn product<I: Iterator<Item = Vec3>>(iter: I) -> Self {
+        iter.fold(Vec3::new(1., 1., 1.), |acc, v| acc * v)
+    }
+}
+
 impl Display for Vec3 {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "{} {} {}", self.x, self.y, self.z)
+        write!(f, "{} {} {}", self.x(), self.y(), self.z())
     }
 }
 
@@ -322,6 +415,22 @@ mod tests {
         assert_eq!(c.y(), 6.);
         assert_eq!(c.z(), -3.);
     }
+
+    #[test]
+    fn test_refract() {
+        let n = Vec3::new(0.0, 0.0, 1.0);
+        // Normal incidence goes straight through
+        let straight = refract(&Vec3::new(0.0, 0.0, -1.0), &n, 1.0 / 1.5);
+        assert!((straight - Vec3::new(0.0, 0.0, -1.0)).magnitude() < scalar_tolerance(1e-12));
+        // At 45 degrees the sine of the refracted angle follows Snell's law, and the ray keeps
+        // going into the surface
+        let incident = Vec3::new(1.0, 0.0, -1.0).normalize();
+        let refracted = refract(&incident, &n, 1.0 / 1.5);
+        let sin_theta = f64::sin(std::f64::consts::FRAC_PI_4) / 1.5;
+        assert!((refracted.magnitude() - 1.0).abs() < scalar_tolerance(1e-12));
+        assert!((refracted.x() - sin_theta).abs() < scalar_tolerance(1e-12));
+        assert!(refracted.y().abs() < scalar_tolerance(1e-12) && refracted.z() < 0.0);
+    }
     #[test]
     fn test_array() {
         let a = Vec3::new(1., 2., 3.);
@@ -330,4 +439,107 @@ mod tests {
         assert_eq!(b[1], 2.);
         assert_eq!(b[2], 3.);
     }
+    #[test]
+    fn test_orthonormal_basis() {
+        let normals = [
+            Vec3::new(0., 0., 1.),
+            Vec3::new(0., 0., -1.),
+            Vec3::new(1., 0., 0.),
+            Vec3::new(0., -1., 0.),
+            Vec3::new(1e-9, -1e-9, -1.).normalize(),
+            Vec3::new(1e-5, 1e-5, 1.).normalize(),
+            Vec3::new(1., 2., 3.).normalize(),
+            Vec3::new(-0.3, 0.8, -0.5).normalize(),
+        ];
+        for n in normals {
+            let (t, b) = n.orthonormal_basis();
+            assert!(
+                (t.magnitude() - 1.).abs() < scalar_tolerance(1e-12),
+                "{}",
+                n
+            );
+            assert!(
+                (b.magnitude() - 1.).abs() < scalar_tolerance(1e-12),
+                "{}",
+                n
+            );
+            assert!(t.dot_product(&b).abs() < scalar_tolerance(1e-12), "{}", n);
+            assert!(t.dot_product(&n).abs() < scalar_tolerance(1e-12), "{}", n);
+            assert!(b.dot_product(&n).abs() < scalar_tolerance(1e-12), "{}", n);
+            assert!(
+                (t.cross_product(&b) - n).magnitude() < scalar_tolerance(1e-12),
+                "{}",
+                n
+            );
+        }
+    }
+    #[test]
+    fn test_f32_round_trip() {
+        let v = Vec3::new(0.1, -123.456, 1.0e-3);
+        let array = v.as_f32_array();
+        assert_eq!(array, [0.1_f32, -123.456_f32, 1.0e-3_f32]);
+        let back = Vec3::from_f32_array(array);
+        for (original, converted) in v.as_array().iter().zip(back.as_array()) {
+            assert!((original - converted).abs() <= original.abs() * f64::from(f32::EPSILON));
+        }
+        assert_eq!(
+            Vec3::from_f32_array([0.5, 2.0, -4.0]),
+            Vec3::new(0.5, 2.0, -4.0)
+        );
+    }
+    #[test]
+    fn test_spherical_round_trip() {
+        let directions = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(1.0, 2.0, -3.0),
+            Vec3::new(-0.5, -0.2, 0.7),
+        ];
+        for direction in directions {
+            let direction = direction.normalize();
+            let (theta, phi) = direction.to_spherical();
+            assert!((0.0..=std::f64::consts::PI).contains(&theta));
+            assert!((0.0..=2.0 * std::f64::consts::PI).contains(&phi));
+            assert!((from_spherical(theta, phi) - direction).magnitude() < 1e-12);
+        }
+        // Poles, where phi doesn't matter
+        assert_eq!(Vec3::new(0.0, -1.0, 0.0).to_spherical().0, 0.0);
+        assert!(
+            (from_spherical(std::f64::consts::PI, 1.0) - Vec3::new(0.0, 1.0, 0.0)).magnitude()
+                < 1e-12
+        );
+    }
+    #[test]
+    fn test_project_and_reject() {
+        let a = Vec3::new(1., 1., 0.);
+        let axis = Vec3::new(1., 0., 0.);
+        assert_eq!(a.project_onto(&axis), Vec3::new(1., 0., 0.));
+        assert_eq!(a.reject_from(&axis), Vec3::new(0., 1., 0.));
+        assert_eq!(a.project_onto(&Vec3::default()), Vec3::default());
+        assert_eq!(a.reject_from(&Vec3::default()), a);
+    }
+    #[test]
+    fn test_sum_product_and_mean() {
+        let vectors = [
+            Vec3::new(1., 2., 3.),
+            Vec3::new(-4., 0.5, 1.),
+            Vec3::new(0., 1.5, -2.),
+        ];
+        let sum: Vec3 = vectors.iter().sum();
+        assert_eq!(sum, vectors[0] + vectors[1] + vectors[2]);
+        assert_eq!(vectors.into_iter().sum::<Vec3>(), sum);
+        let product: Vec3 = vectors.into_iter().product();
+        assert_eq!(product, vectors[0] * vectors[1] * vectors[2]);
+        assert_eq!(Vec3::mean(vectors.into_iter()), sum / 3.);
+        assert_eq!(Vec3::mean(std::iter::empty()), Vec3::default());
+    }
+    #[test]
+    fn test_recip() {
+        let a = Vec3::new(2., -4., 0.).recip();
+        assert_eq!(a.x(), 0.5);
+        assert_eq!(a.y(), -0.25);
+        assert_eq!(a.z(), f64::INFINITY);
+    }
 }